@@ -0,0 +1,58 @@
+use std::collections::BTreeMap;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use rustorrent::bencode::{BencodeString, BencodeValue};
+
+fn string(s: &str) -> BencodeValue {
+    BencodeValue::String(BencodeString::String(s.to_string()))
+}
+
+/// A metainfo file shaped like a real ~4GB torrent: a long piece hash string
+/// plus a few hundred file entries.
+fn torrent() -> BencodeValue {
+    let files = (0..500)
+        .map(|i| {
+            BencodeValue::Dict(BTreeMap::from([
+                ("length".to_string(), BencodeValue::Int(8 << 20)),
+                (
+                    "path".to_string(),
+                    BencodeValue::List(vec![string("disc"), string(&format!("track{}.flac", i))]),
+                ),
+            ]))
+        })
+        .collect();
+
+    let info = BencodeValue::Dict(BTreeMap::from([
+        ("name".to_string(), string("album")),
+        ("piece length".to_string(), BencodeValue::Int(256 << 10)),
+        (
+            "pieces".to_string(),
+            BencodeValue::String(BencodeString::Bytes(vec![0xab; 20 * 16_000])),
+        ),
+        ("files".to_string(), BencodeValue::List(files)),
+    ]));
+
+    BencodeValue::Dict(BTreeMap::from([
+        (
+            "announce".to_string(),
+            string("http://tracker.example/announce"),
+        ),
+        ("info".to_string(), info),
+    ]))
+}
+
+fn bencode(c: &mut Criterion) {
+    let value = torrent();
+    let encoded = value.encode();
+
+    let mut group = c.benchmark_group("bencode");
+    group.throughput(Throughput::Bytes(encoded.len() as u64));
+    group.bench_function("parse", |b| {
+        b.iter(|| BencodeValue::parse(black_box(&encoded)).unwrap())
+    });
+    group.bench_function("encode", |b| b.iter(|| black_box(&value).encode()));
+    group.finish();
+}
+
+criterion_group!(benches, bencode);
+criterion_main!(benches);
@@ -0,0 +1,47 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rustorrent::client::bitfield::Bitfield;
+
+const NUM_PIECES: usize = 16_000;
+
+fn bitfield(c: &mut Criterion) {
+    let bytes = (0..NUM_PIECES.div_ceil(8))
+        .map(|i| (i * 37) as u8)
+        .collect::<Vec<u8>>();
+    let bitfield = Bitfield::from_bytes(&bytes, NUM_PIECES).unwrap();
+
+    let mut group = c.benchmark_group("bitfield");
+    group.bench_function("from_bytes", |b| {
+        b.iter(|| Bitfield::from_bytes(black_box(&bytes), NUM_PIECES))
+    });
+    group.bench_function("to_bytes", |b| b.iter(|| black_box(&bitfield).to_bytes()));
+    group.bench_function("set_all", |b| {
+        b.iter(|| {
+            let mut bitfield = Bitfield::new(NUM_PIECES);
+            for i in 0..NUM_PIECES {
+                bitfield.set(i, true).unwrap();
+            }
+            bitfield
+        })
+    });
+    group.bench_function("count_set", |b| {
+        b.iter(|| {
+            (0..NUM_PIECES)
+                .filter(|&i| black_box(&bitfield).is_set(i).unwrap())
+                .count()
+        })
+    });
+    group.bench_function("count_ones", |b| {
+        b.iter(|| black_box(&bitfield).count_ones())
+    });
+    group.bench_function("interested", |b| {
+        let ours = !&bitfield;
+        b.iter(|| {
+            let (theirs, ours): (&Bitfield, &Bitfield) = black_box((&bitfield, &ours));
+            (theirs & &!ours).any()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bitfield);
+criterion_main!(benches);
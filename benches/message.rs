@@ -0,0 +1,59 @@
+use bytes::{Bytes, BytesMut};
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use futures::executor::block_on;
+use rustorrent::client::message::{
+    receive_message, send_message, Message, MessageCodec, MessageId,
+};
+
+const BLOCK_SIZE: usize = 16 * 1024;
+
+fn piece_message() -> Message {
+    let mut payload = Vec::with_capacity(8 + BLOCK_SIZE);
+    payload.extend_from_slice(&7u32.to_be_bytes());
+    payload.extend_from_slice(&(3 * BLOCK_SIZE as u32).to_be_bytes());
+    payload.extend(std::iter::repeat_n(0x5a, BLOCK_SIZE));
+    Message::new(MessageId::Piece, Bytes::from(payload))
+}
+
+fn serialize(message: &Message) -> Vec<u8> {
+    let mut buf = Vec::new();
+    // writing into a Vec never pends, so there is no need for a runtime
+    if block_on(send_message(&mut buf, message)).is_err() {
+        unreachable!("writing to a Vec cannot fail");
+    }
+    buf
+}
+
+fn message(c: &mut Criterion) {
+    let piece = piece_message();
+    let request = Message::new(MessageId::Request, Bytes::from(vec![0; 12]));
+    let encoded_piece = serialize(&piece);
+    let encoded_request = serialize(&request);
+
+    let mut group = c.benchmark_group("message");
+    group.throughput(Throughput::Bytes(encoded_piece.len() as u64));
+    group.bench_function("send_piece", |b| b.iter(|| serialize(black_box(&piece))));
+    group.bench_function("receive_piece", |b| {
+        b.iter(|| block_on(receive_message(&mut black_box(encoded_piece.as_slice()))).unwrap())
+    });
+    group.bench_function("decode_piece", |b| {
+        let codec = MessageCodec::default();
+        b.iter(|| {
+            let mut buf = BytesMut::from(black_box(encoded_piece.as_slice()));
+            codec.decode(&mut buf).unwrap()
+        })
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group("message");
+    group.bench_function("send_request", |b| {
+        b.iter(|| serialize(black_box(&request)))
+    });
+    group.bench_function("receive_request", |b| {
+        b.iter(|| block_on(receive_message(&mut black_box(encoded_request.as_slice()))).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, message);
+criterion_main!(benches);
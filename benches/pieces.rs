@@ -0,0 +1,103 @@
+use std::collections::BTreeMap;
+
+use bytes::Bytes;
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use rustorrent::{
+    bencode::{BencodeString, BencodeValue},
+    client::{file_manager::FileManager, hasher::PieceHasher},
+    config::ClientConfig,
+    metainfo::Metainfo,
+};
+use sha1::{Digest, Sha1};
+
+const PIECE_LENGTH: usize = 256 * 1024;
+const BLOCK_SIZE: usize = 16 * 1024;
+const NUM_PIECES: usize = 16;
+
+fn metainfo(data: &[u8]) -> Metainfo {
+    let pieces = data
+        .chunks(PIECE_LENGTH)
+        .flat_map(|piece| Sha1::digest(piece).to_vec())
+        .collect();
+
+    let info = BencodeValue::Dict(BTreeMap::from([
+        (
+            "name".to_string(),
+            BencodeValue::String(BencodeString::String("bench.bin".to_string())),
+        ),
+        ("length".to_string(), BencodeValue::Int(data.len() as i64)),
+        (
+            "piece length".to_string(),
+            BencodeValue::Int(PIECE_LENGTH as i64),
+        ),
+        (
+            "pieces".to_string(),
+            BencodeValue::String(BencodeString::Bytes(pieces)),
+        ),
+    ]));
+
+    let torrent = BencodeValue::Dict(BTreeMap::from([
+        (
+            "announce".to_string(),
+            BencodeValue::String(BencodeString::String("http://localhost/".to_string())),
+        ),
+        ("info".to_string(), info),
+    ]));
+    Metainfo::new(torrent).ok().unwrap()
+}
+
+fn pieces(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let data = (0..PIECE_LENGTH * NUM_PIECES)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<u8>>();
+    let metainfo = metainfo(&data);
+    let hashes = metainfo.get_peices().clone();
+    let mut file_manager = FileManager::new(
+        dir.path().to_str().unwrap().to_string(),
+        &metainfo.info,
+        &ClientConfig::default(),
+    )
+    .unwrap();
+
+    let piece = Bytes::copy_from_slice(&data[..PIECE_LENGTH]);
+    let mut group = c.benchmark_group("pieces");
+    group.throughput(Throughput::Bytes(PIECE_LENGTH as u64));
+    group.bench_function("write_piece", |b| {
+        b.iter(|| {
+            for begin in (0..PIECE_LENGTH).step_by(BLOCK_SIZE) {
+                let block = piece.slice(begin..begin + BLOCK_SIZE);
+                file_manager.save_block(0, begin as u32, block).unwrap();
+            }
+        })
+    });
+
+    for (index, hash) in hashes.iter().enumerate() {
+        let begin = index * PIECE_LENGTH;
+        let piece = Bytes::copy_from_slice(&data[begin..begin + PIECE_LENGTH]);
+        file_manager.save_block(index, 0, piece).unwrap();
+        assert!(file_manager.verify_piece(index, hash));
+    }
+    group.bench_function("verify_piece", |b| {
+        b.iter(|| file_manager.verify_piece(black_box(3), &hashes[3]))
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group("recheck");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.bench_function("serial", |b| {
+        b.iter(|| {
+            hashes
+                .iter()
+                .enumerate()
+                .all(|(index, hash)| file_manager.verify_piece(index, hash))
+        })
+    });
+    let (results, _) = futures::channel::mpsc::unbounded();
+    let hasher = PieceHasher::new(0, file_manager.clone(), results);
+    group.bench_function("pool", |b| b.iter(|| hasher.verify_all(&hashes)));
+    group.finish();
+}
+
+criterion_group!(benches, pieces);
+criterion_main!(benches);
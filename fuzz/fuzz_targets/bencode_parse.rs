@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rustorrent::bencode::BencodeValue;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((value, _)) = BencodeValue::parse(data) {
+        // whatever we accept must survive a round trip
+        let (reparsed, _) = BencodeValue::parse(&value.encode()).unwrap();
+        assert_eq!(reparsed, value);
+    }
+});
@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rustorrent::client::validate_handshake;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 20 {
+        return;
+    }
+    let (info_hash, handshake) = data.split_at(20);
+    if let Ok(peer_id) = validate_handshake(handshake, info_hash) {
+        assert_eq!(peer_id.len(), 20);
+    }
+});
@@ -0,0 +1,13 @@
+#![no_main]
+
+use futures::executor::block_on;
+use libfuzzer_sys::fuzz_target;
+use rustorrent::client::message::receive_message;
+
+fuzz_target!(|data: &[u8]| {
+    // reading from a slice never pends, so no runtime is needed
+    let mut stream = data;
+    while let Ok(message) = block_on(receive_message(&mut stream)) {
+        let _ = message.get_payload();
+    }
+});
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rustorrent::tracker::Tracker;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Tracker::parse_response(data);
+});
@@ -16,9 +16,9 @@ fn encode_string(text: &str) -> Vec<u8> {
     format!("{}:{}", text.len(), text).into_bytes()
 }
 
-fn encode_bytes(bytes: &Vec<u8>) -> Vec<u8> {
+fn encode_bytes(bytes: &[u8]) -> Vec<u8> {
     let mut result = format!("{}:", bytes.len()).into_bytes();
-    result.extend_from_slice(&bytes);
+    result.extend_from_slice(bytes);
     result
 }
 
@@ -26,7 +26,7 @@ fn encode_int(int: &i64) -> Vec<u8> {
     format!("i{}e", int).into_bytes()
 }
 
-fn encode_list(list: &Vec<BencodeValue>) -> Vec<u8> {
+fn encode_list(list: &[BencodeValue]) -> Vec<u8> {
     let mut result = Vec::new();
     result.push(b'l');
     for item in list {
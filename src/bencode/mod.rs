@@ -3,6 +3,10 @@ use std::{collections::BTreeMap, fmt::Display};
 
 mod encoder;
 mod parser;
+mod pretty;
+mod span;
+
+pub use self::span::Span;
 
 #[derive(Debug, PartialEq)]
 pub struct ParseError {
@@ -16,12 +20,23 @@ impl Display for ParseError {
     }
 }
 
+impl std::error::Error for ParseError {}
+
 #[derive(Debug, PartialEq)]
 pub enum BencodeString {
     String(String),
     Bytes(Vec<u8>),
 }
 
+impl BencodeString {
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            BencodeString::String(string) => string.as_bytes(),
+            BencodeString::Bytes(bytes) => bytes,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum BencodeValue {
     String(BencodeString),
@@ -63,14 +78,203 @@ impl BencodeValue {
         encoder::encode_bencode(self)
     }
 
-    pub fn parse(data: &Vec<u8>) -> Result<(BencodeValue, Vec<u8>), ParseError> {
+    pub fn parse(data: &[u8]) -> Result<(BencodeValue, Vec<u8>), ParseError> {
         parser::parse_bencode(data)
     }
 
+    /// Parses like [`BencodeValue::parse`], also returning where the value
+    /// and everything in it sit in `data`.
+    pub fn parse_spanned(data: &[u8]) -> Result<(BencodeValue, Span, Vec<u8>), ParseError> {
+        parser::parse_spanned(data, 0, false)
+    }
+
+    /// Parses only canonical bencode: integers need digits and a closing
+    /// `e`, and dictionary keys must be unique and sorted. Meant for checking
+    /// torrents we write; use [`BencodeValue::parse`] for what peers and
+    /// trackers send.
+    pub fn parse_strict(data: &[u8]) -> Result<(BencodeValue, Vec<u8>), ParseError> {
+        let (value, _, rest) = parser::parse_spanned(data, 0, true)?;
+        Ok((value, rest))
+    }
+
     pub fn get_value(&self, key: &str) -> Option<&BencodeValue> {
         match self {
             BencodeValue::Dict(dict) => dict.get(key),
             _ => None,
         }
     }
+
+    /// Follows dot-separated keys through nested dictionaries, as in
+    /// `info.pieces`.
+    pub fn get_dict_path(&self, path: &str) -> Option<&BencodeValue> {
+        path.split('.')
+            .try_fold(self, |value, key| value.get_value(key))
+    }
+
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.get_value(key)?.as_str()
+    }
+
+    pub fn get_bytes(&self, key: &str) -> Option<&[u8]> {
+        self.get_value(key)?.as_bytes()
+    }
+
+    pub fn get_int(&self, key: &str) -> Option<i64> {
+        self.get_value(key)?.as_int()
+    }
+
+    pub fn get_list(&self, key: &str) -> Option<&[BencodeValue]> {
+        self.get_value(key)?.as_list()
+    }
+
+    pub fn get_dict(&self, key: &str) -> Option<&BTreeMap<String, BencodeValue>> {
+        self.get_value(key)?.as_dict()
+    }
+
+    /// The string, if it is valid UTF-8.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            BencodeValue::String(BencodeString::String(s)) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Any string, UTF-8 or not.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            BencodeValue::String(s) => Some(s.as_bytes()),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            BencodeValue::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[BencodeValue]> {
+        match self {
+            BencodeValue::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<String, BencodeValue>> {
+        match self {
+            BencodeValue::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for BencodeValue {
+    fn from(s: &str) -> Self {
+        BencodeValue::String(BencodeString::String(s.to_string()))
+    }
+}
+
+impl From<String> for BencodeValue {
+    fn from(s: String) -> Self {
+        BencodeValue::String(BencodeString::String(s))
+    }
+}
+
+impl From<&[u8]> for BencodeValue {
+    fn from(bytes: &[u8]) -> Self {
+        BencodeValue::String(BencodeString::Bytes(bytes.to_vec()))
+    }
+}
+
+impl From<Vec<u8>> for BencodeValue {
+    fn from(bytes: Vec<u8>) -> Self {
+        BencodeValue::String(BencodeString::Bytes(bytes))
+    }
+}
+
+macro_rules! from_int {
+    ($($int:ty),*) => {
+        $(
+            impl From<$int> for BencodeValue {
+                fn from(i: $int) -> Self {
+                    BencodeValue::Int(i as i64)
+                }
+            }
+        )*
+    };
+}
+
+from_int!(i32, i64, u16, u32, u64, usize);
+
+impl From<Vec<BencodeValue>> for BencodeValue {
+    fn from(list: Vec<BencodeValue>) -> Self {
+        BencodeValue::List(list)
+    }
+}
+
+impl From<BTreeMap<String, BencodeValue>> for BencodeValue {
+    fn from(dict: BTreeMap<String, BencodeValue>) -> Self {
+        BencodeValue::Dict(dict)
+    }
+}
+
+/// Builds a [`BencodeValue::Dict`], converting each value with
+/// [`BencodeValue::from`].
+///
+/// ```
+/// use rustorrent::{bencode::BencodeValue, dict};
+///
+/// let value = dict! {
+///     "interval" => 1800,
+///     "peers" => vec![1u8, 2, 3, 4, 0x1a, 0xe1],
+/// };
+/// assert_eq!(value.get_int("interval"), Some(1800));
+/// ```
+#[macro_export]
+macro_rules! dict {
+    ($($key:expr => $value:expr),* $(,)?) => {
+        $crate::bencode::BencodeValue::Dict(::std::collections::BTreeMap::from([
+            $((
+                ::std::string::ToString::to_string(&$key),
+                $crate::bencode::BencodeValue::from($value),
+            )),*
+        ]))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_typed_values_and_nested_paths() {
+        let value = crate::dict! {
+            "announce" => "http://tracker/announce",
+            "info" => crate::dict! {
+                "piece length" => 16_384,
+                "pieces" => vec![0xffu8; 20],
+            },
+            "urls" => vec![BencodeValue::from("a"), BencodeValue::from("b")],
+        };
+
+        assert_eq!(value.get_str("announce"), Some("http://tracker/announce"));
+        assert_eq!(value.get_int("announce"), None);
+        assert_eq!(value.get_list("urls").map(<[_]>::len), Some(2));
+        assert_eq!(
+            value
+                .get_dict_path("info.piece length")
+                .and_then(BencodeValue::as_int),
+            Some(16_384)
+        );
+        assert_eq!(value.get_dict("info").map(BTreeMap::len), Some(2));
+        assert_eq!(
+            value
+                .get_value("info")
+                .and_then(|info| info.get_bytes("pieces")),
+            Some(&[0xff; 20][..])
+        );
+        assert_eq!(value.get_dict_path("info.missing"), None);
+        assert_eq!(value.get_dict_path("announce.deeper"), None);
+    }
 }
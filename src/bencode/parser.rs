@@ -1,8 +1,11 @@
 use std::collections::BTreeMap;
 
-use super::{BencodeString, BencodeValue, ParseError};
+use super::{
+    span::{Span, SpanItems},
+    BencodeString, BencodeValue, ParseError,
+};
 
-fn parse_string(input: &Vec<u8>) -> Result<(BencodeString, Vec<u8>), ParseError> {
+fn parse_string(input: &[u8]) -> Result<(BencodeString, Vec<u8>), ParseError> {
     let mut length = 0;
     let mut i = 0;
     while let Some(char) = input.get(i) {
@@ -38,8 +41,8 @@ fn parse_string(input: &Vec<u8>) -> Result<(BencodeString, Vec<u8>), ParseError>
     Ok((str, input[i + 1 + length..].to_vec()))
 }
 
-fn parse_int(input: &Vec<u8>) -> Result<(i64, Vec<u8>), ParseError> {
-    if input.get(0) != Some(&b'i') {
+fn parse_int(input: &[u8], strict: bool) -> Result<(i64, Vec<u8>), ParseError> {
+    if input.first() != Some(&b'i') {
         return Err(ParseError {
             value: String::from_utf8_lossy(input).to_string(),
             message: String::from("Bencode Integer must start with 'i'"),
@@ -80,7 +83,21 @@ fn parse_int(input: &Vec<u8>) -> Result<(i64, Vec<u8>), ParseError> {
         }
 
         if char.is_ascii_digit() {
-            int = int * 10 + (*char - b'0') as i64;
+            let digit = (*char - b'0') as i64;
+            // built up negative so that i64::MIN fits
+            int = int
+                .checked_mul(10)
+                .and_then(|int| {
+                    if is_negative {
+                        int.checked_sub(digit)
+                    } else {
+                        int.checked_add(digit)
+                    }
+                })
+                .ok_or_else(|| ParseError {
+                    value: String::from_utf8_lossy(input).to_string(),
+                    message: "Bencode Integer out of range".to_string(),
+                })?;
         } else {
             return Err(ParseError {
                 value: String::from_utf8_lossy(input).to_string(),
@@ -91,15 +108,31 @@ fn parse_int(input: &Vec<u8>) -> Result<(i64, Vec<u8>), ParseError> {
         i += 1;
     }
 
-    if is_negative {
-        int = -int;
+    if strict && i == starting_index {
+        return Err(ParseError {
+            value: String::from_utf8_lossy(input).to_string(),
+            message: "Bencode Integer has no digits".to_string(),
+        });
     }
 
-    Ok((int, input[i + 1..].to_vec()))
+    match input.get(i + 1..) {
+        Some(rest) => Ok((int, rest.to_vec())),
+        None if !strict => Ok((int, Vec::new())),
+        None => Err(ParseError {
+            value: String::from_utf8_lossy(input).to_string(),
+            message: "Bencode Integer must end with 'e'".to_string(),
+        }),
+    }
 }
 
-fn parse_list(input: &Vec<u8>) -> Result<(Vec<BencodeValue>, Vec<u8>), ParseError> {
-    if input.get(0) != Some(&b'l') {
+type SpannedList = (Vec<BencodeValue>, Vec<Span>, Vec<u8>);
+
+fn parse_spanned_list(
+    input: &[u8],
+    offset: usize,
+    strict: bool,
+) -> Result<SpannedList, ParseError> {
+    if input.first() != Some(&b'l') {
         return Err(ParseError {
             value: String::from_utf8_lossy(input).to_string(),
             message: String::from("Bencode List must start with 'l'"),
@@ -108,14 +141,17 @@ fn parse_list(input: &Vec<u8>) -> Result<(Vec<BencodeValue>, Vec<u8>), ParseErro
 
     let mut rest = input[1..].to_vec();
     let mut list = Vec::new();
-    while let Some(char) = rest.get(0) {
+    let mut spans = Vec::new();
+    while let Some(char) = rest.first() {
         if *char == b'e' {
-            return Ok((list, rest[1..].to_vec()));
+            return Ok((list, spans, rest[1..].to_vec()));
         }
 
-        let (value, updated_rest) = parse_bencode(&rest)?;
+        let start = offset + input.len() - rest.len();
+        let (value, span, updated_rest) = parse_spanned(&rest, start, strict)?;
         rest = updated_rest;
         list.push(value);
+        spans.push(span);
     }
 
     Err(ParseError {
@@ -124,8 +160,18 @@ fn parse_list(input: &Vec<u8>) -> Result<(Vec<BencodeValue>, Vec<u8>), ParseErro
     })
 }
 
-fn parse_dict(input: &Vec<u8>) -> Result<(BTreeMap<String, BencodeValue>, Vec<u8>), ParseError> {
-    if input.get(0) != Some(&b'd') {
+type SpannedDict = (
+    BTreeMap<String, BencodeValue>,
+    BTreeMap<String, Span>,
+    Vec<u8>,
+);
+
+fn parse_spanned_dict(
+    input: &[u8],
+    offset: usize,
+    strict: bool,
+) -> Result<SpannedDict, ParseError> {
+    if input.first() != Some(&b'd') {
         return Err(ParseError {
             value: String::from_utf8_lossy(input).to_string(),
             message: String::from("Bencode Dict must start with 'd'"),
@@ -134,17 +180,33 @@ fn parse_dict(input: &Vec<u8>) -> Result<(BTreeMap<String, BencodeValue>, Vec<u8
 
     let mut rest = input[1..].to_vec();
     let mut dict = BTreeMap::new();
-    while let Some(char) = rest.get(0) {
+    let mut spans = BTreeMap::new();
+    let mut last_key: Option<BencodeString> = None;
+    while let Some(char) = rest.first() {
         if *char == b'e' {
-            return Ok((dict, rest[1..].to_vec()));
+            return Ok((dict, spans, rest[1..].to_vec()));
         }
 
         let (key, key_rest) = parse_string(&rest)?;
-        let (value, updated_rest) = parse_bencode(&key_rest)?;
-        match key {
-            BencodeString::String(s) => dict.insert(s, value),
-            BencodeString::Bytes(b) => dict.insert(String::from_utf8_lossy(&b).to_string(), value),
-        };
+        if strict {
+            let previous = last_key.as_ref().map(BencodeString::as_bytes);
+            if previous >= Some(key.as_bytes()) {
+                return Err(ParseError {
+                    value: String::from_utf8_lossy(key.as_bytes()).to_string(),
+                    message: if previous == Some(key.as_bytes()) {
+                        "Duplicate Bencode Dict key".to_string()
+                    } else {
+                        "Bencode Dict keys out of order".to_string()
+                    },
+                });
+            }
+        }
+        let start = offset + input.len() - key_rest.len();
+        let (value, span, updated_rest) = parse_spanned(&key_rest, start, strict)?;
+        let name = String::from_utf8_lossy(key.as_bytes()).to_string();
+        spans.insert(name.clone(), span);
+        dict.insert(name, value);
+        last_key = Some(key);
 
         rest = updated_rest;
     }
@@ -155,39 +217,62 @@ fn parse_dict(input: &Vec<u8>) -> Result<(BTreeMap<String, BencodeValue>, Vec<u8
     })
 }
 
-pub fn parse_bencode(input: &Vec<u8>) -> Result<(BencodeValue, Vec<u8>), ParseError> {
-    match input.get(0) {
-        Some(char) => match char {
-            b'i' => {
-                let (int, rest) = parse_int(input)?;
-                Ok((BencodeValue::Int(int), rest))
-            }
-            b'l' => {
-                let (list, rest) = parse_list(input)?;
-                Ok((BencodeValue::List(list), rest))
-            }
-            b'd' => {
-                let (dict, rest) = parse_dict(input)?;
-                Ok((BencodeValue::Dict(dict), rest))
-            }
-            _ => {
-                let (string, rest) = parse_string(input)?;
-                Ok((BencodeValue::String(string), rest))
-            }
-        },
+pub fn parse_bencode(input: &[u8]) -> Result<(BencodeValue, Vec<u8>), ParseError> {
+    let (value, _, rest) = parse_spanned(input, 0, false)?;
+    Ok((value, rest))
+}
+
+/// Parses a value that starts `offset` bytes into the whole input, noting
+/// where it and everything in it sit. Strict parsing also rejects integers
+/// without digits or a terminator, and dictionaries whose keys are repeated
+/// or out of order.
+pub fn parse_spanned(
+    input: &[u8],
+    offset: usize,
+    strict: bool,
+) -> Result<(BencodeValue, Span, Vec<u8>), ParseError> {
+    let (value, items, rest) = match input.first() {
+        Some(b'i') => {
+            let (int, rest) = parse_int(input, strict)?;
+            (BencodeValue::Int(int), SpanItems::None, rest)
+        }
+        Some(b'l') => {
+            let (list, spans, rest) = parse_spanned_list(input, offset, strict)?;
+            (BencodeValue::List(list), SpanItems::List(spans), rest)
+        }
+        Some(b'd') => {
+            let (dict, spans, rest) = parse_spanned_dict(input, offset, strict)?;
+            (BencodeValue::Dict(dict), SpanItems::Dict(spans), rest)
+        }
+        Some(_) => {
+            let (string, rest) = parse_string(input)?;
+            (BencodeValue::String(string), SpanItems::None, rest)
+        }
         None => {
             return Err(ParseError {
                 value: String::from_utf8_lossy(input).to_string(),
                 message: String::from("Invalid Bencode Value"),
             })
         }
-    }
+    };
+    let span = Span::new(offset..offset + input.len() - rest.len(), items);
+    Ok((value, span, rest))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn parse_list(input: &[u8]) -> Result<(Vec<BencodeValue>, Vec<u8>), ParseError> {
+        let (list, _, rest) = parse_spanned_list(input, 0, false)?;
+        Ok((list, rest))
+    }
+
+    fn parse_dict(input: &[u8]) -> Result<(BTreeMap<String, BencodeValue>, Vec<u8>), ParseError> {
+        let (dict, _, rest) = parse_spanned_dict(input, 0, false)?;
+        Ok((dict, rest))
+    }
+
     fn to_byte_vec(s: &str) -> Vec<u8> {
         s.bytes().collect::<Vec<u8>>()
     }
@@ -233,13 +318,16 @@ mod tests {
 
     #[test]
     fn test_parse_int() {
-        assert_eq!(Ok((3, Vec::new())), parse_int(&to_byte_vec("i3e")));
-        assert_eq!(Ok((-3, Vec::new())), parse_int(&to_byte_vec("i-3e")));
-        assert_eq!(Ok((0, Vec::new())), parse_int(&to_byte_vec("i0e")));
-        assert_eq!(Ok((4096, Vec::new())), parse_int(&to_byte_vec("i4096e")));
+        assert_eq!(Ok((3, Vec::new())), parse_int(&to_byte_vec("i3e"), false));
+        assert_eq!(Ok((-3, Vec::new())), parse_int(&to_byte_vec("i-3e"), false));
+        assert_eq!(Ok((0, Vec::new())), parse_int(&to_byte_vec("i0e"), false));
+        assert_eq!(
+            Ok((4096, Vec::new())),
+            parse_int(&to_byte_vec("i4096e"), false)
+        );
         assert_eq!(
             Ok((0, to_byte_vec("4:spam"))),
-            parse_int(&to_byte_vec("i0e4:spam"))
+            parse_int(&to_byte_vec("i0e4:spam"), false)
         );
 
         assert_eq!(
@@ -247,28 +335,28 @@ mod tests {
                 value: "i02e".to_string(),
                 message: "Integer cannot be prefixed with 0".to_string()
             }),
-            parse_int(&to_byte_vec("i02e"))
+            parse_int(&to_byte_vec("i02e"), false)
         );
         assert_eq!(
             Err(ParseError {
                 value: "i-0e".to_string(),
                 message: "Invalid Bencode Integer".to_string()
             }),
-            parse_int(&to_byte_vec("i-0e"))
+            parse_int(&to_byte_vec("i-0e"), false)
         );
         assert_eq!(
             Err(ParseError {
                 value: "i-02e".to_string(),
                 message: "Invalid Bencode Integer".to_string()
             }),
-            parse_int(&to_byte_vec("i-02e"))
+            parse_int(&to_byte_vec("i-02e"), false)
         );
         assert_eq!(
             Err(ParseError {
                 value: "iinvalide".to_string(),
                 message: "Could not parse Bencode Integer".to_string()
             }),
-            parse_int(&to_byte_vec("iinvalide"))
+            parse_int(&to_byte_vec("iinvalide"), false)
         );
     }
 
@@ -389,4 +477,98 @@ mod tests {
             parse_dict(&to_byte_vec("d"))
         );
     }
+
+    #[test]
+    fn test_parse_spanned() {
+        // keys out of order, which re-encoding would sort
+        let input = to_byte_vec("d4:infod1:bi1e1:ai2ee4:listl3:eggi3eee");
+        let (value, span, rest) = parse_spanned(&input, 0, false).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(span.range(), 0..input.len());
+        assert_eq!(span.raw(&input), &input[..]);
+
+        let info = span.get("info").unwrap();
+        assert_eq!(info.raw(&input), b"d1:bi1e1:ai2ee");
+        assert_ne!(value.get_value("info").unwrap().encode(), info.raw(&input));
+        assert_eq!(info.get("a").unwrap().raw(&input), b"i2e");
+
+        let list = span.get("list").unwrap();
+        assert_eq!(list.index(0).unwrap().raw(&input), b"3:egg");
+        assert_eq!(
+            span.get_path("list").unwrap().index(1).unwrap().raw(&input),
+            b"i3e"
+        );
+        assert_eq!(span.get_path("info.b").unwrap().range(), 11..14);
+        assert!(span.get("missing").is_none());
+        assert!(list.get("egg").is_none());
+    }
+
+    #[test]
+    fn test_parse_int_range() {
+        assert_eq!(
+            Ok((i64::MAX, Vec::new())),
+            parse_int(&to_byte_vec("i9223372036854775807e"), false)
+        );
+        assert_eq!(
+            Ok((i64::MIN, Vec::new())),
+            parse_int(&to_byte_vec("i-9223372036854775808e"), false)
+        );
+        for input in [
+            "i9223372036854775808e",
+            "i-9223372036854775809e",
+            "i99999999999999999999e",
+        ] {
+            assert_eq!(
+                Err("Bencode Integer out of range".to_string()),
+                parse_int(&to_byte_vec(input), false).map_err(|e| e.message),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_trailing_data() {
+        assert_eq!(
+            Ok((BencodeValue::List(vec![]), to_byte_vec("e"))),
+            parse_bencode(&to_byte_vec("lee"))
+        );
+        assert_eq!(
+            Ok((BencodeValue::Dict(BTreeMap::new()), to_byte_vec("le"))),
+            parse_bencode(&to_byte_vec("dele"))
+        );
+        assert_eq!(
+            Ok((BencodeValue::Int(1), to_byte_vec("de"))),
+            parse_bencode(&to_byte_vec("i1ede"))
+        );
+        assert!(parse_bencode(&to_byte_vec("l")).is_err());
+        assert!(parse_bencode(&to_byte_vec("d")).is_err());
+    }
+
+    #[test]
+    fn test_strict_mode() {
+        let strict = |s: &str| parse_spanned(&to_byte_vec(s), 0, true).map(|(v, _, _)| v);
+        let lenient = |s: &str| parse_spanned(&to_byte_vec(s), 0, false).map(|(v, _, _)| v);
+
+        // accepted leniently, rejected strictly
+        for input in ["i12", "ie", "i-e", "d1:bi1e1:ai2ee", "d1:ai1e1:ai2ee"] {
+            assert!(lenient(input).is_ok(), "{}", input);
+            assert!(strict(input).is_err(), "{}", input);
+        }
+        assert_eq!(Ok(BencodeValue::Int(12)), lenient("i12"));
+        assert_eq!(
+            Ok(BencodeValue::Dict(BTreeMap::from([(
+                "a".to_string(),
+                BencodeValue::Int(2)
+            )]))),
+            lenient("d1:ai1e1:ai2ee")
+        );
+        assert_eq!(
+            Err("Duplicate Bencode Dict key".to_string()),
+            strict("d1:ai1e1:ai2ee").map_err(|e| e.message)
+        );
+
+        let canonical = "d1:ai1e1:bli2eli-3eee2:bbde1:c0:e";
+        assert_eq!(lenient(canonical), strict(canonical));
+    }
 }
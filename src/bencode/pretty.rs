@@ -0,0 +1,168 @@
+use std::fmt::Write;
+
+use super::{BencodeString, BencodeValue};
+
+/// Bytes of a binary string shown before the rest is elided.
+const BYTES_SHOWN: usize = 16;
+
+impl BencodeValue {
+    /// An indented, JSON-like dump for reading. Binary strings are shown as
+    /// their length and leading bytes in hex.
+    pub fn to_pretty(&self) -> String {
+        let mut out = String::new();
+        write_pretty(&mut out, self, 0);
+        out
+    }
+
+    /// Converts to JSON. Dictionaries become objects and binary strings
+    /// become hex strings.
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        write_json(&mut out, self);
+        out
+    }
+}
+
+fn write_pretty(out: &mut String, value: &BencodeValue, depth: usize) {
+    let indent = "  ".repeat(depth + 1);
+    match value {
+        BencodeValue::String(BencodeString::String(s)) => write_json_string(out, s),
+        BencodeValue::String(BencodeString::Bytes(bytes)) => {
+            let shown = hex(&bytes[..bytes.len().min(BYTES_SHOWN)]);
+            let more = if bytes.len() > BYTES_SHOWN { "..." } else { "" };
+            let _ = write!(out, "<{} bytes: {}{}>", bytes.len(), shown, more);
+        }
+        BencodeValue::Int(i) => {
+            let _ = write!(out, "{}", i);
+        }
+        BencodeValue::List(list) if list.is_empty() => out.push_str("[]"),
+        BencodeValue::List(list) => {
+            out.push_str("[\n");
+            for (i, item) in list.iter().enumerate() {
+                out.push_str(&indent);
+                write_pretty(out, item, depth + 1);
+                out.push_str(if i + 1 < list.len() { ",\n" } else { "\n" });
+            }
+            out.push_str(&indent[2..]);
+            out.push(']');
+        }
+        BencodeValue::Dict(dict) if dict.is_empty() => out.push_str("{}"),
+        BencodeValue::Dict(dict) => {
+            out.push_str("{\n");
+            for (i, (key, item)) in dict.iter().enumerate() {
+                out.push_str(&indent);
+                write_json_string(out, key);
+                out.push_str(": ");
+                write_pretty(out, item, depth + 1);
+                out.push_str(if i + 1 < dict.len() { ",\n" } else { "\n" });
+            }
+            out.push_str(&indent[2..]);
+            out.push('}');
+        }
+    }
+}
+
+fn write_json(out: &mut String, value: &BencodeValue) {
+    match value {
+        BencodeValue::String(BencodeString::String(s)) => write_json_string(out, s),
+        BencodeValue::String(BencodeString::Bytes(bytes)) => write_json_string(out, &hex(bytes)),
+        BencodeValue::Int(i) => {
+            let _ = write!(out, "{}", i);
+        }
+        BencodeValue::List(list) => {
+            out.push('[');
+            for (i, item) in list.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_json(out, item);
+            }
+            out.push(']');
+        }
+        BencodeValue::Dict(dict) => {
+            out.push('{');
+            for (i, (key, item)) in dict.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_json_string(out, key);
+                out.push(':');
+                write_json(out, item);
+            }
+            out.push('}');
+        }
+    }
+}
+
+fn write_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn sample() -> BencodeValue {
+        BencodeValue::Dict(BTreeMap::from([
+            (
+                "name".to_string(),
+                BencodeValue::String(BencodeString::String("a \"b\"\n".to_string())),
+            ),
+            (
+                "pieces".to_string(),
+                BencodeValue::String(BencodeString::Bytes((0..20).collect())),
+            ),
+            (
+                "sizes".to_string(),
+                BencodeValue::List(vec![BencodeValue::Int(1), BencodeValue::Int(-2)]),
+            ),
+            ("empty".to_string(), BencodeValue::List(Vec::new())),
+        ]))
+    }
+
+    #[test]
+    fn pretty_prints_nested_values() {
+        let expected = [
+            "{",
+            "  \"empty\": [],",
+            "  \"name\": \"a \\\"b\\\"\\n\",",
+            "  \"pieces\": <20 bytes: 000102030405060708090a0b0c0d0e0f...>,",
+            "  \"sizes\": [",
+            "    1,",
+            "    -2",
+            "  ]",
+            "}",
+        ]
+        .join("\n");
+        assert_eq!(sample().to_pretty(), expected);
+    }
+
+    #[test]
+    fn converts_to_json() {
+        assert_eq!(
+            sample().to_json(),
+            "{\"empty\":[],\"name\":\"a \\\"b\\\"\\n\",\
+             \"pieces\":\"000102030405060708090a0b0c0d0e0f10111213\",\"sizes\":[1,-2]}"
+        );
+    }
+}
@@ -0,0 +1,56 @@
+use std::{collections::BTreeMap, ops::Range};
+
+/// Where a parsed value sits in the bytes it was parsed from. Re-encoding a
+/// value may not give back those bytes, since byte strings that are valid
+/// UTF-8 become strings and dictionaries come out sorted, so anything that
+/// hashes or signs part of a file should take that part through its span.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    range: Range<usize>,
+    items: SpanItems,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum SpanItems {
+    None,
+    List(Vec<Span>),
+    Dict(BTreeMap<String, Span>),
+}
+
+impl Span {
+    pub(super) fn new(range: Range<usize>, items: SpanItems) -> Self {
+        Self { range, items }
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.range.clone()
+    }
+
+    /// The value's original bytes, given the input it was parsed from.
+    pub fn raw<'a>(&self, input: &'a [u8]) -> &'a [u8] {
+        &input[self.range()]
+    }
+
+    /// The span of `key`'s value, if this is a dictionary.
+    pub fn get(&self, key: &str) -> Option<&Span> {
+        match &self.items {
+            SpanItems::Dict(spans) => spans.get(key),
+            _ => None,
+        }
+    }
+
+    /// The span of the item at `index`, if this is a list.
+    pub fn index(&self, index: usize) -> Option<&Span> {
+        match &self.items {
+            SpanItems::List(spans) => spans.get(index),
+            _ => None,
+        }
+    }
+
+    /// Follows dot-separated keys through nested dictionaries, as
+    /// [`BencodeValue::get_dict_path`](super::BencodeValue::get_dict_path)
+    /// does.
+    pub fn get_path(&self, path: &str) -> Option<&Span> {
+        path.split('.').try_fold(self, |span, key| span.get(key))
+    }
+}
@@ -1,9 +1,15 @@
 use core::fmt;
-use std::fmt::{Debug, Display, Formatter};
+use std::{
+    fmt::{Debug, Display, Formatter},
+    ops::{BitAnd, BitOr, Not},
+};
 
-#[derive(Debug)]
+/// One bit per piece, packed eight to a byte with the first piece in the
+/// high bit, as on the wire.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Bitfield {
-    bitfield: Vec<bool>,
+    bytes: Vec<u8>,
+    len: usize,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -22,71 +28,164 @@ impl Display for OutOfBoundsError {
     }
 }
 
+impl std::error::Error for OutOfBoundsError {}
+
+/// Why bytes received as a bitfield can't be one.
+#[derive(Debug, PartialEq, Eq)]
+pub enum BitfieldError {
+    /// The byte count doesn't match the number of pieces.
+    Length { len: usize, expected: usize },
+    /// A bit past the last piece is set.
+    SpareBits,
+}
+
+impl Display for BitfieldError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            BitfieldError::Length { len, expected } => {
+                write!(f, "Bitfield of {} bytes, expected {}", len, expected)
+            }
+            BitfieldError::SpareBits => write!(f, "Bitfield has spare bits set"),
+        }
+    }
+}
+
+impl std::error::Error for BitfieldError {}
+
 impl Bitfield {
     pub fn new(size: usize) -> Self {
-        let bitfield = vec![false; size];
-        Self { bitfield }
+        Self {
+            bytes: vec![0; size.div_ceil(8)],
+            len: size,
+        }
     }
 
     pub fn len(&self) -> usize {
-        self.bitfield.len()
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
     }
 
-    pub fn iter(&self) -> std::slice::Iter<bool> {
-        self.bitfield.iter()
+    pub fn count_ones(&self) -> usize {
+        self.bytes.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    /// Whether any bit is set.
+    pub fn any(&self) -> bool {
+        self.bytes.iter().any(|&b| b != 0)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = bool> + '_ {
+        (0..self.len).map(|i| self.bit(i))
     }
 
     pub fn set(&mut self, index: usize, value: bool) -> Result<(), OutOfBoundsError> {
-        if index >= self.bitfield.len() {
+        if index >= self.len {
             return Err(OutOfBoundsError {
                 index,
-                len: self.len(),
+                len: self.len,
             });
         }
-        self.bitfield[index] = value;
+        let mask = 0x80 >> (index % 8);
+        if value {
+            self.bytes[index / 8] |= mask;
+        } else {
+            self.bytes[index / 8] &= !mask;
+        }
         Ok(())
     }
 
     pub fn is_set(&self, index: usize) -> Result<bool, OutOfBoundsError> {
-        if index >= self.bitfield.len() {
+        if index >= self.len {
             return Err(OutOfBoundsError {
                 index,
-                len: self.len(),
+                len: self.len,
             });
         }
+        Ok(self.bit(index))
+    }
 
-        Ok(self.bitfield[index])
+    fn bit(&self, index: usize) -> bool {
+        self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        for chunk in self.bitfield.chunks(8) {
-            let mut byte = 0;
-            for (i, &bit) in chunk.iter().enumerate() {
-                if bit {
-                    byte |= 1 << (7 - i);
-                }
-            }
-            bytes.push(byte);
+        self.bytes.clone()
+    }
+
+    /// Reads a bitfield of `len` bits as sent by a peer, which must be
+    /// exactly long enough and leave every spare bit clear.
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Result<Self, BitfieldError> {
+        let expected = len.div_ceil(8);
+        if bytes.len() != expected {
+            return Err(BitfieldError::Length {
+                len: bytes.len(),
+                expected,
+            });
         }
-        bytes
-    }
-
-    pub fn from_bytes(bytes: &[u8], len: usize) -> Self {
-        let mut bitfield = Bitfield::new(len);
-        for (i, &byte) in bytes.iter().enumerate() {
-            for j in 0..8 {
-                if i * 8 + j >= len {
-                    break;
-                }
-                // coolio: right shift the byte to get the desired bit to the rightmost position
-                // then bitwise AND with 1 to get *only* the bit value removing leading bits
-                // then check if that bit is set
-                let bit = (byte >> (7 - j)) & 1 == 1;
-                bitfield.set(i * 8 + j, bit).unwrap();
-            }
+        let bitfield = Self {
+            bytes: bytes.to_vec(),
+            len,
+        };
+        if bitfield.spare_bits() != 0 {
+            return Err(BitfieldError::SpareBits);
         }
-        bitfield
+        Ok(bitfield)
+    }
+
+    /// The bits of the last byte past the end of the bitfield.
+    fn spare_bits(&self) -> u8 {
+        match (self.bytes.last(), self.len % 8) {
+            (Some(&last), used) if used > 0 => last & (0xff >> used),
+            _ => 0,
+        }
+    }
+
+    fn zip_with(&self, other: &Bitfield, op: impl Fn(u8, u8) -> u8) -> Bitfield {
+        debug_assert_eq!(self.len, other.len);
+        Bitfield {
+            bytes: self
+                .bytes
+                .iter()
+                .zip(&other.bytes)
+                .map(|(&a, &b)| op(a, b))
+                .collect(),
+            len: self.len.min(other.len),
+        }
+    }
+}
+
+impl BitAnd for &Bitfield {
+    type Output = Bitfield;
+
+    fn bitand(self, other: &Bitfield) -> Bitfield {
+        self.zip_with(other, |a, b| a & b)
+    }
+}
+
+impl BitOr for &Bitfield {
+    type Output = Bitfield;
+
+    fn bitor(self, other: &Bitfield) -> Bitfield {
+        self.zip_with(other, |a, b| a | b)
+    }
+}
+
+impl Not for &Bitfield {
+    type Output = Bitfield;
+
+    fn not(self) -> Bitfield {
+        let mut inverted = Bitfield {
+            bytes: self.bytes.iter().map(|b| !b).collect(),
+            len: self.len,
+        };
+        let spare = inverted.spare_bits();
+        if let Some(last) = inverted.bytes.last_mut() {
+            *last &= !spare;
+        }
+        inverted
     }
 }
 
@@ -98,16 +197,16 @@ mod tests {
     fn test_bitfield() {
         let mut bitfield = Bitfield::new(10);
         assert_eq!(bitfield.len(), 10);
-        assert_eq!(bitfield.is_set(0).unwrap(), false);
-        assert_eq!(bitfield.is_set(1).unwrap(), false);
-        assert_eq!(bitfield.is_set(2).unwrap(), false);
-        assert_eq!(bitfield.is_set(3).unwrap(), false);
-        assert_eq!(bitfield.is_set(4).unwrap(), false);
-        assert_eq!(bitfield.is_set(5).unwrap(), false);
-        assert_eq!(bitfield.is_set(6).unwrap(), false);
-        assert_eq!(bitfield.is_set(7).unwrap(), false);
-        assert_eq!(bitfield.is_set(8).unwrap(), false);
-        assert_eq!(bitfield.is_set(9).unwrap(), false);
+        assert!(!bitfield.is_set(0).unwrap());
+        assert!(!bitfield.is_set(1).unwrap());
+        assert!(!bitfield.is_set(2).unwrap());
+        assert!(!bitfield.is_set(3).unwrap());
+        assert!(!bitfield.is_set(4).unwrap());
+        assert!(!bitfield.is_set(5).unwrap());
+        assert!(!bitfield.is_set(6).unwrap());
+        assert!(!bitfield.is_set(7).unwrap());
+        assert!(!bitfield.is_set(8).unwrap());
+        assert!(!bitfield.is_set(9).unwrap());
 
         bitfield.set(0, true).unwrap();
         bitfield.set(1, true).unwrap();
@@ -120,16 +219,16 @@ mod tests {
         bitfield.set(8, true).unwrap();
         bitfield.set(9, true).unwrap();
 
-        assert_eq!(bitfield.is_set(0).unwrap(), true);
-        assert_eq!(bitfield.is_set(1).unwrap(), true);
-        assert_eq!(bitfield.is_set(2).unwrap(), true);
-        assert_eq!(bitfield.is_set(3).unwrap(), true);
-        assert_eq!(bitfield.is_set(4).unwrap(), true);
-        assert_eq!(bitfield.is_set(5).unwrap(), true);
-        assert_eq!(bitfield.is_set(6).unwrap(), true);
-        assert_eq!(bitfield.is_set(7).unwrap(), true);
-        assert_eq!(bitfield.is_set(8).unwrap(), true);
-        assert_eq!(bitfield.is_set(9).unwrap(), true);
+        assert!(bitfield.is_set(0).unwrap());
+        assert!(bitfield.is_set(1).unwrap());
+        assert!(bitfield.is_set(2).unwrap());
+        assert!(bitfield.is_set(3).unwrap());
+        assert!(bitfield.is_set(4).unwrap());
+        assert!(bitfield.is_set(5).unwrap());
+        assert!(bitfield.is_set(6).unwrap());
+        assert!(bitfield.is_set(7).unwrap());
+        assert!(bitfield.is_set(8).unwrap());
+        assert!(bitfield.is_set(9).unwrap());
 
         let bytes = bitfield.to_bytes();
         assert_eq!(bytes, vec![0b11111111, 0b11000000]);
@@ -154,16 +253,55 @@ mod tests {
     #[test]
     fn test_from_bytes() {
         let bytes = vec![0b11101110, 0b11000000];
-        let bitfield = Bitfield::from_bytes(&bytes, 10);
-        assert_eq!(bitfield.is_set(0).unwrap(), true);
-        assert_eq!(bitfield.is_set(1).unwrap(), true);
-        assert_eq!(bitfield.is_set(2).unwrap(), true);
-        assert_eq!(bitfield.is_set(3).unwrap(), false);
-        assert_eq!(bitfield.is_set(4).unwrap(), true);
-        assert_eq!(bitfield.is_set(5).unwrap(), true);
-        assert_eq!(bitfield.is_set(6).unwrap(), true);
-        assert_eq!(bitfield.is_set(7).unwrap(), false);
-        assert_eq!(bitfield.is_set(8).unwrap(), true);
-        assert_eq!(bitfield.is_set(9).unwrap(), true);
+        let bitfield = Bitfield::from_bytes(&bytes, 10).unwrap();
+        assert!(bitfield.is_set(0).unwrap());
+        assert!(bitfield.is_set(1).unwrap());
+        assert!(bitfield.is_set(2).unwrap());
+        assert!(!bitfield.is_set(3).unwrap());
+        assert!(bitfield.is_set(4).unwrap());
+        assert!(bitfield.is_set(5).unwrap());
+        assert!(bitfield.is_set(6).unwrap());
+        assert!(!bitfield.is_set(7).unwrap());
+        assert!(bitfield.is_set(8).unwrap());
+        assert!(bitfield.is_set(9).unwrap());
+    }
+
+    #[test]
+    fn rejects_wrong_lengths_and_spare_bits() {
+        assert_eq!(
+            Bitfield::from_bytes(&[0b11000000], 10),
+            Err(BitfieldError::Length {
+                len: 1,
+                expected: 2
+            })
+        );
+        assert_eq!(
+            Bitfield::from_bytes(&[0, 0, 0], 10),
+            Err(BitfieldError::Length {
+                len: 3,
+                expected: 2
+            })
+        );
+        assert_eq!(
+            Bitfield::from_bytes(&[0, 0b00100000], 10),
+            Err(BitfieldError::SpareBits)
+        );
+        assert!(Bitfield::from_bytes(&[0xff], 8).is_ok());
+    }
+
+    #[test]
+    fn combines_bitfields() {
+        let ours = Bitfield::from_bytes(&[0b10100000, 0b10000000], 10).unwrap();
+        let theirs = Bitfield::from_bytes(&[0b11000000, 0b11000000], 10).unwrap();
+
+        assert_eq!((&ours & &theirs).to_bytes(), [0b10000000, 0b10000000]);
+        assert_eq!((&ours | &theirs).to_bytes(), [0b11100000, 0b11000000]);
+        // spare bits stay clear
+        assert_eq!((!&ours).to_bytes(), [0b01011111, 0b01000000]);
+
+        let wanted = &theirs & &!&ours;
+        assert_eq!(wanted.count_ones(), 2);
+        assert!(wanted.any());
+        assert!(!(&ours & &!&ours).any());
     }
 }
@@ -0,0 +1,189 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Poll, Waker},
+};
+
+use futures::future::poll_fn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryKind {
+    /// Messages read off a socket that the coordinator hasn't handled yet.
+    Receive,
+    /// Blocks held in memory until their piece is complete.
+    PieceAssembly,
+    DiskCache,
+    /// Blocks queued to be sent to peers.
+    Upload,
+}
+
+impl MemoryKind {
+    const COUNT: usize = 4;
+
+    fn index(self) -> usize {
+        match self {
+            MemoryKind::Receive => 0,
+            MemoryKind::PieceAssembly => 1,
+            MemoryKind::DiskCache => 2,
+            MemoryKind::Upload => 3,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    limit: usize,
+    used: AtomicUsize,
+    used_by: [AtomicUsize; MemoryKind::COUNT],
+    waiters: Mutex<Vec<Waker>>,
+}
+
+/// Shared accounting of the bytes the client holds in memory. Holders of
+/// memory take a [`Reservation`] for it; once the total goes over the limit,
+/// peers stop reading their sockets and the coordinator stops requesting
+/// blocks until enough reservations are dropped.
+///
+/// The limit is soft: every peer may overshoot it by at most one message.
+#[derive(Debug, Clone)]
+pub struct MemoryBudget {
+    inner: Arc<Inner>,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                limit,
+                used: AtomicUsize::new(0),
+                used_by: Default::default(),
+                waiters: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.inner.limit
+    }
+
+    pub fn used(&self) -> usize {
+        self.inner.used.load(Ordering::Acquire)
+    }
+
+    pub fn used_by(&self, kind: MemoryKind) -> usize {
+        self.inner.used_by[kind.index()].load(Ordering::Acquire)
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.used() >= self.inner.limit
+    }
+
+    /// Accounts for `bytes` the caller already holds.
+    pub fn reserve(&self, kind: MemoryKind, bytes: usize) -> Reservation {
+        self.inner.used_by[kind.index()].fetch_add(bytes, Ordering::AcqRel);
+        self.inner.used.fetch_add(bytes, Ordering::AcqRel);
+        Reservation {
+            budget: self.clone(),
+            kind,
+            bytes,
+        }
+    }
+
+    /// Resolves once usage is back under the limit.
+    pub fn available(&self) -> impl Future<Output = ()> + '_ {
+        poll_fn(|cx| {
+            if !self.is_exhausted() {
+                return Poll::Ready(());
+            }
+
+            self.inner.waiters.lock().unwrap().push(cx.waker().clone());
+            // a reservation may have been dropped before we registered
+            if self.is_exhausted() {
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        })
+    }
+
+    fn release(&self, kind: MemoryKind, bytes: usize) {
+        self.inner.used_by[kind.index()].fetch_sub(bytes, Ordering::AcqRel);
+        let used = self.inner.used.fetch_sub(bytes, Ordering::AcqRel) - bytes;
+        if used < self.inner.limit {
+            let waiters = std::mem::take(&mut *self.inner.waiters.lock().unwrap());
+            for waker in waiters {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Memory counted against a [`MemoryBudget`], given back on drop.
+#[derive(Debug)]
+pub struct Reservation {
+    budget: MemoryBudget,
+    kind: MemoryKind,
+    bytes: usize,
+}
+
+impl Reservation {
+    pub fn len(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes == 0
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.release(self.kind, self.bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use futures::executor::block_on;
+
+    use super::*;
+
+    #[test]
+    fn test_reserve_and_release() {
+        let budget = MemoryBudget::new(100);
+        let receive = budget.reserve(MemoryKind::Receive, 60);
+        let upload = budget.reserve(MemoryKind::Upload, 30);
+        assert_eq!(budget.used(), 90);
+        assert_eq!(budget.used_by(MemoryKind::Receive), 60);
+        assert_eq!(budget.used_by(MemoryKind::Upload), 30);
+        assert!(!budget.is_exhausted());
+
+        let more = budget.reserve(MemoryKind::Receive, 20);
+        assert!(budget.is_exhausted());
+
+        drop(receive);
+        drop(more);
+        assert_eq!(budget.used(), 30);
+        assert_eq!(budget.used_by(MemoryKind::Receive), 0);
+        assert!(!budget.is_exhausted());
+        assert_eq!(upload.len(), 30);
+    }
+
+    #[test]
+    fn test_available_waits_for_release() {
+        let budget = MemoryBudget::new(10);
+        let reservation = budget.reserve(MemoryKind::Receive, 10);
+
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(reservation);
+        });
+        block_on(budget.available());
+        assert_eq!(budget.used(), 0);
+        releaser.join().unwrap();
+    }
+}
@@ -0,0 +1,99 @@
+use std::{
+    fmt::Display,
+    ops::{BitAnd, BitOr},
+};
+
+/// Protocol extensions a peer supports, as set in the 8 reserved bytes of its
+/// handshake. Bits we don't know are kept, so they can be told apart from
+/// ones that are off.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct PeerCapabilities(u64);
+
+impl PeerCapabilities {
+    /// The Mainline DHT (BEP 5): the peer accepts Port messages.
+    pub const DHT: Self = Self(1);
+    /// The Fast Extension (BEP 6).
+    pub const FAST: Self = Self(1 << 2);
+    /// The Extension Protocol (BEP 10).
+    pub const EXTENSION_PROTOCOL: Self = Self(1 << 20);
+
+    const NAMED: [(Self, &'static str); 3] = [
+        (Self::DHT, "dht"),
+        (Self::FAST, "fast"),
+        (Self::EXTENSION_PROTOCOL, "extension protocol"),
+    ];
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub fn from_reserved(reserved: [u8; 8]) -> Self {
+        Self(u64::from_be_bytes(reserved))
+    }
+
+    pub fn to_reserved(self) -> [u8; 8] {
+        self.0.to_be_bytes()
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl BitOr for PeerCapabilities {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// What both ends of a connection support.
+impl BitAnd for PeerCapabilities {
+    type Output = Self;
+
+    fn bitand(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+/// Names the extensions we know, separated by commas.
+impl Display for PeerCapabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names = Self::NAMED
+            .iter()
+            .filter(|(flag, _)| self.contains(*flag))
+            .map(|(_, name)| *name)
+            .collect::<Vec<_>>();
+        f.write_str(&names.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_and_writes_reserved_bytes() {
+        // as sent by a client with the DHT, Fast and Extension Protocol bits
+        let reserved = [0, 0, 0, 0, 0, 0x10, 0, 0x05];
+        let capabilities = PeerCapabilities::from_reserved(reserved);
+        assert!(capabilities.contains(PeerCapabilities::DHT));
+        assert!(capabilities.contains(PeerCapabilities::FAST));
+        assert!(capabilities.contains(PeerCapabilities::EXTENSION_PROTOCOL));
+        assert_eq!(capabilities.to_reserved(), reserved);
+        assert_eq!(capabilities.to_string(), "dht, fast, extension protocol");
+
+        let ours = PeerCapabilities::DHT;
+        assert_eq!(capabilities & ours, PeerCapabilities::DHT);
+        assert!(PeerCapabilities::from_reserved([0; 8]).is_empty());
+        // unknown bits survive a round trip
+        let unknown = PeerCapabilities::from_reserved([0x80, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(unknown.to_reserved()[0], 0x80);
+        assert_eq!(unknown.to_string(), "");
+    }
+}
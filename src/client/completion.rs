@@ -0,0 +1,32 @@
+//! What the client does once a download finishes, as the config asks.
+
+use std::{path::Path, process::Command, thread};
+
+use tracing::{debug, warn};
+
+use crate::metainfo::InfoHash;
+
+/// Starts the `on_complete` program for the torrent saved at `path`, without
+/// waiting for it. How it exits is only logged.
+pub(super) fn run_hook(command: &str, name: &str, info_hash: &InfoHash, path: &Path) {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let child = Command::new(command)
+        .env("RUSTORRENT_NAME", name)
+        .env("RUSTORRENT_INFO_HASH", info_hash.to_hex())
+        .env("RUSTORRENT_DIR", dir)
+        .env("RUSTORRENT_PATH", path)
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            warn!(command, error = %e, "failed to run completion hook");
+            return;
+        }
+    };
+    let command = command.to_string();
+    thread::spawn(move || match child.wait() {
+        Ok(status) if status.success() => debug!(command, "completion hook finished"),
+        Ok(status) => warn!(command, %status, "completion hook failed"),
+        Err(e) => warn!(command, error = %e, "completion hook failed"),
+    });
+}
@@ -0,0 +1,114 @@
+//! Cross-seeding: finding the files of a new torrent among data already on
+//! disk, such as another torrent's download of the same release, so it can
+//! be seeded without downloading it again. Files are matched by length and
+//! checked against the new torrent's piece hashes, so they can be laid out
+//! or named differently than the torrent has them.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use sha1::{Digest, Sha1};
+use tracing::debug;
+
+use crate::{config::ClientConfig, metainfo::Info};
+
+use super::{file_manager::local_paths, ClientError};
+
+/// An existing file with the contents of one of the torrent's.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileMatch {
+    /// Where the client expects the file.
+    pub target: PathBuf,
+    pub source: PathBuf,
+}
+
+/// Looks under `data_dir` for each file of the torrent, as it would be laid
+/// out under `output_dir`. A candidate of the right length is checked
+/// against the first piece lying wholly inside the file; files too small to
+/// hold a piece are matched by length and name alone, and left for a
+/// recheck to confirm.
+pub fn find_matches(
+    info: &Info,
+    data_dir: &Path,
+    output_dir: &str,
+    config: &ClientConfig,
+) -> Result<Vec<FileMatch>, ClientError> {
+    let (piece_length, pieces) = match info {
+        Info::SingleFile(info) => (info.base_info.piece_length, &info.base_info.pieces),
+        Info::MultiFile(info) => (info.base_info.piece_length, &info.base_info.pieces),
+    };
+    let targets = local_paths(output_dir, info, config.sanitize_paths)?;
+    let total_length = targets.iter().map(|(_, length)| length).sum::<u64>();
+
+    let mut existing = Vec::new();
+    crate::metainfo::walk(data_dir, &mut existing)?;
+    let mut by_length = HashMap::<u64, Vec<PathBuf>>::new();
+    for (path, length) in existing {
+        by_length.entry(length).or_default().push(path);
+    }
+
+    let mut matches = Vec::new();
+    let mut offset = 0;
+    for (target, length) in targets {
+        let start = offset;
+        offset += length;
+        let Some(candidates) = by_length.get(&length).filter(|_| length > 0) else {
+            continue;
+        };
+
+        let first_piece = start.div_ceil(piece_length);
+        let piece_start = first_piece * piece_length;
+        let piece_end = (piece_start + piece_length).min(total_length);
+        let inner_piece = pieces
+            .get(first_piece as usize)
+            .filter(|_| piece_start < piece_end && piece_end <= start + length);
+        let source = if let Some(hash) = inner_piece {
+            let mut buf = vec![0; (piece_end - piece_start) as usize];
+            candidates.iter().find(|candidate| {
+                let read = File::open(candidate).and_then(|mut file| {
+                    file.seek(SeekFrom::Start(piece_start - start))?;
+                    file.read_exact(&mut buf)
+                });
+                read.is_ok() && Sha1::digest(&buf).as_slice() == hash.as_slice()
+            })
+        } else {
+            candidates
+                .iter()
+                .find(|candidate| candidate.file_name() == target.file_name())
+                .or(candidates.first())
+        };
+        if let Some(source) = source {
+            debug!(target = %target.display(), source = %source.display(), "found existing file");
+            matches.push(FileMatch {
+                target,
+                source: source.clone(),
+            });
+        }
+    }
+    Ok(matches)
+}
+
+/// Puts each matched file where the client expects it, as a hard link or a
+/// copy where linking isn't possible. Files already there are left alone.
+/// Returns how many were put in place.
+pub fn link_matches(matches: &[FileMatch]) -> io::Result<usize> {
+    let mut linked = 0;
+    for FileMatch { target, source } in matches {
+        if target.exists() {
+            continue;
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // a hard link can't cross filesystems
+        if fs::hard_link(source, target).is_err() {
+            fs::copy(source, target)?;
+        }
+        linked += 1;
+    }
+    Ok(linked)
+}
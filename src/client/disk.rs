@@ -0,0 +1,406 @@
+use std::{
+    collections::VecDeque,
+    io,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self as std_mpsc, Receiver, Sender},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use bytes::{Bytes, BytesMut};
+use futures::channel::{mpsc, oneshot};
+use tracing::{debug, trace, warn};
+
+use super::file_manager::{DiskError, FileManager};
+
+/// Most jobs taken off the queue in one go.
+const MAX_BATCH: usize = 64;
+/// Tries at a write before its blocks are reported as failed, when the error
+/// looks like it could pass.
+const WRITE_ATTEMPTS: u32 = 3;
+const WRITE_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+#[derive(Debug)]
+pub enum DiskResult {
+    Written {
+        index: usize,
+        begin: u32,
+        length: u32,
+        result: Result<(), DiskError>,
+    },
+    Read {
+        peer_id: Vec<u8>,
+        index: usize,
+        begin: u32,
+        result: Result<Bytes, DiskError>,
+    },
+}
+
+enum DiskJob {
+    Write {
+        index: usize,
+        begin: u32,
+        data: Bytes,
+    },
+    Read {
+        peer_id: Vec<u8>,
+        index: usize,
+        begin: u32,
+        length: u32,
+    },
+    Flush(oneshot::Sender<io::Result<()>>),
+}
+
+/// Runs file reads and writes on a dedicated thread so the coordinator never
+/// blocks on the disk. Jobs are queued and handled in order, with results
+/// sent back on a channel.
+///
+/// Reads go through an LRU cache of whole pieces, so peers asking for the
+/// same hot pieces don't send each request to the disk.
+#[derive(Debug)]
+pub struct DiskIo {
+    jobs: Sender<DiskJob>,
+    stats: Arc<DiskStats>,
+}
+
+#[derive(Debug, Default)]
+struct DiskStats {
+    /// Reads and writes queued but not done yet.
+    queued: AtomicUsize,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+}
+
+impl DiskIo {
+    /// Up to `read_cache_size` bytes of pieces are kept for reads.
+    pub fn new(
+        file_manager: FileManager,
+        read_cache_size: usize,
+        results: mpsc::UnboundedSender<DiskResult>,
+    ) -> Self {
+        let (jobs, queue) = std_mpsc::channel();
+        let stats = Arc::new(DiskStats::default());
+        let worker = DiskWorker::new(file_manager, read_cache_size, results, Arc::clone(&stats));
+        thread::Builder::new()
+            .name("disk-io".to_string())
+            .spawn(move || worker.run(queue))
+            .expect("failed to start disk thread");
+        Self { jobs, stats }
+    }
+
+    pub fn write(&self, index: usize, begin: u32, data: Bytes) {
+        self.stats.queued.fetch_add(1, Ordering::Relaxed);
+        let _ = self.jobs.send(DiskJob::Write { index, begin, data });
+    }
+
+    pub fn read(&self, peer_id: Vec<u8>, index: usize, begin: u32, length: u32) {
+        self.stats.queued.fetch_add(1, Ordering::Relaxed);
+        let _ = self.jobs.send(DiskJob::Read {
+            peer_id,
+            index,
+            begin,
+            length,
+        });
+    }
+
+    /// Resolves once every job queued so far is done and the files are synced.
+    /// Their results are on the results channel by then.
+    pub async fn flush(&self) -> io::Result<()> {
+        let (tx, rx) = oneshot::channel();
+        if self.jobs.send(DiskJob::Flush(tx)).is_err() {
+            return Err(io::Error::other("disk thread stopped"));
+        }
+        rx.await
+            .unwrap_or_else(|_| Err(io::Error::other("disk thread stopped")))
+    }
+
+    /// Number of reads and writes waiting on the disk thread.
+    pub fn queue_depth(&self) -> usize {
+        self.stats.queued.load(Ordering::Relaxed)
+    }
+
+    /// Reads answered from the read cache, and reads that had to go to disk.
+    pub fn cache_stats(&self) -> (u64, u64) {
+        (
+            self.stats.cache_hits.load(Ordering::Relaxed),
+            self.stats.cache_misses.load(Ordering::Relaxed),
+        )
+    }
+}
+
+struct DiskWorker {
+    file_manager: FileManager,
+    results: mpsc::UnboundedSender<DiskResult>,
+    /// Recently read pieces, most recent last.
+    cache: VecDeque<(usize, Bytes)>,
+    cache_size: usize,
+    cached: usize,
+    stats: Arc<DiskStats>,
+}
+
+impl DiskWorker {
+    fn new(
+        file_manager: FileManager,
+        cache_size: usize,
+        results: mpsc::UnboundedSender<DiskResult>,
+        stats: Arc<DiskStats>,
+    ) -> Self {
+        Self {
+            file_manager,
+            results,
+            cache: VecDeque::new(),
+            cache_size,
+            cached: 0,
+            stats,
+        }
+    }
+
+    /// Handles jobs until every [`DiskIo`] is dropped.
+    fn run(mut self, queue: Receiver<DiskJob>) {
+        while let Ok(job) = queue.recv() {
+            let mut batch = vec![job];
+            batch.extend(queue.try_iter().take(MAX_BATCH - 1));
+            trace!(jobs = batch.len(), "disk batch");
+            self.handle_batch(batch);
+        }
+        debug!("disk thread stopped");
+    }
+
+    /// Writes that follow on from each other are coalesced into one call.
+    /// Jobs otherwise keep their order, so a read or flush sees every write
+    /// queued before it.
+    fn handle_batch(&mut self, batch: Vec<DiskJob>) {
+        let mut run: Vec<(usize, u32, Bytes)> = Vec::new();
+        let jobs = batch
+            .iter()
+            .filter(|job| !matches!(job, DiskJob::Flush(_)))
+            .count();
+        for job in batch {
+            match job {
+                DiskJob::Write { index, begin, data } => {
+                    let contiguous = run.last().is_some_and(|(last_index, last_begin, last)| {
+                        *last_index == index && last_begin + last.len() as u32 == begin
+                    });
+                    if !contiguous {
+                        self.write_run(&mut run);
+                    }
+                    run.push((index, begin, data));
+                }
+                DiskJob::Read {
+                    peer_id,
+                    index,
+                    begin,
+                    length,
+                } => {
+                    self.write_run(&mut run);
+                    let result = self.read(index, begin, length);
+                    let _ = self.results.unbounded_send(DiskResult::Read {
+                        peer_id,
+                        index,
+                        begin,
+                        result,
+                    });
+                }
+                DiskJob::Flush(done) => {
+                    self.write_run(&mut run);
+                    let _ = done.send(self.file_manager.sync());
+                }
+            }
+        }
+        self.write_run(&mut run);
+        self.stats.queued.fetch_sub(jobs, Ordering::Relaxed);
+    }
+
+    fn write_run(&mut self, run: &mut Vec<(usize, u32, Bytes)>) {
+        let Some(&(index, begin, _)) = run.first() else {
+            return;
+        };
+        self.evict(index);
+
+        let data = if run.len() == 1 {
+            run[0].2.clone()
+        } else {
+            let mut data = BytesMut::new();
+            for (_, _, block) in run.iter() {
+                data.extend_from_slice(block);
+            }
+            data.freeze()
+        };
+        let mut attempt = 1;
+        let result = loop {
+            match self.file_manager.save_block(index, begin, data.clone()) {
+                Err(e) if e.is_transient() && attempt < WRITE_ATTEMPTS => {
+                    debug!(piece = index, begin, attempt, error = %e, "retrying disk write");
+                    thread::sleep(WRITE_RETRY_DELAY * attempt);
+                    attempt += 1;
+                }
+                result => break result,
+            }
+        };
+        if let Err(e) = &result {
+            warn!(piece = index, begin, error = %e, "disk write failed");
+        }
+
+        for (index, begin, data) in run.drain(..) {
+            let _ = self.results.unbounded_send(DiskResult::Written {
+                index,
+                begin,
+                length: data.len() as u32,
+                result: result.clone(),
+            });
+        }
+    }
+
+    /// Drops piece `index` from the read cache, as it is being written.
+    fn evict(&mut self, index: usize) {
+        if let Some(position) = self.cache.iter().position(|(cached, _)| *cached == index) {
+            if let Some((_, piece)) = self.cache.remove(position) {
+                self.cached -= piece.len();
+            }
+        }
+    }
+
+    fn read(&mut self, index: usize, begin: u32, length: u32) -> Result<Bytes, DiskError> {
+        let position = self.cache.iter().position(|(cached, _)| *cached == index);
+        let piece = match position.and_then(|i| self.cache.remove(i)) {
+            Some((_, piece)) => {
+                self.stats.cache_hits.fetch_add(1, Ordering::Relaxed);
+                self.cached -= piece.len();
+                piece
+            }
+            None => {
+                self.stats.cache_misses.fetch_add(1, Ordering::Relaxed);
+                Bytes::from(self.file_manager.read_piece(index)?)
+            }
+        };
+        if piece.len() <= self.cache_size {
+            while self.cached + piece.len() > self.cache_size {
+                let Some((_, evicted)) = self.cache.pop_front() else {
+                    break;
+                };
+                self.cached -= evicted.len();
+            }
+            self.cached += piece.len();
+            self.cache.push_back((index, piece.clone()));
+        }
+
+        let (start, end) = (begin as usize, begin as usize + length as usize);
+        if end > piece.len() {
+            return Err(DiskError::OutOfRange(begin as u64));
+        }
+        Ok(piece.slice(start..end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use futures::{executor::block_on, StreamExt};
+
+    use super::*;
+    use crate::{
+        bencode::{BencodeString, BencodeValue},
+        config::{ClientConfig, FileAllocation},
+        metainfo::Metainfo,
+    };
+
+    fn file_manager(dir: &str, length: usize) -> FileManager {
+        let info = BencodeValue::Dict(BTreeMap::from([
+            (
+                "name".to_string(),
+                BencodeValue::String(BencodeString::String("data.bin".to_string())),
+            ),
+            ("length".to_string(), BencodeValue::Int(length as i64)),
+            ("piece length".to_string(), BencodeValue::Int(1024)),
+            (
+                "pieces".to_string(),
+                BencodeValue::String(BencodeString::Bytes(vec![0; 20 * length.div_ceil(1024)])),
+            ),
+        ]));
+        let torrent = BencodeValue::Dict(BTreeMap::from([
+            (
+                "announce".to_string(),
+                BencodeValue::String(BencodeString::String("http://localhost/".to_string())),
+            ),
+            ("info".to_string(), info),
+        ]));
+        FileManager::new(
+            dir.to_string(),
+            &Metainfo::new(torrent).unwrap().info,
+            &ClientConfig::builder()
+                .file_allocation(FileAllocation::None)
+                .build(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn reads_see_earlier_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, mut rx) = mpsc::unbounded();
+        let disk = DiskIo::new(file_manager(dir.path().to_str().unwrap(), 1024), 1024, tx);
+
+        disk.write(0, 0, Bytes::from(vec![1; 512]));
+        disk.write(0, 512, Bytes::from(vec![2; 512]));
+        disk.read(b"peer".to_vec(), 0, 256, 512);
+        block_on(disk.flush()).unwrap();
+        drop(disk);
+
+        let results = block_on(rx.by_ref().collect::<Vec<_>>());
+        assert_eq!(results.len(), 3);
+        let written = results
+            .iter()
+            .filter(|r| matches!(r, DiskResult::Written { result: Ok(()), .. }))
+            .count();
+        assert_eq!(written, 2);
+
+        let Some(DiskResult::Read {
+            peer_id,
+            result: Ok(block),
+            ..
+        }) = results.last()
+        else {
+            panic!("expected a read, got {:?}", results.last());
+        };
+        assert_eq!(peer_id, b"peer");
+        assert_eq!(&block[..256], &[1; 256]);
+        assert_eq!(&block[256..], &[2; 256]);
+    }
+
+    #[test]
+    fn caches_recently_read_pieces() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, rx) = mpsc::unbounded();
+        // room for two of the three pieces
+        let disk = DiskIo::new(file_manager(dir.path().to_str().unwrap(), 3072), 2048, tx);
+
+        for index in 0..3 {
+            disk.write(index, 0, Bytes::from(vec![0; 1024]));
+        }
+        for index in [0, 0, 1, 2, 0, 2] {
+            disk.read(b"peer".to_vec(), index, 0, 16);
+        }
+        block_on(disk.flush()).unwrap();
+        assert_eq!(disk.cache_stats(), (2, 4));
+
+        // a write drops the piece from the cache
+        disk.write(2, 0, Bytes::from(vec![1; 16]));
+        disk.read(b"peer".to_vec(), 2, 0, 16);
+        block_on(disk.flush()).unwrap();
+        assert_eq!(disk.cache_stats(), (2, 5));
+        drop(disk);
+
+        let results = block_on(rx.collect::<Vec<_>>());
+        let Some(DiskResult::Read {
+            result: Ok(block), ..
+        }) = results.last()
+        else {
+            panic!("expected a read, got {:?}", results.last());
+        };
+        assert_eq!(&block[..], &[1; 16]);
+    }
+}
@@ -0,0 +1,47 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use futures::channel::mpsc;
+
+/// Something that happened to a torrent, for applications that would rather
+/// react than poll snapshots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TorrentEvent {
+    PeerConnected(SocketAddr),
+    PeerDisconnected(SocketAddr),
+    /// A piece passed its hash check.
+    PieceCompleted(usize),
+    Paused,
+    Resumed,
+    /// Every piece is downloaded and verified.
+    Finished,
+    /// Followed by `Paused` when resuming could fix it, as when the disk
+    /// fills up; otherwise the torrent has stopped for good.
+    Failed(String),
+    /// Taken out of its session; nothing more will happen to it.
+    Removed,
+}
+
+/// Fans events out to any number of subscribers. Subscribers that have gone
+/// away are dropped on the next emit.
+#[derive(Debug, Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<mpsc::UnboundedSender<TorrentEvent>>>>,
+}
+
+impl EventBus {
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<TorrentEvent> {
+        let (tx, rx) = mpsc::unbounded();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    pub fn emit(&self, event: TorrentEvent) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.unbounded_send(event.clone()).is_ok());
+    }
+}
@@ -0,0 +1,146 @@
+//! The Extension Protocol (BEP 10): one message id under which peers send
+//! the messages of every extension they both support, each tagged with an
+//! id the receiver picked for it in its extended handshake.
+
+use std::{collections::BTreeMap, fmt::Display};
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::bencode::BencodeValue;
+
+use super::message::{Message, MessageId};
+
+/// The extended id of the handshake itself.
+pub const HANDSHAKE_ID: u8 = 0;
+pub const UT_HOLEPUNCH: &str = "ut_holepunch";
+/// The extended id we want hole punch messages sent to us under.
+pub const UT_HOLEPUNCH_ID: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtensionError(pub(super) String);
+
+impl Display for ExtensionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid extended message: {}", self.0)
+    }
+}
+
+impl std::error::Error for ExtensionError {}
+
+/// A message sent under the Extension Protocol's message id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtendedMsg {
+    /// The id the receiver gave the extension.
+    pub id: u8,
+    pub payload: Bytes,
+}
+
+impl ExtendedMsg {
+    pub fn parse(payload: &Bytes) -> Result<Self, ExtensionError> {
+        if payload.is_empty() {
+            return Err(ExtensionError("no extended id".to_string()));
+        }
+        Ok(Self {
+            id: payload[0],
+            payload: payload.slice(1..),
+        })
+    }
+
+    pub fn to_message(self) -> Message {
+        let mut payload = BytesMut::with_capacity(1 + self.payload.len());
+        payload.put_u8(self.id);
+        payload.extend_from_slice(&self.payload);
+        Message::new(MessageId::Extended, payload.freeze())
+    }
+}
+
+/// What a peer tells us about itself in its extended handshake.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtendedHandshake {
+    /// The id each extension the peer supports is to be sent under.
+    pub extensions: BTreeMap<String, u8>,
+    /// The client's name and version.
+    pub client: Option<String>,
+    /// The port the peer accepts connections on.
+    pub port: Option<u16>,
+}
+
+impl ExtendedHandshake {
+    pub fn parse(payload: &[u8]) -> Result<Self, ExtensionError> {
+        let (value, _) = BencodeValue::parse(payload).map_err(|e| ExtensionError(e.to_string()))?;
+        if value.as_dict().is_none() {
+            return Err(ExtensionError("handshake is not a dictionary".to_string()));
+        }
+        // an id of 0 turns an extension off
+        let extensions = value
+            .get_dict("m")
+            .map(|m| {
+                m.iter()
+                    .filter_map(|(name, id)| {
+                        let id = u8::try_from(id.as_int()?).ok()?;
+                        (id != HANDSHAKE_ID).then(|| (name.clone(), id))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(Self {
+            extensions,
+            client: value.get_str("v").map(str::to_string),
+            port: value
+                .get_int("p")
+                .and_then(|port| u16::try_from(port).ok())
+                .filter(|&port| port != 0),
+        })
+    }
+
+    pub fn to_message(&self) -> Message {
+        let extensions = self
+            .extensions
+            .iter()
+            .map(|(name, &id)| (name.clone(), BencodeValue::from(id as u32)))
+            .collect::<BTreeMap<_, _>>();
+        let mut handshake = BTreeMap::from([("m".to_string(), BencodeValue::from(extensions))]);
+        if let Some(client) = &self.client {
+            handshake.insert("v".to_string(), BencodeValue::from(client.as_str()));
+        }
+        if let Some(port) = self.port {
+            handshake.insert("p".to_string(), BencodeValue::from(port));
+        }
+        ExtendedMsg {
+            id: HANDSHAKE_ID,
+            payload: Bytes::from(BencodeValue::from(handshake).encode()),
+        }
+        .to_message()
+    }
+
+    /// The id to send `extension`'s messages under, if the peer supports it.
+    pub fn id(&self, extension: &str) -> Option<u8> {
+        self.extensions.get(extension).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_extended_handshake() {
+        let handshake = ExtendedHandshake {
+            extensions: BTreeMap::from([(UT_HOLEPUNCH.to_string(), 3)]),
+            client: Some("rustorrent 0.1.0".to_string()),
+            port: Some(6881),
+        };
+        let message = handshake.to_message();
+        assert_eq!(message.get_id(), MessageId::Extended);
+        let extended = ExtendedMsg::parse(message.get_payload()).unwrap();
+        assert_eq!(extended.id, HANDSHAKE_ID);
+        let parsed = ExtendedHandshake::parse(&extended.payload).unwrap();
+        assert_eq!(parsed, handshake);
+        assert_eq!(parsed.id(UT_HOLEPUNCH), Some(3));
+
+        let disabled = ExtendedHandshake::parse(b"d1:md12:ut_holepunchi0eee").unwrap();
+        assert_eq!(disabled.id(UT_HOLEPUNCH), None);
+        assert!(ExtendedHandshake::parse(b"li1ee").is_err());
+        assert!(ExtendedMsg::parse(&Bytes::new()).is_err());
+    }
+}
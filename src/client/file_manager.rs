@@ -1,83 +1,1034 @@
 use std::{
-    fs::{create_dir_all, File, OpenOptions},
-    os::unix::fs::FileExt,
+    borrow::Cow,
+    fmt::Display,
+    fs::{self, create_dir_all, File, OpenOptions},
+    io,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
 };
 
+use bytes::Bytes;
 use sha1::Digest;
+use tracing::{debug, instrument, trace, warn};
 
-use crate::metainfo::Info;
+use crate::{
+    config::{ClientConfig, FileAllocation, StorageBackend},
+    metainfo::Info,
+};
+
+use super::bitfield::Bitfield;
+
+/// Positional reads and writes, which leave the file cursor alone on unix so
+/// clones of a file can be used from several threads at once.
+#[cfg(unix)]
+mod platform {
+    use std::{fs::File, io, os::unix::fs::FileExt};
+
+    pub fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        file.read_exact_at(buf, offset)
+    }
+
+    pub fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+        file.write_all_at(buf, offset)
+    }
+}
+
+/// Windows only has seek_read and seek_write, which move the cursor and may
+/// stop short. Nothing else uses the cursor, so moving it is harmless.
+#[cfg(windows)]
+mod platform {
+    use std::{fs::File, io, os::windows::fs::FileExt};
+
+    pub fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match file.seek_read(buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    let rest = buf;
+                    buf = &mut rest[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match file.seek_write(buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    buf = &buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read-only maps of whole files for the `Mmap` storage backend. Writes
+/// still go through the file, which shares its pages with the map.
+#[cfg(all(feature = "mmap", target_pointer_width = "64"))]
+mod mapping {
+    use std::{fs::File, io};
+
+    pub const AVAILABLE: bool = true;
+
+    pub type Map = memmap2::Mmap;
+
+    /// `None` until the file has its full length, as a map can't grow.
+    pub fn map(file: &File, length: u64) -> io::Result<Option<Map>> {
+        if length == 0 || file.metadata()?.len() != length {
+            return Ok(None);
+        }
+        // SAFETY: the file stays open at its full length for as long as the
+        // map lives. Another program truncating it would fault our reads, a
+        // risk every mmap of a shared file takes.
+        unsafe { memmap2::MmapOptions::new().len(length as usize).map(file) }.map(Some)
+    }
+}
+
+/// Without the `mmap` feature, or with an address space too small to map
+/// large torrents into, there are never any maps.
+#[cfg(not(all(feature = "mmap", target_pointer_width = "64")))]
+mod mapping {
+    use std::{fs::File, io, ops::Deref};
+
+    pub const AVAILABLE: bool = false;
+
+    #[derive(Debug)]
+    pub enum Map {}
+
+    impl Deref for Map {
+        type Target = [u8];
+
+        fn deref(&self) -> &[u8] {
+            match *self {}
+        }
+    }
+
+    pub fn map(_file: &File, _length: u64) -> io::Result<Option<Map>> {
+        Ok(None)
+    }
+}
+
+/// Replaces the characters NTFS doesn't allow in a file name with `_`, along
+/// with the trailing dots and spaces Windows strips.
+fn ntfs_name(name: &str) -> Cow<'_, str> {
+    let invalid = |c: char| c.is_control() || "<>:\"/\\|?*".contains(c);
+    let trimmed = name.trim_end_matches(['.', ' ']);
+    if !name.contains(invalid) && trimmed.len() == name.len() {
+        return Cow::Borrowed(name);
+    }
+    let mut sanitized = trimmed.replace(invalid, "_");
+    sanitized.extend(std::iter::repeat_n('_', name.len() - trimmed.len()));
+    Cow::Owned(sanitized)
+}
+
+/// Longest file name most filesystems accept, in bytes.
+const MAX_NAME_LENGTH: usize = 255;
+/// Device names Windows won't create files under, whatever the extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Why a file name from the metainfo can't be used on disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathError {
+    Absolute(String),
+    /// `..` or `.`, which would leave or alias the torrent's directory.
+    Traversal(String),
+    Reserved(String),
+    TooLong(String),
+    InvalidCharacter(String),
+}
+
+impl Display for PathError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PathError::Absolute(name) => write!(f, "absolute path `{}`", name),
+            PathError::Traversal(name) => write!(f, "path component `{}`", name),
+            PathError::Reserved(name) => write!(f, "reserved file name `{}`", name),
+            PathError::TooLong(name) => write!(f, "file name `{}` is too long", name),
+            PathError::InvalidCharacter(name) => {
+                write!(f, "file name `{:?}` has invalid characters", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PathError {}
+
+/// Why the torrent's files couldn't be opened, read or written.
+#[derive(Debug)]
+pub enum DiskError {
+    InvalidPath(PathError),
+    Open(PathBuf, io::Error),
+    Read(PathBuf, io::Error),
+    Write(PathBuf, io::Error),
+    /// A read or write past the end of the torrent's data.
+    OutOfRange(u64),
+}
+
+impl DiskError {
+    fn io_error(&self) -> Option<&io::Error> {
+        match self {
+            DiskError::Open(_, e) | DiskError::Read(_, e) | DiskError::Write(_, e) => Some(e),
+            DiskError::InvalidPath(_) | DiskError::OutOfRange(_) => None,
+        }
+    }
+
+    /// Whether trying again could work without anyone stepping in, unlike a
+    /// full disk, missing permissions or a file deleted from under us.
+    pub fn is_transient(&self) -> bool {
+        self.io_error().is_some_and(|e| {
+            !matches!(
+                e.kind(),
+                io::ErrorKind::StorageFull
+                    | io::ErrorKind::QuotaExceeded
+                    | io::ErrorKind::PermissionDenied
+                    | io::ErrorKind::ReadOnlyFilesystem
+                    | io::ErrorKind::NotFound
+            )
+        })
+    }
+}
+
+impl Display for DiskError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DiskError::InvalidPath(e) => write!(f, "invalid path: {}", e),
+            DiskError::Open(path, e) => write!(f, "failed to open {}: {}", path.display(), e),
+            DiskError::Read(path, e) => write!(f, "failed to read {}: {}", path.display(), e),
+            DiskError::Write(path, e) => write!(f, "failed to write {}: {}", path.display(), e),
+            DiskError::OutOfRange(offset) => write!(f, "offset {} is past the end", offset),
+        }
+    }
+}
+
+impl std::error::Error for DiskError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DiskError::InvalidPath(e) => Some(e),
+            _ => self.io_error().map(|e| e as _),
+        }
+    }
+}
+
+/// io::Error isn't Clone, so copies keep its kind and message.
+impl Clone for DiskError {
+    fn clone(&self) -> Self {
+        let copy = |e: &io::Error| io::Error::new(e.kind(), e.to_string());
+        match self {
+            DiskError::InvalidPath(e) => DiskError::InvalidPath(e.clone()),
+            DiskError::Open(path, e) => DiskError::Open(path.clone(), copy(e)),
+            DiskError::Read(path, e) => DiskError::Read(path.clone(), copy(e)),
+            DiskError::Write(path, e) => DiskError::Write(path.clone(), copy(e)),
+            DiskError::OutOfRange(offset) => DiskError::OutOfRange(*offset),
+        }
+    }
+}
+
+impl From<PathError> for DiskError {
+    fn from(e: PathError) -> Self {
+        DiskError::InvalidPath(e)
+    }
+}
+
+impl From<DiskError> for io::Error {
+    fn from(e: DiskError) -> Self {
+        let kind = match &e {
+            DiskError::InvalidPath(_) => io::ErrorKind::InvalidInput,
+            DiskError::OutOfRange(_) => io::ErrorKind::UnexpectedEof,
+            _ => e.io_error().map_or(io::ErrorKind::Other, io::Error::kind),
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+/// Checks a path component from the metainfo and returns the name to use
+/// for it locally. With `replace`, invalid characters and reserved names are
+/// renamed instead of rejected; everything else is always an error.
+fn local_name(name: &str, first: bool, replace: bool) -> Result<Cow<'_, str>, PathError> {
+    let drive =
+        name.len() >= 2 && name.as_bytes()[1] == b':' && name.as_bytes()[0].is_ascii_alphabetic();
+    if first && (name.starts_with(['/', '\\']) || drive) {
+        return Err(PathError::Absolute(name.to_string()));
+    }
+    if name == ".." || name == "." {
+        return Err(PathError::Traversal(name.to_string()));
+    }
+    if name.len() > MAX_NAME_LENGTH {
+        return Err(PathError::TooLong(name.to_string()));
+    }
+
+    let invalid = |c: char| c.is_control() || c == '/' || c == '\\';
+    let mut local = Cow::Borrowed(name);
+    if name.is_empty() || name.contains(invalid) {
+        if !replace {
+            return Err(PathError::InvalidCharacter(name.to_string()));
+        }
+        local = Cow::Owned(name.replace(invalid, "_"));
+        if local.is_empty() {
+            local = Cow::Borrowed("_");
+        }
+    }
+    let stem = local.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        if !replace {
+            return Err(PathError::Reserved(name.to_string()));
+        }
+        local = Cow::Owned(format!("_{}", local));
+    }
+
+    if cfg!(windows) {
+        if let Cow::Owned(sanitized) = ntfs_name(&local) {
+            local = Cow::Owned(sanitized);
+        }
+    }
+    Ok(local)
+}
+
+/// Where the files of a torrent go under `output_dir`, with their lengths.
+pub(super) fn local_paths(
+    output_dir: &str,
+    info_dict: &Info,
+    replace: bool,
+) -> Result<Vec<(PathBuf, u64)>, PathError> {
+    match info_dict {
+        Info::SingleFile(info) => {
+            let name = local_name(&info.name, true, replace)?;
+            Ok(vec![(
+                PathBuf::from(output_dir).join(name.as_ref()),
+                info.length,
+            )])
+        }
+        Info::MultiFile(info) => {
+            let root =
+                PathBuf::from(output_dir).join(local_name(&info.name, true, replace)?.as_ref());
+            info.files
+                .iter()
+                .map(|file| {
+                    let mut path = root.clone();
+                    for (i, part) in file.path.iter().enumerate() {
+                        path.push(local_name(part, i == 0, replace)?.as_ref());
+                    }
+                    Ok((path, file.length))
+                })
+                .collect()
+        }
+    }
+}
+
+/// Grows `file` to `length` bytes as `allocation` says. Files that are
+/// already long enough, such as ones being resumed, are left alone.
+fn allocate(file: &File, length: u64, allocation: FileAllocation) -> io::Result<()> {
+    if allocation == FileAllocation::None || file.metadata()?.len() >= length {
+        return Ok(());
+    }
+    match allocation {
+        FileAllocation::Full => reserve(file, length),
+        _ => file.set_len(length),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn reserve(file: &File, length: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let len = libc::off_t::try_from(length).map_err(|_| io::ErrorKind::InvalidInput)?;
+    // leaves existing data alone and only fills in what is missing
+    match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, len) } {
+        0 => Ok(()),
+        err => Err(io::Error::from_raw_os_error(err)),
+    }
+}
+
+/// Writes zeros past the end of the file, which is what fallocate falls back
+/// to on filesystems that can't reserve space.
+#[cfg(not(target_os = "linux"))]
+fn reserve(file: &File, length: u64) -> io::Result<()> {
+    const CHUNK: u64 = 1 << 20;
+
+    let zeros = vec![0; CHUNK as usize];
+    let mut offset = file.metadata()?.len();
+    while offset < length {
+        let len = (length - offset).min(CHUNK);
+        platform::write_all_at(file, &zeros[..len as usize], offset)?;
+        offset += len;
+    }
+    Ok(())
+}
+
+/// Appended to the names of files still downloading when the config asks
+/// for part files.
+const PART_SUFFIX: &str = ".part";
+
+fn part_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(PART_SUFFIX);
+    path.with_file_name(name)
+}
+
+/// Maps the file if it can be, falling back to reads if not.
+fn map_file(file: &File, path: &Path, length: u64) -> Option<mapping::Map> {
+    match mapping::map(file, length) {
+        Ok(map) => map,
+        Err(e) => {
+            debug!(path = %path.display(), error = %e, "failed to map file");
+            None
+        }
+    }
+}
+
+/// Removes the directories above `path` left empty, up to but not including
+/// `base`.
+fn remove_empty_parents(path: &Path, base: Option<&Path>) {
+    for dir in path.ancestors().skip(1) {
+        if Some(dir) == base || fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+}
+
+/// One file of the torrent and where it sits in the concatenated torrent data.
+#[derive(Debug)]
+struct FileEntry {
+    /// Where the file goes under the output directory.
+    relative: PathBuf,
+    offset: u64,
+    length: u64,
+    /// Only locked for writing while the file is renamed or moved.
+    disk: RwLock<DiskFile>,
+}
+
+impl FileEntry {
+    /// The directory the torrent's files are laid out under.
+    fn base_dir<'a>(&self, disk: &'a DiskFile) -> Option<&'a Path> {
+        disk.path
+            .ancestors()
+            .nth(self.relative.components().count())
+    }
+}
 
 #[derive(Debug)]
+struct DiskFile {
+    /// The file's final name, which it may not have yet.
+    path: PathBuf,
+    file: File,
+    /// Whether the file is still under its `.part` name.
+    partial: bool,
+    /// Read from instead of `file` when the storage backend maps files.
+    map: Option<mapping::Map>,
+}
+
+impl DiskFile {
+    /// Where the file is on disk right now.
+    fn current_path(&self) -> Cow<'_, Path> {
+        match self.partial {
+            true => Cow::Owned(part_path(&self.path)),
+            false => Cow::Borrowed(&self.path),
+        }
+    }
+}
+
+/// Maps piece offsets onto the torrent's files, splitting reads and writes
+/// that cross file boundaries.
+///
+/// Cloning is cheap and every clone shares the same open files, so reads can
+/// happen on other threads while the original keeps writing.
+#[derive(Debug, Clone)]
 pub struct FileManager {
     piece_length: u64,
-    files: Vec<(File, u64)>,
+    /// Whether files are mapped for reading.
+    mmap: bool,
+    total_length: u64,
+    /// In torrent order, so each file's offset is the sum of the lengths
+    /// before it and a byte range's files can be found by binary search.
+    files: Arc<Vec<FileEntry>>,
 }
 
 impl FileManager {
-    pub fn new(output_dir: String, info_dict: &Info) -> Self {
-        create_dir_all(&output_dir).unwrap();
-        match info_dict {
-            Info::SingleFile(info) => {
-                let file_path = format!("{}/{}", output_dir, info.name);
-                let file = OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .create(true)
-                    .open(file_path)
-                    .unwrap();
-                FileManager {
-                    piece_length: info.base_info.piece_length as u64,
-                    files: vec![(file, info.length)],
-                }
+    /// Single file torrents are stored as `output_dir/name`, multi-file ones
+    /// under `output_dir/name/`, creating any directories along the way.
+    /// Files are then sized as the config's `file_allocation` says.
+    ///
+    /// With `part_files` set, files not already complete on disk are written
+    /// as `name.part` until [`finish_files`](Self::finish_files) renames
+    /// them.
+    ///
+    /// Fails if a file name could escape `output_dir` or isn't a valid name,
+    /// unless the config allows replacing invalid characters, or if a file
+    /// can't be created.
+    pub fn new(
+        output_dir: String,
+        info_dict: &Info,
+        config: &ClientConfig,
+    ) -> Result<Self, DiskError> {
+        let piece_length = match info_dict {
+            Info::SingleFile(info) => info.base_info.piece_length,
+            Info::MultiFile(info) => info.base_info.piece_length,
+        };
+        let paths = local_paths(&output_dir, info_dict, config.sanitize_paths)?;
+        let mmap = config.storage == StorageBackend::Mmap && mapping::AVAILABLE;
+        if config.storage == StorageBackend::Mmap && !mapping::AVAILABLE {
+            warn!("memory-mapped files aren't available in this build, using reads");
+        }
+
+        let mut files = Vec::with_capacity(paths.len());
+        let mut offset = 0;
+        for (path, length) in paths {
+            if let Some(parent) = path.parent() {
+                create_dir_all(parent).map_err(|e| DiskError::Open(parent.to_path_buf(), e))?;
+            }
+            // a file under its final name was finished before, or put there
+            // for us, and is left where it is
+            let partial =
+                config.part_files && length > 0 && (part_path(&path).exists() || !path.exists());
+            let open_path = match partial {
+                true => Cow::Owned(part_path(&path)),
+                false => Cow::Borrowed(&path),
+            };
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&*open_path)
+                .map_err(|e| DiskError::Open(open_path.to_path_buf(), e))?;
+            // the download can still go on, it just may run out of space later
+            if let Err(e) = allocate(&file, length, config.file_allocation) {
+                warn!(path = %path.display(), error = %e, "failed to allocate file");
+            }
+            let map = if mmap {
+                map_file(&file, &path, length)
+            } else {
+                None
+            };
+            files.push(FileEntry {
+                relative: path
+                    .strip_prefix(&output_dir)
+                    .unwrap_or(&path)
+                    .to_path_buf(),
+                offset,
+                length,
+                disk: RwLock::new(DiskFile {
+                    path,
+                    file,
+                    partial,
+                    map,
+                }),
+            });
+            offset += length;
+        }
+
+        Ok(FileManager {
+            piece_length,
+            mmap,
+            total_length: offset,
+            files: Arc::new(files),
+        })
+    }
+
+    #[instrument(level = "trace", skip(self, data), fields(len = data.len()))]
+    pub fn save_block(
+        &mut self,
+        piece_index: usize,
+        begin: u32,
+        data: Bytes,
+    ) -> Result<(), DiskError> {
+        let offset = self.piece_length * piece_index as u64 + begin as u64;
+        self.write_at(offset, &data)
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        for entry in self.files.iter() {
+            entry.disk.read().unwrap().file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the torrent's files, and the directories under `output_dir`
+    /// that are left empty. Files already gone are skipped.
+    pub fn delete_files(&self) -> io::Result<()> {
+        for entry in self.files.iter() {
+            let mut disk = entry.disk.write().unwrap();
+            // Windows won't delete a mapped file
+            disk.map = None;
+            match fs::remove_file(disk.current_path()) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            remove_empty_parents(&disk.path, entry.base_dir(&disk));
+        }
+        Ok(())
+    }
+
+    /// Moves the files under `dest`, laid out as they were under the output
+    /// directory, and removes the directories left empty. Files are renamed,
+    /// or copied and reopened when `dest` is on another filesystem; reads and
+    /// writes of a file wait while it moves.
+    pub fn move_to(&self, dest: &Path) -> io::Result<()> {
+        for entry in self.files.iter() {
+            let mut disk = entry.disk.write().unwrap();
+            let path = dest.join(&entry.relative);
+            if disk.path == path {
+                continue;
+            }
+            let from = disk.current_path().into_owned();
+            let to = match disk.partial {
+                true => part_path(&path),
+                false => path.clone(),
+            };
+            if let Some(parent) = to.parent() {
+                create_dir_all(parent)?;
             }
-            Info::MultiFile(info) => {
-                let mut files = Vec::new();
-                for file_info in &info.files {
-                    let file_path = format!("{}/{}", output_dir, file_info.path.join("/"));
-                    let file = OpenOptions::new()
-                        .read(true)
-                        .write(true)
-                        .create(true)
-                        .open(file_path)
-                        .unwrap();
-                    files.push((file, file_info.length));
+            match fs::rename(&from, &to) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                    fs::copy(&from, &to)?;
+                    disk.file = OpenOptions::new().read(true).write(true).open(&to)?;
+                    disk.map = None;
+                    if self.mmap {
+                        disk.map = map_file(&disk.file, &to, entry.length);
+                    }
+                    fs::remove_file(&from)?;
                 }
-                FileManager {
-                    piece_length: info.base_info.piece_length as u64,
-                    files,
+                Err(e) => return Err(e),
+            }
+            remove_empty_parents(&from, entry.base_dir(&disk));
+            debug!(from = %from.display(), to = %to.display(), "moved file");
+            disk.path = path;
+        }
+        Ok(())
+    }
+
+    /// Gives the part files overlapping `pieces` their final names once every
+    /// piece they hold is complete. Open handles follow the rename.
+    pub fn finish_files(
+        &self,
+        pieces: Range<usize>,
+        is_complete: impl Fn(usize) -> bool,
+    ) -> io::Result<()> {
+        let start = self.piece_length * pieces.start as u64;
+        let end = self.piece_length * pieces.end as u64;
+        for entry in self.files_in(start, end) {
+            if !self.file_pieces(entry).all(&is_complete) {
+                continue;
+            }
+            let mut disk = entry.disk.write().unwrap();
+            if disk.partial {
+                fs::rename(part_path(&disk.path), &disk.path)?;
+                disk.partial = false;
+                debug!(path = %disk.path.display(), "file complete");
+            }
+        }
+        Ok(())
+    }
+
+    /// The files holding some of the bytes `start..end`, found by binary
+    /// search.
+    fn files_in(&self, start: u64, end: u64) -> impl Iterator<Item = &FileEntry> + '_ {
+        let first = self
+            .files
+            .partition_point(|entry| entry.offset + entry.length <= start);
+        self.files[first..]
+            .iter()
+            .take_while(move |entry| entry.offset < end)
+            // empty files share their offset with the next
+            .filter(|entry| entry.length > 0)
+    }
+
+    /// The parts of `offset..offset + len` that fall in each file, as the file,
+    /// the offset within that file and the range within the span.
+    fn spans(
+        &self,
+        offset: u64,
+        len: usize,
+    ) -> impl Iterator<Item = (&FileEntry, u64, Range<usize>)> + '_ {
+        let end = offset + len as u64;
+        self.files_in(offset, end).map(move |entry| {
+            let start = offset.max(entry.offset);
+            let stop = end.min(entry.offset + entry.length);
+            let range = (start - offset) as usize..(stop - offset) as usize;
+            (entry, start - entry.offset, range)
+        })
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<(), DiskError> {
+        if offset + data.len() as u64 > self.total_length {
+            return Err(DiskError::OutOfRange(offset));
+        }
+        for (entry, file_offset, range) in self.spans(offset, data.len()) {
+            trace!(file_offset, len = range.len(), "writing block");
+            let disk = entry.disk.read().unwrap();
+            platform::write_all_at(&disk.file, &data[range], file_offset)
+                .map_err(|e| DiskError::Write(disk.current_path().into_owned(), e))?;
+        }
+        Ok(())
+    }
+
+    /// The pieces holding some of the file, none for an empty one.
+    fn file_pieces(&self, entry: &FileEntry) -> Range<usize> {
+        if entry.length == 0 {
+            return 0..0;
+        }
+        let first = entry.offset / self.piece_length;
+        let last = (entry.offset + entry.length - 1) / self.piece_length;
+        first as usize..last as usize + 1
+    }
+
+    /// Bytes of each file covered by the pieces set in `completed`.
+    pub fn file_progress(&self, completed: &Bitfield) -> Vec<u64> {
+        let mut progress = Vec::with_capacity(self.files.len());
+        for entry in self.files.iter() {
+            let (file_start, file_end) = (entry.offset, entry.offset + entry.length);
+            let mut covered = 0;
+            for index in self.file_pieces(entry) {
+                if !completed.is_set(index).unwrap_or(false) {
+                    continue;
                 }
+                let piece_start = self.piece_length * index as u64;
+                let piece_end = (piece_start + self.piece_length).min(self.total_length);
+                covered += piece_end.min(file_end) - piece_start.max(file_start);
             }
+            progress.push(covered);
         }
+        progress
+    }
+
+    pub fn piece_length(&self) -> u64 {
+        self.piece_length
+    }
+
+    /// The torrent's file, or the directory holding its files.
+    pub fn root_path(&self) -> Option<PathBuf> {
+        let entry = self.files.first()?;
+        let disk = entry.disk.read().unwrap();
+        let top = entry.relative.components().next()?;
+        Some(entry.base_dir(&disk)?.join(top))
+    }
+
+    /// Where file `index` starts in the torrent's data, and its length.
+    pub fn file_span(&self, index: usize) -> Option<(u64, u64)> {
+        self.files
+            .get(index)
+            .map(|entry| (entry.offset, entry.length))
     }
 
-    pub fn save_block(&mut self, piece_index: usize, begin: u32, data: Vec<u8>) {
-        let byte_offset = self.piece_length * piece_index as u64 + begin as u64;
-        let mut accumulated_size = 0;
-        for (file, file_size) in &mut self.files {
-            if byte_offset < accumulated_size + *file_size {
-                file.write_at(&data, byte_offset - accumulated_size)
-                    .unwrap();
-                break;
+    /// Reads a whole piece back from disk, following it across file boundaries.
+    pub fn read_piece(&self, piece_index: usize) -> Result<Vec<u8>, DiskError> {
+        let offset = self.piece_length * piece_index as u64;
+        let length = self
+            .piece_length
+            .min(self.total_length.saturating_sub(offset));
+        let mut buf = vec![0; length as usize];
+        self.read_at(offset, &mut buf)?;
+        Ok(buf)
+    }
+
+    pub fn read_block(
+        &self,
+        piece_index: usize,
+        begin: u32,
+        length: u32,
+    ) -> Result<Vec<u8>, DiskError> {
+        let offset = self.piece_length * piece_index as u64 + begin as u64;
+        let mut buf = vec![0; length as usize];
+        self.read_at(offset, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Fills `buf` from `offset` in the torrent's data.
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), DiskError> {
+        if offset + buf.len() as u64 > self.total_length {
+            return Err(DiskError::OutOfRange(offset));
+        }
+        for (entry, file_offset, range) in self.spans(offset, buf.len()) {
+            let disk = entry.disk.read().unwrap();
+            if let Some(map) = &disk.map {
+                let start = file_offset as usize;
+                buf[range.clone()].copy_from_slice(&map[start..start + range.len()]);
+                continue;
             }
-            accumulated_size += *file_size;
+            platform::read_exact_at(&disk.file, &mut buf[range], file_offset)
+                .map_err(|e| DiskError::Read(disk.current_path().into_owned(), e))?;
         }
+        Ok(())
     }
 
+    #[instrument(level = "debug", skip(self, hash))]
     pub fn verify_piece(&self, piece_index: usize, hash: &[u8]) -> bool {
-        let offset = self.piece_length * piece_index as u64;
-        let mut file_index = 0;
-        let mut accumulated_size = 0;
-        while offset >= self.files[file_index].1 + accumulated_size {
-            accumulated_size += self.files[file_index].1;
-            file_index += 1;
-        }
-        let file = &self.files[file_index].0;
-        let mut buf = vec![0; self.piece_length as usize];
-        file.read_at(&mut buf, offset).unwrap();
-
-        let mut hasher = sha1::Sha1::new();
-        hasher.update(&buf);
-        let result = hasher.finalize().to_vec();
-        hash == result.as_slice()
+        match self.read_piece(piece_index) {
+            Ok(piece) => sha1::Sha1::digest(&piece).as_slice() == hash,
+            Err(e) => {
+                debug!(error = %e, "failed to read piece");
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::{
+        bencode::{BencodeString, BencodeValue},
+        metainfo::Metainfo,
+    };
+
+    fn string(s: &str) -> BencodeValue {
+        BencodeValue::String(BencodeString::String(s.to_string()))
+    }
+
+    fn multi_file_info(files: &[(&str, u64)], piece_length: u64) -> Info {
+        let pieces = files
+            .iter()
+            .map(|(_, length)| length)
+            .sum::<u64>()
+            .div_ceil(piece_length);
+        let files = files
+            .iter()
+            .map(|(path, length)| {
+                BencodeValue::Dict(BTreeMap::from([
+                    ("length".to_string(), BencodeValue::Int(*length as i64)),
+                    (
+                        "path".to_string(),
+                        BencodeValue::List(path.split('/').map(string).collect()),
+                    ),
+                ]))
+            })
+            .collect();
+        let info = BencodeValue::Dict(BTreeMap::from([
+            ("name".to_string(), string("root")),
+            ("files".to_string(), BencodeValue::List(files)),
+            (
+                "piece length".to_string(),
+                BencodeValue::Int(piece_length as i64),
+            ),
+            (
+                "pieces".to_string(),
+                BencodeValue::String(BencodeString::Bytes(vec![0; 20 * pieces as usize])),
+            ),
+        ]));
+        let torrent = BencodeValue::Dict(BTreeMap::from([
+            ("announce".to_string(), string("http://localhost/")),
+            ("info".to_string(), info),
+        ]));
+        Metainfo::new(torrent).unwrap().info
+    }
+
+    fn open(
+        dir: &std::path::Path,
+        info: &Info,
+        allocation: FileAllocation,
+    ) -> Result<FileManager, DiskError> {
+        let config = ClientConfig::builder().file_allocation(allocation).build();
+        FileManager::new(dir.to_str().unwrap().to_string(), info, &config)
+    }
+
+    #[test]
+    fn splits_blocks_across_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let info = multi_file_info(&[("a", 3), ("sub/b", 2), ("sub/deeper/c", 5)], 16);
+        let mut file_manager = open(dir.path(), &info, FileAllocation::None).unwrap();
+
+        let data = Bytes::from_static(b"0123456789");
+        file_manager.save_block(0, 0, data.clone()).unwrap();
+
+        let root = dir.path().join("root");
+        assert_eq!(std::fs::read(root.join("a")).unwrap(), b"012");
+        assert_eq!(std::fs::read(root.join("sub/b")).unwrap(), b"34");
+        assert_eq!(std::fs::read(root.join("sub/deeper/c")).unwrap(), b"56789");
+        assert_eq!(file_manager.read_block(0, 2, 6).unwrap(), b"234567");
+
+        assert!(file_manager.save_block(0, 8, data).is_err());
+    }
+
+    #[test]
+    fn maps_byte_ranges_onto_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let info = multi_file_info(&[("a", 5), ("empty", 0), ("b", 2), ("c", 9)], 4);
+        let file_manager = open(dir.path(), &info, FileAllocation::None).unwrap();
+        let spans = |offset, len| {
+            file_manager
+                .spans(offset, len)
+                .map(|(entry, file_offset, range)| (entry.offset, file_offset, range))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(spans(0, 4), vec![(0, 0, 0..4)]);
+        assert_eq!(spans(4, 4), vec![(0, 4, 0..1), (5, 0, 1..3), (7, 0, 3..4)]);
+        assert_eq!(spans(12, 4), vec![(7, 5, 0..4)]);
+        assert!(spans(16, 0).is_empty());
+
+        let mut completed = Bitfield::new(4);
+        completed.set(1, true).unwrap();
+        assert_eq!(file_manager.file_progress(&completed), vec![1, 0, 2, 1]);
+        let pieces = file_manager
+            .files
+            .iter()
+            .map(|entry| file_manager.file_pieces(entry))
+            .collect::<Vec<_>>();
+        assert_eq!(pieces, vec![0..2, 0..0, 1..2, 1..4]);
+    }
+
+    #[test]
+    fn reads_writes_back_through_maps() {
+        let dir = tempfile::tempdir().unwrap();
+        let info = multi_file_info(&[("a", 3), ("b", 5)], 4);
+        let config = ClientConfig::builder()
+            .storage(StorageBackend::Mmap)
+            .build();
+        let mut file_manager =
+            FileManager::new(dir.path().to_str().unwrap().to_string(), &info, &config).unwrap();
+        let mapped = file_manager
+            .files
+            .iter()
+            .all(|entry| entry.disk.read().unwrap().map.is_some());
+        assert_eq!(mapped, mapping::AVAILABLE);
+
+        file_manager
+            .save_block(0, 0, Bytes::from_static(b"01234567"))
+            .unwrap();
+        assert_eq!(file_manager.read_block(0, 2, 4).unwrap(), b"2345");
+        assert_eq!(file_manager.read_piece(1).unwrap(), b"4567");
+        assert!(file_manager.read_block(1, 2, 4).is_err());
+        file_manager.delete_files().unwrap();
+        assert!(!dir.path().join("root").exists());
+    }
+
+    #[test]
+    fn allocates_files_up_front_without_touching_existing_data() {
+        let info = multi_file_info(&[("a", 3), ("b", 5)], 16);
+        let lengths = |dir: &std::path::Path| {
+            ["a", "b"].map(|name| {
+                std::fs::metadata(dir.join("root").join(name))
+                    .unwrap()
+                    .len()
+            })
+        };
+
+        for (allocation, expected) in [
+            (FileAllocation::None, [0, 0]),
+            (FileAllocation::Sparse, [3, 5]),
+            (FileAllocation::Full, [3, 5]),
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            open(dir.path(), &info, allocation).unwrap();
+            assert_eq!(lengths(dir.path()), expected, "{:?}", allocation);
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("root")).unwrap();
+        std::fs::write(dir.path().join("root/b"), b"xy").unwrap();
+        let file_manager = open(dir.path(), &info, FileAllocation::Full).unwrap();
+        assert_eq!(lengths(dir.path()), [3, 5]);
+        assert_eq!(file_manager.read_block(0, 3, 5).unwrap(), b"xy\0\0\0");
+    }
+
+    #[test]
+    fn renames_part_files_once_their_pieces_complete() {
+        let dir = tempfile::tempdir().unwrap();
+        let info = multi_file_info(&[("a", 3), ("b", 5)], 4);
+        let config = ClientConfig::builder().part_files(true).build();
+        let root = dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(root.join("b"), b"done!").unwrap();
+        let mut file_manager =
+            FileManager::new(dir.path().to_str().unwrap().to_string(), &info, &config).unwrap();
+        assert!(root.join("a.part").exists() && !root.join("a").exists());
+        assert!(!root.join("b.part").exists());
+
+        file_manager
+            .save_block(0, 0, Bytes::from_static(b"abcd"))
+            .unwrap();
+        // "a" ends in piece 0, which isn't complete yet
+        file_manager.finish_files(0..1, |_| false).unwrap();
+        assert!(root.join("a.part").exists());
+        file_manager.finish_files(0..1, |index| index == 0).unwrap();
+        assert!(!root.join("a.part").exists());
+        assert_eq!(std::fs::read(root.join("a")).unwrap(), b"abc");
+        assert_eq!(file_manager.read_block(0, 0, 4).unwrap(), b"abcd");
+
+        file_manager.delete_files().unwrap();
+        assert!(!root.exists());
+    }
+
+    #[test]
+    fn reports_files_it_cannot_create() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("root"), b"").unwrap();
+        let info = multi_file_info(&[("a", 3)], 16);
+        let error = open(dir.path(), &info, FileAllocation::None).unwrap_err();
+        assert!(matches!(&error, DiskError::Open(path, _) if path.ends_with("root")));
+
+        let full = DiskError::Write(PathBuf::from("a"), io::ErrorKind::StorageFull.into());
+        assert!(!full.is_transient());
+        assert!(
+            DiskError::Write(PathBuf::from("a"), io::ErrorKind::TimedOut.into()).is_transient()
+        );
+        assert_eq!(
+            io::Error::from(full.clone()).kind(),
+            io::ErrorKind::StorageFull
+        );
+    }
+
+    #[test]
+    fn replaces_characters_ntfs_rejects() {
+        assert_eq!(ntfs_name("plain.txt"), "plain.txt");
+        assert_eq!(ntfs_name("a:b*c?.txt"), "a_b_c_.txt");
+        assert_eq!(ntfs_name("tab\there"), "tab_here");
+        assert_eq!(ntfs_name("trailing. "), "trailing__");
+    }
+
+    #[test]
+    fn rejects_paths_that_escape_the_output_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let escape = multi_file_info(&[("ok", 1), ("../../.bashrc", 1)], 16);
+        assert!(matches!(
+            open(dir.path(), &escape, FileAllocation::None).unwrap_err(),
+            DiskError::InvalidPath(PathError::Traversal(name)) if name == ".."
+        ));
+        assert!(!dir.path().join("root").exists());
+
+        assert_eq!(
+            local_name("/etc", true, true),
+            Err(PathError::Absolute("/etc".to_string()))
+        );
+        assert_eq!(
+            local_name("C:", true, true),
+            Err(PathError::Absolute("C:".to_string()))
+        );
+        assert!(matches!(
+            local_name(&"x".repeat(256), false, true),
+            Err(PathError::TooLong(_))
+        ));
+    }
+
+    #[test]
+    fn replaces_invalid_names_only_when_asked() {
+        assert_eq!(local_name("notes.txt", false, false).unwrap(), "notes.txt");
+        assert_eq!(
+            local_name("a/b", false, false),
+            Err(PathError::InvalidCharacter("a/b".to_string()))
+        );
+        assert_eq!(local_name("a/b\0c", false, true).unwrap(), "a_b_c");
+        assert_eq!(local_name("", false, true).unwrap(), "_");
+        assert_eq!(
+            local_name("con.txt", false, false),
+            Err(PathError::Reserved("con.txt".to_string()))
+        );
+        assert_eq!(local_name("LPT1", false, true).unwrap(), "_LPT1");
+        assert_eq!(local_name("console", false, false).unwrap(), "console");
     }
 }
@@ -0,0 +1,300 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use futures::channel::mpsc;
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuilder};
+use sha1::{Digest, Sha1};
+use tracing::debug;
+
+use super::file_manager::FileManager;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct HashResult {
+    pub index: usize,
+    pub valid: bool,
+}
+
+/// Reads pieces back from disk and checks their SHA-1 on a dedicated
+/// work-stealing pool, keeping hashing off the coordinator and the runtime.
+/// Each job does its own read, so disk reads for one piece overlap with
+/// hashing of the others.
+///
+/// Pieces are hashed in parallel, but their results are sent in the order
+/// the pieces were submitted.
+#[derive(Debug)]
+pub struct PieceHasher {
+    pool: ThreadPool,
+    file_manager: FileManager,
+    results: Arc<Mutex<OrderedResults>>,
+}
+
+/// Holds back results that finished ahead of earlier submissions.
+#[derive(Debug)]
+struct OrderedResults {
+    sender: mpsc::UnboundedSender<HashResult>,
+    /// Sequence number of the next submission.
+    submitted: u64,
+    /// Sequence number of the next result to send.
+    next: u64,
+    finished: BTreeMap<u64, HashResult>,
+}
+
+impl OrderedResults {
+    fn finish(&mut self, seq: u64, result: HashResult) {
+        self.finished.insert(seq, result);
+        while let Some(result) = self.finished.remove(&self.next) {
+            let _ = self.sender.unbounded_send(result);
+            self.next += 1;
+        }
+    }
+}
+
+impl PieceHasher {
+    /// `threads` of 0 uses one thread per core.
+    pub fn new(
+        threads: usize,
+        file_manager: FileManager,
+        results: mpsc::UnboundedSender<HashResult>,
+    ) -> Self {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("hasher-{}", i))
+            .build()
+            .expect("failed to start hashing threads");
+
+        Self {
+            pool,
+            file_manager,
+            results: Arc::new(Mutex::new(OrderedResults {
+                sender: results,
+                submitted: 0,
+                next: 0,
+                finished: BTreeMap::new(),
+            })),
+        }
+    }
+
+    /// Runs `check` on the pool and sends its result once every piece
+    /// submitted before it has been sent.
+    fn spawn(&self, index: usize, check: impl FnOnce() -> bool + Send + 'static) {
+        let results = Arc::clone(&self.results);
+        let seq = {
+            let mut results = results.lock().unwrap();
+            results.submitted += 1;
+            results.submitted - 1
+        };
+        self.pool.spawn(move || {
+            let valid = check();
+            debug!(piece = index, valid, "piece hashed");
+            results
+                .lock()
+                .unwrap()
+                .finish(seq, HashResult { index, valid });
+        });
+    }
+
+    /// Queues a piece for verification. The outcome is sent on the results
+    /// channel once the piece has been read and hashed.
+    pub fn submit(&self, index: usize, hash: Vec<u8>) {
+        let file_manager = self.file_manager.clone();
+        self.spawn(index, move || file_manager.verify_piece(index, &hash));
+    }
+
+    /// Like [`PieceHasher::submit`], for a piece still held in memory.
+    pub fn submit_data(&self, index: usize, hash: Vec<u8>, data: Bytes) {
+        self.spawn(index, move || Sha1::digest(&data).as_slice() == hash);
+    }
+
+    /// Checks `(hash, data)` pairs held in memory, blocking until all are
+    /// done.
+    pub fn verify_data(&self, pieces: &[(Vec<u8>, Bytes)]) -> Vec<bool> {
+        self.pool.install(|| {
+            pieces
+                .par_iter()
+                .map(|(hash, data)| Sha1::digest(data).as_slice() == hash)
+                .collect()
+        })
+    }
+
+    /// Checks only the given `(index, hash)` pairs, blocking until all are
+    /// done.
+    pub fn verify_pieces(&self, pieces: &[(usize, Vec<u8>)]) -> Vec<bool> {
+        self.pool.install(|| {
+            pieces
+                .par_iter()
+                .map(|(index, hash)| self.file_manager.verify_piece(*index, hash))
+                .collect()
+        })
+    }
+
+    /// Checks every piece in `hashes` and blocks until all are done, returning
+    /// whether each one matched.
+    pub fn verify_all(&self, hashes: &[Vec<u8>]) -> Vec<bool> {
+        self.pool.install(|| {
+            hashes
+                .par_iter()
+                .enumerate()
+                .map(|(index, hash)| self.file_manager.verify_piece(index, hash))
+                .collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use futures::{executor::block_on, StreamExt};
+
+    use super::*;
+    use crate::{
+        bencode::{BencodeString, BencodeValue},
+        config::{ClientConfig, FileAllocation},
+        metainfo::Metainfo,
+    };
+
+    const PIECE_LENGTH: usize = 1024;
+
+    fn file_manager(dir: &str, data: &[u8]) -> (FileManager, Vec<Vec<u8>>) {
+        let hashes = data
+            .chunks(PIECE_LENGTH)
+            .map(|piece| Sha1::digest(piece).to_vec())
+            .collect::<Vec<_>>();
+        let info = BencodeValue::Dict(BTreeMap::from([
+            (
+                "name".to_string(),
+                BencodeValue::String(BencodeString::String("data.bin".to_string())),
+            ),
+            ("length".to_string(), BencodeValue::Int(data.len() as i64)),
+            (
+                "piece length".to_string(),
+                BencodeValue::Int(PIECE_LENGTH as i64),
+            ),
+            (
+                "pieces".to_string(),
+                BencodeValue::String(BencodeString::Bytes(hashes.concat())),
+            ),
+        ]));
+        let torrent = BencodeValue::Dict(BTreeMap::from([
+            (
+                "announce".to_string(),
+                BencodeValue::String(BencodeString::String("http://localhost/".to_string())),
+            ),
+            ("info".to_string(), info),
+        ]));
+        let metainfo = Metainfo::new(torrent).ok().unwrap();
+
+        let mut file_manager = FileManager::new(
+            dir.to_string(),
+            &metainfo.info,
+            &ClientConfig::builder()
+                .file_allocation(FileAllocation::None)
+                .build(),
+        )
+        .unwrap();
+        for (index, piece) in data.chunks(PIECE_LENGTH).enumerate() {
+            file_manager
+                .save_block(index, 0, Bytes::copy_from_slice(piece))
+                .unwrap();
+        }
+        (file_manager, hashes)
+    }
+
+    #[test]
+    fn test_verify_all() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = (0..5 * PIECE_LENGTH + 100)
+            .map(|i| (i % 253) as u8)
+            .collect::<Vec<u8>>();
+        let (file_manager, mut hashes) = file_manager(dir.path().to_str().unwrap(), &data);
+        hashes[2] = vec![0; 20];
+
+        let (tx, _rx) = mpsc::unbounded();
+        let hasher = PieceHasher::new(2, file_manager, tx);
+        assert_eq!(
+            hasher.verify_all(&hashes),
+            vec![true, true, false, true, true, true]
+        );
+    }
+
+    #[test]
+    fn test_submit() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = (0..3 * PIECE_LENGTH)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<u8>>();
+        let (file_manager, hashes) = file_manager(dir.path().to_str().unwrap(), &data);
+
+        let (tx, rx) = mpsc::unbounded();
+        let hasher = PieceHasher::new(2, file_manager, tx);
+        hasher.submit(1, hashes[1].clone());
+        hasher.submit(0, vec![0; 20]);
+        drop(hasher);
+
+        // in the order submitted
+        assert_eq!(
+            block_on(rx.collect::<Vec<_>>()),
+            vec![
+                HashResult {
+                    index: 1,
+                    valid: true
+                },
+                HashResult {
+                    index: 0,
+                    valid: false
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn holds_results_back_until_earlier_ones_finish() {
+        let (tx, mut rx) = mpsc::unbounded();
+        let mut results = OrderedResults {
+            sender: tx,
+            submitted: 3,
+            next: 0,
+            finished: BTreeMap::new(),
+        };
+        let result = |index| HashResult { index, valid: true };
+
+        results.finish(2, result(2));
+        results.finish(1, result(1));
+        assert!(rx.try_next().is_err());
+        results.finish(0, result(0));
+        let sent = std::iter::from_fn(|| rx.try_next().ok().flatten())
+            .map(|r| r.index)
+            .collect::<Vec<_>>();
+        assert_eq!(sent, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn checks_pieces_in_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = vec![7; PIECE_LENGTH];
+        let (file_manager, hashes) = file_manager(dir.path().to_str().unwrap(), &data);
+
+        let (tx, rx) = mpsc::unbounded();
+        let hasher = PieceHasher::new(1, file_manager, tx);
+        let piece = Bytes::from(data);
+        assert_eq!(
+            hasher.verify_data(&[
+                (hashes[0].clone(), piece.clone()),
+                (hashes[0].clone(), piece.slice(1..))
+            ]),
+            vec![true, false]
+        );
+        hasher.submit_data(0, hashes[0].clone(), piece);
+        drop(hasher);
+        assert_eq!(
+            block_on(rx.collect::<Vec<_>>()),
+            vec![HashResult {
+                index: 0,
+                valid: true
+            }]
+        );
+    }
+}
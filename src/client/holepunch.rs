@@ -0,0 +1,159 @@
+//! Hole punching (BEP 55). A peer that can't reach another asks a peer
+//! connected to both to relay a rendezvous; the relay tells each side to
+//! connect to the other, and as both dial at once, the NATs in between see
+//! outgoing connections and let them through.
+
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+use super::{
+    extension::{ExtendedMsg, ExtensionError},
+    message::Message,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HolepunchError {
+    /// The address isn't a peer's.
+    NoSuchPeer,
+    /// The relay isn't connected to the peer.
+    NotConnected,
+    /// The peer doesn't support hole punching.
+    NoSupport,
+    /// The address is the relay's own.
+    NoSelf,
+    Unknown(u32),
+}
+
+impl HolepunchError {
+    fn code(self) -> u32 {
+        match self {
+            HolepunchError::NoSuchPeer => 1,
+            HolepunchError::NotConnected => 2,
+            HolepunchError::NoSupport => 3,
+            HolepunchError::NoSelf => 4,
+            HolepunchError::Unknown(code) => code,
+        }
+    }
+
+    fn from_code(code: u32) -> Self {
+        match code {
+            1 => HolepunchError::NoSuchPeer,
+            2 => HolepunchError::NotConnected,
+            3 => HolepunchError::NoSupport,
+            4 => HolepunchError::NoSelf,
+            code => HolepunchError::Unknown(code),
+        }
+    }
+}
+
+impl Display for HolepunchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HolepunchError::NoSuchPeer => write!(f, "no such peer"),
+            HolepunchError::NotConnected => write!(f, "relay is not connected to the peer"),
+            HolepunchError::NoSupport => write!(f, "peer doesn't support hole punching"),
+            HolepunchError::NoSelf => write!(f, "can't hole punch to the relay itself"),
+            HolepunchError::Unknown(code) => write!(f, "error code {}", code),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HolepunchMsg {
+    /// Asks the relay to put us through to a peer.
+    Rendezvous(SocketAddr),
+    /// Tells us to connect to a peer, which is connecting to us.
+    Connect(SocketAddr),
+    /// Why the relay couldn't put us through to a peer.
+    Error(SocketAddr, HolepunchError),
+}
+
+impl HolepunchMsg {
+    pub fn parse(payload: &[u8]) -> Result<Self, ExtensionError> {
+        let invalid = || ExtensionError(format!("bad hole punch message: {:?}", payload));
+        let (&kind, rest) = payload.split_first().ok_or_else(invalid)?;
+        let (&addr_type, rest) = rest.split_first().ok_or_else(invalid)?;
+        let (ip, rest) = match addr_type {
+            0 if rest.len() == 4 + 6 => {
+                let octets: [u8; 4] = rest[..4].try_into().unwrap();
+                (IpAddr::from(Ipv4Addr::from(octets)), &rest[4..])
+            }
+            1 if rest.len() == 16 + 6 => {
+                let octets: [u8; 16] = rest[..16].try_into().unwrap();
+                (IpAddr::from(Ipv6Addr::from(octets)), &rest[16..])
+            }
+            _ => return Err(invalid()),
+        };
+        let addr = SocketAddr::new(ip, u16::from_be_bytes([rest[0], rest[1]]));
+        let code = u32::from_be_bytes(rest[2..6].try_into().unwrap());
+        match kind {
+            0 => Ok(HolepunchMsg::Rendezvous(addr)),
+            1 => Ok(HolepunchMsg::Connect(addr)),
+            2 => Ok(HolepunchMsg::Error(addr, HolepunchError::from_code(code))),
+            _ => Err(invalid()),
+        }
+    }
+
+    pub fn to_bytes(self) -> Bytes {
+        let (kind, addr, code) = match self {
+            HolepunchMsg::Rendezvous(addr) => (0, addr, 0),
+            HolepunchMsg::Connect(addr) => (1, addr, 0),
+            HolepunchMsg::Error(addr, error) => (2, addr, error.code()),
+        };
+        let mut payload = BytesMut::with_capacity(24);
+        payload.put_u8(kind);
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                payload.put_u8(0);
+                payload.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                payload.put_u8(1);
+                payload.extend_from_slice(&ip.octets());
+            }
+        }
+        payload.put_u16(addr.port());
+        payload.put_u32(code);
+        payload.freeze()
+    }
+
+    /// The message to send a peer that takes hole punch messages under `id`.
+    pub fn to_message(self, id: u8) -> Message {
+        ExtendedMsg {
+            id,
+            payload: self.to_bytes(),
+        }
+        .to_message()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_hole_punch_messages() {
+        let v4 = SocketAddr::from(([192, 0, 2, 1], 6881));
+        let v6 = SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1], 6882));
+        assert_eq!(
+            &HolepunchMsg::Connect(v4).to_bytes()[..],
+            &[1, 0, 192, 0, 2, 1, 0x1a, 0xe1, 0, 0, 0, 0]
+        );
+        for message in [
+            HolepunchMsg::Rendezvous(v4),
+            HolepunchMsg::Connect(v6),
+            HolepunchMsg::Error(v4, HolepunchError::NotConnected),
+            HolepunchMsg::Error(v6, HolepunchError::Unknown(9)),
+        ] {
+            assert_eq!(HolepunchMsg::parse(&message.to_bytes()), Ok(message));
+        }
+
+        assert!(HolepunchMsg::parse(&[]).is_err());
+        assert!(HolepunchMsg::parse(&[0, 0, 192, 0, 2, 1]).is_err());
+        assert!(HolepunchMsg::parse(&[3, 0, 192, 0, 2, 1, 0x1a, 0xe1, 0, 0, 0, 0]).is_err());
+    }
+}
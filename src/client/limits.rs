@@ -0,0 +1,263 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::config::SessionLimits;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    /// A connected peer.
+    Connection,
+    /// An outgoing connection that hasn't finished its handshake.
+    HalfOpen,
+    /// A peer we are unchoking.
+    Upload,
+}
+
+impl Slot {
+    const COUNT: usize = 3;
+
+    fn index(self) -> usize {
+        match self {
+            Slot::Connection => 0,
+            Slot::HalfOpen => 1,
+            Slot::Upload => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Share {
+    weight: u32,
+    /// Upload slots the torrent could use right now.
+    demand: usize,
+}
+
+#[derive(Debug)]
+struct Inner {
+    limits: [usize; Slot::COUNT],
+    used: [AtomicUsize; Slot::COUNT],
+    /// Each torrent's claim on the upload slots, by registration.
+    shares: Mutex<BTreeMap<usize, Share>>,
+    next_share: AtomicUsize,
+}
+
+/// Connection and upload slots shared by the clients of a session. Each
+/// client holds a [`Permit`] for every slot it uses, and goes without when
+/// none are left.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimits {
+    inner: Arc<Inner>,
+}
+
+impl ConnectionLimits {
+    pub fn new(limits: &SessionLimits) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                limits: [limits.max_peers, limits.max_half_open, limits.upload_slots],
+                used: Default::default(),
+                shares: Mutex::new(BTreeMap::new()),
+                next_share: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    pub fn limit(&self, slot: Slot) -> usize {
+        self.inner.limits[slot.index()]
+    }
+
+    pub fn used(&self, slot: Slot) -> usize {
+        self.inner.used[slot.index()].load(Ordering::Acquire)
+    }
+
+    pub fn available(&self, slot: Slot) -> usize {
+        self.limit(slot).saturating_sub(self.used(slot))
+    }
+
+    /// Takes a slot if one is free.
+    pub fn try_acquire(&self, slot: Slot) -> Option<Permit> {
+        let limit = self.limit(slot);
+        self.inner.used[slot.index()]
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                (used < limit).then_some(used + 1)
+            })
+            .ok()?;
+        Some(Permit {
+            limits: self.clone(),
+            slot,
+        })
+    }
+
+    /// Registers a torrent to get a share of the upload slots in proportion
+    /// to `weight`. It is dropped from the split once every clone of the
+    /// share is.
+    pub fn upload_share(&self, weight: u32) -> UploadShare {
+        let id = self.inner.next_share.fetch_add(1, Ordering::Relaxed);
+        self.inner.shares.lock().unwrap().insert(
+            id,
+            Share {
+                weight: weight.max(1),
+                demand: 0,
+            },
+        );
+        UploadShare {
+            registration: Arc::new(Registration {
+                limits: self.clone(),
+                id,
+            }),
+        }
+    }
+}
+
+/// Splits `total` slots between shares by weight, never giving one more than
+/// it wants. What a share doesn't want goes to the others.
+fn allocate(total: usize, shares: &BTreeMap<usize, Share>) -> BTreeMap<usize, usize> {
+    let mut given = shares.keys().map(|&id| (id, 0)).collect::<BTreeMap<_, _>>();
+    for _ in 0..total {
+        // the share furthest below its weight once given one more slot
+        let next = shares
+            .iter()
+            .filter(|(id, share)| given[*id] < share.demand)
+            .min_by(|(a, a_share), (b, b_share)| {
+                let a_ratio = (given[*a] as u64 + 1) * b_share.weight as u64;
+                let b_ratio = (given[*b] as u64 + 1) * a_share.weight as u64;
+                a_ratio.cmp(&b_ratio)
+            });
+        let Some((&id, _)) = next else {
+            break;
+        };
+        *given.get_mut(&id).unwrap() += 1;
+    }
+    given
+}
+
+#[derive(Debug)]
+struct Registration {
+    limits: ConnectionLimits,
+    id: usize,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.limits.inner.shares.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// A torrent's claim on the upload slots of [`ConnectionLimits`]. Torrents
+/// say how many slots they could use, and unchoke no more peers than their
+/// quota, so one torrent with many interested peers can't keep the others
+/// from uploading. Cheap to clone.
+#[derive(Debug, Clone)]
+pub struct UploadShare {
+    registration: Arc<Registration>,
+}
+
+impl UploadShare {
+    fn update(&self, update: impl FnOnce(&mut Share)) {
+        let Registration { limits, id } = &*self.registration;
+        if let Some(share) = limits.inner.shares.lock().unwrap().get_mut(id) {
+            update(share);
+        }
+    }
+
+    pub fn weight(&self) -> u32 {
+        let Registration { limits, id } = &*self.registration;
+        limits.inner.shares.lock().unwrap()[id].weight
+    }
+
+    /// Weights below 1 count as 1.
+    pub fn set_weight(&self, weight: u32) {
+        self.update(|share| share.weight = weight.max(1));
+    }
+
+    /// Sets how many upload slots the torrent could use right now.
+    pub fn set_demand(&self, demand: usize) {
+        self.update(|share| share.demand = demand);
+    }
+
+    /// The upload slots the torrent may use, given what every torrent wants.
+    pub fn quota(&self) -> usize {
+        let Registration { limits, id } = &*self.registration;
+        let shares = limits.inner.shares.lock().unwrap();
+        allocate(limits.limit(Slot::Upload), &shares)[id]
+    }
+}
+
+/// A slot taken from [`ConnectionLimits`], given back on drop.
+#[derive(Debug)]
+pub struct Permit {
+    limits: ConnectionLimits,
+    slot: Slot,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limits.inner.used[self.slot.index()].fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hands_out_slots_up_to_each_limit() {
+        let limits = ConnectionLimits::new(&SessionLimits {
+            max_peers: 2,
+            max_half_open: 1,
+            upload_slots: 0,
+        });
+
+        let first = limits.try_acquire(Slot::Connection).unwrap();
+        let second = limits.clone().try_acquire(Slot::Connection).unwrap();
+        assert!(limits.try_acquire(Slot::Connection).is_none());
+        assert!(limits.try_acquire(Slot::Upload).is_none());
+
+        let dialing = limits.try_acquire(Slot::HalfOpen).unwrap();
+        assert_eq!(limits.available(Slot::HalfOpen), 0);
+
+        drop(first);
+        drop(dialing);
+        assert_eq!(limits.used(Slot::Connection), 1);
+        assert_eq!(limits.available(Slot::HalfOpen), 1);
+        assert!(limits.try_acquire(Slot::Connection).is_some());
+        drop(second);
+        assert_eq!(limits.used(Slot::Connection), 0);
+    }
+
+    #[test]
+    fn splits_upload_slots_by_weight() {
+        let limits = ConnectionLimits::new(&SessionLimits {
+            upload_slots: 6,
+            ..SessionLimits::default()
+        });
+        let busy = limits.upload_share(1);
+        busy.set_demand(10);
+        // alone, a torrent gets every slot it wants
+        assert_eq!(busy.quota(), 6);
+
+        let heavy = limits.upload_share(2);
+        heavy.set_demand(10);
+        assert_eq!((busy.quota(), heavy.quota()), (2, 4));
+
+        // slots one torrent can't use go to the other
+        heavy.set_demand(1);
+        assert_eq!((busy.quota(), heavy.quota()), (5, 1));
+
+        heavy.set_demand(10);
+        heavy.set_weight(1);
+        assert_eq!(heavy.weight(), 1);
+        assert_eq!((busy.quota(), heavy.quota()), (3, 3));
+
+        let idle = limits.upload_share(5);
+        assert_eq!(idle.quota(), 0);
+        assert_eq!(busy.quota(), 3);
+
+        drop(heavy);
+        assert_eq!(busy.quota(), 6);
+    }
+}
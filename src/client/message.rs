@@ -1,19 +1,33 @@
 use std::fmt::Display;
 
-use tokio::{net::TcpStream, task::yield_now};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+/// Largest message accepted from a peer: room for the bitfield of a torrent
+/// with millions of pieces, and far more than any block we request.
+pub const MAX_MESSAGE_LEN: u32 = 1 << 20;
+/// Largest block a peer may ask for or send in one message.
+pub const MAX_BLOCK_LEN: u32 = 1 << 17;
+/// How much is read from the socket at a time.
+const READ_CHUNK: usize = 32 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MessageId {
-    Choke = 0,
-    Unchoke = 1,
-    Interested = 2,
-    NotInterested = 3,
-    Have = 4,
-    Bitfield = 5,
-    Request = 6,
-    Piece = 7,
-    Cancel = 8,
-    Port = 9,
-    KeepAlive = 10,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have,
+    Bitfield,
+    Request,
+    Piece,
+    Cancel,
+    Port,
+    /// Carries the messages of extensions (BEP 10).
+    Extended,
+    KeepAlive,
+    /// An id we don't know, such as one from an extension we don't support.
+    Unknown(u8),
 }
 
 impl MessageId {
@@ -30,6 +44,8 @@ impl MessageId {
             MessageId::Cancel => 8,
             MessageId::Port => 9,
             MessageId::KeepAlive => 10,
+            MessageId::Extended => 20,
+            MessageId::Unknown(id) => *id,
         }
     }
 
@@ -46,7 +62,8 @@ impl MessageId {
             8 => MessageId::Cancel,
             9 => MessageId::Port,
             10 => MessageId::KeepAlive,
-            _ => unreachable!("unhandled message id value: {}", id),
+            20 => MessageId::Extended,
+            id => MessageId::Unknown(id),
         }
     }
 }
@@ -65,6 +82,8 @@ impl Display for MessageId {
             MessageId::Piece => write!(f, "Piece"),
             MessageId::Cancel => write!(f, "Cancel"),
             MessageId::Port => write!(f, "Port"),
+            MessageId::Extended => write!(f, "Extended"),
+            MessageId::Unknown(id) => write!(f, "Unknown({})", id),
         }
     }
 }
@@ -83,19 +102,17 @@ pub struct ReceiveMessageError {
 #[derive(Debug)]
 pub enum ReceiveError {
     ReceiveError(ReceiveMessageError),
-    WouldBlock,
 }
 
+#[derive(Debug)]
 pub enum SendError {
     SendError(SendMessageError),
-    WouldBlock,
 }
 
 impl Display for ReceiveError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ReceiveError::ReceiveError(e) => write!(f, "Failed to receive message: {}", e.error),
-            ReceiveError::WouldBlock => write!(f, "Would block"),
         }
     }
 }
@@ -104,7 +121,6 @@ impl Display for SendError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             SendError::SendError(e) => write!(f, "Failed to send message: {}", e.error),
-            SendError::WouldBlock => write!(f, "Would block"),
         }
     }
 }
@@ -119,19 +135,45 @@ impl Display for SendMessageError {
     }
 }
 
-#[derive(Debug)]
+impl Display for ReceiveMessageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Failed to receive message: {}", self.error)
+    }
+}
+
+impl std::error::Error for SendMessageError {}
+
+impl std::error::Error for ReceiveMessageError {}
+
+impl std::error::Error for SendError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SendError::SendError(e) => Some(e),
+        }
+    }
+}
+
+impl std::error::Error for ReceiveError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReceiveError::ReceiveError(e) => Some(e),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Message {
     len: u32,
     id: u8,
-    payload: Vec<u8>,
+    payload: Bytes,
 }
 
 impl Message {
-    pub fn new(id: MessageId, payload: &Vec<u8>) -> Self {
+    pub fn new(id: MessageId, payload: Bytes) -> Self {
         Self {
             len: payload.len() as u32 + 1, // +1 for the id
             id: id.value(),
-            payload: payload.clone(),
+            payload,
         }
     }
 
@@ -139,26 +181,38 @@ impl Message {
         MessageId::from_value(self.id)
     }
 
-    pub fn get_payload(&self) -> &Vec<u8> {
+    pub fn get_payload(&self) -> &Bytes {
         &self.payload
     }
 
-    fn serialize(&self) -> Vec<u8> {
-        let mut message = Vec::new();
-        message.extend_from_slice(&self.len.to_be_bytes());
-        message.push(self.id);
-        message.extend_from_slice(&self.payload);
-        message
+    /// The message in `frame`, which holds the `len` bytes after the length
+    /// prefix.
+    fn from_frame(len: u32, mut frame: Bytes) -> Self {
+        if len == 0 {
+            return Self {
+                len,
+                id: MessageId::KeepAlive.value(),
+                payload: frame,
+            };
+        }
+        let payload = frame.split_off(1);
+        Self {
+            len,
+            id: frame[0],
+            payload,
+        }
     }
-}
 
-impl Clone for Message {
-    fn clone(&self) -> Self {
-        Self {
-            len: self.len,
-            id: self.id,
-            payload: self.payload.clone(),
+    fn serialize(&self) -> Bytes {
+        if self.id == MessageId::KeepAlive.value() {
+            return Bytes::from_static(&[0; 4]);
         }
+
+        let mut message = BytesMut::with_capacity(4 + self.len as usize);
+        message.put_u32(self.len);
+        message.put_u8(self.id);
+        message.extend_from_slice(&self.payload);
+        message.freeze()
     }
 }
 
@@ -172,92 +226,383 @@ impl Display for Message {
     }
 }
 
-pub async fn send_message(stream: &TcpStream, message: &Message) -> Result<(), SendError> {
-    let mut bytes_written = 0;
-    let serialized_message = message.serialize();
-    while bytes_written < serialized_message.len() {
-        // stream.writable().await.unwrap();
-        match stream.try_write(&serialized_message[bytes_written..]) {
-            Ok(0) => {
-                return Err(SendError::SendError(SendMessageError {
-                    message: message.clone(),
-                    error: "EOF".to_string(),
-                }))
-            }
-            Ok(n) => {
-                bytes_written += n;
-            }
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                return Err(SendError::WouldBlock);
-            }
-            Err(e) => {
-                return Err(SendError::SendError(SendMessageError {
-                    message: message.clone(),
-                    error: format!("Failed to send message: {}", e),
-                }));
-            }
-        };
-        yield_now().await;
+/// A message whose payload is the wrong size for its id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PayloadError {
+    pub id: MessageId,
+    pub len: usize,
+}
+
+impl Display for PayloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid {} payload length: {}", self.id, self.len)
+    }
+}
+
+impl std::error::Error for PayloadError {}
+
+/// The big-endian integer at `at`, once the payload is known to be long
+/// enough.
+fn u32_at(payload: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(payload[at..at + 4].try_into().unwrap())
+}
+
+fn check_len(id: MessageId, payload: &[u8], len: usize) -> Result<(), PayloadError> {
+    if payload.len() != len {
+        return Err(PayloadError {
+            id,
+            len: payload.len(),
+        });
     }
     Ok(())
 }
 
-pub async fn receive_message(stream: &TcpStream) -> Result<Message, ReceiveError> {
-    let mut len = [0u8; 4];
-    let mut bytes_read = 0;
-    while bytes_read < 4 {
-        match stream.try_read(&mut len[bytes_read..]) {
-            Ok(0) => {
-                return Err(ReceiveError::ReceiveError(ReceiveMessageError {
-                    error: "stream was closed".to_string(),
-                }))
-            }
-            Ok(n) => {
-                bytes_read += n;
-            }
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                return Err(ReceiveError::WouldBlock);
-            }
-            Err(e) => {
-                return Err(ReceiveError::ReceiveError(ReceiveMessageError {
-                    error: format!("Failed to read message length: {}", e),
-                }));
-            }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HaveMsg {
+    pub index: u32,
+}
+
+impl HaveMsg {
+    pub fn parse(payload: &[u8]) -> Result<Self, PayloadError> {
+        check_len(MessageId::Have, payload, 4)?;
+        Ok(Self {
+            index: u32_at(payload, 0),
+        })
+    }
+
+    pub fn to_message(self) -> Message {
+        Message::new(
+            MessageId::Have,
+            Bytes::copy_from_slice(&self.index.to_be_bytes()),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestMsg {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+impl RequestMsg {
+    pub fn parse(payload: &[u8]) -> Result<Self, PayloadError> {
+        check_len(MessageId::Request, payload, 12)?;
+        Ok(Self {
+            index: u32_at(payload, 0),
+            begin: u32_at(payload, 4),
+            length: u32_at(payload, 8),
+        })
+    }
+
+    pub fn to_message(self) -> Message {
+        let mut payload = BytesMut::with_capacity(12);
+        payload.put_u32(self.index);
+        payload.put_u32(self.begin);
+        payload.put_u32(self.length);
+        Message::new(MessageId::Request, payload.freeze())
+    }
+}
+
+/// Takes back a [`RequestMsg`] with the same fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CancelMsg {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+impl CancelMsg {
+    pub fn parse(payload: &[u8]) -> Result<Self, PayloadError> {
+        check_len(MessageId::Cancel, payload, 12)?;
+        Ok(Self {
+            index: u32_at(payload, 0),
+            begin: u32_at(payload, 4),
+            length: u32_at(payload, 8),
+        })
+    }
+
+    pub fn to_message(self) -> Message {
+        let mut payload = BytesMut::with_capacity(12);
+        payload.put_u32(self.index);
+        payload.put_u32(self.begin);
+        payload.put_u32(self.length);
+        Message::new(MessageId::Cancel, payload.freeze())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PieceMsg {
+    pub index: u32,
+    pub begin: u32,
+    pub block: Bytes,
+}
+
+impl PieceMsg {
+    pub fn parse(payload: &Bytes) -> Result<Self, PayloadError> {
+        if payload.len() < 8 {
+            return Err(PayloadError {
+                id: MessageId::Piece,
+                len: payload.len(),
+            });
         }
+        Ok(Self {
+            index: u32_at(payload, 0),
+            begin: u32_at(payload, 4),
+            block: payload.slice(8..),
+        })
     }
+
+    pub fn to_message(&self) -> Message {
+        let mut payload = BytesMut::with_capacity(8 + self.block.len());
+        payload.put_u32(self.index);
+        payload.put_u32(self.begin);
+        payload.extend_from_slice(&self.block);
+        Message::new(MessageId::Piece, payload.freeze())
+    }
+}
+
+/// The port a peer's DHT node listens on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortMsg {
+    pub port: u16,
+}
+
+impl PortMsg {
+    pub fn parse(payload: &[u8]) -> Result<Self, PayloadError> {
+        check_len(MessageId::Port, payload, 2)?;
+        Ok(Self {
+            port: u16::from_be_bytes([payload[0], payload[1]]),
+        })
+    }
+
+    pub fn to_message(self) -> Message {
+        Message::new(
+            MessageId::Port,
+            Bytes::copy_from_slice(&self.port.to_be_bytes()),
+        )
+    }
+}
+
+pub async fn send_message<W>(stream: &mut W, message: &Message) -> Result<(), SendError>
+where
+    W: AsyncWrite + Unpin,
+{
+    stream.write_all(&message.serialize()).await.map_err(|e| {
+        SendError::SendError(SendMessageError {
+            message: message.clone(),
+            error: format!("Failed to send message: {}", e),
+        })
+    })
+}
+
+/// Reads a single message straight off `stream`. Peers send a stream of
+/// them, which [`MessageReader`] frames with fewer reads.
+pub async fn receive_message<R>(stream: &mut R) -> Result<Message, ReceiveError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut len = [0u8; 4];
+    stream
+        .read_exact(&mut len)
+        .await
+        .map_err(|e| receive_error(format!("Failed to read message length: {}", e)))?;
+
     let len = u32::from_be_bytes(len);
-    if len == 0 {
-        return Ok(Message {
-            len,
-            id: MessageId::KeepAlive.value(),
-            payload: Vec::new(),
-        });
+    if len > MAX_MESSAGE_LEN {
+        return Err(too_long(len, MAX_MESSAGE_LEN));
     }
+    let mut message = BytesMut::zeroed(len as usize);
+    stream
+        .read_exact(&mut message)
+        .await
+        .map_err(|e| receive_error(format!("Failed to read message: {}", e)))?;
+    Ok(Message::from_frame(len, message.freeze()))
+}
 
-    let mut message = vec![0u8; len as usize];
-    let mut bytes_read = 0;
-    while bytes_read < len as usize {
-        match stream.try_read(&mut message[bytes_read..]) {
-            Ok(0) => {
-                return Err(ReceiveError::ReceiveError(ReceiveMessageError {
-                    error: "stream was closed".to_string(),
-                }))
-            }
-            Ok(n) => {
-                bytes_read += n;
+/// Splits bytes from the wire into length-prefixed messages.
+#[derive(Debug, Clone, Copy)]
+pub struct MessageCodec {
+    max_len: u32,
+}
+
+impl Default for MessageCodec {
+    fn default() -> Self {
+        Self::new(MAX_MESSAGE_LEN)
+    }
+}
+
+impl MessageCodec {
+    /// Messages longer than `max_len` are an error rather than something to
+    /// allocate for.
+    pub fn new(max_len: u32) -> Self {
+        Self { max_len }
+    }
+
+    /// Allows the largest message a peer has any reason to send for a
+    /// torrent of `num_pieces`: its bitfield, or a piece message carrying a
+    /// whole block.
+    pub fn for_torrent(num_pieces: usize) -> Self {
+        let bitfield = 1 + num_pieces.div_ceil(8) as u64;
+        let piece = 1 + 8 + MAX_BLOCK_LEN as u64;
+        Self::new(bitfield.max(piece).min(MAX_MESSAGE_LEN as u64) as u32)
+    }
+
+    /// Takes the first whole message off `buf`, or returns `None` if more
+    /// bytes are needed for it.
+    pub fn decode(&self, buf: &mut BytesMut) -> Result<Option<Message>, ReceiveError> {
+        if buf.len() < 4 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        if len > self.max_len {
+            return Err(too_long(len, self.max_len));
+        }
+        if buf.len() < 4 + len as usize {
+            buf.reserve(4 + len as usize - buf.len());
+            return Ok(None);
+        }
+
+        buf.advance(4);
+        let frame = buf.split_to(len as usize).freeze();
+        Ok(Some(Message::from_frame(len, frame)))
+    }
+}
+
+/// Reads messages from a peer through a [`MessageCodec`], keeping whatever
+/// arrived past the end of one message for the next.
+pub struct MessageReader<R> {
+    reader: R,
+    codec: MessageCodec,
+    buf: BytesMut,
+}
+
+impl<R: AsyncRead + Unpin> MessageReader<R> {
+    pub fn new(reader: R, codec: MessageCodec) -> Self {
+        Self {
+            reader,
+            codec,
+            buf: BytesMut::with_capacity(READ_CHUNK),
+        }
+    }
+
+    pub async fn next(&mut self) -> Result<Message, ReceiveError> {
+        loop {
+            if let Some(message) = self.codec.decode(&mut self.buf)? {
+                return Ok(message);
             }
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                yield_now().await;
+
+            let filled = self.buf.len();
+            self.buf.resize(filled + READ_CHUNK, 0);
+            let read = self.reader.read(&mut self.buf[filled..]).await;
+            let n = match read {
+                Ok(n) => n,
+                Err(e) => return Err(receive_error(format!("Failed to read message: {}", e))),
+            };
+            self.buf.truncate(filled + n);
+            if n == 0 {
+                return Err(receive_error(format!(
+                    "Connection closed with {} bytes of a message unread",
+                    filled
+                )));
             }
-            Err(e) => {
-                return Err(ReceiveError::ReceiveError(ReceiveMessageError {
-                    error: format!("Failed to read message: {}", e),
-                }));
+        }
+    }
+}
+
+fn receive_error(error: String) -> ReceiveError {
+    ReceiveError::ReceiveError(ReceiveMessageError { error })
+}
+
+fn too_long(len: u32, max_len: u32) -> ReceiveError {
+    receive_error(format!(
+        "Message of {} bytes is over the {} byte limit",
+        len, max_len
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_payloads_of_the_wrong_length() {
+        let request = RequestMsg {
+            index: 1,
+            begin: 16384,
+            length: 16384,
+        };
+        let message = request.to_message();
+        assert_eq!(RequestMsg::parse(message.get_payload()), Ok(request));
+        assert_eq!(
+            RequestMsg::parse(&message.get_payload()[..11]),
+            Err(PayloadError {
+                id: MessageId::Request,
+                len: 11
+            })
+        );
+
+        assert_eq!(HaveMsg::parse(&[0, 0, 0, 7]), Ok(HaveMsg { index: 7 }));
+        assert!(HaveMsg::parse(&[0, 0, 7]).is_err());
+        assert!(HaveMsg::parse(&[0, 0, 0, 0, 7]).is_err());
+
+        let piece = PieceMsg::parse(&Bytes::from_static(&[0, 0, 0, 2, 0, 0, 0, 0, 9])).unwrap();
+        assert_eq!(
+            (piece.index, piece.begin, &piece.block[..]),
+            (2, 0, &[9][..])
+        );
+        assert!(PieceMsg::parse(&Bytes::from_static(&[0, 0, 0, 2, 0, 0, 0])).is_err());
+        assert_eq!(PortMsg::parse(&[0x1a, 0xe1]), Ok(PortMsg { port: 6881 }));
+        assert_eq!(
+            PortMsg { port: 6881 }.to_message().get_payload(),
+            &[0x1a, 0xe1][..]
+        );
+    }
+
+    #[test]
+    fn frames_messages_split_anywhere() {
+        let have = Message::new(MessageId::Have, Bytes::from_static(&[0, 0, 0, 3]));
+        let keep_alive = Message::new(MessageId::KeepAlive, Bytes::new());
+        let mut wire = have.serialize().to_vec();
+        wire.extend_from_slice(&keep_alive.serialize());
+        wire.extend_from_slice(&have.serialize());
+
+        let codec = MessageCodec::default();
+        let mut buf = BytesMut::new();
+        let mut ids = Vec::new();
+        // one byte at a time, so the length header is split too
+        for byte in wire {
+            buf.put_u8(byte);
+            while let Some(message) = codec.decode(&mut buf).unwrap() {
+                ids.push(message.get_id());
             }
         }
+        assert_eq!(
+            ids,
+            [MessageId::Have, MessageId::KeepAlive, MessageId::Have]
+        );
+        assert!(buf.is_empty());
     }
-    let id = message[0];
-    let payload = message[1..].to_vec();
 
-    Ok(Message { len, id, payload })
+    #[test]
+    fn rejects_length_prefixes_over_the_limit() {
+        let codec = MessageCodec::new(16);
+        let mut buf = BytesMut::from(&[0, 0, 0, 17, 7][..]);
+        assert!(codec.decode(&mut buf).is_err());
+
+        let mut huge = &[0xff, 0xff, 0xff, 0xff, 7][..];
+        assert!(futures::executor::block_on(receive_message(&mut huge)).is_err());
+
+        // a block fits, and so does the bitfield of a large torrent
+        let codec = MessageCodec::for_torrent(100);
+        let mut piece = BytesMut::new();
+        piece.put_u32(9 + MAX_BLOCK_LEN);
+        assert!(codec.decode(&mut piece).is_ok());
+        piece[3] += 1;
+        assert!(codec.decode(&mut piece).is_err());
+        let mut bitfield = BytesMut::from(&(1 + 500_000u32).to_be_bytes()[..]);
+        assert!(MessageCodec::for_torrent(4_000_000)
+            .decode(&mut bitfield)
+            .is_ok());
+    }
 }
@@ -0,0 +1,191 @@
+use std::{
+    fmt::Write as _,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use futures::{AsyncReadExt, AsyncWriteExt};
+use tracing::{debug, info};
+
+use crate::runtime::{Runtime, TcpListener};
+
+/// Largest request head the exporter reads before giving up.
+const MAX_REQUEST_LENGTH: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    PiecesCompleted,
+    BytesDownloaded,
+    BytesUploaded,
+    PeersConnected,
+    PeersDisconnected,
+    TrackerErrors,
+    PeersBanned,
+    /// Peers connected right now.
+    Peers,
+    /// Reads and writes waiting on the disk thread.
+    DiskQueueDepth,
+    ReadCacheHits,
+    ReadCacheMisses,
+}
+
+impl Metric {
+    pub const ALL: [Metric; 11] = [
+        Metric::PiecesCompleted,
+        Metric::BytesDownloaded,
+        Metric::BytesUploaded,
+        Metric::PeersConnected,
+        Metric::PeersDisconnected,
+        Metric::TrackerErrors,
+        Metric::PeersBanned,
+        Metric::Peers,
+        Metric::DiskQueueDepth,
+        Metric::ReadCacheHits,
+        Metric::ReadCacheMisses,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Metric::PiecesCompleted => "rustorrent_pieces_completed_total",
+            Metric::BytesDownloaded => "rustorrent_downloaded_bytes_total",
+            Metric::BytesUploaded => "rustorrent_uploaded_bytes_total",
+            Metric::PeersConnected => "rustorrent_peer_connections_total",
+            Metric::PeersDisconnected => "rustorrent_peer_disconnections_total",
+            Metric::TrackerErrors => "rustorrent_tracker_errors_total",
+            Metric::PeersBanned => "rustorrent_peers_banned_total",
+            Metric::Peers => "rustorrent_peers",
+            Metric::DiskQueueDepth => "rustorrent_disk_queue_depth",
+            Metric::ReadCacheHits => "rustorrent_read_cache_hits_total",
+            Metric::ReadCacheMisses => "rustorrent_read_cache_misses_total",
+        }
+    }
+
+    fn help(&self) -> &'static str {
+        match self {
+            Metric::PiecesCompleted => "Pieces that passed their hash check.",
+            Metric::BytesDownloaded => "Block bytes received from peers.",
+            Metric::BytesUploaded => "Block bytes sent to peers.",
+            Metric::PeersConnected => "Peer connections opened.",
+            Metric::PeersDisconnected => "Peer connections closed.",
+            Metric::TrackerErrors => "Tracker announces that failed.",
+            Metric::PeersBanned => {
+                "Peer addresses banned for sending bad data or breaking the protocol."
+            }
+            Metric::Peers => "Peers currently connected.",
+            Metric::DiskQueueDepth => "Disk reads and writes waiting to run.",
+            Metric::ReadCacheHits => "Upload reads answered from the read cache.",
+            Metric::ReadCacheMisses => "Upload reads that went to disk.",
+        }
+    }
+
+    fn is_gauge(&self) -> bool {
+        matches!(self, Metric::Peers | Metric::DiskQueueDepth)
+    }
+}
+
+/// Counters the client keeps while it runs. Cloning is cheap and every clone
+/// sees the same values, so one can be handed to an exporter.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    values: Arc<[AtomicU64; Metric::ALL.len()]>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, metric: Metric) -> u64 {
+        self.values[metric as usize].load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn add(&self, metric: Metric, n: u64) {
+        self.values[metric as usize].fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set(&self, metric: Metric, value: u64) {
+        self.values[metric as usize].store(value, Ordering::Relaxed);
+    }
+
+    /// Every metric in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for metric in Metric::ALL {
+            let kind = if metric.is_gauge() {
+                "gauge"
+            } else {
+                "counter"
+            };
+            let _ = writeln!(out, "# HELP {} {}", metric.name(), metric.help());
+            let _ = writeln!(out, "# TYPE {} {}", metric.name(), kind);
+            let _ = writeln!(out, "{} {}", metric.name(), self.get(metric));
+        }
+        out
+    }
+}
+
+/// Answers every HTTP request on `listener` with the current metrics until
+/// the listener fails.
+pub async fn serve<R: Runtime>(
+    runtime: &R,
+    listener: R::TcpListener,
+    metrics: Metrics,
+) -> io::Result<()> {
+    info!(addr = %listener.local_addr()?, "serving metrics");
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let metrics = metrics.clone();
+        runtime.spawn(async move {
+            if let Err(e) = respond(stream, &metrics).await {
+                debug!(%addr, error = %e, "metrics request failed");
+            }
+        });
+    }
+}
+
+async fn respond<S>(mut stream: S, metrics: &Metrics) -> io::Result<()>
+where
+    S: futures::AsyncRead + futures::AsyncWrite + Unpin,
+{
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() + n > MAX_REQUEST_LENGTH {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let body = metrics.render();
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.close().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_prometheus_text() {
+        let metrics = Metrics::new();
+        metrics.add(Metric::BytesDownloaded, 100);
+        metrics.add(Metric::BytesDownloaded, 20);
+        metrics.set(Metric::Peers, 3);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE rustorrent_downloaded_bytes_total counter\n"));
+        assert!(text.contains("\nrustorrent_downloaded_bytes_total 120\n"));
+        assert!(text.contains("# TYPE rustorrent_peers gauge\n"));
+        assert!(text.contains("\nrustorrent_peers 3\n"));
+        assert!(text.contains("\nrustorrent_tracker_errors_total 0\n"));
+    }
+}
@@ -1,43 +1,136 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{BTreeMap, HashMap, HashSet},
     fmt::Display,
-    sync::Arc,
-    time::Duration,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    pin::pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
+use bytes::Bytes;
 use chrono::{DateTime, Utc};
-use pieces::PieceScheduler;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-    sync::{Mutex, RwLock},
-    task::{yield_now, JoinHandle, JoinSet},
-    time::timeout,
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{join_all, select, FutureExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    SinkExt, StreamExt,
 };
-
-mod bitfield;
-mod file_manager;
-mod message;
+use pieces::{BlockOutcome, PieceScheduler};
+use tracing::{debug, info, info_span, trace, warn, Instrument};
+
+pub mod bitfield;
+pub mod budget;
+pub mod capabilities;
+mod completion;
+pub mod cross_seed;
+pub mod disk;
+pub mod event;
+pub mod extension;
+pub mod file_manager;
+pub mod hasher;
+pub mod holepunch;
+pub mod limits;
+pub mod message;
+pub mod metrics;
+pub mod mse;
+mod peer;
+pub mod peer_id;
 mod pieces;
-
+mod pipeline;
+mod pool;
+pub mod priority;
+pub mod rate;
+pub mod reputation;
+pub mod resume;
+#[cfg(feature = "webtorrent")]
+pub mod rtc;
+pub mod selection;
+pub mod state;
+pub mod stream;
+pub mod transport;
+pub mod webseed;
+#[cfg(feature = "webtorrent")]
+mod webtorrent;
+mod write_cache;
+
+#[cfg(feature = "tokio")]
+use crate::runtime::TokioRuntime;
 use crate::{
-    client::message::{receive_message, send_message},
-    tracker::{Peer, Tracker},
+    config::{ClientConfig, EncryptionPolicy, ProxyConfig},
+    dht::{self, Dht},
+    metainfo::{InfoHash, MetaInfoError},
+    nat::{self, PortMapper},
+    proxy,
+    runtime::{timeout, Runtime, TcpListener},
+    tracker::{AnnounceEvent, Peer, PeerSource, Peers, Tracker, TrackerError, TransferStats},
 };
 
 use self::{
     bitfield::Bitfield,
-    message::{Message, MessageId, ReceiveError, SendError, SendMessageError},
+    budget::{MemoryBudget, MemoryKind},
+    capabilities::PeerCapabilities,
+    disk::DiskResult,
+    event::{EventBus, TorrentEvent},
+    extension::{
+        ExtendedHandshake, ExtendedMsg, ExtensionError, HANDSHAKE_ID, UT_HOLEPUNCH, UT_HOLEPUNCH_ID,
+    },
+    file_manager::{DiskError, FileManager, PathError},
+    hasher::HashResult,
+    holepunch::{HolepunchError, HolepunchMsg},
+    limits::{ConnectionLimits, Slot, UploadShare},
+    message::{
+        CancelMsg, HaveMsg, Message, MessageCodec, MessageId, PayloadError, PieceMsg, PortMsg,
+        RequestMsg, SendMessageError, MAX_BLOCK_LEN,
+    },
+    metrics::{Metric, Metrics},
+    mse::MseStream,
+    peer::{PeerEvent, PeerOptions, PeerState},
+    pipeline::Pipeline,
+    pool::PeerPool,
+    priority::PriorityHandle,
+    rate::RateMeter,
+    reputation::Reputation,
+    resume::{resume_path, ResumeData, ResumeError},
+    selection::PieceSelectionStrategy,
+    state::{ClientState, PeerSummary, StateHandle},
+    stream::FileStream,
+    transport::PeerTransport,
+    webseed::WebSeed,
 };
 
 const PSTR: &[u8; 19] = b"BitTorrent protocol";
 const HANDSHAKE_LEN: usize = 49 + PSTR.len();
-const MB: u64 = 1 << 20;
-
+const EVENT_QUEUE_SIZE: usize = 1024;
+const STATE_PUBLISH_INTERVAL: Duration = Duration::from_millis(250);
+const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+/// Re-announce interval used until a tracker has told us its own.
+const DEFAULT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(300);
+/// Least time between the announces made because we ran out of peers to
+/// connect to.
+const REPLENISH_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+/// How long a web seed is left alone after a failed request.
+const WEB_SEED_RETRY_DELAY: Duration = Duration::from_secs(30);
+/// Disk reads and writes failing in a row before the torrent stops, when
+/// each failure looked like it could pass.
+const MAX_DISK_FAILURES: u32 = 5;
+/// Requests a peer can have waiting on us before further ones are dropped.
+const MAX_QUEUED_UPLOADS: usize = 250;
+
+/// A peer that connected to us and passed the handshake, with the peer id
+/// and capabilities it sent.
+type IncomingPeer<S> = (Vec<u8>, PeerCapabilities, Peer, S);
+
+#[derive(Debug)]
 pub struct PeerConnectionError {
     pub peer: Peer,
 }
 
+#[derive(Debug)]
 pub enum HandshakePhase {
     Send,
     Receive,
@@ -52,6 +145,7 @@ impl Display for HandshakePhase {
     }
 }
 
+#[derive(Debug)]
 pub struct HandshakeError {
     peer: Peer,
     handshake: Vec<u8>,
@@ -59,31 +153,49 @@ pub struct HandshakeError {
     message: String,
 }
 
+impl Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Peer: {}, Status: {}, Message: {} Handshake:\n{}",
+            self.peer,
+            self.status,
+            self.message,
+            self.handshake
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>()
+        )
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+#[derive(Debug)]
 pub enum ClientError {
+    Io(io::Error),
+    Metainfo(MetaInfoError),
+    Tracker(TrackerError),
     ValidateHandshakeError(String),
     GetPeersError(String),
     HandshakeError(HandshakeError),
     SendMessageError((Vec<u8>, SendMessageError)),
     ReceiveMessageError((Vec<u8>, Option<Message>, String)),
     ProcessMessagesError(String),
+    InvalidPath(PathError),
+    /// The disk kept failing, so the torrent stopped until someone looks.
+    Disk(DiskError),
 }
 
 impl Display for ClientError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            ClientError::Io(e) => write!(f, "Io: {}", e),
+            ClientError::Metainfo(e) => write!(f, "Metainfo: {}", e),
+            ClientError::Tracker(e) => write!(f, "Tracker: {}", e),
             ClientError::ValidateHandshakeError(e) => write!(f, "ValidateHandshakeError: {}", e),
             ClientError::GetPeersError(e) => write!(f, "GetPeersError: {}", e),
-            ClientError::HandshakeError(e) => write!(
-                f,
-                "HandshakeError: Peer: {}, Status: {}, Message: {} Handshake:\n{}",
-                e.peer,
-                e.status,
-                e.message,
-                e.handshake
-                    .iter()
-                    .map(|b| format!("{:02x}", b))
-                    .collect::<String>()
-            ),
+            ClientError::HandshakeError(e) => write!(f, "HandshakeError: {}", e),
             ClientError::SendMessageError(e) => {
                 write!(f, "SendMessageError: PeerId: {:?}, Error: {}", e.0, e.1)
             }
@@ -97,415 +209,1525 @@ impl Display for ClientError {
                 )
             }
             ClientError::ProcessMessagesError(e) => write!(f, "ProcessMessagesError: {}", e),
+            ClientError::InvalidPath(e) => write!(f, "InvalidPath: {}", e),
+            ClientError::Disk(e) => write!(f, "Disk: {}", e),
         }
     }
 }
 
-struct PeerState {
-    peer_id: Vec<u8>,
-    stream: TcpStream,
-    bitfield: Option<Bitfield>,
-    last_touch: DateTime<Utc>,
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Io(e) => Some(e),
+            ClientError::Metainfo(e) => Some(e),
+            ClientError::Tracker(e) => Some(e),
+            ClientError::HandshakeError(e) => Some(e),
+            ClientError::SendMessageError((_, e)) => Some(e),
+            ClientError::InvalidPath(e) => Some(e),
+            ClientError::Disk(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
-    am_choking: bool,
-    am_interested: bool,
-    peer_choking: bool,
-    peer_interested: bool,
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        ClientError::Io(e)
+    }
 }
 
-impl PeerState {
-    pub fn new(peer_id: &Vec<u8>, stream: TcpStream) -> Self {
-        Self {
-            peer_id: peer_id.clone(),
-            stream,
-            last_touch: Utc::now(),
+impl From<MetaInfoError> for ClientError {
+    fn from(e: MetaInfoError) -> Self {
+        ClientError::Metainfo(e)
+    }
+}
 
-            bitfield: None,
-            am_choking: true,
-            am_interested: false,
-            peer_choking: true,
-            peer_interested: false,
+impl From<TrackerError> for ClientError {
+    fn from(e: TrackerError) -> Self {
+        ClientError::Tracker(e)
+    }
+}
+
+impl From<PathError> for ClientError {
+    fn from(e: PathError) -> Self {
+        ClientError::InvalidPath(e)
+    }
+}
+
+impl From<DiskError> for ClientError {
+    fn from(e: DiskError) -> Self {
+        match e {
+            DiskError::InvalidPath(e) => ClientError::InvalidPath(e),
+            e => ClientError::Disk(e),
         }
     }
 }
 
-pub struct Client {
+type WebSeedResult = (usize, usize, io::Result<Vec<u8>>);
+type DialResult<S> = (SocketAddr, Result<IncomingPeer<S>, ClientError>);
+
+impl From<PayloadError> for ClientError {
+    fn from(e: PayloadError) -> Self {
+        ClientError::ProcessMessagesError(e.to_string())
+    }
+}
+
+impl From<ExtensionError> for ClientError {
+    fn from(e: ExtensionError) -> Self {
+        ClientError::ProcessMessagesError(e.to_string())
+    }
+}
+
+pub struct Client<R: Runtime> {
+    runtime: R,
     tracker: Tracker,
-    peers: Arc<RwLock<HashMap<Vec<u8>, Arc<Mutex<PeerState>>>>>,
-    piece_scheduler: Arc<RwLock<PieceScheduler>>,
-    send_queue: Arc<Mutex<VecDeque<(Vec<u8>, Message)>>>,
-    receive_queue: Arc<Mutex<VecDeque<(Vec<u8>, Message)>>>,
-    total_downloaded: Arc<Mutex<u64>>,
+    config: ClientConfig,
+    peers: HashMap<Vec<u8>, PeerState>,
+    piece_scheduler: PieceScheduler,
+    hash_rx: mpsc::UnboundedReceiver<HashResult>,
+    disk_rx: mpsc::UnboundedReceiver<DiskResult>,
+    budget: MemoryBudget,
+    /// Connection and upload slots, shared with the session's other torrents.
+    limits: ConnectionLimits,
+    /// This torrent's part of the upload slots in `limits`.
+    upload_share: UploadShare,
+    /// Peers we stopped requesting from because the memory budget ran out.
+    throttled: HashSet<Vec<u8>>,
+    events_tx: mpsc::Sender<PeerEvent>,
+    events_rx: mpsc::Receiver<PeerEvent>,
+    /// Peers that connected to us, over TCP or any other transport.
+    incoming_tx: mpsc::Sender<IncomingPeer<Box<dyn PeerTransport>>>,
+    incoming_rx: mpsc::Receiver<IncomingPeer<Box<dyn PeerTransport>>>,
+    deadlines_tx: mpsc::UnboundedSender<(usize, Duration)>,
+    deadlines_rx: mpsc::UnboundedReceiver<(usize, Duration)>,
+    /// Every peer we have been told about, for replacing lost connections.
+    pool: PeerPool,
+    /// Offences per address, and the addresses banned for them.
+    reputation: Reputation,
+    /// Addresses that sent blocks of each piece still being verified, to
+    /// blame if it turns out corrupt.
+    piece_sources: HashMap<usize, HashSet<IpAddr>>,
+    dial_tx: mpsc::UnboundedSender<DialResult<MseStream<R::TcpStream>>>,
+    dial_rx: mpsc::UnboundedReceiver<DialResult<MseStream<R::TcpStream>>>,
+    web_seeds: Vec<WebSeed>,
+    /// Pieces fetched from web seeds, tagged with the seed's index.
+    web_seed_tx: mpsc::UnboundedSender<WebSeedResult>,
+    web_seed_rx: mpsc::UnboundedReceiver<WebSeedResult>,
+    /// Stops the listener task when dropped.
+    listener_shutdown: Option<oneshot::Sender<()>>,
+    /// Stops announcing to WebSocket trackers when dropped.
+    #[cfg(feature = "webtorrent")]
+    webtorrent_shutdown: Option<oneshot::Sender<()>>,
+    /// The port the listener bound, once it has.
+    listen_port: Option<u16>,
+    /// Port mapping kept up by the listener task when NAT traversal is on.
+    port_mapper: PortMapper,
+    total_downloaded: u64,
+    total_uploaded: u64,
+    seeding_time: Duration,
+    seeding_done: bool,
+    download_meter: RateMeter,
+    upload_meter: RateMeter,
+    metrics: Metrics,
     start_time: DateTime<Utc>,
+    state: Option<StateHandle>,
+    last_published: Instant,
+    resume_path: PathBuf,
+    last_resume_save: Instant,
+    last_announce: Instant,
+    next_announce: Instant,
+    events: EventBus,
+    dht: Option<Dht<R>>,
+    /// Addresses we have asked relays to hole punch to, so each is only
+    /// asked for once.
+    holepunched: HashSet<SocketAddr>,
+    /// Disk reads and writes that failed since the last one that worked.
+    disk_failures: u32,
+    /// Set once the disk fails for good, to stop downloading or seeding.
+    disk_error: Option<DiskError>,
 }
 
-impl Client {
-    pub fn new(tracker: Tracker, output_dir: String) -> Self {
-        let piece_scheduler = PieceScheduler::new(&tracker.get_metainfo().info, output_dir);
-        Self {
+#[cfg(feature = "tokio")]
+impl Client<TokioRuntime> {
+    pub fn new(
+        tracker: Tracker,
+        output_dir: String,
+        config: ClientConfig,
+    ) -> Result<Self, ClientError> {
+        Self::with_runtime(tracker, output_dir, config, TokioRuntime)
+    }
+}
+
+impl<R: Runtime> Client<R> {
+    /// Picks up any progress recorded in a resume file in `output_dir`,
+    /// re-verifying the saved pieces before trusting them. Fails if the
+    /// torrent's file names can't be used safely.
+    pub fn with_runtime(
+        tracker: Tracker,
+        output_dir: String,
+        config: ClientConfig,
+        runtime: R,
+    ) -> Result<Self, ClientError> {
+        let config = config.clamped();
+        let info = &tracker.get_metainfo().info;
+        let resume_path = resume_path(&output_dir, info);
+        let (hash_tx, hash_rx) = mpsc::unbounded();
+        let (disk_tx, disk_rx) = mpsc::unbounded();
+        let budget = MemoryBudget::new(config.memory_budget);
+        let mut piece_scheduler =
+            PieceScheduler::new(info, output_dir, &config, budget.clone(), hash_tx, disk_tx)?;
+
+        let mut total_downloaded = 0;
+        let mut total_uploaded = 0;
+        let mut seeding_time = Duration::ZERO;
+        let mut seeding_done = false;
+        match ResumeData::load(&resume_path, piece_scheduler.len()) {
+            Ok(resume) if tracker.get_metainfo().info_hash() == &resume.info_hash => {
+                total_downloaded = piece_scheduler.restore(&resume.pieces, &resume.unfinished);
+                total_uploaded = resume.uploaded;
+                seeding_time = resume.seeding_time;
+                seeding_done = resume.seeding_done;
+                info!(
+                    pieces = piece_scheduler.to_bitfield().count_ones(),
+                    "resumed download"
+                );
+            }
+            Ok(_) => warn!(path = %resume_path.display(), "resume file is for another torrent"),
+            Err(ResumeError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => warn!(path = %resume_path.display(), error = %e, "ignoring resume file"),
+        }
+
+        let (events_tx, events_rx) = mpsc::channel(EVENT_QUEUE_SIZE);
+        let (incoming_tx, incoming_rx) = mpsc::channel(EVENT_QUEUE_SIZE);
+        let (deadlines_tx, deadlines_rx) = mpsc::unbounded();
+        let (dial_tx, dial_rx) = mpsc::unbounded();
+        let (web_seed_tx, web_seed_rx) = mpsc::unbounded();
+        let web_seeds = tracker
+            .get_metainfo()
+            .url_list
+            .iter()
+            .map(|url| WebSeed::new(url, info))
+            .collect();
+        let limits = ConnectionLimits::new(&config.session_limits);
+        let upload_share = limits.upload_share(config.upload_weight);
+        Ok(Self {
+            runtime,
             tracker,
-            peers: Arc::new(RwLock::new(HashMap::new())),
-            piece_scheduler: Arc::new(RwLock::new(piece_scheduler)),
-            send_queue: Arc::new(Mutex::new(VecDeque::new())),
-            receive_queue: Arc::new(Mutex::new(VecDeque::new())),
-            total_downloaded: Arc::new(Mutex::new(0)),
+            config,
+            peers: HashMap::new(),
+            piece_scheduler,
+            hash_rx,
+            disk_rx,
+            budget,
+            limits,
+            upload_share,
+            throttled: HashSet::new(),
+            events_tx,
+            events_rx,
+            incoming_tx,
+            incoming_rx,
+            deadlines_tx,
+            deadlines_rx,
+            pool: PeerPool::new(),
+            reputation: Reputation::new(),
+            piece_sources: HashMap::new(),
+            dial_tx,
+            dial_rx,
+            web_seeds,
+            web_seed_tx,
+            web_seed_rx,
+            listener_shutdown: None,
+            #[cfg(feature = "webtorrent")]
+            webtorrent_shutdown: None,
+            listen_port: None,
+            port_mapper: PortMapper::new(),
+            total_downloaded,
+            total_uploaded,
+            seeding_time,
+            seeding_done,
+            download_meter: RateMeter::new(),
+            upload_meter: RateMeter::new(),
+            metrics: Metrics::new(),
             start_time: Utc::now(),
+            state: None,
+            last_published: Instant::now(),
+            resume_path,
+            last_resume_save: Instant::now(),
+            last_announce: Instant::now(),
+            next_announce: Instant::now(),
+            events: EventBus::default(),
+            dht: None,
+            holepunched: HashSet::new(),
+            disk_failures: 0,
+            disk_error: None,
+        })
+    }
+
+    /// Returns the current state of the client.
+    pub fn snapshot(&self) -> ClientState {
+        let now = Utc::now();
+        let elapsed = now
+            .signed_duration_since(self.start_time)
+            .num_milliseconds();
+        let download_rate = if elapsed > 0 {
+            self.total_downloaded as f64 * 1000.0 / elapsed as f64
+        } else {
+            0.0
+        };
+
+        ClientState {
+            taken_at: now,
+            started_at: self.start_time,
+            total_length: self.tracker.get_metainfo().get_length(),
+            downloaded: self.total_downloaded,
+            uploaded: self.total_uploaded,
+            download_rate,
+            recent_download_rate: self
+                .download_meter
+                .rate(Instant::now(), self.total_downloaded),
+            recent_upload_rate: self.upload_meter.rate(Instant::now(), self.total_uploaded),
+            tracker: self.tracker.status(),
+            peers: self
+                .peers
+                .iter()
+                .map(|(peer_id, peer)| PeerSummary {
+                    requests_in_flight: peer.requests.len(),
+                    since_last_block: peer.pipeline.last_block().map(|at| at.elapsed()),
+                    rtt: peer.pipeline.rtt(),
+                    bandwidth_delay: peer.pipeline.bandwidth_delay(peer.download_rate()),
+                    snubbed: peer.is_snubbed(self.config.snub_timeout),
+                    peer_id: peer_id.clone(),
+                    addr: peer.addr,
+                    am_choking: peer.am_choking,
+                    am_interested: peer.am_interested,
+                    peer_choking: peer.peer_choking,
+                    peer_interested: peer.peer_interested,
+                    client: peer.client.clone(),
+                    pieces: peer.bitfield.as_ref().map_or(0, |b| b.count_ones()),
+                    downloaded: peer.downloaded,
+                    uploaded: peer.uploaded,
+                    download_rate: peer.download_rate(),
+                    upload_rate: peer.upload_rate(),
+                })
+                .collect(),
+            pieces: self.piece_scheduler.summaries(),
+            memory_used: self.budget.used(),
+            memory_limit: self.budget.limit(),
         }
     }
 
-    pub async fn download(&mut self, num_peers: u32) -> Result<(), ClientError> {
-        self.connect_to_peers(num_peers).await?;
+    /// Returns a handle that can read snapshots from other tasks or threads
+    /// while [`Client::download`] is running. The client republishes its state
+    /// a few times a second.
+    pub fn state_handle(&mut self) -> StateHandle {
+        if let Some(state) = &self.state {
+            return state.clone();
+        }
 
-        let mut join_set = JoinSet::new();
-        let num_pieces = self.piece_scheduler.read().await.len();
+        let state = StateHandle::new(self.snapshot());
+        self.state = Some(state.clone());
+        state
+    }
 
-        join_set.spawn(self.send_messages());
-        join_set.spawn(self.retrieve_messages());
-        join_set.spawn(self.process_messages(num_pieces));
-        join_set.spawn(self.keep_alive());
+    /// Counters for an exporter, see [`metrics::serve`].
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
 
-        while join_set.join_next().await.is_some() {}
+    /// Downloads piece `index` ahead of everything else, aiming to have it
+    /// within `deadline`. Close to the deadline its blocks are requested from
+    /// more than one peer.
+    pub fn set_piece_deadline(&mut self, index: usize, deadline: Duration) {
+        if !self
+            .piece_scheduler
+            .set_deadline(index, Instant::now() + deadline)
+        {
+            return;
+        }
+        let peer_ids = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| !peer.peer_choking)
+            .map(|(peer_id, _)| peer_id.clone())
+            .collect::<Vec<_>>();
+        for peer_id in peer_ids {
+            if self.piece_scheduler.has_deadline_piece(&peer_id) {
+                self.request_blocks(&peer_id, 1);
+            }
+        }
+    }
 
+    /// A handle for [`Client::set_piece_deadline`] that works while
+    /// [`Client::download`] is running.
+    pub fn priority_handle(&self) -> PriorityHandle {
+        PriorityHandle::new(self.deadlines_tx.clone())
+    }
+
+    /// Shares connection and upload slots with other clients, as a session
+    /// does for its torrents. Meant to be called before connecting to peers.
+    pub fn set_connection_limits(&mut self, limits: ConnectionLimits) {
+        self.upload_share = limits.upload_share(self.upload_share.weight());
+        self.limits = limits;
+    }
+
+    /// Picks pieces with `selection` instead of random first, then rarest
+    /// first.
+    pub fn set_piece_selection(&mut self, selection: impl PieceSelectionStrategy + 'static) {
+        self.piece_scheduler.set_selection(Box::new(selection));
+    }
+
+    /// This torrent's claim on the upload slots it shares with others, for
+    /// changing its weight while it runs.
+    pub fn upload_share(&self) -> &UploadShare {
+        &self.upload_share
+    }
+
+    /// Reads file `file_index` of the torrent in order, waiting for pieces
+    /// as it goes and asking for them ahead of the rest. Returns `None` if
+    /// there is no such file.
+    pub fn read_stream(&mut self, file_index: usize) -> Option<FileStream> {
+        FileStream::new(
+            self.file_manager().clone(),
+            file_index,
+            &self.state_handle(),
+            self.priority_handle(),
+            &self.events,
+        )
+    }
+
+    pub(crate) fn file_manager(&self) -> &FileManager {
+        self.piece_scheduler.file_manager()
+    }
+
+    /// Deletes the downloaded files and the resume file. Meant for after
+    /// [`Client::shutdown`], once nothing more gets written.
+    pub fn delete_data(&self) -> io::Result<()> {
+        self.file_manager().delete_files()?;
+        match std::fs::remove_file(&self.resume_path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Moves the downloaded files under `dir`, laid out as they were under
+    /// the output directory, along with the resume file. Reading and writing
+    /// carry on from there.
+    pub fn move_data(&mut self, dir: &str) -> io::Result<()> {
+        self.file_manager().move_to(Path::new(dir))?;
+        let resume = resume_path(dir, &self.tracker.get_metainfo().info);
+        let old = std::mem::replace(&mut self.resume_path, resume);
+        self.maybe_save_resume(true);
+        if old == self.resume_path {
+            return Ok(());
+        }
+        match std::fs::remove_file(old) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// The bus the client reports peer and piece events on.
+    pub fn events(&self) -> EventBus {
+        self.events.clone()
+    }
+
+    fn publish_state(&mut self, force: bool) {
+        let now = Instant::now();
+        self.download_meter.record(now, self.total_downloaded);
+        self.upload_meter.record(now, self.total_uploaded);
+        self.metrics.set(Metric::Peers, self.peers.len() as u64);
+        self.metrics.set(
+            Metric::DiskQueueDepth,
+            self.piece_scheduler.disk_queue_depth() as u64,
+        );
+        self.metrics
+            .set(Metric::TrackerErrors, self.tracker.status().failures);
+        let (hits, misses) = self.piece_scheduler.read_cache_stats();
+        self.metrics.set(Metric::ReadCacheHits, hits);
+        self.metrics.set(Metric::ReadCacheMisses, misses);
+
+        let Some(state) = &self.state else {
+            return;
+        };
+        if force || self.last_published.elapsed() >= STATE_PUBLISH_INTERVAL {
+            state.publish(self.snapshot());
+            self.last_published = Instant::now();
+        }
+    }
+
+    /// Saves which pieces are done, so a later client for the same output
+    /// directory can skip them. This also happens periodically while
+    /// downloading.
+    pub fn save_resume(&self) -> Result<(), ResumeError> {
+        let resume = ResumeData {
+            info_hash: *self.tracker.get_metainfo().info_hash(),
+            pieces: self.piece_scheduler.to_bitfield(),
+            downloaded: self.total_downloaded,
+            uploaded: self.total_uploaded,
+            files: self.piece_scheduler.file_progress(),
+            seeding_time: self.seeding_time,
+            seeding_done: self.seeding_done,
+            unfinished: self.piece_scheduler.unfinished_pieces(),
+        };
+        resume.save(&self.resume_path)
+    }
+
+    fn maybe_save_resume(&mut self, force: bool) {
+        if force || self.last_resume_save.elapsed() >= RESUME_SAVE_INTERVAL {
+            if let Err(e) = self.save_resume() {
+                warn!(path = %self.resume_path.display(), error = %e, "failed to save resume file");
+            }
+            self.last_resume_save = Instant::now();
+        }
+    }
+
+    pub async fn download(&mut self) -> Result<(), ClientError> {
+        if self.piece_scheduler.is_complete() {
+            info!("all pieces already downloaded");
+            self.publish_state(true);
+            return Ok(());
+        }
+
+        self.start_listener().await?;
+        #[cfg(feature = "webtorrent")]
+        self.start_webtorrent()?;
+
+        // the first announce is due straight away, and the peers it finds
+        // are dialed from the pool as the loop goes
+        while !self.piece_scheduler.is_complete() {
+            self.take_disk_error()?;
+            self.replenish_peers();
+            // picks up upload slots other torrents have given back
+            self.fill_upload_slots();
+            self.request_web_seeds();
+            let wake = self.next_retry();
+            self.handle_next(wake).await;
+        }
+
+        self.maybe_save_resume(true);
+        if self.tracker.is_started() {
+            self.announce_event(AnnounceEvent::Completed).await;
+        }
+        self.run_completion_actions();
+        self.publish_state(true);
         Ok(())
     }
 
-    fn process_messages(&self, num_pieces: usize) -> JoinHandle<()> {
-        let peers = Arc::clone(&self.peers);
-        let receive_queue = Arc::clone(&self.receive_queue);
-        let piece_scheduler = Arc::clone(&self.piece_scheduler);
-        let send_queue = Arc::clone(&self.send_queue);
-        let total_downloaded = Arc::clone(&self.total_downloaded);
-        let total_length = self.tracker.get_metainfo().get_length() as u64;
-        let start_time = self.start_time;
-
-        tokio::spawn(async move {
-            while *total_downloaded.lock().await < total_length {
-                let Some((peer_id, message)) = receive_queue.lock().await.pop_front() else {
-                    yield_now().await;
-                    continue;
-                };
+    /// Moves the files as the config asks, then starts its hook.
+    fn run_completion_actions(&mut self) {
+        if let Some(dir) = self.config.move_completed_to.clone() {
+            match self.move_data(&dir) {
+                Ok(()) => info!(dir, "moved download"),
+                Err(e) => warn!(dir, error = %e, "failed to move download"),
+            }
+        }
+        let metainfo = self.tracker.get_metainfo();
+        if let (Some(command), Some(path)) =
+            (&self.config.on_complete, self.file_manager().root_path())
+        {
+            completion::run_hook(command, metainfo.name(), metainfo.info_hash(), &path);
+        }
+    }
 
-                let mut should_remove = false;
+    /// Keeps uploading to peers after the download until the share ratio or
+    /// seeding time in the config is reached, then leaves the swarm as
+    /// [`Client::shutdown`] does. Returns straight away if the download isn't
+    /// complete, no limit is set, or a limit was already reached.
+    pub async fn seed(&mut self) -> Result<(), ClientError> {
+        let limited = self.config.seed_ratio.is_some() || self.config.seed_time.is_some();
+        if !limited || self.seeding_done || !self.piece_scheduler.is_complete() {
+            return Ok(());
+        }
 
-                {
-                    let id_to_peer = peers.read().await;
-                    let Some(peer) = id_to_peer.get(&peer_id) else {
-                        continue;
-                    };
+        self.start_listener().await?;
+        #[cfg(feature = "webtorrent")]
+        self.start_webtorrent()?;
+        info!("seeding");
+        let mut last = Instant::now();
+        while !self.seed_limit_reached() {
+            self.take_disk_error()?;
+            self.replenish_peers();
+            self.fill_upload_slots();
+            let time_left = self
+                .config
+                .seed_time
+                .map(|limit| Instant::now() + limit.saturating_sub(self.seeding_time));
+            let wake = self.next_retry().into_iter().chain(time_left).min();
+            self.handle_next(wake).await;
+
+            // counted as we go, as the future may be dropped at any await
+            let now = Instant::now();
+            self.seeding_time += now - last;
+            last = now;
+        }
+
+        info!(
+            uploaded = self.total_uploaded,
+            seeding_time = ?self.seeding_time,
+            "seeding limit reached"
+        );
+        self.seeding_done = true;
+        self.shutdown().await;
+        Ok(())
+    }
+
+    fn seed_limit_reached(&self) -> bool {
+        let length = self.tracker.get_metainfo().get_length().max(1);
+        let ratio = self.total_uploaded as f64 / length as f64;
+        self.config.seed_ratio.is_some_and(|limit| ratio >= limit)
+            || self
+                .config
+                .seed_time
+                .is_some_and(|limit| self.seeding_time >= limit)
+    }
+
+    /// Waits for the next peer event, disk or hash result, or timer and
+    /// handles it, waking up at `wake` at the latest.
+    async fn handle_next(&mut self, wake: Option<Instant>) {
+        // keeps published peer timings such as snubbing current while the
+        // swarm is quiet
+        let publish_at = self
+            .state
+            .is_some()
+            .then(|| self.last_published + STATE_PUBLISH_INTERVAL);
+        let wake = wake.into_iter().chain(publish_at).min();
+        let now = Instant::now();
+        let until_announce = self.next_announce.saturating_duration_since(now);
+        let until_wake = wake.map_or(until_announce, |at| at.saturating_duration_since(now));
+        // we hold a sender for every channel ourselves, so none of them close
+        futures::select! {
+            event = self.events_rx.select_next_some() => self.handle_peer_event(event),
+            incoming = self.incoming_rx.select_next_some() => self.add_incoming_peer(incoming),
+            result = self.hash_rx.select_next_some() => self.handle_hash_result(result),
+            result = self.disk_rx.select_next_some() => self.handle_disk_result(result),
+            (index, deadline) = self.deadlines_rx.select_next_some() => {
+                self.set_piece_deadline(index, deadline)
+            }
+            result = self.dial_rx.select_next_some() => self.handle_dial_result(result),
+            result = self.web_seed_rx.select_next_some() => self.handle_web_seed_result(result),
+            _ = self.runtime.sleep(until_announce).fuse() => self.reannounce().await,
+            _ = self.runtime.sleep(until_wake).fuse() => {}
+        }
+
+        self.resume_throttled();
+        self.publish_state(false);
+    }
+
+    /// Leaves the swarm cleanly: stops accepting peers, closes every peer
+    /// connection, finishes checking downloaded pieces and flushes them to
+    /// disk, saves the resume file and tells the tracker we stopped.
+    /// [`Client::download`] can be called again afterwards.
+    pub async fn shutdown(&mut self) {
+        self.listener_shutdown = None;
+        #[cfg(feature = "webtorrent")]
+        {
+            self.webtorrent_shutdown = None;
+        }
+        self.disconnect_peers();
+
+        self.flush_disk().await;
+        for result in self.piece_scheduler.verify_pending() {
+            self.handle_hash_result(result);
+        }
+        // pieces checked in memory were only queued for writing just now,
+        // along with the blocks of pieces still assembling there
+        self.piece_scheduler.spill_write_cache();
+        self.flush_disk().await;
+        self.maybe_save_resume(true);
+
+        if self.tracker.is_started() {
+            self.announce_event(AnnounceEvent::Stopped).await;
+        }
+        self.publish_state(true);
+        info!("shut down");
+    }
 
-                    let message_id = message.get_id();
-                    println!(
-                        "Processing \"{}\" message from {}",
-                        message_id,
-                        String::from_utf8_lossy(&peer_id)
+    /// Waits for queued writes to land and applies their results.
+    async fn flush_disk(&mut self) {
+        if let Err(e) = self.piece_scheduler.flush().await {
+            warn!(error = %e, "failed to flush downloaded data");
+        }
+        while let Ok(Some(result)) = self.disk_rx.try_next() {
+            self.handle_disk_result(result);
+        }
+    }
+
+    /// Hashes whatever is on disk and rebuilds the set of pieces we have from
+    /// it, so only missing or damaged pieces are downloaded afterwards. Peers
+    /// are disconnected first, as their requests refer to the old state.
+    pub async fn recheck(&mut self) {
+        self.disconnect_peers();
+        self.flush_disk().await;
+
+        self.total_downloaded = self.piece_scheduler.recheck();
+        self.piece_sources.clear();
+        info!(
+            pieces = self.piece_scheduler.to_bitfield().count_ones(),
+            total = self.piece_scheduler.len(),
+            "rechecked pieces on disk"
+        );
+        self.maybe_save_resume(true);
+        self.publish_state(true);
+    }
+
+    fn update_transfer(&mut self) {
+        let mapping = self.port_mapper.mapping();
+        self.tracker.set_external_addr(
+            mapping.as_ref().map(|m| m.external_port),
+            mapping.and_then(|m| m.external_ip),
+        );
+        self.tracker.set_transfer(TransferStats {
+            uploaded: self.total_uploaded,
+            downloaded: self.total_downloaded,
+            left: self.piece_scheduler.bytes_left(),
+        });
+    }
+
+    /// Plans the next announce after the tracker's interval, or sooner with
+    /// backoff if the last one failed.
+    fn schedule_announce(&mut self) {
+        let interval = self
+            .tracker
+            .retry_delay()
+            .unwrap_or_else(|| self.tracker.interval().unwrap_or(DEFAULT_ANNOUNCE_INTERVAL));
+        self.last_announce = Instant::now();
+        self.next_announce = self.last_announce + interval;
+    }
+
+    async fn announce_event(&mut self, event: AnnounceEvent) {
+        self.update_transfer();
+        if let Err(e) = self.tracker.announce(&self.runtime, Some(event)).await {
+            warn!(?event, error = %e, "tracker announce failed");
+        }
+    }
+
+    /// Announces again once the tracker's interval is up, adding the fresh
+    /// peer lists of the tracker and the DHT to the pool our connections are
+    /// topped up from.
+    async fn reannounce(&mut self) {
+        self.update_transfer();
+        let announced = self.tracker.get_peers(&self.runtime).await;
+        self.schedule_announce();
+        let mut peers = match announced {
+            Ok(peers) => peers,
+            Err(e) => {
+                warn!(error = %e, "tracker announce failed");
+                Vec::new()
+            }
+        };
+        let info_hash = *self.tracker.get_metainfo().info_hash();
+        for addr in self.get_dht_peers(&info_hash).await {
+            if !peers.iter().any(|peer| peer.addr == addr) {
+                peers.push(Peer {
+                    addr,
+                    peer_id: None,
+                    source: PeerSource::Dht,
+                });
+            }
+        }
+        let peers = self.allowed_peers(peers);
+        self.pool.add(peers, Instant::now())
+    }
+
+    /// Dials peers from the pool while we have fewer than `max_peers`, and
+    /// announces early once nobody is left to try.
+    fn replenish_peers(&mut self) {
+        let dialing = self.pool.dialing();
+        let wanted = self
+            .config
+            .max_peers
+            .saturating_sub(self.peers.len() + dialing)
+            .min(self.config.max_half_open.saturating_sub(dialing))
+            .min(self.limits.available(Slot::Connection));
+        let slots = (0..wanted)
+            .map_while(|_| self.limits.try_acquire(Slot::HalfOpen))
+            .collect::<Vec<_>>();
+        if slots.is_empty() {
+            return;
+        }
+
+        let Ok(handshake) = self.get_handshake() else {
+            return;
+        };
+        let info_hash = *self.tracker.get_metainfo().info_hash();
+        let due = self.pool.take_due(Instant::now(), slots.len());
+        for (peer, slot) in due.into_iter().zip(slots) {
+            let addr = peer.addr;
+            let span = info_span!("connect", %addr);
+            let runtime = self.runtime.clone();
+            let proxy = self.config.proxy.clone();
+            let options = self.dial_options();
+            let handshake = handshake.clone();
+            let results = self.dial_tx.clone();
+            self.runtime.spawn(
+                async move {
+                    let result = dial(
+                        &runtime,
+                        proxy.as_ref(),
+                        peer,
+                        &handshake,
+                        &info_hash,
+                        options,
+                    )
+                    .await;
+                    drop(slot);
+                    let _ = results.unbounded_send((addr, result));
+                }
+                .instrument(span),
+            );
+        }
+
+        // a failing tracker is left to its backoff
+        if !self.pool.has_idle() && self.pool.dialing() == 0 && self.tracker.retry_delay().is_none()
+        {
+            let interval = self
+                .tracker
+                .min_interval()
+                .map_or(REPLENISH_ANNOUNCE_INTERVAL, |min| {
+                    min.max(REPLENISH_ANNOUNCE_INTERVAL)
+                });
+            self.next_announce = self.next_announce.min(self.last_announce + interval);
+        }
+    }
+
+    fn handle_dial_result(&mut self, (addr, result): DialResult<MseStream<R::TcpStream>>) {
+        match result {
+            Ok((peer_id, ..)) if peer_id == self.tracker.peer_id() => {
+                debug!(%addr, "dialed ourselves, not trying the address again");
+                self.pool.mark_own(addr);
+            }
+            Ok(incoming) => {
+                let peer_id = incoming.0.clone();
+                let full = self.peers.len() >= self.config.max_peers;
+                self.add_incoming_peer(incoming);
+                if self.peers.contains_key(&peer_id) {
+                    self.pool.connected(addr);
+                } else if full {
+                    // others got in first, which is no fault of the peer's
+                    self.pool.release(addr);
+                } else {
+                    self.pool.failed(addr, Instant::now());
+                }
+            }
+            Err(e) => {
+                debug!(%addr, error = %e, "failed to connect to peer");
+                self.pool.failed(addr, Instant::now());
+                self.request_holepunch(addr);
+            }
+        }
+    }
+
+    /// When the loop next has to wake up to retry a web seed or a peer.
+    fn next_retry(&self) -> Option<Instant> {
+        let seeds = self.web_seeds.iter().filter_map(|seed| seed.retry_at);
+        // peers due now are only retried once there is room for them
+        let dialing = self.pool.dialing();
+        let room = self.peers.len() + dialing < self.config.max_peers
+            && dialing < self.config.max_half_open
+            && self.limits.available(Slot::Connection) > 0
+            && self.limits.available(Slot::HalfOpen) > 0;
+        let peers = self.pool.next_retry().filter(|_| room);
+        seeds.chain(peers).min()
+    }
+
+    fn handle_peer_event(&mut self, event: PeerEvent) {
+        match event {
+            // the reservation is released once the message has been handled
+            PeerEvent::Message(peer_id, message, _reservation) => {
+                if let Err(e) = self.process_message(&peer_id, message) {
+                    warn!(
+                        peer = %String::from_utf8_lossy(&peer_id),
+                        error = %e,
+                        "dropping peer"
                     );
-                    match message_id {
-                        MessageId::Choke => {
-                            peer.lock().await.peer_choking = true;
-                        }
-                        MessageId::Unchoke => {
-                            peer.lock().await.peer_choking = false;
-
-                            let scheduled_piece =
-                                piece_scheduler.write().await.schedule_piece(&peer_id);
-
-                            match scheduled_piece {
-                                Some((index, begin, length)) => {
-                                    if !peer.lock().await.peer_choking {
-                                        let mut payload = Vec::new();
-                                        payload.extend_from_slice(&index.to_be_bytes());
-                                        payload.extend_from_slice(&begin.to_be_bytes());
-                                        payload.extend_from_slice(&length.to_be_bytes());
-                                        let message = Message::new(MessageId::Request, &payload);
-                                        send_queue
-                                            .lock()
-                                            .await
-                                            .push_back((peer_id.clone(), message));
-                                    }
-                                }
-                                None => send_queue.lock().await.push_back((
-                                    peer_id.clone(),
-                                    Message::new(MessageId::NotInterested, &Vec::new()),
-                                )),
-                            };
-                        }
-                        MessageId::Interested => {
-                            peer.lock().await.peer_interested = true;
-                            // figure out how to choke
+                    let ip = self.peers.get(&peer_id).map(|p| p.addr.ip());
+                    self.remove_peer(&peer_id);
+                    if let Some(ip) = ip {
+                        if self.reputation.protocol_violation(ip) {
+                            self.ban(ip);
                         }
-                        MessageId::NotInterested => {
-                            let mut peer = peer.lock().await;
-                            peer.peer_interested = false;
-                            peer.am_choking = true;
-                        }
-                        MessageId::Have => {
-                            let payload = message.get_payload();
-                            let piece_index = u32::from_be_bytes(payload[0..4].try_into().unwrap());
-                            if peer.lock().await.bitfield.is_none() {
-                                peer.lock().await.bitfield = Some(Bitfield::new(num_pieces));
-                            };
-
-                            if let Some(bitfield) = &mut peer.lock().await.bitfield {
-                                should_remove = bitfield.set(piece_index as usize, true).is_err();
-                                if piece_scheduler.read().await.is_interested(bitfield) {
-                                    send_queue.lock().await.push_back((
-                                        peer_id.clone(),
-                                        Message::new(MessageId::Interested, &Vec::new()),
-                                    ));
-                                } else {
-                                    send_queue.lock().await.push_back((
-                                        peer_id.clone(),
-                                        Message::new(MessageId::NotInterested, &Vec::new()),
-                                    ));
-                                }
-                            }
-
-                            piece_scheduler
-                                .write()
-                                .await
-                                .add_peer_have(&peer_id, piece_index as usize);
-                        }
-                        MessageId::Bitfield => {
-                            let payload = message.get_payload();
-                            if payload.len() * 8 < num_pieces {
-                                println!("Invalid bitfield length, disconnecting peer...");
-                                should_remove = true;
-                            } else {
-                                let bitfield = Bitfield::from_bytes(payload, num_pieces);
-
-                                piece_scheduler
-                                    .write()
-                                    .await
-                                    .add_peer_count(&peer_id, &bitfield);
-
-                                if piece_scheduler.read().await.is_interested(&bitfield) {
-                                    send_queue.lock().await.push_back((
-                                        peer_id.clone(),
-                                        Message::new(MessageId::Interested, &Vec::new()),
-                                    ));
-                                } else {
-                                    send_queue.lock().await.push_back((
-                                        peer_id.clone(),
-                                        Message::new(MessageId::NotInterested, &Vec::new()),
-                                    ));
-                                }
-
-                                peer.lock().await.bitfield = Some(bitfield);
-                            }
-                        }
-                        MessageId::Request => {}
-                        MessageId::Piece => {
-                            let payload = message.get_payload();
-                            let index = u32::from_be_bytes(payload[0..4].try_into().unwrap());
-                            let begin = u32::from_be_bytes(payload[4..8].try_into().unwrap());
-                            let block = &payload[8..];
-                            piece_scheduler.write().await.set_block(
-                                index as usize,
-                                begin,
-                                block.to_vec(),
-                            );
-                            *total_downloaded.lock().await += block.len() as u64;
-                            let total_downloaded = *total_downloaded.lock().await;
-                            let now = Utc::now();
-                            let duration =
-                                now.signed_duration_since(start_time).num_seconds() as f64;
-                            let speed = if duration > 0.0 {
-                                total_downloaded as f64 / duration
-                            } else {
-                                0.0
-                            };
-                            println!(
-                                "{:.2}/{:.2}MB - {:.2}% {:.2}MB/s",
-                                total_downloaded as f64 / MB as f64,
-                                total_length as f64 / MB as f64,
-                                total_downloaded as f64 / total_length as f64 * 100.0,
-                                speed / MB as f64,
-                            );
-
-                            if peer.lock().await.peer_choking {
-                                send_queue.lock().await.push_back((
-                                    peer_id.clone(),
-                                    Message::new(MessageId::Interested, &Vec::new()),
-                                ));
-                            } else {
-                                if let Some((index, begin, length)) =
-                                    piece_scheduler.write().await.schedule_piece(&peer_id)
-                                {
-                                    let mut payload = Vec::new();
-                                    payload.extend_from_slice(&index.to_be_bytes());
-                                    payload.extend_from_slice(&begin.to_be_bytes());
-                                    payload.extend_from_slice(&length.to_be_bytes());
-                                    send_queue.lock().await.push_back((
-                                        peer_id.clone(),
-                                        Message::new(MessageId::Request, &payload),
-                                    ));
-                                } else {
-                                    send_queue.lock().await.push_back((
-                                        peer_id.clone(),
-                                        Message::new(MessageId::NotInterested, &Vec::new()),
-                                    ));
-                                }
-                            }
-                        }
-                        MessageId::Cancel => {}
-                        MessageId::KeepAlive => {}
-                        MessageId::Port => {}
                     }
                 }
+            }
+            PeerEvent::Disconnected(peer_id, reason) => {
+                warn!(
+                    peer = %String::from_utf8_lossy(&peer_id),
+                    reason,
+                    "peer connection closed"
+                );
+                self.remove_peer(&peer_id);
+            }
+        }
+    }
 
-                if should_remove {
-                    peers.write().await.remove(&peer_id);
-                    piece_scheduler.write().await.remove_peer_count(&peer_id);
+    /// Starts a fetch on every idle web seed for a piece no peer can give us.
+    fn request_web_seeds(&mut self) {
+        let now = Instant::now();
+        for (i, seed) in self.web_seeds.iter_mut().enumerate() {
+            if !seed.is_ready(now) {
+                continue;
+            }
+            seed.retry_at = None;
+            let Some((index, length)) = self.piece_scheduler.claim_unavailable_piece() else {
+                return;
+            };
+            seed.busy = true;
+            let span = info_span!("web_seed", url = %seed.url(), piece = index);
+            let seed = seed.clone();
+            let runtime = self.runtime.clone();
+            let results = self.web_seed_tx.clone();
+            self.runtime.spawn(
+                async move {
+                    let result = seed.fetch_piece(&runtime, index, length).await;
+                    let _ = results.unbounded_send((i, index, result));
+                }
+                .instrument(span),
+            );
+        }
+    }
+
+    fn handle_web_seed_result(&mut self, (seed, index, result): WebSeedResult) {
+        let seed = &mut self.web_seeds[seed];
+        seed.busy = false;
+        match result {
+            Ok(data) => {
+                if let BlockOutcome::Stored(stored) =
+                    self.piece_scheduler.set_piece(index, Bytes::from(data))
+                {
+                    self.total_downloaded += stored as u64;
+                    self.metrics.add(Metric::BytesDownloaded, stored as u64);
                 }
             }
-        })
+            Err(e) => {
+                warn!(url = %seed.url(), piece = index, error = %e, "web seed request failed");
+                seed.retry_at = Some(Instant::now() + WEB_SEED_RETRY_DELAY);
+                self.piece_scheduler.release_piece(index);
+            }
+        }
     }
 
-    fn keep_alive(&self) -> JoinHandle<()> {
-        let peers = Arc::clone(&self.peers);
-        let send_queue = Arc::clone(&self.send_queue);
-        let total_length = self.tracker.get_metainfo().get_length();
-        let total_downloaded = Arc::clone(&self.total_downloaded);
-
-        tokio::spawn(async move {
-            while *total_downloaded.lock().await < total_length {
-                for (peer_id, peer) in peers.read().await.iter() {
-                    if (Utc::now() - peer.lock().await.last_touch).num_seconds() > 60 {
-                        send_queue.lock().await.push_back((
-                            peer_id.clone(),
-                            Message::new(MessageId::KeepAlive, &Vec::new()),
-                        ));
-                    }
+    fn handle_hash_result(&mut self, result: HashResult) {
+        let index = result.index;
+        match self.piece_scheduler.finish_verification(result) {
+            Some(true) => self.piece_completed(index),
+            Some(false) => self.piece_corrupt(index),
+            None => {}
+        }
+    }
+
+    /// Announces a verified piece, now on disk, to every peer.
+    fn piece_completed(&mut self, index: usize) {
+        self.piece_sources.remove(&index);
+        let have = HaveMsg {
+            index: index as u32,
+        }
+        .to_message();
+        for peer in self.peers.values() {
+            peer.send(have.clone());
+        }
+        // peers with nothing else we lack are no longer interesting
+        let peer_ids = self.peers.keys().cloned().collect::<Vec<_>>();
+        for peer_id in peer_ids {
+            self.update_interest(&peer_id);
+        }
+        self.metrics.add(Metric::PiecesCompleted, 1);
+        // readers waiting on the piece check the state before the event
+        self.publish_state(true);
+        self.events.emit(TorrentEvent::PieceCompleted(index));
+        self.maybe_save_resume(false);
+    }
+
+    /// Puts a piece that failed its hash check back up for download.
+    fn piece_corrupt(&mut self, index: usize) {
+        let sources = self.piece_sources.remove(&index).unwrap_or_default();
+        let piece_length = self.piece_scheduler.piece_length(index).unwrap_or(0);
+        self.total_downloaded = self.total_downloaded.saturating_sub(piece_length as u64);
+        for ip in sources {
+            if self.reputation.hash_failure(ip) {
+                self.ban(ip);
+            }
+        }
+
+        let peers = self
+            .peers
+            .iter()
+            .filter(|(_, p)| {
+                p.bitfield
+                    .as_ref()
+                    .is_some_and(|b| b.is_set(index).unwrap_or(false))
+            })
+            .map(|(peer_id, p)| (peer_id.clone(), p.am_interested, p.peer_choking))
+            .collect::<Vec<_>>();
+        for (peer_id, am_interested, peer_choking) in peers {
+            if !am_interested {
+                self.update_interest(&peer_id);
+            } else if !peer_choking {
+                self.request_blocks(&peer_id, 1);
+            }
+        }
+    }
+
+    /// Drops every peer connection. [`Client::download`] reconnects when it
+    /// is next called.
+    pub fn disconnect_peers(&mut self) {
+        let peer_ids = self.peers.keys().cloned().collect::<Vec<_>>();
+        for peer_id in peer_ids {
+            self.remove_peer(&peer_id);
+        }
+    }
+
+    fn remove_peer(&mut self, peer_id: &[u8]) {
+        // dropping the state closes the peer's channel, which ends its task
+        if let Some(peer) = self.peers.remove(peer_id) {
+            self.pool.failed(peer.addr, Instant::now());
+            self.piece_scheduler.remove_peer_count(peer_id);
+            for (index, begin) in peer.requests {
+                self.piece_scheduler.release_block(index as usize, begin);
+            }
+            self.throttled.remove(peer_id);
+            info!(peer = %String::from_utf8_lossy(peer_id), "disconnected from peer");
+            self.metrics.add(Metric::PeersDisconnected, 1);
+            self.events.emit(TorrentEvent::PeerDisconnected(peer.addr));
+            self.fill_upload_slots();
+        }
+    }
+
+    fn set_choking(&mut self, peer_id: &[u8], choking: bool) {
+        let Some(peer) = self.peers.get_mut(peer_id) else {
+            return;
+        };
+        if peer.am_choking == choking {
+            return;
+        }
+        if choking {
+            // a choked peer has to ask again
+            peer.uploads.clear();
+            peer.upload_slot = None;
+        } else {
+            let Some(slot) = self.limits.try_acquire(Slot::Upload) else {
+                return;
+            };
+            peer.upload_slot = Some(slot);
+        }
+        peer.am_choking = choking;
+
+        let message_id = if choking {
+            MessageId::Choke
+        } else {
+            MessageId::Unchoke
+        };
+        self.send_to(peer_id, Message::new(message_id, Bytes::new()));
+    }
+
+    /// Unchokes interested peers until the torrent's share of the upload
+    /// slots is taken, and chokes the slowest ones over it once other
+    /// torrents want their share back. Peers snubbing us are choked first
+    /// and unchoked last.
+    fn fill_upload_slots(&mut self) {
+        let snub_timeout = self.config.snub_timeout;
+        let mut unchoked = self
+            .peers
+            .iter()
+            .filter(|(_, p)| !p.am_choking)
+            .map(|(peer_id, p)| {
+                (
+                    peer_id.clone(),
+                    !p.is_snubbed(snub_timeout),
+                    p.upload_rate(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let mut waiting = self
+            .peers
+            .iter()
+            .filter(|(_, p)| p.am_choking && p.peer_interested)
+            .map(|(peer_id, p)| (peer_id.clone(), p.is_snubbed(snub_timeout)))
+            .collect::<Vec<_>>();
+        waiting.sort_by_key(|&(_, snubbed)| snubbed);
+        let demand = (unchoked.len() + waiting.len()).min(self.config.upload_slots);
+        self.upload_share.set_demand(demand);
+        let quota = self.upload_share.quota();
+
+        if unchoked.len() > quota {
+            unchoked.sort_by(|(_, a_sending, a), (_, b_sending, b)| {
+                a_sending.cmp(b_sending).then(a.total_cmp(b))
+            });
+            let excess = unchoked.len() - quota;
+            for (peer_id, ..) in unchoked.into_iter().take(excess) {
+                self.set_choking(&peer_id, true);
+            }
+            return;
+        }
+        for (peer_id, _) in waiting.into_iter().take(quota - unchoked.len()) {
+            self.set_choking(&peer_id, false);
+        }
+    }
+
+    /// Answers a block request with a Piece message read back from disk.
+    fn serve_request(&mut self, peer_id: &[u8], request: RequestMsg) -> Result<(), ClientError> {
+        let RequestMsg {
+            index,
+            begin,
+            length,
+        } = request;
+
+        let piece_length = self.piece_scheduler.piece_length(index as usize);
+        let in_bounds = piece_length.is_some_and(|piece_length| {
+            begin
+                .checked_add(length)
+                .is_some_and(|end| end <= piece_length)
+        });
+        if length == 0 || length > MAX_BLOCK_LEN || !in_bounds {
+            return Err(ClientError::ProcessMessagesError(format!(
+                "Invalid Request: index = {}, begin = {}, length = {}",
+                index, begin, length
+            )));
+        }
+
+        if !self.piece_scheduler.has_piece(index as usize) {
+            debug!(
+                peer = %String::from_utf8_lossy(peer_id),
+                piece = index,
+                "peer requested a piece we don't have"
+            );
+            return Ok(());
+        }
+
+        let Some(peer) = self.peers.get_mut(peer_id) else {
+            return Ok(());
+        };
+        // the peer can ask again once we have caught up
+        if peer.queued_uploads() >= MAX_QUEUED_UPLOADS || self.budget.is_exhausted() {
+            debug!(
+                peer = %String::from_utf8_lossy(peer_id),
+                queued = peer.queued_uploads(),
+                "too many uploads queued, dropping request"
+            );
+            return Ok(());
+        }
+        let reservation = self.budget.reserve(MemoryKind::Upload, length as usize);
+        peer.uploads.insert((index, begin, length), reservation);
+        self.piece_scheduler
+            .read_block(peer_id, index as usize, begin, length);
+        Ok(())
+    }
+
+    /// Counts disk failures in a row, giving up on the first that won't pass
+    /// by itself or after too many.
+    fn record_disk_result(&mut self, error: Option<&DiskError>) {
+        match error {
+            None => self.disk_failures = 0,
+            // a bad request rather than a failing disk
+            Some(DiskError::OutOfRange(_)) => {}
+            Some(e) => {
+                self.disk_failures += 1;
+                if !e.is_transient() || self.disk_failures >= MAX_DISK_FAILURES {
+                    self.disk_error.get_or_insert_with(|| e.clone());
                 }
             }
-        })
+        }
     }
 
-    fn retrieve_messages(&self) -> JoinHandle<()> {
-        let peers = Arc::clone(&self.peers);
-        let receive_queue = Arc::clone(&self.receive_queue);
-        let piece_scheduler = Arc::clone(&self.piece_scheduler);
-        let total_length = self.tracker.get_metainfo().get_length();
-        let total_downloaded = Arc::clone(&self.total_downloaded);
-
-        tokio::spawn(async move {
-            let mut peers_to_remove = Vec::new();
-            while *total_downloaded.lock().await < total_length {
-                for (peer_id, peer) in peers.read().await.iter() {
-                    match receive_message(&peer.lock().await.stream).await {
-                        Ok(message) => {
-                            println!(
-                                "Received \"{}\" message from {}",
-                                message.get_id(),
-                                String::from_utf8_lossy(peer_id)
-                            );
-                            receive_queue
-                                .lock()
-                                .await
-                                .push_back((peer_id.clone(), message));
-                        }
-                        Err(ReceiveError::WouldBlock) => {
-                            yield_now().await;
-                            continue;
-                        }
-                        Err(e) => {
-                            println!(
-                                "Failed to receive message from peer {:?}: {}",
-                                String::from_utf8_lossy(peer_id),
-                                e.to_string()
-                            );
-                            peers_to_remove.push(peer_id.clone());
-                        }
+    /// The disk error that stopped the torrent, if any. Once returned, the
+    /// torrent can be started again to retry.
+    fn take_disk_error(&mut self) -> Result<(), ClientError> {
+        match self.disk_error.take() {
+            Some(e) => {
+                self.disk_failures = 0;
+                Err(ClientError::Disk(e))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn handle_disk_result(&mut self, result: DiskResult) {
+        match result {
+            DiskResult::Written {
+                index,
+                begin,
+                length,
+                result,
+            } => {
+                if result.is_err() {
+                    self.total_downloaded = self.total_downloaded.saturating_sub(length as u64);
+                }
+                self.record_disk_result(result.as_ref().err());
+                if self.piece_scheduler.finish_write(index, begin, result) {
+                    self.piece_completed(index);
+                }
+            }
+            DiskResult::Read {
+                peer_id,
+                index,
+                begin,
+                result,
+            } => {
+                self.record_disk_result(result.as_ref().err());
+                let block = match result {
+                    Ok(block) => block,
+                    Err(e) => {
+                        warn!(piece = index, begin, error = %e, "failed to read block for upload");
+                        return;
                     }
-                    peer.lock().await.last_touch = Utc::now();
-                    yield_now().await;
+                };
+                // the peer may have gone, been choked or cancelled the request
+                // while the read was queued
+                let Some(peer) = self.peers.get_mut(&peer_id) else {
+                    return;
+                };
+                let Some(reservation) =
+                    peer.uploads
+                        .remove(&(index as u32, begin, block.len() as u32))
+                else {
+                    return;
+                };
+                peer.record_uploaded(block.len());
+                self.total_uploaded += block.len() as u64;
+                self.metrics.add(Metric::BytesUploaded, block.len() as u64);
+
+                let piece = PieceMsg {
+                    index: index as u32,
+                    begin,
+                    block,
+                };
+                if !peer.send_upload(piece.to_message(), reservation) {
+                    debug!(
+                        peer = %String::from_utf8_lossy(&peer_id),
+                        "peer task is gone, discarding message"
+                    );
                 }
+            }
+        }
+    }
 
-                for peer_id in &peers_to_remove {
-                    if peers.write().await.remove(peer_id).is_some() {
-                        piece_scheduler.write().await.remove_peer_count(&peer_id);
-                        println!(
-                            "Disconnected from peer: {:?}",
-                            String::from_utf8_lossy(&peer_id)
-                        );
-                    }
+    fn send_to(&self, peer_id: &[u8], message: Message) {
+        if let Some(peer) = self.peers.get(peer_id) {
+            if !peer.send(message) {
+                debug!(
+                    peer = %String::from_utf8_lossy(peer_id),
+                    "peer task is gone, discarding message"
+                );
+            }
+        }
+    }
+
+    /// Takes back the requests other peers still have for the blocks of
+    /// piece `index` between `begin` and `end`, which `peer_id` just sent.
+    /// They were asked for twice in endgame or to meet a deadline.
+    fn cancel_duplicates(&mut self, peer_id: &[u8], index: u32, begin: u32, end: u64) {
+        let block_size = self.config.block_size as u64;
+        let mut cancels = Vec::new();
+        for (other_id, other) in self.peers.iter_mut() {
+            if other_id.as_slice() == peer_id {
+                continue;
+            }
+            let mut offset = begin as u64;
+            while offset < end {
+                if other.requests.remove(&(index, offset as u32)) {
+                    cancels.push((other_id.clone(), offset as u32));
                 }
+                offset += block_size;
             }
-        })
+        }
+
+        for (other_id, begin) in cancels {
+            let Some(length) = self.piece_scheduler.block_length(index as usize, begin) else {
+                continue;
+            };
+            let cancel = CancelMsg {
+                index,
+                begin,
+                length,
+            };
+            self.send_to(&other_id, cancel.to_message());
+        }
     }
 
-    fn send_messages(&self) -> JoinHandle<()> {
-        let peers = Arc::clone(&self.peers);
-        let send_queue = Arc::clone(&self.send_queue);
-        let piece_scheduler = Arc::clone(&self.piece_scheduler);
-        let total_length = self.tracker.get_metainfo().get_length();
-        let total_downloaded = Arc::clone(&self.total_downloaded);
+    /// Tells the peer whether we are interested in it, as the scheduler
+    /// says we should be, if that changed since we last told it.
+    fn update_interest(&mut self, peer_id: &[u8]) {
+        let interested = self.piece_scheduler.wants_from(peer_id);
+        let Some(peer) = self.peers.get_mut(peer_id) else {
+            return;
+        };
+        if peer.am_interested == interested {
+            return;
+        }
+        peer.am_interested = interested;
+        let message_id = if interested {
+            MessageId::Interested
+        } else {
+            MessageId::NotInterested
+        };
+        self.send_to(peer_id, Message::new(message_id, Bytes::new()));
+    }
 
-        tokio::spawn(async move {
-            while *total_downloaded.lock().await < total_length {
-                let Some((peer_id, message)) = send_queue.lock().await.pop_front() else {
-                    yield_now().await;
-                    continue;
-                };
+    /// Schedules up to `count` block requests for the peer, telling it we are
+    /// no longer interested once there is nothing left to ask it for.
+    fn request_blocks(&mut self, peer_id: &[u8], count: usize) {
+        for _ in 0..count {
+            if self.budget.is_exhausted() {
+                debug!(
+                    peer = %String::from_utf8_lossy(peer_id),
+                    used = self.budget.used(),
+                    "memory budget exhausted, holding off requests"
+                );
+                self.throttled.insert(peer_id.to_vec());
+                break;
+            }
 
-                let send_result = {
-                    let id_to_peer = peers.read().await;
-                    let Some(peer) = id_to_peer.get(&peer_id) else {
-                        // if peer is not found, discard the message
-                        continue;
+            let Some(peer) = self.peers.get_mut(peer_id) else {
+                break;
+            };
+            match self.piece_scheduler.schedule_piece(peer_id, &peer.requests) {
+                Some((index, begin, length)) => {
+                    peer.requests.insert((index, begin));
+                    peer.pipeline
+                        .sent((index, begin), Instant::now(), &peer.requests);
+                    let request = RequestMsg {
+                        index,
+                        begin,
+                        length,
                     };
+                    self.send_to(peer_id, request.to_message());
+                }
+                None => {
+                    self.update_interest(peer_id);
+                    break;
+                }
+            }
+        }
+    }
 
-                    let stream = &peer.lock().await.stream;
-                    println!(
-                        "Sending \"{}\" message to {}",
-                        message.get_id(),
-                        String::from_utf8_lossy(&peer_id)
-                    );
-                    send_message(stream, &message).await
-                };
+    /// Tops the peer's requests up to its pipeline depth.
+    fn fill_pipeline(&mut self, peer_id: &[u8]) {
+        let Some(peer) = self.peers.get(peer_id) else {
+            return;
+        };
+        let depth = peer.pipeline.depth(peer.download_rate());
+        self.request_blocks(peer_id, depth.saturating_sub(peer.requests.len()));
+    }
 
-                match send_result {
-                    Ok(()) => {
-                        let id_to_peer = peers.read().await;
-                        let mut peer = id_to_peer.get(&peer_id).unwrap().lock().await;
-                        peer.last_touch = Utc::now();
+    /// Refills the request pipeline of peers that were throttled, once the
+    /// budget has room again.
+    fn resume_throttled(&mut self) {
+        if self.throttled.is_empty() || self.budget.is_exhausted() {
+            return;
+        }
+
+        for peer_id in std::mem::take(&mut self.throttled) {
+            let unchoked = self.peers.get(&peer_id).is_some_and(|p| !p.peer_choking);
+            if unchoked {
+                self.fill_pipeline(&peer_id);
+            }
+        }
+    }
+
+    fn process_message(&mut self, peer_id: &[u8], message: Message) -> Result<(), ClientError> {
+        let num_pieces = self.piece_scheduler.len();
+        let Some(peer) = self.peers.get_mut(peer_id) else {
+            return Ok(());
+        };
+
+        let message_id = message.get_id();
+        trace!(
+            peer = %String::from_utf8_lossy(peer_id),
+            message = %message_id,
+            "processing message"
+        );
+        match message_id {
+            MessageId::Choke => {
+                peer.peer_choking = true;
+                // the peer drops what we asked for, so others can have it
+                for (index, begin) in std::mem::take(&mut peer.requests) {
+                    self.piece_scheduler.release_block(index as usize, begin);
+                }
+                let unchoked = self
+                    .peers
+                    .iter()
+                    .filter(|(_, other)| !other.peer_choking)
+                    .map(|(other_id, _)| other_id.clone())
+                    .collect::<Vec<_>>();
+                for other_id in unchoked {
+                    self.fill_pipeline(&other_id);
+                }
+            }
+            MessageId::Unchoke => {
+                peer.peer_choking = false;
+                self.fill_pipeline(peer_id);
+            }
+            MessageId::Interested => {
+                peer.peer_interested = true;
+                self.fill_upload_slots();
+            }
+            MessageId::NotInterested => {
+                peer.peer_interested = false;
+                self.set_choking(peer_id, true);
+                self.fill_upload_slots();
+            }
+            MessageId::Have => {
+                let piece_index = HaveMsg::parse(message.get_payload())?.index;
+                let bitfield = peer
+                    .bitfield
+                    .get_or_insert_with(|| Bitfield::new(num_pieces));
+                bitfield.set(piece_index as usize, true).map_err(|e| {
+                    ClientError::ProcessMessagesError(format!("Invalid Have message: {}", e))
+                })?;
+
+                self.piece_scheduler
+                    .add_peer_have(peer_id, piece_index as usize);
+                self.update_interest(peer_id);
+            }
+            MessageId::Bitfield => {
+                let bitfield = Bitfield::from_bytes(message.get_payload(), num_pieces)
+                    .map_err(|e| ClientError::ProcessMessagesError(e.to_string()))?;
+                self.piece_scheduler.add_peer_count(peer_id, &bitfield);
+                peer.bitfield = Some(bitfield);
+                self.update_interest(peer_id);
+            }
+            MessageId::Request => {
+                // requests that were in flight when we choked are dropped
+                let request = RequestMsg::parse(message.get_payload())?;
+                if !peer.am_choking {
+                    self.serve_request(peer_id, request)?;
+                }
+            }
+            MessageId::Piece => {
+                let peer_choking = peer.peer_choking;
+
+                let PieceMsg {
+                    index,
+                    begin,
+                    block,
+                } = PieceMsg::parse(message.get_payload())?;
+                if !peer.requests.remove(&(index, begin)) {
+                    debug!(
+                        peer = %String::from_utf8_lossy(peer_id),
+                        index,
+                        begin,
+                        "discarding block we didn't request"
+                    );
+                    return Ok(());
+                }
+                peer.pipeline.received((index, begin), Instant::now());
+                // a reply covering several blocks answers each of their requests
+                let block_size = self.config.block_size;
+                let end = begin as u64 + block.len() as u64;
+                let mut next = begin as u64 + block_size as u64;
+                while next < end {
+                    peer.requests.remove(&(index, next as u32));
+                    next += block_size as u64;
+                }
+                peer.record_downloaded(block.len());
+                let ip = peer.addr.ip();
+
+                let len = block.len();
+                match self.piece_scheduler.set_block(index as usize, begin, block) {
+                    BlockOutcome::Stored(stored) => {
+                        self.cancel_duplicates(peer_id, index, begin, end);
+                        self.piece_sources
+                            .entry(index as usize)
+                            .or_default()
+                            .insert(ip);
+                        self.total_downloaded += stored as u64;
+                        self.metrics.add(Metric::BytesDownloaded, stored as u64);
                     }
-                    Err(SendError::WouldBlock) => {
-                        send_queue.lock().await.push_back((peer_id, message));
+                    BlockOutcome::Duplicate => {}
+                    BlockOutcome::Invalid => {
+                        self.piece_scheduler.release_block(index as usize, begin);
+                        return Err(ClientError::ProcessMessagesError(format!(
+                            "Block of {} bytes at {} in piece {} doesn't match the request",
+                            len, begin, index
+                        )));
                     }
-                    Err(_) => {
-                        println!(
-                            "Failed to send message to peer: {:?}",
-                            String::from_utf8_lossy(&peer_id)
+                }
+
+                if peer_choking {
+                    self.update_interest(peer_id);
+                } else {
+                    self.fill_pipeline(peer_id);
+                }
+            }
+            MessageId::Cancel => {
+                let CancelMsg {
+                    index,
+                    begin,
+                    length,
+                } = CancelMsg::parse(message.get_payload())?;
+                peer.uploads.remove(&(index, begin, length));
+            }
+            MessageId::KeepAlive => {}
+            MessageId::Port => {
+                let PortMsg { port } = PortMsg::parse(message.get_payload())?;
+                if !peer.capabilities.contains(PeerCapabilities::DHT) {
+                    debug!(
+                        peer = %String::from_utf8_lossy(peer_id),
+                        port,
+                        "ignoring port message from peer without dht"
+                    );
+                } else if let Some(dht) = &self.dht {
+                    dht.add_node(SocketAddr::new(peer.addr.ip(), port));
+                }
+            }
+            MessageId::Extended
+                if peer
+                    .capabilities
+                    .contains(PeerCapabilities::EXTENSION_PROTOCOL) =>
+            {
+                let extended = ExtendedMsg::parse(message.get_payload())?;
+                match extended.id {
+                    HANDSHAKE_ID => {
+                        peer.extensions = ExtendedHandshake::parse(&extended.payload)?;
+                        debug!(
+                            peer = %String::from_utf8_lossy(peer_id),
+                            client = peer.extensions.client,
+                            extensions = ?peer.extensions.extensions.keys(),
+                            "received extended handshake"
                         );
-                        if peers.write().await.remove(&peer_id).is_some() {
-                            piece_scheduler.write().await.remove_peer_count(&peer_id);
-                            println!(
-                                "Disconnected from peer: {:?}",
-                                String::from_utf8_lossy(&peer_id)
-                            );
-                        }
                     }
+                    UT_HOLEPUNCH_ID if self.holepunch_enabled() => {
+                        let holepunch = HolepunchMsg::parse(&extended.payload)?;
+                        self.handle_holepunch(peer_id, holepunch);
+                    }
+                    id => debug!(
+                        peer = %String::from_utf8_lossy(peer_id),
+                        id,
+                        "ignoring message for an extension we don't support"
+                    ),
                 }
             }
-        })
+            // from a peer that never said it supports extensions, it is as
+            // good as an unknown message
+            MessageId::Extended | MessageId::Unknown(_) => {
+                let id = message_id.value();
+                if self.config.drop_unknown_messages {
+                    return Err(ClientError::ProcessMessagesError(format!(
+                        "Unknown message id: {}",
+                        id
+                    )));
+                }
+                debug!(
+                    peer = %String::from_utf8_lossy(peer_id),
+                    id,
+                    "ignoring message with unknown id"
+                );
+            }
+        }
+
+        Ok(())
     }
 
     fn get_handshake(&self) -> Result<Vec<u8>, ClientError> {
         let mut handshake = Vec::new();
 
-        let info_hash = self
-            .tracker
-            .get_metainfo()
-            .get_info_hash()
-            .map_err(|_| ClientError::GetPeersError(String::from("Failed to get info hash")))?;
+        let info_hash = *self.tracker.get_metainfo().info_hash();
 
         let peer_id = self.tracker.peer_id();
 
         handshake.push(PSTR.len() as u8);
         handshake.extend_from_slice(PSTR);
-        handshake.extend_from_slice(&[0; 8]);
-        handshake.extend_from_slice(&info_hash);
+        handshake.extend_from_slice(&self.capabilities().to_reserved());
+        handshake.extend_from_slice(&info_hash.truncated());
         handshake.extend_from_slice(&peer_id);
 
         #[cfg(debug_assertions)]
@@ -513,142 +1735,745 @@ impl Client {
         Ok(handshake)
     }
 
-    fn validate_handshake(handshake: &[u8], info_hash: &Vec<u8>) -> Result<Vec<u8>, ClientError> {
-        if handshake.len() != HANDSHAKE_LEN {
-            return Err(ClientError::ValidateHandshakeError(
-                "Invalid handshake length".to_string(),
-            ));
+    fn dial_options(&self) -> DialOptions {
+        DialOptions {
+            connect_timeout: self.config.connect_timeout,
+            handshake_timeout: self.config.handshake_timeout,
+            encryption: self.config.encryption,
         }
+    }
 
-        let pstr_len = handshake[0] as usize;
-        if pstr_len != b"BitTorrent protocol".len() {
-            return Err(ClientError::ValidateHandshakeError(
-                "Invalid protocol string length".to_string(),
-            ));
+    /// The protocol extensions we advertise in our handshake.
+    fn capabilities(&self) -> PeerCapabilities {
+        if self.dht_enabled() {
+            PeerCapabilities::EXTENSION_PROTOCOL | PeerCapabilities::DHT
+        } else {
+            PeerCapabilities::EXTENSION_PROTOCOL
         }
+    }
 
-        if &handshake[1..20] != b"BitTorrent protocol" {
-            return Err(ClientError::ValidateHandshakeError(
-                "Invalid protocol string".to_string(),
-            ));
+    fn extended_handshake(&self) -> ExtendedHandshake {
+        let mut extensions = BTreeMap::new();
+        if self.holepunch_enabled() {
+            extensions.insert(UT_HOLEPUNCH.to_string(), UT_HOLEPUNCH_ID);
         }
-
-        if &handshake[28..48] != info_hash {
-            return Err(ClientError::ValidateHandshakeError(
-                "Invalid info hash".to_string(),
-            ));
+        ExtendedHandshake {
+            extensions,
+            client: Some(self.config.client_name.clone()),
+            port: self.listen_port,
         }
+    }
 
-        let peer_id = handshake[48..68].to_vec();
+    /// Like the DHT, hole punching brings in peers the tracker didn't, and
+    /// a proxy hides the addresses it works with.
+    fn holepunch_enabled(&self) -> bool {
+        self.config.holepunch
+            && self.config.proxy.is_none()
+            && !self.tracker.get_metainfo().is_private()
+    }
 
-        Ok(peer_id)
+    fn handle_holepunch(&mut self, peer_id: &[u8], message: HolepunchMsg) {
+        match message {
+            HolepunchMsg::Rendezvous(target) => self.relay_holepunch(peer_id, target),
+            HolepunchMsg::Connect(addr) => {
+                debug!(%addr, "connecting to peer through a hole punch");
+                if self
+                    .peers
+                    .values()
+                    .any(|p| p.addr == addr || p.listen_addr() == addr)
+                {
+                    return;
+                }
+                let peer = Peer {
+                    addr,
+                    peer_id: None,
+                    source: PeerSource::Holepunch,
+                };
+                let now = Instant::now();
+                self.pool.add(self.allowed_peers(vec![peer]), now);
+                // the other side is dialing us right now
+                self.pool.wake(addr, now);
+            }
+            HolepunchMsg::Error(addr, error) => debug!(%addr, %error, "hole punch failed"),
+        }
     }
 
-    async fn initiate_handshake(
-        stream: &mut TcpStream,
-        handshake: &Vec<u8>,
-        info_hash: &Vec<u8>,
-        peer: &Peer,
-    ) -> Result<Vec<u8>, ClientError> {
-        stream.write_all(handshake).await.map_err(|e| {
-            ClientError::HandshakeError(HandshakeError {
-                peer: peer.clone(),
-                handshake: handshake.to_vec(),
-                status: HandshakePhase::Send,
-                message: format!("Failed to send handshake: {}", e),
-            })
-        })?;
-
-        let mut response = vec![0u8; HANDSHAKE_LEN];
-        stream.read_exact(&mut response).await.map_err(|e| {
-            ClientError::HandshakeError(HandshakeError {
-                peer: peer.clone(),
-                handshake: handshake.to_vec(),
-                status: HandshakePhase::Receive,
-                message: format!("Failed to receive handshake: {}", e),
-            })
-        })?;
+    /// Tells the peer that asked and `target` to connect to each other, or
+    /// the peer that asked why they can't.
+    fn relay_holepunch(&self, peer_id: &[u8], target: SocketAddr) {
+        let Some(initiator) = self.peers.get(peer_id) else {
+            return;
+        };
+        let Some(reply_id) = initiator.extensions.id(UT_HOLEPUNCH) else {
+            return;
+        };
+        let from = initiator.listen_addr();
+        let reply = if target.port() == 0 || target.ip().is_unspecified() {
+            HolepunchMsg::Error(target, HolepunchError::NoSuchPeer)
+        } else if target == initiator.addr || target == from {
+            HolepunchMsg::Error(target, HolepunchError::NoSelf)
+        } else {
+            let target_peer = self
+                .peers
+                .values()
+                .find(|p| p.addr == target || p.listen_addr() == target);
+            match target_peer {
+                None => HolepunchMsg::Error(target, HolepunchError::NotConnected),
+                Some(p) => match p.extensions.id(UT_HOLEPUNCH) {
+                    None => HolepunchMsg::Error(target, HolepunchError::NoSupport),
+                    Some(id) => {
+                        p.send(HolepunchMsg::Connect(from).to_message(id));
+                        HolepunchMsg::Connect(target)
+                    }
+                },
+            }
+        };
+        debug!(%from, %target, ?reply, "relaying hole punch");
+        initiator.send(reply.to_message(reply_id));
+    }
 
-        Self::validate_handshake(&response, info_hash)
+    /// Asks the peers that relay hole punches to put us through to `addr`,
+    /// which we couldn't dial. Each address is only asked for once.
+    fn request_holepunch(&mut self, addr: SocketAddr) {
+        if !self.holepunch_enabled() || self.holepunched.contains(&addr) {
+            return;
+        }
+        let relays = self
+            .peers
+            .values()
+            .filter(|p| p.addr != addr)
+            .filter_map(|p| Some((p, p.extensions.id(UT_HOLEPUNCH)?)))
+            .collect::<Vec<_>>();
+        if relays.is_empty() {
+            return;
+        }
+        debug!(%addr, relays = relays.len(), "asking peers to hole punch");
+        for (relay, id) in relays {
+            relay.send(HolepunchMsg::Rendezvous(addr).to_message(id));
+        }
+        self.holepunched.insert(addr);
     }
 
-    async fn connect_to_peers(&mut self, min_connections: u32) -> Result<(), ClientError> {
-        println!("Connecting to peers...");
-        while self.peers.read().await.len() < min_connections as usize {
-            let mut handles = JoinSet::new();
-            for peer in
-                self.tracker.get_peers().await.map_err(|e| {
-                    ClientError::GetPeersError(format!("Failed to get peers: {}", e))
-                })?
-            {
-                let handshake = self.get_handshake()?;
-                let info_hash = self.tracker.get_metainfo().get_info_hash().map_err(|_| {
-                    ClientError::GetPeersError(String::from("Failed to get info hash"))
-                })?;
-                let bitfield = self.piece_scheduler.read().await.to_bitfield().to_bytes();
+    /// Performs the handshake over an already established connection to `peer`
+    /// and adds it to the peer set, returning the remote peer id. Unless
+    /// encryption is disabled the stream is encrypted first, with no fallback
+    /// to plaintext.
+    pub async fn add_peer_stream<S>(
+        &mut self,
+        peer: Peer,
+        stream: S,
+    ) -> Result<Vec<u8>, ClientError>
+    where
+        S: PeerTransport,
+    {
+        let handshake = self.get_handshake()?;
+        let info_hash = *self.tracker.get_metainfo().info_hash();
+
+        let mut stream = match self.config.encryption {
+            EncryptionPolicy::Disabled => MseStream::plain(stream),
+            policy => mse::initiate(stream, &info_hash.truncated(), policy)
+                .await
+                .map_err(|e| {
+                    ClientError::GetPeersError(format!("Encryption handshake failed: {}", e))
+                })?,
+        };
+        let (peer_id, capabilities) =
+            initiate_handshake(&mut stream, &handshake, &info_hash, &peer).await?;
+        if !self.add_peer(peer_id.clone(), capabilities, peer, stream) {
+            return Err(ClientError::GetPeersError(
+                "Session connection limit reached".to_string(),
+            ));
+        }
+        Ok(peer_id)
+    }
 
-                let peers = Arc::clone(&mut self.peers);
-                let send_queue = Arc::clone(&self.send_queue);
+    /// Accepts inbound connections on the configured port. Peers that pass the
+    /// handshake are queued for the coordinator to register.
+    async fn start_listener(&mut self) -> Result<(), ClientError> {
+        if !self.config.listen || self.listener_shutdown.is_some() {
+            return Ok(());
+        }
 
-                handles.spawn(async move {
-                    let mut stream = match timeout(
-                        Duration::from_secs(5),
-                        TcpStream::connect(peer.addr),
+        let Some((listeners, addr)) = self.bind_listener().await else {
+            warn!(ports = ?self.config.ports(), "failed to listen for incoming peers");
+            return Ok(());
+        };
+        let addr = listeners[0].local_addr().unwrap_or(addr);
+        self.listen_port = Some(addr.port());
+        self.tracker.set_port(addr.port());
+        if addr.is_ipv6() {
+            if let Some(ip) = nat::global_ipv6() {
+                debug!(%ip, "announcing our IPv6 address");
+                self.tracker.set_ipv6(Some(ip));
+            }
+        }
+
+        let handshake = self.get_handshake()?;
+        let info_hash = *self.tracker.get_metainfo().info_hash();
+        let runtime = self.runtime.clone();
+        let incoming = self.incoming_tx.clone();
+        let options = AcceptOptions {
+            handshake_timeout: self.config.handshake_timeout,
+            encryption: self.config.encryption,
+            // shared by every listener, so the limit holds across them
+            handshakes: HandshakeLimit {
+                running: Arc::new(AtomicUsize::new(0)),
+                max: self.config.max_half_open,
+            },
+        };
+        let port_mapper = self.config.nat.then(|| self.port_mapper.clone());
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+        self.runtime.spawn(
+            async move {
+                let accept = pin!(join_all(listeners.into_iter().map(|listener| {
+                    accept_peers(
+                        &runtime,
+                        listener,
+                        handshake.clone(),
+                        info_hash,
+                        options.clone(),
+                        incoming.clone(),
                     )
-                    .await
-                    {
-                        Ok(Ok(stream)) => stream,
-                        Ok(Err(e)) => {
-                            return Err(ClientError::GetPeersError(format!(
-                                "Failed to connect to peer: {}",
-                                e
-                            )))
-                        }
-                        Err(_) => {
-                            return Err(ClientError::GetPeersError(format!(
-                                "Failed to connect to peer: {} - timed out",
-                                peer.addr
-                            )))
-                        }
-                    };
+                })));
+                let map_port = pin!(async {
+                    match port_mapper {
+                        Some(port_mapper) => port_mapper.run(&runtime, addr.port()).await,
+                        None => futures::future::pending().await,
+                    }
+                });
+                select(select(accept, map_port), shutdown_rx).await;
+            }
+            .instrument(info_span!("listener", %addr)),
+        );
+        self.listener_shutdown = Some(shutdown_tx);
+        info!(%addr, "listening for incoming peers");
+        Ok(())
+    }
 
-                    let peer_id =
-                        Self::initiate_handshake(&mut stream, &handshake, &info_hash, &peer)
-                            .await?;
+    /// WebRTC goes around a proxy, like the DHT.
+    #[cfg(feature = "webtorrent")]
+    fn webtorrent_enabled(&self) -> bool {
+        self.config.webtorrent
+            && self.config.proxy.is_none()
+            && !self.tracker.websocket_trackers().is_empty()
+    }
 
-                    if peers.read().await.len() >= min_connections as usize {
-                        return Err(ClientError::GetPeersError(String::from(
-                            "Already connected to minimum number of peers",
-                        )));
+    /// Announces to the torrent's WebSocket trackers in the background,
+    /// taking the WebRTC peers they put us in touch with as incoming peers.
+    /// Needs a tokio runtime, as the WebRTC stack spawns its own tasks.
+    #[cfg(feature = "webtorrent")]
+    fn start_webtorrent(&mut self) -> Result<(), ClientError> {
+        if !self.webtorrent_enabled() || self.webtorrent_shutdown.is_some() {
+            return Ok(());
+        }
+
+        let trackers = self.tracker.websocket_trackers();
+        info!(trackers = trackers.len(), "joining WebTorrent swarm");
+        let webtorrent = webtorrent::WebTorrent {
+            trackers,
+            info_hash: *self.tracker.get_metainfo().info_hash(),
+            peer_id: self.tracker.peer_id().to_vec(),
+            handshake: self.get_handshake()?,
+            handshake_timeout: self.config.handshake_timeout,
+            ice_servers: self.config.ice_servers.clone(),
+            state: self.state_handle(),
+            incoming: self.incoming_tx.clone(),
+        };
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        self.runtime.spawn(async move {
+            select(pin!(webtorrent.run()), shutdown_rx).await;
+        });
+        self.webtorrent_shutdown = Some(shutdown_tx);
+        Ok(())
+    }
+
+    /// Binds the first port of the configured range that is free, on the
+    /// IPv6 wildcard where there is one. That also accepts IPv4 peers on
+    /// dual-stack hosts; where it doesn't, the IPv4 wildcard is bound on the
+    /// same port as well.
+    async fn bind_listener(&self) -> Option<(Vec<R::TcpListener>, SocketAddr)> {
+        for port in self.config.ports() {
+            let addr = SocketAddr::from((Ipv6Addr::UNSPECIFIED, port));
+            match self.runtime.listen(addr).await {
+                Ok(listener) => {
+                    let mut listeners = vec![listener];
+                    // fails with the port in use when the IPv6 socket took it
+                    let ipv4 = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
+                    if let Ok(listener) = self.runtime.listen(ipv4).await {
+                        debug!(%ipv4, "IPv6 listener is IPv6 only, listening on IPv4 too");
+                        listeners.push(listener);
                     }
+                    return Some((listeners, addr));
+                }
+                Err(e) => debug!(%addr, error = %e, "no IPv6 listener, falling back to IPv4"),
+            }
+            let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
+            match self.runtime.listen(addr).await {
+                Ok(listener) => return Some((vec![listener], addr)),
+                Err(e) => debug!(%addr, error = %e, "can't listen, trying the next port"),
+            }
+        }
+        None
+    }
 
-                    send_queue.lock().await.push_back((
-                        peer_id.clone(),
-                        Message::new(MessageId::Bitfield, &bitfield),
-                    ));
-                    peers.write().await.insert(
-                        peer_id.clone(),
-                        Arc::new(Mutex::new(PeerState::new(&peer_id, stream))),
-                    );
+    /// The port we accept peers on, once listening.
+    pub fn listen_port(&self) -> Option<u16> {
+        self.listen_port
+    }
 
-                    println!("Connected to peer: {:?}", peer.addr);
+    /// Bans `ip` for the rest of the session, dropping every connection to it.
+    fn ban(&mut self, ip: IpAddr) {
+        warn!(%ip, "banning misbehaving peer address");
+        self.metrics.add(Metric::PeersBanned, 1);
+        let peer_ids = self
+            .peers
+            .iter()
+            .filter(|(_, p)| p.addr.ip() == ip)
+            .map(|(peer_id, _)| peer_id.clone())
+            .collect::<Vec<_>>();
+        for peer_id in peer_ids {
+            self.remove_peer(&peer_id);
+        }
+        self.pool.forget(ip);
+    }
 
-                    Ok(peer_id)
-                });
-            }
+    /// Drops banned peers, and for private torrents those from sources other
+    /// than the tracker.
+    fn allowed_peers(&self, peers: Peers) -> Peers {
+        let private = self.tracker.get_metainfo().is_private();
+        peers
+            .into_iter()
+            .filter(|peer| !private || peer.source.allowed_when_private())
+            .filter(|peer| !self.reputation.is_banned(peer.addr.ip()))
+            .collect()
+    }
 
-            while let Some(handle) = handles.join_next().await {
-                let conection_result =
-                    handle.map_err(|e| ClientError::GetPeersError(format!("{}", e)))?;
+    fn add_incoming_peer<S: PeerTransport>(
+        &mut self,
+        (peer_id, capabilities, peer, stream): IncomingPeer<S>,
+    ) {
+        if peer_id == self.tracker.peer_id() {
+            debug!(addr = %peer.addr, "dropping connection to ourselves");
+        } else if self.reputation.is_banned(peer.addr.ip()) {
+            debug!(addr = %peer.addr, "dropping connection from banned address");
+        } else if self.peers.contains_key(&peer_id) {
+            debug!(addr = %peer.addr, "dropping duplicate connection");
+        } else if self.peers.len() >= self.config.max_peers {
+            debug!(addr = %peer.addr, "too many peers, dropping incoming connection");
+        } else {
+            self.add_peer(peer_id, capabilities, peer, stream);
+        }
+    }
+
+    /// The DHT talks to nodes directly over UDP, so it stays off behind a
+    /// proxy.
+    fn dht_enabled(&self) -> bool {
+        self.config.dht && self.config.proxy.is_none() && !self.tracker.get_metainfo().is_private()
+    }
 
-                if let Err(e) = conection_result {
-                    // #[cfg(debug_assertions)]
-                    // eprintln!("{}", e);
+    /// Looks the torrent up on the DHT, joining the network on first use.
+    async fn get_dht_peers(&mut self, info_hash: &InfoHash) -> Vec<SocketAddr> {
+        if !self.dht_enabled() {
+            return Vec::new();
+        }
+
+        if self.dht.is_none() {
+            let port = self.listen_port.unwrap_or(self.config.port);
+            let addr = SocketAddr::from(([0, 0, 0, 0], port));
+            match Dht::bind(self.runtime.clone(), addr).await {
+                Ok(node) => {
+                    // along with the nodes the torrent names, if any
+                    let hosts = self
+                        .config
+                        .dht_bootstrap
+                        .iter()
+                        .chain(&self.tracker.get_metainfo().nodes)
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    let bootstrap = dht::resolve_nodes(&self.runtime, &hosts).await;
+                    node.bootstrap(&bootstrap).await;
+                    self.dht = Some(node);
+                }
+                Err(e) => {
+                    warn!(error = %e, "failed to start dht node");
+                    return Vec::new();
                 }
             }
         }
 
-        println!("Connected to {} new peers", self.peers.read().await.len());
-        Ok(())
+        let Some(node) = self.dht.as_ref() else {
+            return Vec::new();
+        };
+        let peers = node
+            .get_peers(
+                info_hash.truncated(),
+                Some(self.listen_port.unwrap_or(self.config.port)),
+            )
+            .await;
+        info!(peers = peers.len(), "found peers on the dht");
+        peers
+    }
+
+    /// Starts the peer's task, unless the session has no connections left.
+    /// Returns whether the peer was added.
+    fn add_peer<S>(
+        &mut self,
+        peer_id: Vec<u8>,
+        capabilities: PeerCapabilities,
+        peer: Peer,
+        stream: S,
+    ) -> bool
+    where
+        S: PeerTransport,
+    {
+        let Some(connection) = self.limits.try_acquire(Slot::Connection) else {
+            debug!(addr = %peer.addr, "session connection limit reached, dropping peer");
+            return false;
+        };
+        let mut peer = PeerState::spawn(
+            &self.runtime,
+            peer_id.clone(),
+            peer.addr,
+            stream,
+            self.events_tx.clone(),
+            self.budget.clone(),
+            PeerOptions {
+                keep_alive_interval: self.config.keep_alive_interval,
+                idle_timeout: self.config.peer_timeout,
+                codec: MessageCodec::for_torrent(self.piece_scheduler.len()),
+                pipeline: Pipeline::new(&self.config),
+            },
+        );
+        peer.connection = Some(connection);
+        peer.capabilities = capabilities;
+        let bitfield = self.piece_scheduler.to_bitfield().to_bytes();
+        peer.send(Message::new(MessageId::Bitfield, Bytes::from(bitfield)));
+        // tell peers that run a dht node where to find ours
+        let shared = capabilities & self.capabilities();
+        if let (true, Some(node)) = (shared.contains(PeerCapabilities::DHT), &self.dht) {
+            if let Ok(addr) = node.local_addr() {
+                peer.send(PortMsg { port: addr.port() }.to_message());
+            }
+        }
+        if shared.contains(PeerCapabilities::EXTENSION_PROTOCOL) {
+            peer.send(self.extended_handshake().to_message());
+        }
+        info!(
+            peer_id = %String::from_utf8_lossy(&peer_id),
+            client = peer.client.as_ref().map(|c| c.to_string()),
+            %capabilities,
+            "connected to peer"
+        );
+        self.metrics.add(Metric::PeersConnected, 1);
+        self.events.emit(TorrentEvent::PeerConnected(peer.addr));
+        self.peers.insert(peer_id, peer);
+        true
+    }
+}
+
+/// Opens a TCP connection to `addr`, through `proxy` if there is one, giving
+/// up after `connect_timeout`.
+async fn connect<R: Runtime>(
+    runtime: &R,
+    proxy: Option<&ProxyConfig>,
+    addr: SocketAddr,
+    connect_timeout: Duration,
+) -> Result<R::TcpStream, ClientError> {
+    let stream = async {
+        match proxy {
+            Some(proxy) => proxy::connect_addr(runtime, proxy, addr).await,
+            None => runtime.connect(addr).await,
+        }
+    };
+    match timeout(runtime, connect_timeout, stream).await {
+        Some(Ok(stream)) => Ok(stream),
+        Some(Err(e)) => Err(ClientError::Io(e)),
+        None => Err(ClientError::Io(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("connecting to {} timed out", addr),
+        ))),
+    }
+}
+
+/// How an outgoing connection is set up.
+#[derive(Debug, Clone, Copy)]
+struct DialOptions {
+    connect_timeout: Duration,
+    /// Covers the whole attempt, from dialing to the peer's handshake.
+    handshake_timeout: Duration,
+    encryption: EncryptionPolicy,
+}
+
+/// Connects to `peer`, encrypting the connection as `options` says, and
+/// exchanges handshakes. Gives up if that takes longer than the handshake
+/// timeout, so a silent peer can't hold a half-open slot forever.
+async fn dial<R: Runtime>(
+    runtime: &R,
+    proxy: Option<&ProxyConfig>,
+    peer: Peer,
+    handshake: &[u8],
+    info_hash: &InfoHash,
+    options: DialOptions,
+) -> Result<IncomingPeer<MseStream<R::TcpStream>>, ClientError> {
+    let DialOptions {
+        connect_timeout,
+        handshake_timeout,
+        encryption,
+    } = options;
+    let addr = peer.addr;
+    let attempt = async {
+        let mut stream = match encryption {
+            EncryptionPolicy::Disabled => {
+                MseStream::plain(connect(runtime, proxy, peer.addr, connect_timeout).await?)
+            }
+            policy => {
+                let stream = connect(runtime, proxy, peer.addr, connect_timeout).await?;
+                let encrypted = timeout(
+                    runtime,
+                    connect_timeout,
+                    mse::initiate(stream, &info_hash.truncated(), policy),
+                )
+                .await;
+                match encrypted {
+                    Some(Ok(stream)) => stream,
+                    // the peer may not support encryption, so try again without it
+                    _ if policy == EncryptionPolicy::Enabled => {
+                        debug!("encryption handshake failed, retrying in plaintext");
+                        MseStream::plain(connect(runtime, proxy, peer.addr, connect_timeout).await?)
+                    }
+                    Some(Err(e)) => {
+                        return Err(ClientError::GetPeersError(format!(
+                            "Encryption handshake failed: {}",
+                            e
+                        )))
+                    }
+                    None => {
+                        return Err(ClientError::GetPeersError(format!(
+                            "Encryption handshake with {} timed out",
+                            peer.addr
+                        )))
+                    }
+                }
+            }
+        };
+
+        let (peer_id, capabilities) =
+            initiate_handshake(&mut stream, handshake, info_hash, &peer).await?;
+        Ok((peer_id, capabilities, peer, stream))
+    };
+    timeout(runtime, handshake_timeout, attempt)
+        .await
+        .unwrap_or_else(|| {
+            Err(ClientError::Io(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("handshake with {} timed out", addr),
+            )))
+        })
+}
+
+/// Checks a peer's handshake against our info hash, returning its peer id
+/// and the capabilities set in its reserved bytes.
+pub fn validate_handshake(
+    handshake: &[u8],
+    info_hash: &InfoHash,
+) -> Result<(Vec<u8>, PeerCapabilities), ClientError> {
+    if handshake.len() != HANDSHAKE_LEN {
+        return Err(ClientError::ValidateHandshakeError(
+            "Invalid handshake length".to_string(),
+        ));
     }
+
+    let pstr_len = handshake[0] as usize;
+    if pstr_len != b"BitTorrent protocol".len() {
+        return Err(ClientError::ValidateHandshakeError(
+            "Invalid protocol string length".to_string(),
+        ));
+    }
+
+    if &handshake[1..20] != b"BitTorrent protocol" {
+        return Err(ClientError::ValidateHandshakeError(
+            "Invalid protocol string".to_string(),
+        ));
+    }
+
+    if handshake[28..48] != info_hash.truncated() {
+        return Err(ClientError::ValidateHandshakeError(
+            "Invalid info hash".to_string(),
+        ));
+    }
+
+    let mut reserved = [0; 8];
+    reserved.copy_from_slice(&handshake[20..28]);
+    let peer_id = handshake[48..68].to_vec();
+
+    Ok((peer_id, PeerCapabilities::from_reserved(reserved)))
+}
+
+async fn accept_peers<R: Runtime>(
+    runtime: &R,
+    listener: R::TcpListener,
+    handshake: Vec<u8>,
+    info_hash: InfoHash,
+    options: AcceptOptions,
+    incoming: mpsc::Sender<IncomingPeer<Box<dyn PeerTransport>>>,
+) {
+    let AcceptOptions {
+        handshake_timeout,
+        encryption,
+        handshakes,
+    } = options;
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!(error = %e, "failed to accept connection");
+                continue;
+            }
+        };
+
+        // IPv4 peers on a dual-stack listener show up as mapped addresses
+        let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+        let Some(running) = handshakes.try_start() else {
+            debug!(%addr, "too many incoming handshakes, dropping connection");
+            continue;
+        };
+        let peer = Peer {
+            addr,
+            peer_id: None,
+            source: PeerSource::Incoming,
+        };
+        let handshake = handshake.clone();
+        let mut incoming = incoming.clone();
+        let task_runtime = runtime.clone();
+        runtime.spawn(
+            async move {
+                let result = timeout(&task_runtime, handshake_timeout, async {
+                    let mut stream = mse::accept(stream, &info_hash.truncated(), encryption)
+                        .await
+                        .map_err(|e| {
+                            ClientError::GetPeersError(format!(
+                                "Encryption handshake failed: {}",
+                                e
+                            ))
+                        })?;
+                    let (peer_id, capabilities) =
+                        accept_handshake(&mut stream, &handshake, &info_hash, &peer).await?;
+                    let stream: Box<dyn PeerTransport> = Box::new(stream);
+                    Ok::<_, ClientError>((peer_id, capabilities, stream))
+                })
+                .await;
+                drop(running);
+                match result {
+                    Some(Ok((peer_id, capabilities, stream))) => {
+                        let _ = incoming.send((peer_id, capabilities, peer, stream)).await;
+                    }
+                    Some(Err(e)) => debug!(error = %e, "rejected incoming peer"),
+                    None => debug!("incoming handshake timed out"),
+                }
+            }
+            .instrument(info_span!("incoming", %addr)),
+        );
+    }
+}
+
+/// How incoming connections are taken on.
+#[derive(Clone)]
+struct AcceptOptions {
+    handshake_timeout: Duration,
+    encryption: EncryptionPolicy,
+    handshakes: HandshakeLimit,
+}
+
+/// Bounds the incoming handshakes in progress, as each holds a task and a
+/// socket before we know the peer is any use.
+#[derive(Clone)]
+struct HandshakeLimit {
+    running: Arc<AtomicUsize>,
+    max: usize,
+}
+
+impl HandshakeLimit {
+    /// Counts one more handshake if there is room, until the guard is dropped.
+    fn try_start(&self) -> Option<RunningHandshake> {
+        self.running
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |running| {
+                (running < self.max).then_some(running + 1)
+            })
+            .ok()?;
+        Some(RunningHandshake(self.running.clone()))
+    }
+}
+
+struct RunningHandshake(Arc<AtomicUsize>);
+
+impl Drop for RunningHandshake {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// The inbound side of the handshake: the remote peer speaks first, and we
+/// only answer once it has asked for our torrent.
+async fn accept_handshake<S>(
+    stream: &mut S,
+    handshake: &[u8],
+    info_hash: &InfoHash,
+    peer: &Peer,
+) -> Result<(Vec<u8>, PeerCapabilities), ClientError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut request = vec![0u8; HANDSHAKE_LEN];
+    stream.read_exact(&mut request).await.map_err(|e| {
+        ClientError::HandshakeError(HandshakeError {
+            peer: peer.clone(),
+            handshake: handshake.to_vec(),
+            status: HandshakePhase::Receive,
+            message: format!("Failed to receive handshake: {}", e),
+        })
+    })?;
+    let handshake_result = validate_handshake(&request, info_hash)?;
+
+    stream.write_all(handshake).await.map_err(|e| {
+        ClientError::HandshakeError(HandshakeError {
+            peer: peer.clone(),
+            handshake: handshake.to_vec(),
+            status: HandshakePhase::Send,
+            message: format!("Failed to send handshake: {}", e),
+        })
+    })?;
+
+    Ok(handshake_result)
+}
+
+async fn initiate_handshake<S>(
+    stream: &mut S,
+    handshake: &[u8],
+    info_hash: &InfoHash,
+    peer: &Peer,
+) -> Result<(Vec<u8>, PeerCapabilities), ClientError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(handshake).await.map_err(|e| {
+        ClientError::HandshakeError(HandshakeError {
+            peer: peer.clone(),
+            handshake: handshake.to_vec(),
+            status: HandshakePhase::Send,
+            message: format!("Failed to send handshake: {}", e),
+        })
+    })?;
+
+    let mut response = vec![0u8; HANDSHAKE_LEN];
+    stream.read_exact(&mut response).await.map_err(|e| {
+        ClientError::HandshakeError(HandshakeError {
+            peer: peer.clone(),
+            handshake: handshake.to_vec(),
+            status: HandshakePhase::Receive,
+            message: format!("Failed to receive handshake: {}", e),
+        })
+    })?;
+
+    validate_handshake(&response, info_hash)
 }
@@ -0,0 +1,433 @@
+//! Message Stream Encryption: a Diffie-Hellman exchange followed by RC4 over
+//! the rest of the connection, which hides BitTorrent traffic from simple
+//! protocol filters.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use num_bigint::BigUint;
+use rand::Rng;
+use sha1::{Digest, Sha1};
+
+use crate::config::EncryptionPolicy;
+
+/// The 768-bit safe prime every MSE implementation uses, with generator 2.
+const PRIME: &[u8; 96] = &[
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xC9, 0x0F, 0xDA, 0xA2, 0x21, 0x68, 0xC2, 0x34,
+    0xC4, 0xC6, 0x62, 0x8B, 0x80, 0xDC, 0x1C, 0xD1, 0x29, 0x02, 0x4E, 0x08, 0x8A, 0x67, 0xCC, 0x74,
+    0x02, 0x0B, 0xBE, 0xA6, 0x3B, 0x13, 0x9B, 0x22, 0x51, 0x4A, 0x08, 0x79, 0x8E, 0x34, 0x04, 0xDD,
+    0xEF, 0x95, 0x19, 0xB3, 0xCD, 0x3A, 0x43, 0x1B, 0x30, 0x2B, 0x0A, 0x6D, 0xF2, 0x5F, 0x14, 0x37,
+    0x4F, 0xE1, 0x35, 0x6D, 0x6D, 0x51, 0xC2, 0x45, 0xE4, 0x85, 0xB5, 0x76, 0x62, 0x5E, 0x7E, 0xC6,
+    0xF4, 0x4C, 0x42, 0xE9, 0xA6, 0x3A, 0x36, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x05, 0x63,
+];
+const KEY_LEN: usize = PRIME.len();
+/// Random padding after each public key is at most this long.
+const MAX_PAD_LEN: usize = 512;
+/// Eight zero bytes both sides encrypt so the other can find the RC4 stream.
+const VC: [u8; 8] = [0; 8];
+
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+
+/// The first bytes of a plaintext BitTorrent handshake.
+const PLAINTEXT_HEADER: &[u8] = b"\x13BitTorrent protocol";
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// RC4 with the first 1024 bytes of keystream thrown away, as MSE requires.
+#[derive(Clone)]
+struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut rc4 = Self::unskipped(key);
+        rc4.apply(&mut [0; 1024]);
+        rc4
+    }
+
+    fn unskipped(key: &[u8]) -> Self {
+        let mut s = [0u8; 256];
+        for (i, b) in s.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+        Self { s, i: 0, j: 0 }
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for b in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            let k = self.s[self.s[self.i as usize].wrapping_add(self.s[self.j as usize]) as usize];
+            *b ^= k;
+        }
+    }
+}
+
+/// A connection that may be RC4 encrypted. Once the handshake is done it
+/// reads and writes plain BitTorrent messages either way.
+pub struct MseStream<S> {
+    inner: S,
+    read_cipher: Option<Rc4>,
+    write_cipher: Option<Rc4>,
+    /// Plaintext read during the handshake that belongs to the peer protocol.
+    buffered: Vec<u8>,
+}
+
+impl<S> MseStream<S> {
+    /// Wraps a stream that is not encrypted.
+    pub fn plain(inner: S) -> Self {
+        Self {
+            inner,
+            read_cipher: None,
+            write_cipher: None,
+            buffered: Vec::new(),
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.write_cipher.is_some()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if !this.buffered.is_empty() {
+            let n = buf.len().min(this.buffered.len());
+            buf[..n].copy_from_slice(&this.buffered[..n]);
+            this.buffered.drain(..n);
+            return Poll::Ready(Ok(n));
+        }
+        let n = ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(cipher) = &mut this.read_cipher {
+            cipher.apply(&mut buf[..n]);
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MseStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(cipher) = &mut this.write_cipher else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+        // encrypt with a copy of the cipher so only the bytes the inner
+        // stream takes advance the keystream
+        let mut next = cipher.clone();
+        let mut data = buf.to_vec();
+        next.apply(&mut data);
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &data))?;
+        if n == data.len() {
+            *cipher = next;
+        } else {
+            cipher.apply(&mut data[..n]);
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn xor(a: [u8; 20], b: [u8; 20]) -> [u8; 20] {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+struct KeyPair {
+    private: BigUint,
+    public: [u8; KEY_LEN],
+}
+
+impl KeyPair {
+    fn generate() -> Self {
+        let private = BigUint::from_bytes_be(&rand::thread_rng().gen::<[u8; 20]>());
+        let public = BigUint::from(2u32).modpow(&private, &BigUint::from_bytes_be(PRIME));
+        Self {
+            private,
+            public: to_key(&public),
+        }
+    }
+
+    fn secret(&self, remote: &[u8]) -> [u8; KEY_LEN] {
+        let remote = BigUint::from_bytes_be(remote);
+        to_key(&remote.modpow(&self.private, &BigUint::from_bytes_be(PRIME)))
+    }
+}
+
+/// Left-pads `n` to the width of the prime.
+fn to_key(n: &BigUint) -> [u8; KEY_LEN] {
+    let bytes = n.to_bytes_be();
+    let mut key = [0u8; KEY_LEN];
+    key[KEY_LEN - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+fn padding() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let len = rng.gen_range(0..=MAX_PAD_LEN);
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+/// Reads until the stream has produced `pattern`, giving up after `limit`
+/// bytes. Reads one byte at a time so nothing past the pattern is consumed.
+async fn sync<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    pattern: &[u8],
+    limit: usize,
+) -> io::Result<()> {
+    let mut seen = Vec::with_capacity(limit);
+    let mut byte = [0u8; 1];
+    while !seen.ends_with(pattern) {
+        if seen.len() >= limit {
+            return Err(invalid("no encryption handshake sync point"));
+        }
+        stream.read_exact(&mut byte).await?;
+        seen.push(byte[0]);
+    }
+    Ok(())
+}
+
+async fn read_encrypted<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    cipher: &mut Rc4,
+    len: usize,
+) -> io::Result<Vec<u8>> {
+    let mut data = vec![0u8; len];
+    stream.read_exact(&mut data).await?;
+    cipher.apply(&mut data);
+    Ok(data)
+}
+
+fn provided(policy: EncryptionPolicy) -> u32 {
+    match policy {
+        EncryptionPolicy::Forced => CRYPTO_RC4,
+        _ => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+    }
+}
+
+/// The outgoing side of the handshake. `info_hash` is the torrent we are
+/// about to ask the peer for.
+pub async fn initiate<S>(
+    mut stream: S,
+    info_hash: &[u8],
+    policy: EncryptionPolicy,
+) -> io::Result<MseStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let keys = KeyPair::generate();
+    let mut message = keys.public.to_vec();
+    message.extend_from_slice(&padding());
+    stream.write_all(&message).await?;
+
+    let mut remote = [0u8; KEY_LEN];
+    stream.read_exact(&mut remote).await?;
+    let secret = keys.secret(&remote);
+    let mut encrypt = Rc4::new(&hash(&[b"keyA", &secret, info_hash]));
+    let mut decrypt = Rc4::new(&hash(&[b"keyB", &secret, info_hash]));
+
+    let mut message = hash(&[b"req1", &secret]).to_vec();
+    message.extend_from_slice(&xor(hash(&[b"req2", info_hash]), hash(&[b"req3", &secret])));
+    let mut payload = VC.to_vec();
+    payload.extend_from_slice(&provided(policy).to_be_bytes());
+    // no PadC and no initial payload
+    payload.extend_from_slice(&[0, 0, 0, 0]);
+    encrypt.apply(&mut payload);
+    message.extend_from_slice(&payload);
+    stream.write_all(&message).await?;
+
+    // the reply starts after PadB with VC under the peer's keystream
+    let mut vc = VC;
+    decrypt.apply(&mut vc);
+    sync(&mut stream, &vc, MAX_PAD_LEN + VC.len()).await?;
+
+    let reply = read_encrypted(&mut stream, &mut decrypt, 6).await?;
+    let selected = u32::from_be_bytes(reply[..4].try_into().unwrap());
+    let pad_len = u16::from_be_bytes([reply[4], reply[5]]) as usize;
+    if pad_len > MAX_PAD_LEN {
+        return Err(invalid("encryption padding too long"));
+    }
+    read_encrypted(&mut stream, &mut decrypt, pad_len).await?;
+
+    let mut stream = MseStream::plain(stream);
+    match selected {
+        CRYPTO_RC4 => {
+            stream.read_cipher = Some(decrypt);
+            stream.write_cipher = Some(encrypt);
+        }
+        CRYPTO_PLAINTEXT if policy != EncryptionPolicy::Forced => {}
+        _ => return Err(invalid("peer selected an unsupported encryption method")),
+    }
+    Ok(stream)
+}
+
+/// The incoming side of the handshake. A peer that opens with a plaintext
+/// BitTorrent handshake is let through unless `policy` is forced, and the
+/// bytes already read are replayed to the caller.
+pub async fn accept<S>(
+    mut stream: S,
+    info_hash: &[u8],
+    policy: EncryptionPolicy,
+) -> io::Result<MseStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut remote = [0u8; KEY_LEN];
+    let header = &mut remote[..PLAINTEXT_HEADER.len()];
+    stream.read_exact(header).await?;
+    if header == PLAINTEXT_HEADER {
+        if policy == EncryptionPolicy::Forced {
+            return Err(invalid("peer did not encrypt the connection"));
+        }
+        let mut stream = MseStream::plain(stream);
+        stream.buffered = PLAINTEXT_HEADER.to_vec();
+        return Ok(stream);
+    }
+    if policy == EncryptionPolicy::Disabled {
+        return Err(invalid("peer tried to encrypt the connection"));
+    }
+    stream
+        .read_exact(&mut remote[PLAINTEXT_HEADER.len()..])
+        .await?;
+
+    let keys = KeyPair::generate();
+    let mut message = keys.public.to_vec();
+    message.extend_from_slice(&padding());
+    stream.write_all(&message).await?;
+    let secret = keys.secret(&remote);
+
+    sync(&mut stream, &hash(&[b"req1", &secret]), MAX_PAD_LEN + 20).await?;
+    let mut skey = [0u8; 20];
+    stream.read_exact(&mut skey).await?;
+    if skey != xor(hash(&[b"req2", info_hash]), hash(&[b"req3", &secret])) {
+        return Err(invalid("peer asked for a different torrent"));
+    }
+
+    let mut decrypt = Rc4::new(&hash(&[b"keyA", &secret, info_hash]));
+    let mut encrypt = Rc4::new(&hash(&[b"keyB", &secret, info_hash]));
+    let request = read_encrypted(&mut stream, &mut decrypt, 14).await?;
+    if request[..8] != VC {
+        return Err(invalid("bad encryption verification constant"));
+    }
+    let provide = u32::from_be_bytes(request[8..12].try_into().unwrap());
+    let pad_len = u16::from_be_bytes([request[12], request[13]]) as usize;
+    if pad_len > MAX_PAD_LEN {
+        return Err(invalid("encryption padding too long"));
+    }
+    read_encrypted(&mut stream, &mut decrypt, pad_len).await?;
+    let ia_len = read_encrypted(&mut stream, &mut decrypt, 2).await?;
+    let ia_len = u16::from_be_bytes([ia_len[0], ia_len[1]]) as usize;
+    let initial = read_encrypted(&mut stream, &mut decrypt, ia_len).await?;
+
+    let selected = if provide & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if provide & CRYPTO_PLAINTEXT != 0 && policy != EncryptionPolicy::Forced {
+        CRYPTO_PLAINTEXT
+    } else {
+        return Err(invalid("no common encryption method"));
+    };
+    let mut reply = VC.to_vec();
+    reply.extend_from_slice(&selected.to_be_bytes());
+    // no PadD
+    reply.extend_from_slice(&[0, 0]);
+    encrypt.apply(&mut reply);
+    stream.write_all(&reply).await?;
+
+    let mut stream = MseStream::plain(stream);
+    stream.buffered = initial;
+    if selected == CRYPTO_RC4 {
+        stream.read_cipher = Some(decrypt);
+        stream.write_cipher = Some(encrypt);
+    }
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{executor::block_on, future::join};
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    use super::*;
+
+    #[test]
+    fn rc4_matches_known_keystream() {
+        let mut data = *b"Plaintext";
+        Rc4::unskipped(b"Key").apply(&mut data);
+        assert_eq!(data, [0xbb, 0xf3, 0x16, 0xe8, 0xd9, 0x40, 0xaf, 0x0a, 0xd3]);
+    }
+
+    #[test]
+    fn handshake_negotiates_rc4() {
+        let info_hash = [7u8; 20];
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let (outgoing, incoming) = block_on(join(
+            initiate(a.compat(), &info_hash, EncryptionPolicy::Enabled),
+            accept(b.compat(), &info_hash, EncryptionPolicy::Forced),
+        ));
+        let (mut outgoing, mut incoming) = (outgoing.unwrap(), incoming.unwrap());
+        assert!(outgoing.is_encrypted() && incoming.is_encrypted());
+
+        block_on(async {
+            outgoing.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            incoming.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+        });
+    }
+
+    #[test]
+    fn accept_replays_plaintext_handshake() {
+        let (a, b) = tokio::io::duplex(1024);
+        let mut a = a.compat();
+        block_on(a.write_all(b"\x13BitTorrent protocol")).unwrap();
+
+        let mut stream = block_on(accept(b.compat(), &[0; 20], EncryptionPolicy::Enabled)).unwrap();
+        assert!(!stream.is_encrypted());
+        let mut buf = [0u8; 20];
+        block_on(stream.read_exact(&mut buf)).unwrap();
+        assert_eq!(&buf, PLAINTEXT_HEADER);
+
+        let (a, b) = tokio::io::duplex(1024);
+        let mut a = a.compat();
+        block_on(a.write_all(b"\x13BitTorrent protocol")).unwrap();
+        assert!(block_on(accept(b.compat(), &[0; 20], EncryptionPolicy::Forced)).is_err());
+    }
+}
@@ -0,0 +1,292 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    pin::pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use futures::{
+    channel::mpsc,
+    future::select,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadHalf, WriteHalf},
+    SinkExt, StreamExt,
+};
+use tracing::{info_span, trace, Instrument};
+
+use crate::runtime::{timeout, Runtime};
+
+use super::{
+    bitfield::Bitfield,
+    budget::{MemoryBudget, MemoryKind, Reservation},
+    capabilities::PeerCapabilities,
+    extension::ExtendedHandshake,
+    limits::Permit,
+    message::{send_message, Message, MessageCodec, MessageId, MessageReader},
+    peer_id::{self, PeerClient},
+    pipeline::Pipeline,
+    rate::RateMeter,
+    transport::PeerTransport,
+};
+
+/// How a peer's task runs its connection.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerOptions {
+    /// A keep-alive is sent after this long without sending anything else.
+    pub keep_alive_interval: Duration,
+    /// The peer is dropped after sending nothing for this long.
+    pub idle_timeout: Duration,
+    /// Frames incoming messages; the peer is dropped for one over its limit.
+    pub codec: MessageCodec,
+    pub pipeline: Pipeline,
+}
+
+pub enum PeerEvent {
+    /// The reservation accounts for the message until the event is dropped.
+    Message(Vec<u8>, Message, Reservation),
+    Disconnected(Vec<u8>, String),
+}
+
+/// The coordinator's view of a connected peer. The socket itself is owned by
+/// the peer's task; the coordinator only talks to it through `sender`.
+pub struct PeerState {
+    /// Blocks carry their upload reservation until they are written.
+    sender: mpsc::UnboundedSender<(Message, Option<Reservation>)>,
+    /// Blocks handed to the peer's task that haven't been written yet.
+    sent_uploads: Arc<AtomicUsize>,
+    pub addr: SocketAddr,
+    /// What the peer ID says the peer is running.
+    pub client: Option<PeerClient>,
+    /// The extensions the peer set in its handshake.
+    pub capabilities: PeerCapabilities,
+    /// What the peer said in its extended handshake, if it sent one.
+    pub extensions: ExtendedHandshake,
+    pub bitfield: Option<Bitfield>,
+
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+
+    /// Blocks we have asked the peer for and not received yet, by piece
+    /// index and offset.
+    pub requests: HashSet<(u32, u32)>,
+    /// How many requests to keep in flight.
+    pub pipeline: Pipeline,
+    /// Blocks the peer asked us for that are still being read from disk, by
+    /// piece index, offset and length.
+    pub uploads: HashMap<(u32, u32, u32), Reservation>,
+    /// Counts the connection against the session's limit.
+    pub connection: Option<Permit>,
+    /// Held while we unchoke the peer.
+    pub upload_slot: Option<Permit>,
+    /// Bytes of piece data the peer has sent us.
+    pub downloaded: u64,
+    /// Bytes of piece data we have sent the peer.
+    pub uploaded: u64,
+    download_meter: RateMeter,
+    upload_meter: RateMeter,
+}
+
+impl PeerState {
+    pub fn spawn<R, S>(
+        runtime: &R,
+        peer_id: Vec<u8>,
+        addr: SocketAddr,
+        stream: S,
+        mut events: mpsc::Sender<PeerEvent>,
+        budget: MemoryBudget,
+        options: PeerOptions,
+    ) -> Self
+    where
+        R: Runtime,
+        S: PeerTransport,
+    {
+        let (sender, outgoing) = mpsc::unbounded();
+        let sent_uploads = Arc::new(AtomicUsize::new(0));
+        let written = sent_uploads.clone();
+        let client = peer_id::identify(&peer_id);
+        let span = info_span!(
+            "peer",
+            peer_id = %String::from_utf8_lossy(&peer_id),
+            %addr,
+            client = client.as_ref().map(|c| c.to_string()),
+        );
+
+        let task_runtime = runtime.clone();
+        runtime.spawn(
+            async move {
+                let (reader, writer) = stream.split();
+                let result = {
+                    let read = pin!(read_messages(
+                        &task_runtime,
+                        &peer_id,
+                        MessageReader::new(reader, options.codec),
+                        events.clone(),
+                        &budget,
+                        options.idle_timeout
+                    ));
+                    let write = pin!(write_messages(
+                        &task_runtime,
+                        writer,
+                        outgoing,
+                        &written,
+                        options.keep_alive_interval
+                    ));
+                    select(read, write).await.factor_first().0
+                };
+
+                if let Err(e) = result {
+                    let _ = events.send(PeerEvent::Disconnected(peer_id, e)).await;
+                }
+            }
+            .instrument(span),
+        );
+
+        Self {
+            sender,
+            sent_uploads,
+            addr,
+            client,
+            capabilities: PeerCapabilities::empty(),
+            extensions: ExtendedHandshake::default(),
+            bitfield: None,
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            requests: HashSet::new(),
+            pipeline: options.pipeline,
+            uploads: HashMap::new(),
+            connection: None,
+            upload_slot: None,
+            downloaded: 0,
+            uploaded: 0,
+            download_meter: RateMeter::new(),
+            upload_meter: RateMeter::new(),
+        }
+    }
+
+    pub fn record_downloaded(&mut self, bytes: usize) {
+        self.downloaded += bytes as u64;
+        self.download_meter.record(Instant::now(), self.downloaded);
+    }
+
+    pub fn record_uploaded(&mut self, bytes: usize) {
+        self.uploaded += bytes as u64;
+        self.upload_meter.record(Instant::now(), self.uploaded);
+    }
+
+    /// Bytes per second the peer has sent us over the last few seconds.
+    pub fn download_rate(&self) -> f64 {
+        self.download_meter.rate(Instant::now(), self.downloaded)
+    }
+
+    /// Bytes per second we have sent the peer over the last few seconds.
+    pub fn upload_rate(&self) -> f64 {
+        self.upload_meter.rate(Instant::now(), self.uploaded)
+    }
+
+    /// Whether the peer unchoked us yet sent none of the blocks we asked for
+    /// in `timeout`. Snubbed peers are the first we choke in turn.
+    pub fn is_snubbed(&self, timeout: Duration) -> bool {
+        !self.peer_choking
+            && self
+                .pipeline
+                .is_stalled(Instant::now(), self.requests.len(), timeout)
+    }
+
+    /// Where the peer accepts connections: the port from its extended
+    /// handshake, as it may have connected to us from another one.
+    pub fn listen_addr(&self) -> SocketAddr {
+        match self.extensions.port {
+            Some(port) => SocketAddr::new(self.addr.ip(), port),
+            None => self.addr,
+        }
+    }
+
+    /// Queues a message for the peer's task. Returns false if the task is gone.
+    pub fn send(&self, message: Message) -> bool {
+        self.sender.unbounded_send((message, None)).is_ok()
+    }
+
+    /// Queues a block for the peer, keeping its reservation until it is
+    /// written. Returns false if the task is gone.
+    pub fn send_upload(&self, message: Message, reservation: Reservation) -> bool {
+        self.sent_uploads.fetch_add(1, Ordering::AcqRel);
+        self.sender
+            .unbounded_send((message, Some(reservation)))
+            .is_ok()
+    }
+
+    /// Requests from the peer we have taken on and not written out yet.
+    pub fn queued_uploads(&self) -> usize {
+        self.uploads.len() + self.sent_uploads.load(Ordering::Acquire)
+    }
+}
+
+async fn read_messages<R, S>(
+    runtime: &R,
+    peer_id: &[u8],
+    mut reader: MessageReader<ReadHalf<S>>,
+    mut events: mpsc::Sender<PeerEvent>,
+    budget: &MemoryBudget,
+    idle_timeout: Duration,
+) -> Result<(), String>
+where
+    R: Runtime,
+    S: AsyncRead,
+{
+    loop {
+        if budget.is_exhausted() {
+            trace!("memory budget exhausted, pausing reads");
+            budget.available().await;
+        }
+
+        let message = timeout(runtime, idle_timeout, reader.next())
+            .await
+            .ok_or_else(|| format!("nothing received for {:?}", idle_timeout))?
+            .map_err(|e| e.to_string())?;
+        trace!(message = %message.get_id(), "received message");
+
+        let reservation = budget.reserve(MemoryKind::Receive, message.get_payload().len());
+        let event = PeerEvent::Message(peer_id.to_vec(), message, reservation);
+        if events.send(event).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+async fn write_messages<R, S>(
+    runtime: &R,
+    mut writer: WriteHalf<S>,
+    mut outgoing: mpsc::UnboundedReceiver<(Message, Option<Reservation>)>,
+    sent_uploads: &AtomicUsize,
+    keep_alive_interval: Duration,
+) -> Result<(), String>
+where
+    R: Runtime,
+    S: AsyncWrite,
+{
+    loop {
+        let (message, upload) = match timeout(runtime, keep_alive_interval, outgoing.next()).await {
+            Some(Some(queued)) => queued,
+            // the coordinator dropped us
+            Some(None) => return Ok(()),
+            // nothing sent for a whole interval
+            None => (Message::new(MessageId::KeepAlive, Bytes::new()), None),
+        };
+
+        trace!(message = %message.get_id(), "sending message");
+        send_message(&mut writer, &message)
+            .await
+            .map_err(|e| e.to_string())?;
+        if upload.is_some() {
+            sent_uploads.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use std::fmt::Display;
+
+/// The client software a peer ID says the peer is running.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerClient {
+    pub name: String,
+    pub version: String,
+}
+
+impl Display for PeerClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.version.is_empty() {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{} {}", self.name, self.version)
+        }
+    }
+}
+
+/// Clients using Azureus-style IDs, `-XXVVVV-` followed by random bytes.
+const AZUREUS_CLIENTS: &[(&str, &str)] = &[
+    ("7T", "aTorrent"),
+    ("AG", "Ares"),
+    ("AZ", "Vuze"),
+    ("BC", "BitComet"),
+    ("BI", "BiglyBT"),
+    ("BT", "BitTorrent"),
+    ("DE", "Deluge"),
+    ("FD", "Free Download Manager"),
+    ("FX", "Freebox BitTorrent"),
+    ("KT", "KTorrent"),
+    ("LT", "libtorrent"),
+    ("lt", "libTorrent"),
+    ("LW", "LimeWire"),
+    ("PI", "PicoTorrent"),
+    ("qB", "qBittorrent"),
+    ("rT", "rustorrent"),
+    ("SD", "Thunder"),
+    ("TL", "Tribler"),
+    ("TR", "Transmission"),
+    ("UM", "µTorrent Mac"),
+    ("UT", "µTorrent"),
+    ("WW", "WebTorrent"),
+    ("XL", "Xunlei"),
+];
+
+/// Clients using Shadow-style IDs, a letter followed by an encoded version.
+const SHADOW_CLIENTS: &[(u8, &str)] = &[
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT BitTorrent"),
+];
+
+/// Works out which client sent `peer_id` from the conventions most clients
+/// follow. Returns `None` for IDs that match none of them.
+pub fn identify(peer_id: &[u8]) -> Option<PeerClient> {
+    azureus(peer_id)
+        .or_else(|| mainline(peer_id))
+        .or_else(|| shadow(peer_id))
+}
+
+/// `-TR4050-`: a two letter client code and four version characters.
+fn azureus(peer_id: &[u8]) -> Option<PeerClient> {
+    if peer_id.len() < 8 || peer_id[0] != b'-' || peer_id[7] != b'-' {
+        return None;
+    }
+    let code = std::str::from_utf8(&peer_id[1..3]).ok()?;
+    let version = std::str::from_utf8(&peer_id[3..7]).ok()?;
+    if !version.bytes().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+    let name = AZUREUS_CLIENTS
+        .iter()
+        .find(|(c, _)| *c == code)
+        .map_or_else(
+            || format!("Unknown ({})", code),
+            |(_, name)| name.to_string(),
+        );
+
+    let digits = version.as_bytes();
+    let version = match code {
+        // 2.94 is -TR2940-, 4.0.5 is -TR4050-
+        "TR" if digits[0] < b'3' => format!(
+            "{}.{}{}",
+            digits[0] as char, digits[1] as char, digits[2] as char
+        ),
+        _ => {
+            let mut parts = digits[..3]
+                .iter()
+                .map(|&c| version_number(c).to_string())
+                .collect::<Vec<_>>();
+            // the last character is a build number or a release tag
+            match digits[3] {
+                b'0' => {}
+                c if c.is_ascii_digit() => parts.push((c as char).to_string()),
+                c => parts.last_mut().unwrap().push(c as char),
+            }
+            parts.join(".")
+        }
+    };
+    Some(PeerClient { name, version })
+}
+
+/// `M4-3-6--`: a letter then dash-separated version numbers.
+fn mainline(peer_id: &[u8]) -> Option<PeerClient> {
+    let name = match peer_id.first()? {
+        b'M' => "Mainline",
+        b'Q' => "Queen Bee",
+        _ => return None,
+    };
+    let head = std::str::from_utf8(peer_id.get(1..8)?).ok()?;
+    let version = head.trim_end_matches('-');
+    let numbers = version.split('-').collect::<Vec<_>>();
+    if !(2..=3).contains(&numbers.len())
+        || !numbers
+            .iter()
+            .all(|n| !n.is_empty() && n.bytes().all(|c| c.is_ascii_digit()))
+    {
+        return None;
+    }
+    Some(PeerClient {
+        name: name.to_string(),
+        version: numbers.join("."),
+    })
+}
+
+/// `S58B-----`: a letter, up to five encoded version characters padded with
+/// dashes, then `---`.
+fn shadow(peer_id: &[u8]) -> Option<PeerClient> {
+    let (_, name) = SHADOW_CLIENTS
+        .iter()
+        .find(|(c, _)| peer_id.first() == Some(c))?;
+    if peer_id.get(6..9)? != b"---" {
+        return None;
+    }
+    let version = peer_id
+        .get(1..6)?
+        .iter()
+        .take_while(|&&c| c != b'-')
+        .map(|&c| shadow_number(c).map(|n| n.to_string()))
+        .collect::<Option<Vec<_>>>()?;
+    if version.is_empty() {
+        return None;
+    }
+    Some(PeerClient {
+        name: name.to_string(),
+        version: version.join("."),
+    })
+}
+
+fn version_number(c: u8) -> u32 {
+    match c {
+        b'0'..=b'9' => (c - b'0') as u32,
+        b'A'..=b'Z' => (c - b'A') as u32 + 10,
+        b'a'..=b'z' => (c - b'a') as u32 + 10,
+        _ => 0,
+    }
+}
+
+fn shadow_number(c: u8) -> Option<u32> {
+    match c {
+        b'0'..=b'9' => Some((c - b'0') as u32),
+        b'A'..=b'Z' => Some((c - b'A') as u32 + 10),
+        b'a'..=b'z' => Some((c - b'a') as u32 + 36),
+        b'.' => Some(62),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(peer_id: &[u8]) -> Option<String> {
+        identify(peer_id).map(|client| client.to_string())
+    }
+
+    #[test]
+    fn identifies_common_clients() {
+        assert_eq!(
+            client(b"-TR4050-abcdefghijkl").as_deref(),
+            Some("Transmission 4.0.5")
+        );
+        assert_eq!(
+            client(b"-TR2940-abcdefghijkl").as_deref(),
+            Some("Transmission 2.94")
+        );
+        assert_eq!(
+            client(b"-qB4250-abcdefghijkl").as_deref(),
+            Some("qBittorrent 4.2.5")
+        );
+        assert_eq!(
+            client(b"-UT355W-abcdefghijkl").as_deref(),
+            Some("µTorrent 3.5.5W")
+        );
+        assert_eq!(
+            client(b"-LT1234-abcdefghijkl").as_deref(),
+            Some("libtorrent 1.2.3.4")
+        );
+        assert_eq!(
+            client(b"-ZZ1000-abcdefghijkl").as_deref(),
+            Some("Unknown (ZZ) 1.0.0")
+        );
+        assert_eq!(
+            client(b"M4-3-6--abcdefghijkl").as_deref(),
+            Some("Mainline 4.3.6")
+        );
+        assert_eq!(
+            client(b"S58B-----abcdefghijk").as_deref(),
+            Some("Shadow 5.8.11")
+        );
+        assert_eq!(client(&[0xff; 20]), None);
+    }
+}
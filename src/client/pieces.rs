@@ -1,16 +1,50 @@
-use std::collections::HashSet;
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    ops::Range,
+    time::{Duration, Instant},
+};
 
-use crate::metainfo::Info;
+use bytes::Bytes;
+use futures::channel::mpsc;
+use tracing::{debug, warn};
 
-use super::{bitfield::Bitfield, file_manager::FileManager};
+use crate::{config::ClientConfig, metainfo::Info};
 
-pub const BLOCK_SIZE: u32 = 2 << 13; // 16KB
+/// How close to its deadline a piece has to be before its outstanding blocks
+/// are requested again from other peers.
+const DUPLICATE_REQUEST_WINDOW: Duration = Duration::from_secs(1);
+
+use super::{
+    bitfield::Bitfield,
+    budget::MemoryBudget,
+    disk::{DiskIo, DiskResult},
+    file_manager::{DiskError, FileManager},
+    hasher::{HashResult, PieceHasher},
+    selection::{Candidate, PieceSelectionStrategy, RandomFirst},
+    state::PieceSummary,
+    write_cache::WriteCache,
+};
+
+/// What became of block data a peer sent us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockOutcome {
+    /// Stored, with how many of its bytes we didn't already have.
+    Stored(u32),
+    /// Every block it covers was already downloaded.
+    Duplicate,
+    /// It doesn't line up with the blocks of the piece.
+    Invalid,
+}
 
 #[derive(Debug)]
 pub struct Block {
     begin: u32,
     length: u32,
     requested: bool,
+    /// Requested a second time, in endgame or because its piece is close to
+    /// its deadline, so two requests for it are outstanding.
+    duplicated: bool,
     completed: bool,
 }
 
@@ -19,19 +53,47 @@ pub struct Piece {
     index: usize,
     blocks: Vec<Block>,
     hash: Vec<u8>,
+    /// Set once the piece has passed its hash check.
     completed: bool,
+    /// Writes handed to the disk thread that haven't landed yet: one per
+    /// block, or one for the whole piece if it was assembled in memory.
+    pending_writes: usize,
     peers: HashSet<Vec<u8>>,
+    /// When the piece is wanted by, which puts it ahead of every other piece.
+    deadline: Option<Instant>,
 }
 
 #[derive(Debug)]
 pub struct PieceScheduler {
     pieces: Vec<Piece>,
     file_manager: FileManager,
-    any_complete: bool,
+    hasher: PieceHasher,
+    disk: DiskIo,
+    write_cache: WriteCache,
+    /// Number of pieces that have passed their hash check.
+    completed: usize,
+    block_size: u32,
+    /// For each peer, how many pieces it has that we don't. We are
+    /// interested in a peer exactly while this is non-zero.
+    wanted_from: HashMap<Vec<u8>, usize>,
+    selection: Box<dyn PieceSelectionStrategy>,
 }
 
 impl PieceScheduler {
-    pub fn new(info_dict: &Info, output_dir: String) -> Self {
+    /// Verification results for finished pieces are sent on `hash_results`
+    /// and must be handed back through [`PieceScheduler::finish_verification`].
+    /// Likewise, the outcome of every block write is sent on `disk_results`
+    /// and handed back through [`PieceScheduler::finish_write`]. Pieces
+    /// assembled in memory are counted against `budget`.
+    pub fn new(
+        info_dict: &Info,
+        output_dir: String,
+        config: &ClientConfig,
+        budget: MemoryBudget,
+        hash_results: mpsc::UnboundedSender<HashResult>,
+        disk_results: mpsc::UnboundedSender<DiskResult>,
+    ) -> Result<Self, DiskError> {
+        let block_size = config.block_size;
         let (piece_hashes, piece_length, total_size) = match info_dict {
             Info::SingleFile(info) => (
                 info.base_info.pieces.clone(),
@@ -45,27 +107,24 @@ impl PieceScheduler {
             ),
         };
 
-        assert!(
-            piece_length as u32 % BLOCK_SIZE == 0,
-            "piece length must be a multiple of the block size"
-        );
-
-        let mut remaining_size = total_size as u32;
+        // blocks are `block_size` long except the last of each piece, which
+        // may be short, as may the last piece
+        let mut remaining_size = total_size;
         let mut pieces = Vec::new();
         for (i, hash) in piece_hashes.iter().enumerate() {
+            let piece_size = piece_length.min(remaining_size);
+            remaining_size -= piece_size;
             let mut blocks = Vec::new();
-            let mut offset: u32 = 0;
-            while offset < piece_length as u32 && remaining_size > 0 {
-                let length = BLOCK_SIZE.min(remaining_size);
-                let block = Block {
-                    begin: offset,
-                    length,
+            let mut offset = 0;
+            while offset < piece_size {
+                let length = (block_size as u64).min(piece_size - offset);
+                blocks.push(Block {
+                    begin: offset as u32,
+                    length: length as u32,
                     requested: false,
+                    duplicated: false,
                     completed: false,
-                };
-                blocks.push(block);
-
-                remaining_size -= length;
+                });
                 offset += length;
             }
 
@@ -74,22 +133,181 @@ impl PieceScheduler {
                 blocks,
                 hash: hash.to_vec(),
                 completed: false,
+                pending_writes: 0,
                 peers: HashSet::new(),
+                deadline: None,
             };
             pieces.push(piece);
         }
 
-        Self {
+        let file_manager = FileManager::new(output_dir, info_dict, config)?;
+        let hasher = PieceHasher::new(config.hash_threads, file_manager.clone(), hash_results);
+        let disk = DiskIo::new(file_manager.clone(), config.read_cache_size, disk_results);
+        Ok(Self {
             pieces,
-            any_complete: false,
-            file_manager: FileManager::new(output_dir, info_dict),
-        }
+            completed: 0,
+            block_size,
+            wanted_from: HashMap::new(),
+            selection: Box::new(RandomFirst::default()),
+            file_manager,
+            hasher,
+            disk,
+            write_cache: WriteCache::new(config.write_cache_size, budget),
+        })
     }
 
     pub fn len(&self) -> usize {
         self.pieces.len()
     }
 
+    pub fn file_manager(&self) -> &FileManager {
+        &self.file_manager
+    }
+
+    pub fn summaries(&self) -> Vec<PieceSummary> {
+        self.pieces
+            .iter()
+            .map(|p| PieceSummary {
+                completed: p.completed,
+                availability: p.peers.len(),
+            })
+            .collect()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pieces.iter().all(|p| p.completed)
+    }
+
+    /// Marks the pieces in `completed` as done if they still pass their hash
+    /// check on disk, and the blocks of `unfinished` pieces as downloaded.
+    /// Those can't be checked until their piece is complete. Returns the
+    /// number of bytes recovered.
+    pub fn restore(&mut self, completed: &Bitfield, unfinished: &[(usize, Bitfield)]) -> u64 {
+        let candidates = self
+            .pieces
+            .iter()
+            .filter(|p| completed.is_set(p.index).unwrap_or(false))
+            .map(|p| (p.index, p.hash.clone()))
+            .collect::<Vec<_>>();
+        let results = self.hasher.verify_pieces(&candidates);
+
+        let mut restored = 0;
+        for ((index, _), valid) in candidates.into_iter().zip(results) {
+            if !valid {
+                warn!(piece = index, "saved piece failed verification");
+                continue;
+            }
+
+            let piece = &mut self.pieces[index];
+            for block in &mut piece.blocks {
+                block.requested = true;
+                block.completed = true;
+                restored += block.length as u64;
+            }
+            piece.completed = true;
+            self.completed += 1;
+        }
+        for (index, blocks) in unfinished {
+            let Some(piece) = self.pieces.get_mut(*index) else {
+                continue;
+            };
+            if piece.completed || piece.blocks.len() != blocks.len() {
+                continue;
+            }
+            for (block, on_disk) in piece.blocks.iter_mut().zip(blocks.iter()) {
+                if on_disk && !block.completed {
+                    block.requested = true;
+                    block.completed = true;
+                    restored += block.length as u64;
+                }
+            }
+            // saved while waiting on its hash check
+            if piece.blocks.iter().all(|b| b.completed) {
+                self.hasher.submit(piece.index, piece.hash.clone());
+            }
+        }
+        self.recount_wanted();
+        self.finish_files(0..self.pieces.len());
+        restored
+    }
+
+    /// Hashes every piece on disk again and rebuilds which ones we have from
+    /// the result, returning the number of bytes that checked out. Blocks
+    /// until all pieces are checked.
+    pub fn recheck(&mut self) -> u64 {
+        let hashes = self
+            .pieces
+            .iter()
+            .map(|p| p.hash.clone())
+            .collect::<Vec<_>>();
+        let results = self.hasher.verify_all(&hashes);
+
+        let mut verified = 0;
+        self.completed = 0;
+        self.write_cache.clear();
+        for (piece, valid) in self.pieces.iter_mut().zip(results) {
+            piece.completed = valid;
+            piece.deadline = None;
+            for block in &mut piece.blocks {
+                block.requested = valid;
+                block.duplicated = false;
+                block.completed = valid;
+                if valid {
+                    verified += block.length as u64;
+                }
+            }
+            self.completed += valid as usize;
+        }
+        self.recount_wanted();
+        self.finish_files(0..self.pieces.len());
+        verified
+    }
+
+    /// Bytes in pieces that have not been verified yet.
+    pub fn bytes_left(&self) -> u64 {
+        self.pieces
+            .iter()
+            .filter(|p| !p.completed)
+            .flat_map(|p| &p.blocks)
+            .map(|b| b.length as u64)
+            .sum()
+    }
+
+    pub fn file_progress(&self) -> Vec<u64> {
+        self.file_manager.file_progress(&self.to_bitfield())
+    }
+
+    pub fn has_piece(&self, index: usize) -> bool {
+        self.pieces.get(index).is_some_and(|p| p.completed)
+    }
+
+    /// Length of the piece at `index`; only the last piece can be short.
+    pub fn piece_length(&self, index: usize) -> Option<u32> {
+        let piece = self.pieces.get(index)?;
+        Some(piece.blocks.iter().map(|b| b.length).sum())
+    }
+
+    pub fn block_length(&self, index: usize, begin: u32) -> Option<u32> {
+        let bucket = self.block_bucket(index, begin)?;
+        Some(self.pieces[index].blocks[bucket].length)
+    }
+
+    /// Position of the block starting at `begin` within its piece, if a block
+    /// starts there.
+    fn block_bucket(&self, index: usize, begin: u32) -> Option<usize> {
+        let piece = self.pieces.get(index)?;
+        if !begin.is_multiple_of(self.block_size) {
+            return None;
+        }
+        let bucket = (begin / self.block_size) as usize;
+        (bucket < piece.blocks.len()).then_some(bucket)
+    }
+
+    /// Queues a read for `peer_id`, answered with a [`DiskResult::Read`].
+    pub fn read_block(&self, peer_id: &[u8], index: usize, begin: u32, length: u32) {
+        self.disk.read(peer_id.to_vec(), index, begin, length);
+    }
+
     pub fn to_bitfield(&self) -> Bitfield {
         let mut bitfield = Bitfield::new(self.len());
         for piece in &self.pieces {
@@ -98,109 +316,803 @@ impl PieceScheduler {
         bitfield
     }
 
-    fn get_rarest_noncompleted_piece(&self, peer_id: &Vec<u8>) -> Option<&Piece> {
+    fn set_requested(&mut self, index: usize, begin: u32) {
+        if let Some(bucket) = self.block_bucket(index, begin) {
+            self.pieces[index].blocks[bucket].requested = true;
+        }
+    }
+
+    /// Drops one request for a block, as the peer we asked won't send it.
+    /// The block is only put back up for download once no other peer has it
+    /// outstanding.
+    pub fn release_block(&mut self, index: usize, begin: u32) {
+        let Some(bucket) = self.block_bucket(index, begin) else {
+            return;
+        };
+        let block = &mut self.pieces[index].blocks[bucket];
+        if block.completed {
+            return;
+        }
+        if block.duplicated {
+            block.duplicated = false;
+        } else {
+            block.requested = false;
+        }
+    }
+
+    /// Stores data starting at block `begin` of piece `index`. It may cover
+    /// several whole blocks, as some peers answer with more than one at a
+    /// time; blocks we already have are skipped.
+    pub fn set_block(&mut self, index: usize, begin: u32, data: Bytes) -> BlockOutcome {
+        let Some(first) = self.block_bucket(index, begin) else {
+            return BlockOutcome::Invalid;
+        };
+        let piece = &mut self.pieces[index];
+
+        // the data has to end exactly where one of the blocks does
+        let mut covered = 0;
+        let mut end = 0;
+        for block in &piece.blocks[first..] {
+            if end >= data.len() {
+                break;
+            }
+            end += block.length as usize;
+            covered += 1;
+        }
+        if data.is_empty() || end != data.len() {
+            return BlockOutcome::Invalid;
+        }
+
+        debug!(piece = index, begin, len = data.len(), "block received");
+        // a piece is only assembled in memory if none of it went to disk
+        let fresh = piece.pending_writes == 0 && piece.blocks.iter().all(|b| !b.completed);
+        let piece_length = piece.blocks.iter().map(|b| b.length as usize).sum();
+        let cached = self.write_cache.contains(index)
+            || (fresh && self.write_cache.admit(index, piece_length));
+
+        let mut stored = 0;
+        let mut offset = 0;
+        for block in &mut piece.blocks[first..first + covered] {
+            let length = block.length as usize;
+            if !block.completed {
+                block.completed = true;
+                let block_data = data.slice(offset..offset + length);
+                if cached {
+                    self.write_cache
+                        .insert(index, block.begin as usize, &block_data);
+                } else {
+                    piece.pending_writes += 1;
+                    self.disk.write(index, block.begin, block_data);
+                }
+                stored += block.length;
+            }
+            offset += length;
+        }
+        if stored == 0 {
+            return BlockOutcome::Duplicate;
+        }
+
+        if cached && piece.blocks.iter().all(|b| b.completed) {
+            if let Some(data) = self.write_cache.assemble(index) {
+                debug!(piece = index, "piece assembled, verifying");
+                self.hasher.submit_data(index, piece.hash.clone(), data);
+            }
+        }
+        BlockOutcome::Stored(stored)
+    }
+
+    /// Applies the outcome of a write. A block that failed to write is
+    /// scheduled again; once every block of a piece is on disk the piece is
+    /// sent for verification. Returns true if the write completed a piece
+    /// that was verified in memory.
+    pub fn finish_write(
+        &mut self,
+        index: usize,
+        begin: u32,
+        result: Result<(), DiskError>,
+    ) -> bool {
+        let Some(piece) = self.pieces.get_mut(index) else {
+            return false;
+        };
+        piece.pending_writes = piece.pending_writes.saturating_sub(1);
+
+        if self.write_cache.contains(index) {
+            self.write_cache.remove(index);
+            if let Err(e) = result {
+                warn!(piece = index, error = %e, "failed to write piece");
+                for block in &mut piece.blocks {
+                    block.requested = false;
+                    block.duplicated = false;
+                    block.completed = false;
+                }
+                return false;
+            }
+            debug!(piece = index, "piece completed");
+            piece.completed = true;
+            piece.deadline = None;
+            self.completed += 1;
+            self.piece_completed(index);
+            return true;
+        }
+
+        if let Err(e) = result {
+            warn!(piece = index, begin, error = %e, "failed to write block");
+            let block_bucket = (begin / self.block_size) as usize;
+            if let Some(block) = piece.blocks.get_mut(block_bucket) {
+                block.requested = false;
+                block.duplicated = false;
+                block.completed = false;
+            }
+            return false;
+        }
+
+        if !piece.completed && piece.pending_writes == 0 && piece.blocks.iter().all(|b| b.completed)
+        {
+            debug!(piece = piece.index, "piece downloaded, verifying");
+            self.hasher.submit(index, piece.hash.clone());
+        }
+        false
+    }
+
+    /// Pieces with some but not all blocks on disk, and which blocks those
+    /// are, for the resume file.
+    pub fn unfinished_pieces(&self) -> Vec<(usize, Bitfield)> {
         self.pieces
             .iter()
             .filter(|p| {
-                !p.completed
-                    && p.blocks.iter().any(|b| !b.requested && !b.completed)
-                    && p.peers.contains(peer_id)
+                !p.completed && p.pending_writes == 0 && !self.write_cache.contains(p.index)
             })
-            .min_by_key(|p| p.peers.len())
+            .filter(|p| p.blocks.iter().any(|b| b.completed))
+            .map(|p| {
+                let mut blocks = Bitfield::new(p.blocks.len());
+                for (i, block) in p.blocks.iter().enumerate() {
+                    let _ = blocks.set(i, block.completed);
+                }
+                (p.index, blocks)
+            })
+            .collect()
     }
 
-    fn set_requested(&mut self, index: usize, begin: u32) {
-        let piece = &mut self.pieces[index];
+    /// Sends the blocks of pieces still assembling in memory to disk, so they
+    /// outlive a shutdown. The pieces carry on block by block afterwards.
+    pub fn spill_write_cache(&mut self) {
+        for piece in &mut self.pieces {
+            if piece.completed {
+                continue;
+            }
+            let Some(data) = self.write_cache.take_assembling(piece.index) else {
+                continue;
+            };
+            for block in piece.blocks.iter().filter(|b| b.completed) {
+                let range = block.begin as usize..(block.begin + block.length) as usize;
+                piece.pending_writes += 1;
+                self.disk.write(piece.index, block.begin, data.slice(range));
+            }
+        }
+    }
 
-        let block_bucket: usize = begin.div_ceil(BLOCK_SIZE).try_into().unwrap();
-        let block = &mut piece.blocks[block_bucket];
-        block.requested = true;
+    /// Waits for every queued disk write to land and be synced.
+    pub async fn flush(&self) -> io::Result<()> {
+        self.disk.flush().await
     }
 
-    pub fn set_block(&mut self, index: usize, begin: u32, data: Vec<u8>) {
-        let piece = &mut self.pieces[index];
+    pub fn disk_queue_depth(&self) -> usize {
+        self.disk.queue_depth()
+    }
 
-        let block_bucket: usize = begin.div_ceil(BLOCK_SIZE).try_into().unwrap();
-        let block = &mut piece.blocks[block_bucket];
-        self.file_manager.save_block(index, begin, data);
-        block.completed = true;
-        if piece.blocks.iter().all(|b| b.completed) {
-            println!("Piece {} completed", piece.index);
-            piece.completed = true;
-            self.any_complete = true;
+    /// Upload reads answered from the read cache, and reads that went to disk.
+    pub fn read_cache_stats(&self) -> (u64, u64) {
+        self.disk.cache_stats()
+    }
 
-            // if !self.file_manager.verify_piece(index, &piece.hash) {
-            //     println!("Piece {} failed verification", piece.index);
-            //     for block in &mut piece.blocks {
-            //         block.completed = false;
-            //     }
-            //     piece.completed = false;
-            // }
+    /// Applies a hash check. A piece that failed has all of its blocks reset
+    /// so they get scheduled again. Returns whether the piece is now complete,
+    /// or `None` if the piece was no longer waiting on a check. A piece that
+    /// passed in memory is only complete once it is written, which
+    /// [`PieceScheduler::finish_write`] reports.
+    pub fn finish_verification(&mut self, result: HashResult) -> Option<bool> {
+        let piece = self.pieces.get_mut(result.index)?;
+        if piece.completed || piece.pending_writes > 0 || !piece.blocks.iter().all(|b| b.completed)
+        {
+            return None;
         }
+
+        if let Some(data) = self.write_cache.assembled(result.index) {
+            if result.valid {
+                piece.pending_writes += 1;
+                self.disk.write(result.index, 0, data);
+                return None;
+            }
+            self.write_cache.remove(result.index);
+        }
+
+        if result.valid {
+            debug!(piece = piece.index, "piece completed");
+            piece.completed = true;
+            piece.deadline = None;
+            self.completed += 1;
+            self.piece_completed(result.index);
+        } else {
+            warn!(piece = piece.index, "piece failed verification");
+            for block in &mut piece.blocks {
+                block.requested = false;
+                block.duplicated = false;
+                block.completed = false;
+            }
+        }
+        Some(result.valid)
     }
 
-    pub fn add_peer_count(&mut self, peer_id: &Vec<u8>, bitfield: &Bitfield) {
+    /// Checks the pieces still queued on the hasher right away, blocking until
+    /// all are done.
+    pub fn verify_pending(&self) -> Vec<HashResult> {
+        let (in_memory, on_disk): (Vec<_>, Vec<_>) = self
+            .pieces
+            .iter()
+            .filter(|p| {
+                !p.completed && p.pending_writes == 0 && p.blocks.iter().all(|b| b.completed)
+            })
+            .map(|p| (p.index, p.hash.clone()))
+            .partition(|(index, _)| self.write_cache.assembled(*index).is_some());
+
+        let assembled = in_memory
+            .iter()
+            .filter_map(|(index, hash)| Some((hash.clone(), self.write_cache.assembled(*index)?)))
+            .collect::<Vec<_>>();
+        let results = self
+            .hasher
+            .verify_data(&assembled)
+            .into_iter()
+            .chain(self.hasher.verify_pieces(&on_disk));
+        in_memory
+            .into_iter()
+            .chain(on_disk)
+            .zip(results)
+            .map(|((index, _), valid)| HashResult { index, valid })
+            .collect()
+    }
+
+    pub fn add_peer_count(&mut self, peer_id: &[u8], bitfield: &Bitfield) {
         for (i, bit) in bitfield.iter().enumerate() {
-            if *bit {
-                self.pieces[i].peers.insert(peer_id.clone());
+            if bit {
+                self.add_peer_have(peer_id, i);
             }
         }
     }
 
-    pub fn add_peer_have(&mut self, peer_id: &Vec<u8>, i: usize) {
-        self.pieces[i].peers.insert(peer_id.clone());
+    pub fn add_peer_have(&mut self, peer_id: &[u8], i: usize) {
+        let Some(piece) = self.pieces.get_mut(i) else {
+            return;
+        };
+        if piece.peers.insert(peer_id.to_vec()) && !piece.completed {
+            *self.wanted_from.entry(peer_id.to_vec()).or_default() += 1;
+        }
     }
 
-    pub fn remove_peer_count(&mut self, peer_id: &Vec<u8>) {
+    pub fn remove_peer_count(&mut self, peer_id: &[u8]) {
         for piece in &mut self.pieces {
             piece.peers.remove(peer_id);
         }
+        self.wanted_from.remove(peer_id);
     }
 
-    pub fn schedule_piece(&mut self, peer_id: &Vec<u8>) -> Option<(u32, u32, u32)> {
-        let piece = if !self.any_complete {
-            let pieces = self
-                .pieces
-                .iter()
-                .filter(|p| {
-                    !p.completed
-                        && p.blocks.iter().any(|b| !b.requested)
-                        && p.peers.contains(peer_id)
-                })
-                .collect::<Vec<&Piece>>();
-
-            if pieces.is_empty() {
-                None
-            } else {
-                Some(pieces[rand::random::<usize>() % pieces.len()])
+    /// Whether the peer has a piece we haven't completed, which is when we
+    /// should be interested in it.
+    pub fn wants_from(&self, peer_id: &[u8]) -> bool {
+        self.wanted_from
+            .get(peer_id)
+            .is_some_and(|&count| count > 0)
+    }
+
+    /// Counts again which pieces each peer has that we lack, after pieces
+    /// were marked complete or incomplete in bulk.
+    fn recount_wanted(&mut self) {
+        self.wanted_from.clear();
+        for piece in self.pieces.iter().filter(|p| !p.completed) {
+            for peer_id in &piece.peers {
+                *self.wanted_from.entry(peer_id.clone()).or_default() += 1;
+            }
+        }
+    }
+
+    fn piece_completed(&mut self, index: usize) {
+        for peer_id in &self.pieces[index].peers {
+            if let Some(count) = self.wanted_from.get_mut(peer_id) {
+                *count = count.saturating_sub(1);
             }
+        }
+        self.finish_files(index..index + 1);
+    }
+
+    /// Renames the part files `pieces` completed, if any.
+    fn finish_files(&self, pieces: Range<usize>) {
+        let result = self
+            .file_manager
+            .finish_files(pieces, |index| self.pieces[index].completed);
+        if let Err(e) = result {
+            warn!(error = %e, "failed to rename finished file");
+        }
+    }
+
+    /// Puts the piece ahead of rarest-first ordering until it completes.
+    /// Returns false if there is no such piece or it is already done.
+    pub fn set_deadline(&mut self, index: usize, deadline: Instant) -> bool {
+        match self.pieces.get_mut(index) {
+            Some(piece) if !piece.completed => {
+                piece.deadline = Some(deadline);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the peer has any piece that has a deadline.
+    pub fn has_deadline_piece(&self, peer_id: &[u8]) -> bool {
+        self.pieces
+            .iter()
+            .any(|p| p.deadline.is_some() && !p.completed && p.peers.contains(peer_id))
+    }
+
+    /// The next block of the most urgent piece the peer has. Blocks that are
+    /// already requested are handed out once more when their piece is within
+    /// [`DUPLICATE_REQUEST_WINDOW`] of its deadline, in case the first peer
+    /// is slow, unless they are among the peer's own `outstanding` requests.
+    fn schedule_deadline_block(
+        &mut self,
+        peer_id: &[u8],
+        outstanding: &HashSet<(u32, u32)>,
+    ) -> Option<(u32, u32, u32)> {
+        let now = Instant::now();
+        let mut urgent = self
+            .pieces
+            .iter_mut()
+            .filter(|p| p.deadline.is_some() && !p.completed && p.peers.contains(peer_id))
+            .collect::<Vec<_>>();
+        urgent.sort_by_key(|p| p.deadline);
+
+        for piece in urgent {
+            let near = piece
+                .deadline
+                .is_some_and(|deadline| deadline <= now + DUPLICATE_REQUEST_WINDOW);
+            let index = piece.index as u32;
+            if let Some(block) = piece
+                .blocks
+                .iter_mut()
+                .find(|b| !b.requested && !b.completed)
+            {
+                block.requested = true;
+                return Some((index, block.begin, block.length));
+            }
+            if near {
+                if let Some(block) = piece.blocks.iter_mut().find(|b| {
+                    !b.completed && !b.duplicated && !outstanding.contains(&(index, b.begin))
+                }) {
+                    block.duplicated = true;
+                    return Some((index, block.begin, block.length));
+                }
+            }
+        }
+        None
+    }
+
+    /// Changes how pieces are picked from now on.
+    pub fn set_selection(&mut self, selection: Box<dyn PieceSelectionStrategy>) {
+        self.selection = selection;
+    }
+
+    /// The next block to request from the peer, given the blocks it already
+    /// has outstanding, by piece index and offset.
+    pub fn schedule_piece(
+        &mut self,
+        peer_id: &[u8],
+        outstanding: &HashSet<(u32, u32)>,
+    ) -> Option<(u32, u32, u32)> {
+        if let Some(request) = self.schedule_deadline_block(peer_id, outstanding) {
+            return Some(request);
+        }
+
+        let candidates = self
+            .pieces
+            .iter()
+            .filter(|p| {
+                !p.completed
+                    && p.blocks.iter().any(|b| !b.requested && !b.completed)
+                    && p.peers.contains(peer_id)
+            })
+            .map(|p| Candidate {
+                index: p.index,
+                availability: p.peers.len(),
+                started: p.blocks.iter().any(|b| b.requested || b.completed),
+            })
+            .collect::<Vec<_>>();
+        let piece = if candidates.is_empty() {
+            None
         } else {
-            self.get_rarest_noncompleted_piece(peer_id)
+            // a strategy can't make us ask for a piece the peer can't give
+            self.selection
+                .select(&candidates, self.completed)
+                .filter(|&index| candidates.iter().any(|c| c.index == index))
+                .map(|index| &self.pieces[index])
         };
 
-        let request = piece.map(|piece| {
-            let block = piece
+        let request = piece.and_then(|piece| {
+            piece
                 .blocks
                 .iter()
                 .find(|b| !b.requested && !b.completed)
-                .unwrap();
-            (piece.index as u32, block.begin, block.length)
+                .map(|block| (piece.index as u32, block.begin, block.length))
         });
 
         if let Some((piece_index, block_begin, _)) = request {
             self.set_requested(piece_index as usize, block_begin);
+            return request;
+        }
+        self.schedule_endgame_block(peer_id, outstanding)
+    }
+
+    /// Once every missing block has been asked for, asks the peer too for one
+    /// another peer has outstanding, so a slow peer can't hold up the end of
+    /// the download. Each block is asked for twice at most, and the request
+    /// that loses the race is cancelled.
+    fn schedule_endgame_block(
+        &mut self,
+        peer_id: &[u8],
+        outstanding: &HashSet<(u32, u32)>,
+    ) -> Option<(u32, u32, u32)> {
+        let endgame = self
+            .pieces
+            .iter()
+            .filter(|p| !p.completed)
+            .flat_map(|p| &p.blocks)
+            .all(|b| b.requested || b.completed);
+        if !endgame {
+            return None;
         }
 
-        request
+        self.pieces
+            .iter_mut()
+            .filter(|p| !p.completed && p.peers.contains(peer_id))
+            .find_map(|piece| {
+                let index = piece.index as u32;
+                let block = piece.blocks.iter_mut().find(|b| {
+                    !b.completed && !b.duplicated && !outstanding.contains(&(index, b.begin))
+                })?;
+                block.duplicated = true;
+                Some((index, block.begin, block.length))
+            })
     }
 
-    pub fn is_interested(&self, bitfield: &Bitfield) -> bool {
-        for (i, bit) in bitfield.iter().enumerate() {
-            // if the peer has a piece that isn't completed
-            if !self.pieces[i].completed && *bit {
-                return true;
+    /// Claims the first piece no connected peer has and nobody has started,
+    /// for a web seed to fetch whole. Returns its index and length.
+    pub fn claim_unavailable_piece(&mut self) -> Option<(usize, u32)> {
+        let piece = self.pieces.iter_mut().find(|p| {
+            !p.completed
+                && p.peers.is_empty()
+                && p.blocks.iter().all(|b| !b.requested && !b.completed)
+        })?;
+        for block in &mut piece.blocks {
+            block.requested = true;
+        }
+        let length = piece.blocks.iter().map(|b| b.length).sum();
+        Some((piece.index, length))
+    }
+
+    /// Puts a claimed piece's unfinished blocks back up for download.
+    pub fn release_piece(&mut self, index: usize) {
+        if let Some(piece) = self.pieces.get_mut(index) {
+            for block in piece.blocks.iter_mut().filter(|b| !b.completed) {
+                block.requested = false;
+                block.duplicated = false;
             }
         }
-        false
+    }
+
+    /// Stores a whole piece, as fetched from a web seed.
+    pub fn set_piece(&mut self, index: usize, data: Bytes) -> BlockOutcome {
+        if self.piece_length(index) != Some(data.len() as u32) {
+            warn!(
+                piece = index,
+                len = data.len(),
+                "web seed returned the wrong length"
+            );
+            self.release_piece(index);
+            return BlockOutcome::Invalid;
+        }
+        self.set_block(index, 0, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::{
+        bencode::{BencodeString, BencodeValue},
+        config::DEFAULT_MEMORY_BUDGET,
+        metainfo::Metainfo,
+    };
+
+    const PIECE_LENGTH: usize = 32 * 1024;
+
+    fn scheduler(dir: &str, num_pieces: usize) -> PieceScheduler {
+        scheduler_with(dir, PIECE_LENGTH, num_pieces * PIECE_LENGTH)
+    }
+
+    fn scheduler_with(dir: &str, piece_length: usize, length: usize) -> PieceScheduler {
+        let num_pieces = length.div_ceil(piece_length);
+        let info = BencodeValue::Dict(BTreeMap::from([
+            (
+                "name".to_string(),
+                BencodeValue::String(BencodeString::String("data.bin".to_string())),
+            ),
+            ("length".to_string(), BencodeValue::Int(length as i64)),
+            (
+                "piece length".to_string(),
+                BencodeValue::Int(piece_length as i64),
+            ),
+            (
+                "pieces".to_string(),
+                BencodeValue::String(BencodeString::Bytes(vec![0; 20 * num_pieces])),
+            ),
+        ]));
+        let torrent = BencodeValue::Dict(BTreeMap::from([
+            (
+                "announce".to_string(),
+                BencodeValue::String(BencodeString::String("http://localhost/".to_string())),
+            ),
+            ("info".to_string(), info),
+        ]));
+        let metainfo = Metainfo::new(torrent).unwrap();
+        let (hash_tx, _) = mpsc::unbounded();
+        let (disk_tx, _) = mpsc::unbounded();
+        PieceScheduler::new(
+            &metainfo.info,
+            dir.to_string(),
+            &ClientConfig::default(),
+            MemoryBudget::new(DEFAULT_MEMORY_BUDGET),
+            hash_tx,
+            disk_tx,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn schedules_deadline_pieces_first_and_duplicates_near_deadline() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut scheduler = scheduler(dir.path().to_str().unwrap(), 4);
+        let peer = b"peer".to_vec();
+        let mut bitfield = Bitfield::new(4);
+        for i in 0..4 {
+            bitfield.set(i, true).unwrap();
+        }
+        scheduler.add_peer_count(&peer, &bitfield);
+
+        assert!(scheduler.set_deadline(3, Instant::now()));
+        assert!(!scheduler.set_deadline(4, Instant::now()));
+
+        // both blocks of the urgent piece, then both again as duplicates
+        let block = scheduler.block_size;
+        let requests = (0..4)
+            .map(|_| scheduler.schedule_piece(&peer, &HashSet::new()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            requests,
+            vec![
+                (3, 0, block),
+                (3, block, block),
+                (3, 0, block),
+                (3, block, block)
+            ]
+        );
+
+        let next = scheduler.schedule_piece(&peer, &HashSet::new()).unwrap();
+        assert_ne!(next.0, 3);
+    }
+
+    #[test]
+    fn duplicates_deadline_blocks_only_to_other_peers() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut scheduler = scheduler(dir.path().to_str().unwrap(), 2);
+        let mut bitfield = Bitfield::new(2);
+        bitfield.set(1, true).unwrap();
+        let (first, second) = (b"first".to_vec(), b"second".to_vec());
+        scheduler.add_peer_count(&first, &bitfield);
+        scheduler.add_peer_count(&second, &bitfield);
+        assert!(scheduler.set_deadline(1, Instant::now()));
+
+        let mut outstanding = HashSet::new();
+        while let Some((index, begin, _)) = scheduler.schedule_piece(&first, &outstanding) {
+            assert!(outstanding.insert((index, begin)));
+        }
+        assert_eq!(outstanding.len(), 2);
+
+        let block = scheduler.block_size;
+        let duplicate = scheduler.schedule_piece(&second, &HashSet::new());
+        assert_eq!(duplicate, Some((1, 0, block)));
+    }
+
+    #[test]
+    fn asks_other_peers_for_outstanding_blocks_in_endgame() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut scheduler = scheduler(dir.path().to_str().unwrap(), 2);
+        let mut bitfield = Bitfield::new(2);
+        bitfield.set(0, true).unwrap();
+        bitfield.set(1, true).unwrap();
+        let (first, second, third) = (b"first".to_vec(), b"second".to_vec(), b"third".to_vec());
+        for peer in [&first, &second, &third] {
+            scheduler.add_peer_count(peer, &bitfield);
+        }
+
+        let mut outstanding = HashSet::new();
+        while let Some((index, begin, _)) = scheduler.schedule_piece(&first, &outstanding) {
+            assert!(outstanding.insert((index, begin)));
+        }
+        assert_eq!(outstanding.len(), 4);
+
+        // every block once more, from someone else
+        let mut duplicates = HashSet::new();
+        while let Some((index, begin, _)) = scheduler.schedule_piece(&second, &duplicates) {
+            assert!(duplicates.insert((index, begin)));
+        }
+        assert_eq!(duplicates, outstanding);
+        assert_eq!(scheduler.schedule_piece(&third, &HashSet::new()), None);
+    }
+
+    #[test]
+    fn keeps_duplicated_blocks_requested_until_both_are_released() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut scheduler = scheduler(dir.path().to_str().unwrap(), 1);
+        let (first, second) = (b"first".to_vec(), b"second".to_vec());
+        scheduler.add_peer_have(&first, 0);
+        scheduler.add_peer_have(&second, 0);
+
+        let mut outstanding = HashSet::new();
+        while let Some((index, begin, _)) = scheduler.schedule_piece(&first, &outstanding) {
+            outstanding.insert((index, begin));
+        }
+        let (index, begin, _) = scheduler.schedule_piece(&second, &HashSet::new()).unwrap();
+        let bucket = scheduler.block_bucket(index as usize, begin).unwrap();
+
+        // the second peer chokes, but the first still has it outstanding
+        scheduler.release_block(index as usize, begin);
+        let block = &scheduler.pieces[index as usize].blocks[bucket];
+        assert!(block.requested && !block.duplicated);
+
+        scheduler.release_block(index as usize, begin);
+        assert!(!scheduler.pieces[index as usize].blocks[bucket].requested);
+    }
+
+    #[test]
+    fn web_seeds_claim_pieces_no_peer_has() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut scheduler = scheduler(dir.path().to_str().unwrap(), 3);
+        let peer = b"peer".to_vec();
+        scheduler.add_peer_have(&peer, 0);
+
+        let length = scheduler.piece_length(1).unwrap();
+        assert_eq!(scheduler.claim_unavailable_piece(), Some((1, length)));
+        assert_eq!(scheduler.claim_unavailable_piece(), Some((2, length)));
+        assert_eq!(scheduler.claim_unavailable_piece(), None);
+
+        scheduler.release_piece(1);
+        assert_eq!(scheduler.claim_unavailable_piece(), Some((1, length)));
+    }
+
+    #[test]
+    fn checks_pieces_in_memory_before_writing_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut scheduler = scheduler(dir.path().to_str().unwrap(), 2);
+        let block = ClientConfig::default().block_size;
+
+        for begin in [0, block] {
+            let data = Bytes::from(vec![1; block as usize]);
+            assert_eq!(
+                scheduler.set_block(0, begin, data),
+                BlockOutcome::Stored(block)
+            );
+        }
+        futures::executor::block_on(scheduler.flush()).unwrap();
+        assert_eq!(scheduler.disk_queue_depth(), 0);
+
+        // the test hashes are all zeros, so the piece fails and is never
+        // written
+        let results = scheduler.verify_pending();
+        assert_eq!(
+            results,
+            vec![HashResult {
+                index: 0,
+                valid: false
+            }]
+        );
+        let result = results.into_iter().next().unwrap();
+        assert_eq!(scheduler.finish_verification(result), Some(false));
+        let on_disk = std::fs::read(dir.path().join("data.bin")).unwrap_or_default();
+        assert!(on_disk.iter().all(|&b| b == 0));
+        assert_eq!(
+            scheduler.set_block(0, 0, Bytes::from(vec![1; block as usize])),
+            BlockOutcome::Stored(block)
+        );
+    }
+
+    #[test]
+    fn set_block_checks_alignment_and_skips_duplicates() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut scheduler = scheduler(dir.path().to_str().unwrap(), 2);
+        let block = ClientConfig::default().block_size;
+        let data = |len: u32| Bytes::from(vec![0; len as usize]);
+
+        // not on a block boundary, past the piece, or not a whole block
+        assert_eq!(
+            scheduler.set_block(0, 1, data(block)),
+            BlockOutcome::Invalid
+        );
+        assert_eq!(
+            scheduler.set_block(0, 2 * block, data(block)),
+            BlockOutcome::Invalid
+        );
+        assert_eq!(
+            scheduler.set_block(0, 0, data(block - 1)),
+            BlockOutcome::Invalid
+        );
+        assert_eq!(
+            scheduler.set_block(0, 0, data(block + 1)),
+            BlockOutcome::Invalid
+        );
+
+        assert_eq!(
+            scheduler.set_block(0, block, data(block)),
+            BlockOutcome::Stored(block)
+        );
+        assert_eq!(
+            scheduler.set_block(0, block, data(block)),
+            BlockOutcome::Duplicate
+        );
+        // a reply spanning both blocks only adds the one we were missing
+        assert_eq!(
+            scheduler.set_block(0, 0, data(2 * block)),
+            BlockOutcome::Stored(block)
+        );
+        assert_eq!(scheduler.set_piece(1, data(block)), BlockOutcome::Invalid);
+    }
+
+    #[test]
+    fn splits_odd_piece_lengths_into_short_final_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let scheduler = scheduler_with(dir.path().to_str().unwrap(), 40_000, 100_000);
+        let block = ClientConfig::default().block_size;
+
+        assert_eq!(scheduler.len(), 3);
+        assert_eq!(scheduler.piece_length(0), Some(40_000));
+        assert_eq!(scheduler.piece_length(2), Some(20_000));
+        assert_eq!(scheduler.block_length(0, 0), Some(block));
+        assert_eq!(
+            scheduler.block_length(0, 2 * block),
+            Some(40_000 - 2 * block)
+        );
+        assert_eq!(scheduler.block_length(2, block), Some(20_000 - block));
+        assert_eq!(scheduler.block_length(2, 2 * block), None);
+        assert_eq!(scheduler.bytes_left(), 100_000);
+    }
+
+    #[test]
+    fn tracks_which_peers_have_pieces_we_lack() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut scheduler = scheduler(dir.path().to_str().unwrap(), 4);
+        let peer = b"peer".to_vec();
+        assert!(!scheduler.wants_from(&peer));
+
+        scheduler.add_peer_have(&peer, 2);
+        scheduler.add_peer_have(&peer, 2);
+        scheduler.add_peer_have(&peer, 9);
+        assert!(scheduler.wants_from(&peer));
+
+        scheduler.pieces[2].completed = true;
+        scheduler.piece_completed(2);
+        assert!(!scheduler.wants_from(&peer));
+
+        scheduler.add_peer_have(&peer, 1);
+        scheduler.remove_peer_count(&peer);
+        assert!(!scheduler.wants_from(&peer));
     }
 }
@@ -0,0 +1,173 @@
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
+
+use crate::config::ClientConfig;
+
+/// Sizes a peer's request pipeline to its bandwidth-delay product: enough
+/// blocks to cover the peer's download rate for a round trip plus the
+/// configured queue time. A pipeline that is too short caps the rate, so
+/// measuring the rate again and again lets the depth grow until the peer
+/// can't go any faster. It also keeps the times a UI or the choker needs to
+/// tell whether the peer is still sending.
+#[derive(Debug, Clone, Copy)]
+pub struct Pipeline {
+    min_depth: usize,
+    max_depth: usize,
+    block_size: u32,
+    queue_time: Duration,
+    /// The one request being timed, and when it was sent.
+    probe: Option<((u32, u32), Instant)>,
+    /// Smoothed time from request to block.
+    rtt: Option<Duration>,
+    /// When the last block we asked for arrived.
+    last_block: Option<Instant>,
+    /// When we last had reason to expect a block: the latest block, or the
+    /// first request after none were outstanding.
+    waiting_since: Option<Instant>,
+}
+
+impl Pipeline {
+    pub fn new(config: &ClientConfig) -> Self {
+        Self {
+            min_depth: config.pipeline_depth,
+            max_depth: config.max_pipeline_depth.max(config.pipeline_depth),
+            block_size: config.block_size,
+            queue_time: config.request_queue_time,
+            probe: None,
+            rtt: None,
+            last_block: None,
+            waiting_since: None,
+        }
+    }
+
+    /// Notes a request for the block at `(index, begin)`. It is timed unless
+    /// another request still in `outstanding` already is.
+    pub fn sent(&mut self, block: (u32, u32), now: Instant, outstanding: &HashSet<(u32, u32)>) {
+        if outstanding.len() <= 1 {
+            self.waiting_since = Some(now);
+        }
+        let timing = self
+            .probe
+            .is_some_and(|(probe, _)| probe != block && outstanding.contains(&probe));
+        if !timing {
+            self.probe = Some((block, now));
+        }
+    }
+
+    /// Notes that the block at `(index, begin)` arrived.
+    pub fn received(&mut self, block: (u32, u32), now: Instant) {
+        self.last_block = Some(now);
+        self.waiting_since = Some(now);
+        let Some((probe, sent_at)) = self.probe else {
+            return;
+        };
+        if probe != block {
+            return;
+        }
+        let sample = now.saturating_duration_since(sent_at);
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => (rtt * 7 + sample) / 8,
+            None => sample,
+        });
+        self.probe = None;
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    pub fn last_block(&self) -> Option<Instant> {
+        self.last_block
+    }
+
+    /// Bytes a peer sending `rate` bytes a second has on the way at once.
+    pub fn bandwidth_delay(&self, rate: f64) -> u64 {
+        (rate * self.rtt.unwrap_or_default().as_secs_f64()) as u64
+    }
+
+    /// Whether `outstanding` requests have gone unanswered for longer than
+    /// `timeout`.
+    pub fn is_stalled(&self, now: Instant, outstanding: usize, timeout: Duration) -> bool {
+        outstanding > 0
+            && self
+                .waiting_since
+                .is_some_and(|since| now.saturating_duration_since(since) > timeout)
+    }
+
+    /// Requests to keep in flight to a peer sending `rate` bytes a second.
+    pub fn depth(&self, rate: f64) -> usize {
+        let window = self.queue_time + self.rtt.unwrap_or_default();
+        let blocks = (rate * window.as_secs_f64() / self.block_size as f64).ceil();
+        (blocks as usize).clamp(self.min_depth, self.max_depth)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pipeline() -> Pipeline {
+        let config = ClientConfig::builder()
+            .block_size(16 * 1024)
+            .pipeline_depth(5)
+            .max_pipeline_depth(100)
+            .request_queue_time(Duration::from_secs(1))
+            .build();
+        Pipeline::new(&config)
+    }
+
+    #[test]
+    fn grows_with_rate_and_latency_within_bounds() {
+        let mut pipeline = pipeline();
+        assert_eq!(pipeline.depth(0.0), 5);
+        // 20 blocks a second for a second
+        assert_eq!(pipeline.depth(20.0 * 16384.0), 20);
+        assert_eq!(pipeline.depth(1e9), 100);
+
+        let now = Instant::now();
+        let outstanding = HashSet::from([(0, 0)]);
+        pipeline.sent((0, 0), now, &outstanding);
+        pipeline.received((0, 0), now + Duration::from_secs(1));
+        assert_eq!(pipeline.rtt, Some(Duration::from_secs(1)));
+        assert_eq!(pipeline.depth(20.0 * 16384.0), 40);
+    }
+
+    #[test]
+    fn stalls_when_requests_go_unanswered() {
+        let mut pipeline = pipeline();
+        let now = Instant::now();
+        let timeout = Duration::from_secs(60);
+        pipeline.sent((0, 0), now, &HashSet::from([(0, 0)]));
+        assert!(!pipeline.is_stalled(now + Duration::from_secs(30), 1, timeout));
+        assert!(pipeline.is_stalled(now + Duration::from_secs(61), 1, timeout));
+        assert!(!pipeline.is_stalled(now + Duration::from_secs(61), 0, timeout));
+
+        // each block restarts the wait
+        pipeline.received((0, 0), now + Duration::from_secs(50));
+        assert_eq!(pipeline.last_block(), Some(now + Duration::from_secs(50)));
+        assert!(!pipeline.is_stalled(now + Duration::from_secs(100), 1, timeout));
+        assert_eq!(pipeline.bandwidth_delay(1000.0), 50_000);
+    }
+
+    #[test]
+    fn times_one_request_at_a_time() {
+        let mut pipeline = pipeline();
+        let now = Instant::now();
+        let mut outstanding = HashSet::from([(0, 0), (0, 16384)]);
+        pipeline.sent((0, 0), now, &outstanding);
+        pipeline.sent((0, 16384), now + Duration::from_secs(1), &outstanding);
+        pipeline.received((0, 16384), now + Duration::from_secs(2));
+        assert_eq!(pipeline.rtt, None);
+        pipeline.received((0, 0), now + Duration::from_secs(2));
+        assert_eq!(pipeline.rtt, Some(Duration::from_secs(2)));
+
+        // a timed request that went away, say to a choke, stops being timed
+        pipeline.sent((1, 0), now, &outstanding);
+        outstanding.clear();
+        pipeline.sent((2, 0), now + Duration::from_secs(1), &outstanding);
+        pipeline.received((2, 0), now + Duration::from_secs(3));
+        assert_eq!(pipeline.rtt, Some(Duration::from_secs(2)));
+    }
+}
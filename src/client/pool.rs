@@ -0,0 +1,257 @@
+//! The peers we have heard of, whether or not we are connected to them, so
+//! connections that drop or fail can be replaced for as long as the download
+//! runs.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use crate::tracker::Peer;
+
+/// The wait before the first retry of a peer. It doubles with every failure.
+const RETRY_BASE: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+/// Peers are forgotten after failing this many times in a row, until a
+/// tracker tells us about them again.
+const MAX_FAILURES: u32 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Idle,
+    Dialing,
+    Connected,
+}
+
+#[derive(Debug)]
+struct Candidate {
+    peer: Peer,
+    state: State,
+    failures: u32,
+    retry_at: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct PeerPool {
+    candidates: HashMap<SocketAddr, Candidate>,
+    /// Addresses that turned out to be our own, never dialed again.
+    own: HashSet<SocketAddr>,
+}
+
+impl PeerPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds peers we haven't heard of yet, ready to be dialed straight away.
+    /// A peer we already know keeps the better of its two sources.
+    pub fn add(&mut self, peers: impl IntoIterator<Item = Peer>, now: Instant) {
+        for peer in peers {
+            if self.own.contains(&peer.addr) {
+                continue;
+            }
+            let source = peer.source;
+            let candidate = self.candidates.entry(peer.addr).or_insert(Candidate {
+                peer,
+                state: State::Idle,
+                failures: 0,
+                retry_at: now,
+            });
+            if source.priority() < candidate.peer.source.priority() {
+                candidate.peer.source = source;
+            }
+        }
+    }
+
+    /// Up to `limit` idle peers that are due a connection attempt, by source
+    /// priority and then fewest failures. They count as dialing until
+    /// reported back.
+    pub fn take_due(&mut self, now: Instant, limit: usize) -> Vec<Peer> {
+        let mut due = self
+            .candidates
+            .values_mut()
+            .filter(|c| c.state == State::Idle && c.retry_at <= now)
+            .collect::<Vec<_>>();
+        due.sort_by_key(|c| (c.peer.source.priority(), c.failures, c.retry_at));
+        due.into_iter()
+            .take(limit)
+            .map(|c| {
+                c.state = State::Dialing;
+                c.peer.clone()
+            })
+            .collect()
+    }
+
+    pub fn connected(&mut self, addr: SocketAddr) {
+        if let Some(candidate) = self.candidates.get_mut(&addr) {
+            candidate.state = State::Connected;
+            candidate.failures = 0;
+        }
+    }
+
+    /// Makes an idle peer due straight away, whatever its backoff.
+    pub fn wake(&mut self, addr: SocketAddr, now: Instant) {
+        if let Some(candidate) = self.candidates.get_mut(&addr) {
+            if candidate.state == State::Idle {
+                candidate.retry_at = now;
+            }
+        }
+    }
+
+    /// Puts a peer we dialed but had no room for back, without counting it
+    /// as a failure.
+    pub fn release(&mut self, addr: SocketAddr) {
+        if let Some(candidate) = self.candidates.get_mut(&addr) {
+            candidate.state = State::Idle;
+        }
+    }
+
+    /// Forgets a peer that answered with our own peer id, for good.
+    pub fn mark_own(&mut self, addr: SocketAddr) {
+        self.candidates.remove(&addr);
+        self.own.insert(addr);
+    }
+
+    /// Backs off from a peer we couldn't connect to or that hung up.
+    pub fn failed(&mut self, addr: SocketAddr, now: Instant) {
+        let Some(candidate) = self.candidates.get_mut(&addr) else {
+            return;
+        };
+        candidate.failures += 1;
+        if candidate.failures >= MAX_FAILURES {
+            self.candidates.remove(&addr);
+            return;
+        }
+        candidate.state = State::Idle;
+        candidate.retry_at =
+            now + (RETRY_BASE * 2u32.pow(candidate.failures - 1)).min(MAX_RETRY_DELAY);
+    }
+
+    /// Drops every peer at `ip`, such as when it has been banned.
+    pub fn forget(&mut self, ip: IpAddr) {
+        self.candidates.retain(|addr, _| addr.ip() != ip);
+    }
+
+    pub fn dialing(&self) -> usize {
+        self.count(State::Dialing)
+    }
+
+    /// Whether any peer is left to try, now or later.
+    pub fn has_idle(&self) -> bool {
+        self.count(State::Idle) > 0
+    }
+
+    /// When the next idle peer is due.
+    pub fn next_retry(&self) -> Option<Instant> {
+        self.candidates
+            .values()
+            .filter(|c| c.state == State::Idle)
+            .map(|c| c.retry_at)
+            .min()
+    }
+
+    fn count(&self, state: State) -> usize {
+        self.candidates
+            .values()
+            .filter(|c| c.state == state)
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracker::PeerSource;
+
+    fn peer(port: u16) -> Peer {
+        from(port, PeerSource::Tracker)
+    }
+
+    fn from(port: u16, source: PeerSource) -> Peer {
+        Peer {
+            addr: SocketAddr::from(([127, 0, 0, 1], port)),
+            peer_id: None,
+            source,
+        }
+    }
+
+    #[test]
+    fn backs_off_failed_peers_then_forgets_them() {
+        let mut pool = PeerPool::new();
+        let now = Instant::now();
+        pool.add([peer(1), peer(2)], now);
+        assert_eq!(pool.take_due(now, 1).len(), 1);
+        assert_eq!(pool.dialing(), 1);
+        assert_eq!(pool.take_due(now, 5).len(), 1);
+        assert!(pool.take_due(now, 5).is_empty());
+
+        let addr = peer(1).addr;
+        pool.failed(addr, now);
+        assert!(pool.take_due(now, 5).is_empty());
+        assert_eq!(pool.next_retry(), Some(now + RETRY_BASE));
+        assert_eq!(pool.take_due(now + RETRY_BASE, 5).len(), 1);
+
+        pool.failed(addr, now);
+        assert_eq!(pool.next_retry(), Some(now + RETRY_BASE * 2));
+        for _ in 2..MAX_FAILURES {
+            pool.failed(addr, now);
+        }
+        assert!(!pool.has_idle());
+
+        pool.connected(peer(2).addr);
+        assert_eq!(pool.dialing(), 0);
+    }
+
+    #[test]
+    fn skips_our_own_address_and_peers_already_dialing() {
+        let mut pool = PeerPool::new();
+        let now = Instant::now();
+        pool.add([peer(1), peer(2)], now);
+        assert_eq!(pool.take_due(now, 5).len(), 2);
+        // announced again while we are still dialing
+        pool.add([peer(1), peer(2)], now);
+        assert!(pool.take_due(now, 5).is_empty());
+
+        pool.mark_own(peer(1).addr);
+        pool.add([peer(1)], now);
+        pool.release(peer(2).addr);
+        let due = pool.take_due(now, 5);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].addr, peer(2).addr);
+
+        // a hole punch can't wait out the backoff
+        pool.failed(peer(2).addr, now);
+        assert!(pool.take_due(now, 5).is_empty());
+        pool.wake(peer(2).addr, now);
+        assert_eq!(pool.take_due(now, 5).len(), 1);
+    }
+
+    #[test]
+    fn dials_better_sources_first() {
+        let mut pool = PeerPool::new();
+        let now = Instant::now();
+        pool.add(
+            [
+                from(1, PeerSource::Dht),
+                from(2, PeerSource::Tracker),
+                from(3, PeerSource::Lsd),
+                from(4, PeerSource::Dht),
+            ],
+            now,
+        );
+        // heard of again from the tracker
+        pool.add(
+            [from(4, PeerSource::Tracker), from(3, PeerSource::Dht)],
+            now,
+        );
+
+        let due = pool.take_due(now, 3);
+        assert_eq!(due[0].addr.port(), 3);
+        assert_eq!(due[0].source, PeerSource::Lsd);
+        assert!(due[1..].iter().all(|p| p.source == PeerSource::Tracker));
+        let rest = pool.take_due(now, 5);
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].addr.port(), 1);
+    }
+}
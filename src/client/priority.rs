@@ -0,0 +1,26 @@
+use std::time::Duration;
+
+use futures::channel::mpsc;
+
+/// Sets piece deadlines on a client while [`Client::download`] is running.
+/// Cheap to clone.
+///
+/// [`Client::download`]: super::Client::download
+#[derive(Debug, Clone)]
+pub struct PriorityHandle {
+    deadlines: mpsc::UnboundedSender<(usize, Duration)>,
+}
+
+impl PriorityHandle {
+    pub(super) fn new(deadlines: mpsc::UnboundedSender<(usize, Duration)>) -> Self {
+        Self { deadlines }
+    }
+
+    /// Asks for piece `index` to be downloaded within `deadline`, see
+    /// [`Client::set_piece_deadline`].
+    ///
+    /// [`Client::set_piece_deadline`]: super::Client::set_piece_deadline
+    pub fn set_piece_deadline(&self, index: usize, deadline: Duration) {
+        let _ = self.deadlines.unbounded_send((index, deadline));
+    }
+}
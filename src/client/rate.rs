@@ -0,0 +1,76 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// How far back the rate looks.
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+/// A sample closer than this to the previous one is dropped.
+const MIN_SAMPLE_GAP: Duration = Duration::from_millis(200);
+
+/// Rate of a growing byte counter over the last few seconds.
+#[derive(Debug, Clone, Default)]
+pub struct RateMeter {
+    /// Counter values and when they were seen, oldest first.
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl RateMeter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the counter's value at `now`.
+    pub fn record(&mut self, now: Instant, total: u64) {
+        while self
+            .samples
+            .front()
+            .is_some_and(|(at, _)| now.saturating_duration_since(*at) > RATE_WINDOW)
+        {
+            self.samples.pop_front();
+        }
+        let recent = self
+            .samples
+            .back()
+            .is_some_and(|(at, _)| now.saturating_duration_since(*at) < MIN_SAMPLE_GAP);
+        if !recent {
+            self.samples.push_back((now, total));
+        }
+    }
+
+    /// Bytes per second between the oldest sample in the window and `total`
+    /// at `now`.
+    pub fn rate(&self, now: Instant, total: u64) -> f64 {
+        let Some((at, value)) = self
+            .samples
+            .iter()
+            .find(|(at, _)| now.saturating_duration_since(*at) <= RATE_WINDOW)
+        else {
+            return 0.0;
+        };
+        let elapsed = now.saturating_duration_since(*at).as_secs_f64();
+        if elapsed <= 0.0 {
+            return 0.0;
+        }
+        total.saturating_sub(*value) as f64 / elapsed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measures_recent_rate_only() {
+        let start = Instant::now();
+        let mut meter = RateMeter::new();
+        meter.record(start, 0);
+        meter.record(start + Duration::from_secs(1), 1000);
+        assert_eq!(meter.rate(start + Duration::from_secs(2), 2000), 1000.0);
+
+        // the first burst falls out of the window
+        meter.record(start + Duration::from_secs(10), 100_000);
+        meter.record(start + Duration::from_secs(12), 100_000);
+        assert_eq!(meter.rate(start + Duration::from_secs(14), 100_000), 0.0);
+    }
+}
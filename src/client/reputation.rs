@@ -0,0 +1,82 @@
+//! Remembers which addresses have sent us bad data or broken the protocol,
+//! and bans the ones that keep doing it for the rest of the session.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+};
+
+/// Pieces an address may help corrupt before it is banned.
+const MAX_HASH_FAILURES: u32 = 3;
+/// Protocol violations an address may commit before it is banned.
+const MAX_PROTOCOL_VIOLATIONS: u32 = 3;
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Record {
+    hash_failures: u32,
+    protocol_violations: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct Reputation {
+    records: HashMap<IpAddr, Record>,
+    banned: HashSet<IpAddr>,
+}
+
+impl Reputation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.banned.contains(&ip)
+    }
+
+    /// Blames `ip` for a piece that failed its hash check. Returns true if
+    /// this got it banned.
+    pub fn hash_failure(&mut self, ip: IpAddr) -> bool {
+        let record = self.records.entry(ip).or_default();
+        record.hash_failures += 1;
+        let offending = record.hash_failures >= MAX_HASH_FAILURES;
+        offending && self.banned.insert(ip)
+    }
+
+    /// Records a malformed or unexpected message from `ip`. Returns true if
+    /// this got it banned.
+    pub fn protocol_violation(&mut self, ip: IpAddr) -> bool {
+        let record = self.records.entry(ip).or_default();
+        record.protocol_violations += 1;
+        let offending = record.protocol_violations >= MAX_PROTOCOL_VIOLATIONS;
+        offending && self.banned.insert(ip)
+    }
+
+    pub fn banned(&self) -> impl Iterator<Item = &IpAddr> {
+        self.banned.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn bans_repeat_offenders_once() {
+        let mut reputation = Reputation::new();
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+        for _ in 1..MAX_HASH_FAILURES {
+            assert!(!reputation.hash_failure(ip));
+        }
+        assert!(!reputation.protocol_violation(other));
+        assert!(!reputation.is_banned(ip));
+
+        assert!(reputation.hash_failure(ip));
+        assert!(reputation.is_banned(ip));
+        assert!(!reputation.hash_failure(ip));
+        assert!(!reputation.is_banned(other));
+        assert_eq!(reputation.banned().count(), 1);
+    }
+}
@@ -0,0 +1,244 @@
+use std::{
+    fmt::Display,
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    bencode::BencodeValue,
+    dict,
+    metainfo::{Info, InfoHash},
+};
+
+use super::bitfield::Bitfield;
+
+#[derive(Debug)]
+pub enum ResumeError {
+    Io(io::Error),
+    Invalid(String),
+}
+
+impl Display for ResumeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResumeError::Io(e) => write!(f, "Io: {}", e),
+            ResumeError::Invalid(e) => write!(f, "Invalid resume file: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ResumeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ResumeError::Io(e) => Some(e),
+            ResumeError::Invalid(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for ResumeError {
+    fn from(e: io::Error) -> Self {
+        ResumeError::Io(e)
+    }
+}
+
+/// Download progress saved next to the output, so an interrupted download
+/// can pick up where it left off.
+#[derive(Debug)]
+pub struct ResumeData {
+    pub info_hash: InfoHash,
+    /// Pieces that had passed their hash check.
+    pub pieces: Bitfield,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// Bytes of each file covered by completed pieces.
+    pub files: Vec<u64>,
+    /// Time spent seeding after the download finished.
+    pub seeding_time: Duration,
+    /// Seeding stopped because a ratio or time limit was reached.
+    pub seeding_done: bool,
+    /// Pieces not complete yet, with the blocks of each already on disk.
+    pub unfinished: Vec<(usize, Bitfield)>,
+}
+
+/// `<output_dir>/<torrent name>.resume`
+pub fn resume_path(output_dir: &str, info: &Info) -> PathBuf {
+    let name = match info {
+        Info::SingleFile(info) => &info.name,
+        Info::MultiFile(info) => &info.name,
+    };
+    Path::new(output_dir).join(format!("{}.resume", name))
+}
+
+impl ResumeData {
+    pub fn load(path: &Path, num_pieces: usize) -> Result<Self, ResumeError> {
+        let data = fs::read(path)?;
+        Self::decode(&data, num_pieces)
+    }
+
+    /// Writes to a temporary file first, so a crash mid-write never leaves a
+    /// truncated resume file behind.
+    pub fn save(&self, path: &Path) -> Result<(), ResumeError> {
+        let tmp = path.with_extension("resume.tmp");
+        fs::write(&tmp, self.encode())?;
+        Ok(fs::rename(&tmp, path)?)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let files = self
+            .files
+            .iter()
+            .map(|&len| BencodeValue::from(len))
+            .collect::<Vec<_>>();
+        let unfinished = self
+            .unfinished
+            .iter()
+            .map(|(index, blocks)| {
+                dict! {
+                    "piece" => *index as u64,
+                    "blocks" => blocks.len() as u64,
+                    "bitmask" => blocks.to_bytes(),
+                }
+            })
+            .collect::<Vec<_>>();
+        dict! {
+            "info_hash" => self.info_hash.as_bytes(),
+            "pieces" => self.pieces.to_bytes(),
+            "downloaded" => self.downloaded,
+            "uploaded" => self.uploaded,
+            "seeding_time" => self.seeding_time.as_secs(),
+            "seeding_done" => self.seeding_done as u64,
+            "files" => files,
+            "unfinished" => unfinished,
+        }
+        .encode()
+    }
+
+    pub fn decode(data: &[u8], num_pieces: usize) -> Result<Self, ResumeError> {
+        let (value, _) =
+            BencodeValue::parse(data).map_err(|e| ResumeError::Invalid(e.to_string()))?;
+
+        let missing = |key: &str| ResumeError::Invalid(format!("missing {}", key));
+        let bytes = |key: &str| {
+            value
+                .get_bytes(key)
+                .map(<[u8]>::to_vec)
+                .ok_or_else(|| missing(key))
+        };
+        let int = |key: &str| {
+            value
+                .get_int(key)
+                .and_then(|value| u64::try_from(value).ok())
+                .ok_or_else(|| missing(key))
+        };
+
+        let pieces = bytes("pieces")?;
+        if pieces.len() != num_pieces.div_ceil(8) {
+            return Err(ResumeError::Invalid(format!(
+                "expected a bitfield for {} pieces, got {} bytes",
+                num_pieces,
+                pieces.len()
+            )));
+        }
+
+        let files = value
+            .get_list("files")
+            .ok_or_else(|| missing("files"))?
+            .iter()
+            .map(|file| {
+                file.as_int()
+                    .and_then(|len| u64::try_from(len).ok())
+                    .ok_or_else(|| ResumeError::Invalid("invalid file progress".to_string()))
+            })
+            .collect::<Result<_, _>>()?;
+
+        // missing from files written before partial pieces were kept
+        let unfinished = value
+            .get_list("unfinished")
+            .unwrap_or_default()
+            .iter()
+            .map(|piece| {
+                let invalid = || ResumeError::Invalid("invalid unfinished piece".to_string());
+                let int = |key| {
+                    piece
+                        .get_int(key)
+                        .and_then(|value| usize::try_from(value).ok())
+                        .ok_or_else(invalid)
+                };
+                let index = int("piece")?;
+                if index >= num_pieces {
+                    return Err(invalid());
+                }
+                let bitmask = piece.get_bytes("bitmask").ok_or_else(invalid)?;
+                let blocks = Bitfield::from_bytes(bitmask, int("blocks")?)
+                    .map_err(|e| ResumeError::Invalid(e.to_string()))?;
+                Ok((index, blocks))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            info_hash: InfoHash::from_bytes(&bytes("info_hash")?)
+                .ok_or_else(|| ResumeError::Invalid("invalid info hash".to_string()))?,
+            pieces: Bitfield::from_bytes(&pieces, num_pieces)
+                .map_err(|e| ResumeError::Invalid(e.to_string()))?,
+            downloaded: int("downloaded")?,
+            uploaded: int("uploaded")?,
+            files,
+            // missing from files written before seeding limits existed
+            seeding_time: Duration::from_secs(int("seeding_time").unwrap_or(0)),
+            seeding_done: int("seeding_done").unwrap_or(0) != 0,
+            unfinished,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resume_data() -> ResumeData {
+        let mut pieces = Bitfield::new(10);
+        pieces.set(0, true).unwrap();
+        pieces.set(9, true).unwrap();
+        ResumeData {
+            info_hash: InfoHash::V1([0xab; 20]),
+            pieces,
+            downloaded: 2048,
+            uploaded: 512,
+            files: vec![1024, 1024],
+            seeding_time: Duration::from_secs(90),
+            seeding_done: true,
+            unfinished: vec![(4, Bitfield::from_bytes(&[0b1010_0000], 3).unwrap())],
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let data = resume_data();
+        let decoded = ResumeData::decode(&data.encode(), 10).unwrap();
+
+        assert_eq!(decoded.info_hash, data.info_hash);
+        assert_eq!(decoded.pieces.to_bytes(), data.pieces.to_bytes());
+        assert_eq!(decoded.downloaded, 2048);
+        assert_eq!(decoded.uploaded, 512);
+        assert_eq!(decoded.files, vec![1024, 1024]);
+        assert_eq!(decoded.seeding_time, Duration::from_secs(90));
+        assert!(decoded.seeding_done);
+        assert_eq!(decoded.unfinished.len(), 1);
+        assert_eq!(decoded.unfinished[0].0, 4);
+        assert_eq!(
+            decoded.unfinished[0].1.iter().collect::<Vec<_>>(),
+            [true, false, true]
+        );
+    }
+
+    #[test]
+    fn rejects_bitfield_of_wrong_size() {
+        let data = resume_data();
+        assert!(matches!(
+            ResumeData::decode(&data.encode(), 100),
+            Err(ResumeError::Invalid(_))
+        ));
+    }
+}
@@ -0,0 +1,282 @@
+//! WebRTC data channels as a peer transport. WebTorrent peers run the usual
+//! peer wire protocol over a data channel, which is set up by swapping an
+//! offer and an answer through a WebSocket tracker.
+
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use futures::io::{AsyncRead, AsyncWrite};
+use tokio::sync::oneshot;
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+use webrtc::{
+    api::{setting_engine::SettingEngine, APIBuilder, API},
+    data::data_channel::{DataChannel, PollDataChannel},
+    data_channel::RTCDataChannel,
+    ice_transport::ice_server::RTCIceServer,
+    peer_connection::{
+        configuration::RTCConfiguration, sdp::session_description::RTCSessionDescription,
+        RTCPeerConnection,
+    },
+    stats::StatsReportType,
+};
+
+/// The label WebTorrent gives its data channels.
+const CHANNEL_LABEL: &str = "webtorrent";
+
+fn to_io(e: webrtc::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+/// Closes the connection once nothing uses it.
+struct Connection(Arc<RTCPeerConnection>);
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let connection = self.0.clone();
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                let _ = connection.close().await;
+            });
+        }
+    }
+}
+
+/// Sends the channel down `opened` once it opens, detached so it can be
+/// read and written as a stream.
+fn on_open(channel: Arc<RTCDataChannel>, opened: oneshot::Sender<Arc<DataChannel>>) {
+    let detaching = channel.clone();
+    channel.on_open(Box::new(move || {
+        Box::pin(async move {
+            if let Ok(channel) = detaching.detach().await {
+                let _ = opened.send(channel);
+            }
+        })
+    }));
+}
+
+/// Makes and answers offers for data channels.
+pub struct RtcConnector {
+    api: API,
+    config: RTCConfiguration,
+}
+
+impl RtcConnector {
+    /// `ice_servers` are STUN or TURN URLs, for getting through NATs.
+    pub fn new(ice_servers: &[String]) -> Self {
+        let mut settings = SettingEngine::default();
+        settings.detach_data_channels();
+        Self {
+            api: APIBuilder::new().with_setting_engine(settings).build(),
+            config: RTCConfiguration {
+                ice_servers: ice_servers
+                    .iter()
+                    .map(|url| RTCIceServer {
+                        urls: vec![url.clone()],
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Sets `description` as ours and waits for every ICE candidate, since
+    /// the tracker only passes on a single description.
+    async fn describe(
+        connection: &RTCPeerConnection,
+        description: RTCSessionDescription,
+    ) -> io::Result<String> {
+        let mut gathered = connection.gathering_complete_promise().await;
+        connection
+            .set_local_description(description)
+            .await
+            .map_err(to_io)?;
+        let _ = gathered.recv().await;
+        connection
+            .local_description()
+            .await
+            .map(|description| description.sdp)
+            .ok_or_else(|| io::Error::other("no local description"))
+    }
+
+    /// An offer for the tracker to hand to some peer, and what connects once
+    /// the peer answers.
+    pub async fn offer(&self) -> io::Result<(String, PendingOffer)> {
+        let connection = Connection(Arc::new(
+            self.api
+                .new_peer_connection(self.config.clone())
+                .await
+                .map_err(to_io)?,
+        ));
+        let channel = connection
+            .0
+            .create_data_channel(CHANNEL_LABEL, None)
+            .await
+            .map_err(to_io)?;
+        let (opened_tx, opened) = oneshot::channel();
+        on_open(channel, opened_tx);
+
+        let offer = connection.0.create_offer(None).await.map_err(to_io)?;
+        let sdp = Self::describe(&connection.0, offer).await?;
+        Ok((sdp, PendingOffer { connection, opened }))
+    }
+
+    /// Our answer to a peer's offer, and the stream that opens once the peer
+    /// has it.
+    pub async fn answer(&self, offer: String) -> io::Result<(String, PendingOffer)> {
+        let connection = Connection(Arc::new(
+            self.api
+                .new_peer_connection(self.config.clone())
+                .await
+                .map_err(to_io)?,
+        ));
+        let (opened_tx, opened) = oneshot::channel();
+        let opened_tx = Mutex::new(Some(opened_tx));
+        connection.0.on_data_channel(Box::new(move |channel| {
+            if let Some(opened_tx) = opened_tx.lock().unwrap().take() {
+                on_open(channel, opened_tx);
+            }
+            Box::pin(async {})
+        }));
+
+        let offer = RTCSessionDescription::offer(offer).map_err(to_io)?;
+        connection
+            .0
+            .set_remote_description(offer)
+            .await
+            .map_err(to_io)?;
+        let answer = connection.0.create_answer(None).await.map_err(to_io)?;
+        let sdp = Self::describe(&connection.0, answer).await?;
+        Ok((sdp, PendingOffer { connection, opened }))
+    }
+}
+
+/// A connection waiting on the other side: for the answer to our offer, or
+/// for the peer to open the channel of the offer we answered. Dropping it
+/// gives up on the connection.
+pub struct PendingOffer {
+    connection: Connection,
+    opened: oneshot::Receiver<Arc<DataChannel>>,
+}
+
+impl PendingOffer {
+    /// Takes the peer's answer to our offer.
+    pub async fn answered(self, answer: String) -> io::Result<RtcStream> {
+        let answer = RTCSessionDescription::answer(answer).map_err(to_io)?;
+        self.connection
+            .0
+            .set_remote_description(answer)
+            .await
+            .map_err(to_io)?;
+        self.open().await
+    }
+
+    /// Waits for the data channel to open. Never returns if the peer doesn't
+    /// connect, so callers need a timeout.
+    pub async fn open(self) -> io::Result<RtcStream> {
+        let channel = self
+            .opened
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::ConnectionAborted))?;
+        let addr = remote_addr(&self.connection.0).await;
+        Ok(RtcStream {
+            channel: PollDataChannel::new(channel).compat(),
+            addr,
+            _connection: self.connection,
+        })
+    }
+}
+
+/// The address of the peer's end of the nominated ICE candidate pair.
+async fn remote_addr(connection: &RTCPeerConnection) -> Option<SocketAddr> {
+    let reports = connection.get_stats().await.reports;
+    let pair = reports.values().find_map(|report| match report {
+        StatsReportType::CandidatePair(pair) if pair.nominated => Some(pair),
+        _ => None,
+    })?;
+    match reports.get(&pair.remote_candidate_id)? {
+        StatsReportType::RemoteCandidate(candidate) => {
+            Some(SocketAddr::new(candidate.ip.parse().ok()?, candidate.port))
+        }
+        _ => None,
+    }
+}
+
+/// An open data channel to a peer.
+pub struct RtcStream {
+    channel: Compat<PollDataChannel>,
+    addr: Option<SocketAddr>,
+    _connection: Connection,
+}
+
+impl RtcStream {
+    /// Where the peer is, if ICE says. Browsers often hide their addresses
+    /// behind mDNS names.
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.addr
+    }
+}
+
+impl AsyncRead for RtcStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.channel).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for RtcStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.channel).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.channel).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.channel).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn connects_data_channels_through_offer_and_answer() {
+        // host candidates are enough on one machine
+        let connector = RtcConnector::new(&[]);
+        let (offer, pending) = connector.offer().await.unwrap();
+        let (answer, answering) = connector.answer(offer).await.unwrap();
+
+        let connect = async {
+            let (ours, theirs) =
+                futures::future::join(pending.answered(answer), answering.open()).await;
+            (ours.unwrap(), theirs.unwrap())
+        };
+        let (mut ours, mut theirs) = tokio::time::timeout(Duration::from_secs(10), connect)
+            .await
+            .expect("data channel did not open");
+
+        ours.write_all(b"hello").await.unwrap();
+        let mut received = [0; 5];
+        theirs.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"hello");
+    }
+}
@@ -0,0 +1,97 @@
+//! How the next piece to download from a peer is picked. The client asks its
+//! [`PieceSelectionStrategy`] whenever a peer has room for another request,
+//! so a strategy can trade swarm health for playback order and back. Pieces
+//! given a deadline while streaming come first, whatever the strategy.
+
+use std::fmt::Debug;
+
+/// A piece the peer can give us: we lack it, the peer has it, and some of its
+/// blocks haven't been requested from anyone yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Candidate {
+    pub index: usize,
+    /// How many connected peers have the piece.
+    pub availability: usize,
+    /// Whether any of its blocks have been requested or downloaded already.
+    pub started: bool,
+}
+
+pub trait PieceSelectionStrategy: Debug + Send + Sync {
+    /// The index of the piece to request a block of next, from `candidates`,
+    /// which is never empty. `completed` is the number of pieces we have.
+    /// Returning `None` asks the peer for nothing this time.
+    fn select(&mut self, candidates: &[Candidate], completed: usize) -> Option<usize>;
+}
+
+/// Pieces fewest peers have first, so they spread before their holders
+/// leave.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RarestFirst;
+
+impl PieceSelectionStrategy for RarestFirst {
+    fn select(&mut self, candidates: &[Candidate], _completed: usize) -> Option<usize> {
+        candidates
+            .iter()
+            .min_by_key(|c| c.availability)
+            .map(|c| c.index)
+    }
+}
+
+/// Random pieces until `pieces` are complete, then rarest first. A rare piece
+/// is slow to get, and until we finish one we have nothing to trade.
+#[derive(Debug, Clone, Copy)]
+pub struct RandomFirst {
+    pub pieces: usize,
+}
+
+/// The client's default: a random first piece.
+impl Default for RandomFirst {
+    fn default() -> Self {
+        Self { pieces: 1 }
+    }
+}
+
+impl PieceSelectionStrategy for RandomFirst {
+    fn select(&mut self, candidates: &[Candidate], completed: usize) -> Option<usize> {
+        if completed >= self.pieces {
+            return RarestFirst.select(candidates, completed);
+        }
+        Some(candidates[rand::random::<usize>() % candidates.len()].index)
+    }
+}
+
+/// Pieces in order, for playing the data back as it arrives. Hard on the
+/// swarm, as every peer using it wants the same pieces.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sequential;
+
+impl PieceSelectionStrategy for Sequential {
+    fn select(&mut self, candidates: &[Candidate], _completed: usize) -> Option<usize> {
+        candidates.iter().map(|c| c.index).min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(index: usize, availability: usize) -> Candidate {
+        Candidate {
+            index,
+            availability,
+            started: false,
+        }
+    }
+
+    #[test]
+    fn strategies_pick_from_candidates() {
+        let candidates = [candidate(4, 3), candidate(2, 5), candidate(7, 1)];
+        assert_eq!(RarestFirst.select(&candidates, 0), Some(7));
+        assert_eq!(Sequential.select(&candidates, 0), Some(2));
+
+        let mut random = RandomFirst { pieces: 2 };
+        let picked = random.select(&candidates, 1).unwrap();
+        assert!(candidates.iter().any(|c| c.index == picked));
+        assert_eq!(random.select(&candidates, 2), Some(7));
+    }
+}
@@ -0,0 +1,185 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use arc_swap::ArcSwap;
+use chrono::{DateTime, Utc};
+
+use super::peer_id::PeerClient;
+use crate::tracker::TrackerStatus;
+
+/// A point-in-time copy of everything a UI or exporter might want to show.
+/// It owns all of its data, so holding on to one never blocks the client.
+#[derive(Debug, Clone)]
+pub struct ClientState {
+    pub taken_at: DateTime<Utc>,
+    pub started_at: DateTime<Utc>,
+    pub total_length: u64,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// Average download rate since the client started, in bytes per second.
+    pub download_rate: f64,
+    /// Download and upload rates over the last few seconds.
+    pub recent_download_rate: f64,
+    pub recent_upload_rate: f64,
+    pub tracker: TrackerStatus,
+    pub peers: Vec<PeerSummary>,
+    pub pieces: Vec<PieceSummary>,
+    pub memory_used: usize,
+    pub memory_limit: usize,
+}
+
+impl ClientState {
+    pub fn progress(&self) -> f64 {
+        if self.total_length == 0 {
+            return 1.0;
+        }
+        self.downloaded as f64 / self.total_length as f64
+    }
+
+    pub fn completed_pieces(&self) -> usize {
+        self.pieces.iter().filter(|p| p.completed).count()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pieces.iter().all(|p| p.completed)
+    }
+}
+
+/// The numbers a progress bar needs, taken from a [`ClientState`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub total_length: u64,
+    pub completed_pieces: usize,
+    pub total_pieces: usize,
+    pub peers: usize,
+    /// Bytes per second over the last few seconds.
+    pub download_rate: f64,
+    pub upload_rate: f64,
+}
+
+impl Progress {
+    /// Share of pieces verified, from 0 to 100.
+    pub fn percent(&self) -> f64 {
+        if self.total_pieces == 0 {
+            return 100.0;
+        }
+        self.completed_pieces as f64 * 100.0 / self.total_pieces as f64
+    }
+
+    /// Time left at the current download rate, if there is one.
+    pub fn eta(&self) -> Option<Duration> {
+        let left = self.total_length.saturating_sub(self.downloaded);
+        if left == 0 {
+            return Some(Duration::ZERO);
+        }
+        if self.download_rate <= 0.0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64(left as f64 / self.download_rate))
+    }
+}
+
+impl From<&ClientState> for Progress {
+    fn from(state: &ClientState) -> Self {
+        Self {
+            downloaded: state.downloaded,
+            uploaded: state.uploaded,
+            total_length: state.total_length,
+            completed_pieces: state.completed_pieces(),
+            total_pieces: state.pieces.len(),
+            peers: state.peers.len(),
+            download_rate: state.recent_download_rate,
+            upload_rate: state.recent_upload_rate,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PeerSummary {
+    pub peer_id: Vec<u8>,
+    pub addr: SocketAddr,
+    pub client: Option<PeerClient>,
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+    /// Number of pieces the peer has told us it has.
+    pub pieces: usize,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// Bytes per second over the last few seconds.
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    /// Blocks we have asked the peer for and not received yet.
+    pub requests_in_flight: usize,
+    /// Time since the peer last sent a block we asked for.
+    pub since_last_block: Option<Duration>,
+    /// Smoothed time from request to block.
+    pub rtt: Option<Duration>,
+    /// Bytes on the way from the peer at its current rate and round trip.
+    pub bandwidth_delay: u64,
+    /// Whether the peer unchoked us but stopped answering our requests.
+    pub snubbed: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PieceSummary {
+    pub completed: bool,
+    /// Number of connected peers that have the piece.
+    pub availability: usize,
+}
+
+/// A cheap, cloneable reader of the state the client last published. Reads
+/// never take a lock and never wait on the coordinator.
+#[derive(Debug, Clone)]
+pub struct StateHandle {
+    state: Arc<ArcSwap<ClientState>>,
+}
+
+impl StateHandle {
+    pub(super) fn new(state: ClientState) -> Self {
+        Self {
+            state: Arc::new(ArcSwap::from_pointee(state)),
+        }
+    }
+
+    pub(super) fn publish(&self, state: ClientState) {
+        self.state.store(Arc::new(state));
+    }
+
+    pub fn snapshot(&self) -> Arc<ClientState> {
+        self.state.load_full()
+    }
+
+    pub fn progress(&self) -> Progress {
+        Progress::from(self.state.load().as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates_time_left_from_recent_rate() {
+        let mut progress = Progress {
+            downloaded: 1000,
+            uploaded: 0,
+            total_length: 5000,
+            completed_pieces: 1,
+            total_pieces: 4,
+            peers: 2,
+            download_rate: 0.0,
+            upload_rate: 0.0,
+        };
+        assert_eq!(progress.percent(), 25.0);
+        assert_eq!(progress.eta(), None);
+
+        progress.download_rate = 500.0;
+        assert_eq!(progress.eta(), Some(Duration::from_secs(8)));
+
+        progress.downloaded = 5000;
+        assert_eq!(progress.eta(), Some(Duration::ZERO));
+    }
+}
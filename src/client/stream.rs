@@ -0,0 +1,172 @@
+//! Reading a torrent's files in order while they download, for players and
+//! servers that want to start before the whole torrent is there.
+
+use std::{
+    io::{self, SeekFrom},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{
+    channel::mpsc,
+    io::{AsyncRead, AsyncSeek},
+    StreamExt,
+};
+
+use super::{
+    event::{EventBus, TorrentEvent},
+    file_manager::FileManager,
+    priority::PriorityHandle,
+    state::StateHandle,
+};
+
+/// How soon the piece a reader is waiting on is asked for. Each piece of the
+/// read-ahead gets this much longer than the one before it.
+const STREAM_DEADLINE: Duration = Duration::from_secs(2);
+/// Pieces past the one being read that are asked for early.
+const READ_AHEAD: usize = 4;
+
+/// One file of a torrent as an [`AsyncRead`], which waits for pieces that
+/// aren't downloaded yet instead of returning their bytes. Reads go straight
+/// to disk on the calling task.
+pub struct FileStream {
+    file_manager: FileManager,
+    priority: PriorityHandle,
+    events: mpsc::UnboundedReceiver<TorrentEvent>,
+    completed: Vec<bool>,
+    /// Where the file starts in the torrent's data.
+    start: u64,
+    length: u64,
+    position: u64,
+    /// The last piece a deadline was set for.
+    prioritized: Option<usize>,
+}
+
+impl FileStream {
+    pub(crate) fn new(
+        file_manager: FileManager,
+        file_index: usize,
+        state: &StateHandle,
+        priority: PriorityHandle,
+        events: &EventBus,
+    ) -> Option<Self> {
+        let (start, length) = file_manager.file_span(file_index)?;
+        // subscribe first, so a piece finishing in between is not missed
+        let events = events.subscribe();
+        let completed = state
+            .snapshot()
+            .pieces
+            .iter()
+            .map(|piece| piece.completed)
+            .collect();
+        Some(Self {
+            file_manager,
+            priority,
+            events,
+            completed,
+            start,
+            length,
+            position: 0,
+            prioritized: None,
+        })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Asks for `piece` and the few after it, unless that was already done.
+    fn prioritize(&mut self, piece: usize) {
+        if self.prioritized.is_some_and(|last| last >= piece) {
+            return;
+        }
+        let last_piece =
+            ((self.start + self.length - 1) / self.file_manager.piece_length()) as usize;
+        let end = (piece + READ_AHEAD).min(last_piece);
+        for (i, index) in (piece..=end).enumerate() {
+            if !self.completed[index] {
+                self.priority
+                    .set_piece_deadline(index, STREAM_DEADLINE * (i as u32 + 1));
+            }
+        }
+        self.prioritized = Some(end);
+    }
+
+    fn poll_events(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        while let Poll::Ready(event) = self.events.poll_next_unpin(cx) {
+            match event {
+                Some(TorrentEvent::PieceCompleted(index)) => {
+                    if let Some(completed) = self.completed.get_mut(index) {
+                        *completed = true;
+                    }
+                }
+                Some(TorrentEvent::Finished) => self.completed.fill(true),
+                Some(TorrentEvent::Failed(e)) => return Err(io::Error::other(e)),
+                Some(TorrentEvent::Removed) => return Err(io::ErrorKind::NotFound.into()),
+                Some(_) => {}
+                None => return Err(io::ErrorKind::BrokenPipe.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AsyncRead for FileStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.position >= this.length || buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let offset = this.start + this.position;
+        let piece_length = this.file_manager.piece_length();
+        let piece = (offset / piece_length) as usize;
+        if !this.completed[piece] {
+            this.poll_events(cx)?;
+            if !this.completed[piece] {
+                this.prioritize(piece);
+                return Poll::Pending;
+            }
+        }
+
+        // stop at the end of the piece, the next one may not be here yet
+        let piece_end = (piece as u64 + 1) * piece_length;
+        let len = (buf.len() as u64)
+            .min(piece_end - offset)
+            .min(this.length - this.position) as usize;
+        this.file_manager.read_at(offset, &mut buf[..len])?;
+        this.position += len as u64;
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncSeek for FileStream {
+    fn poll_seek(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        pos: SeekFrom,
+    ) -> Poll<io::Result<u64>> {
+        let this = self.get_mut();
+        let position = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => this.length.checked_add_signed(n),
+            SeekFrom::Current(n) => this.position.checked_add_signed(n),
+        };
+        let Some(position) = position else {
+            return Poll::Ready(Err(io::ErrorKind::InvalidInput.into()));
+        };
+        this.position = position;
+        // read ahead from the new position
+        this.prioritized = None;
+        Poll::Ready(Ok(position))
+    }
+}
@@ -0,0 +1,9 @@
+use futures::io::{AsyncRead, AsyncWrite};
+
+/// A byte stream a peer connection can run over. TCP, encrypted streams and
+/// in-memory pipes all qualify, and any other transport only needs to be
+/// `AsyncRead + AsyncWrite` to plug into [`PeerState`](super::peer::PeerState)
+/// and the handshake.
+pub trait PeerTransport: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T> PeerTransport for T where T: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
@@ -0,0 +1,197 @@
+use std::{io, time::Instant};
+
+use crate::{metainfo::Info, runtime::Runtime};
+
+/// One of the torrent's files on a web seed.
+#[derive(Debug, Clone)]
+struct RemoteFile {
+    url: String,
+    /// Where the file starts in the concatenated torrent data.
+    offset: u64,
+    length: u64,
+}
+
+/// An HTTP server holding a copy of the torrent's files (BEP 19). Pieces are
+/// fetched with range requests against the files they overlap.
+#[derive(Debug, Clone)]
+pub struct WebSeed {
+    url: String,
+    piece_length: u64,
+    files: Vec<RemoteFile>,
+    /// Whether a request to this seed is in flight.
+    pub(crate) busy: bool,
+    /// Not used again before this after a failed request.
+    pub(crate) retry_at: Option<Instant>,
+}
+
+impl WebSeed {
+    /// A single-file torrent's seed URL names the file itself, unless it ends
+    /// in `/`; a multi-file torrent's names the directory holding the
+    /// torrent's root directory.
+    pub fn new(url: &str, info: &Info) -> Self {
+        let (piece_length, files) = match info {
+            Info::SingleFile(info) => {
+                let file_url = if url.ends_with('/') {
+                    format!("{}{}", url, encode(&info.name))
+                } else {
+                    url.to_string()
+                };
+                (
+                    info.base_info.piece_length,
+                    vec![RemoteFile {
+                        url: file_url,
+                        offset: 0,
+                        length: info.length,
+                    }],
+                )
+            }
+            Info::MultiFile(info) => {
+                let base = url.trim_end_matches('/');
+                let mut offset = 0;
+                let files = info
+                    .files
+                    .iter()
+                    .map(|file| {
+                        let mut file_url = format!("{}/{}", base, encode(&info.name));
+                        for part in &file.path {
+                            file_url.push('/');
+                            file_url.push_str(&encode(part));
+                        }
+                        let remote = RemoteFile {
+                            url: file_url,
+                            offset,
+                            length: file.length,
+                        };
+                        offset += file.length;
+                        remote
+                    })
+                    .collect();
+                (info.base_info.piece_length, files)
+            }
+        };
+        Self {
+            url: url.to_string(),
+            piece_length,
+            files,
+            busy: false,
+            retry_at: None,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Whether the seed can take a request at `now`.
+    pub fn is_ready(&self, now: Instant) -> bool {
+        !self.busy && self.retry_at.is_none_or(|at| at <= now)
+    }
+
+    /// The URL and byte range in that file for each part of the piece.
+    fn requests(&self, index: usize, length: u32) -> Vec<(String, u64, u64)> {
+        let start = self.piece_length * index as u64;
+        let end = start + length as u64;
+        self.files
+            .iter()
+            .filter(|file| file.offset < end && start < file.offset + file.length)
+            .map(|file| {
+                let from = start.max(file.offset) - file.offset;
+                let to = end.min(file.offset + file.length) - file.offset;
+                (file.url.clone(), from, to)
+            })
+            .collect()
+    }
+
+    /// Downloads a whole piece, which may take one request per file it spans.
+    pub async fn fetch_piece<R: Runtime>(
+        &self,
+        runtime: &R,
+        index: usize,
+        length: u32,
+    ) -> io::Result<Vec<u8>> {
+        let mut piece = Vec::with_capacity(length as usize);
+        for (url, from, to) in self.requests(index, length) {
+            let data = runtime.http_get_range(&url, from..to).await?;
+            if data.len() as u64 != to - from {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!(
+                        "{} returned {} bytes instead of {}",
+                        url,
+                        data.len(),
+                        to - from
+                    ),
+                ));
+            }
+            piece.extend_from_slice(&data);
+        }
+        Ok(piece)
+    }
+}
+
+/// Percent-encodes a path segment.
+fn encode(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::{BaseInfo, FileData, MultiFileInfo};
+
+    #[test]
+    fn splits_piece_across_file_urls() {
+        let info = Info::MultiFile(MultiFileInfo {
+            base_info: BaseInfo {
+                pieces: vec![vec![0; 20]; 2],
+                piece_length: 16,
+                private: None,
+            },
+            name: "my torrent".to_string(),
+            files: vec![
+                FileData {
+                    path: vec!["a.txt".to_string()],
+                    length: 10,
+                    md5sum: None,
+                },
+                FileData {
+                    path: vec!["dir".to_string(), "b.txt".to_string()],
+                    length: 20,
+                    md5sum: None,
+                },
+            ],
+        });
+        let seed = WebSeed::new("http://example.com/files/", &info);
+        assert_eq!(
+            seed.requests(0, 16),
+            vec![
+                (
+                    "http://example.com/files/my%20torrent/a.txt".to_string(),
+                    0,
+                    10
+                ),
+                (
+                    "http://example.com/files/my%20torrent/dir/b.txt".to_string(),
+                    0,
+                    6
+                ),
+            ]
+        );
+        assert_eq!(
+            seed.requests(1, 14),
+            vec![(
+                "http://example.com/files/my%20torrent/dir/b.txt".to_string(),
+                6,
+                20
+            )]
+        );
+    }
+}
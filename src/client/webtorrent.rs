@@ -0,0 +1,204 @@
+//! Joins WebTorrent swarms: announces to the torrent's WebSocket trackers
+//! with WebRTC offers, answers the offers they relay to us, and hands the
+//! peers that connect over to the client like incoming TCP peers.
+
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use futures::{channel::mpsc, future::join_all, SinkExt};
+use tokio::time::{sleep, timeout, timeout_at, Instant};
+use tracing::{debug, info_span, warn, Instrument};
+
+use crate::{
+    metainfo::InfoHash,
+    tracker::{
+        websocket::{announce_request, answer_request, WsMessage, WsOffer, WsTracker},
+        AnnounceEvent, Peer, PeerSource, TrackerError, TransferStats,
+    },
+};
+
+use super::{
+    accept_handshake, initiate_handshake,
+    rtc::{PendingOffer, RtcConnector},
+    state::StateHandle,
+    transport::PeerTransport,
+    ClientError, IncomingPeer,
+};
+
+/// Offers sent with every announce, as WebTorrent does.
+const OFFERS_PER_ANNOUNCE: usize = 5;
+/// Used until the tracker tells us its interval.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(120);
+const RETRY_BASE: Duration = Duration::from_secs(15);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30 * 60);
+
+pub(super) struct WebTorrent {
+    pub trackers: Vec<String>,
+    pub info_hash: InfoHash,
+    pub peer_id: Vec<u8>,
+    pub handshake: Vec<u8>,
+    pub handshake_timeout: Duration,
+    pub ice_servers: Vec<String>,
+    /// Where the byte counts to announce come from.
+    pub state: StateHandle,
+    pub incoming: mpsc::Sender<IncomingPeer<Box<dyn PeerTransport>>>,
+}
+
+impl WebTorrent {
+    /// Keeps announcing to every tracker, reconnecting with backoff when one
+    /// hangs up.
+    pub async fn run(self) {
+        let this = Arc::new(self);
+        let connector = Arc::new(RtcConnector::new(&this.ice_servers));
+        join_all(this.trackers.iter().map(|announce| {
+            let this = this.clone();
+            let connector = connector.clone();
+            async move {
+                let mut failures = 0;
+                loop {
+                    match this.announce_to(announce, &connector).await {
+                        Ok(()) => {
+                            debug!("tracker hung up");
+                            failures = 0;
+                        }
+                        Err(e) => {
+                            warn!(error = %e, "WebSocket tracker failed");
+                            failures += 1;
+                        }
+                    }
+                    let delay = RETRY_BASE * 2u32.saturating_pow(failures.min(16));
+                    sleep(delay.min(MAX_RETRY_DELAY)).await;
+                }
+            }
+            .instrument(info_span!("websocket tracker", announce))
+        }))
+        .await;
+    }
+
+    fn transfer(&self) -> TransferStats {
+        let state = self.state.snapshot();
+        TransferStats {
+            uploaded: state.uploaded,
+            downloaded: state.downloaded,
+            left: state.total_length.saturating_sub(state.downloaded),
+        }
+    }
+
+    /// One connection to a tracker, until it hangs up.
+    async fn announce_to(
+        self: &Arc<Self>,
+        announce: &str,
+        connector: &Arc<RtcConnector>,
+    ) -> Result<(), TrackerError> {
+        let mut tracker = WsTracker::connect(announce).await?;
+        let mut event = Some(AnnounceEvent::Started);
+        let mut interval = DEFAULT_INTERVAL;
+        loop {
+            // offers still unanswered from the last announce are given up on
+            let mut pending = HashMap::new();
+            let mut offers = Vec::new();
+            for _ in 0..OFFERS_PER_ANNOUNCE {
+                match connector.offer().await {
+                    Ok((sdp, offer)) => {
+                        let offer_id = rand::random::<[u8; 20]>();
+                        pending.insert(offer_id, offer);
+                        offers.push(WsOffer { offer_id, sdp });
+                    }
+                    Err(e) => debug!(error = %e, "failed to make a WebRTC offer"),
+                }
+            }
+            let request = announce_request(
+                &self.info_hash,
+                &self.peer_id,
+                self.transfer(),
+                event.take(),
+                &offers,
+            );
+            tracker.send(&request).await?;
+
+            let next_announce = Instant::now() + interval;
+            loop {
+                let message = match timeout_at(next_announce, tracker.next()).await {
+                    Err(_) => break,
+                    Ok(None) => return Ok(()),
+                    Ok(Some(message)) => message?,
+                };
+                match message {
+                    WsMessage::Announced {
+                        interval: Some(seconds),
+                        ..
+                    } => interval = Duration::from_secs(seconds.max(1)),
+                    WsMessage::Announced { .. } => {}
+                    WsMessage::Failure(reason) => warn!(reason, "tracker refused announce"),
+                    WsMessage::Offer {
+                        peer_id,
+                        offer_id,
+                        sdp,
+                    } => {
+                        if peer_id == self.peer_id {
+                            continue;
+                        }
+                        let (answer, offer) = match connector.answer(sdp).await {
+                            Ok(answer) => answer,
+                            Err(e) => {
+                                debug!(error = %e, "failed to answer a WebRTC offer");
+                                continue;
+                            }
+                        };
+                        let request = answer_request(
+                            &self.info_hash,
+                            &self.peer_id,
+                            &peer_id,
+                            &offer_id,
+                            &answer,
+                        );
+                        tracker.send(&request).await?;
+                        tokio::spawn(self.clone().connect(offer, None));
+                    }
+                    WsMessage::Answer { offer_id, sdp, .. } => {
+                        let offer = <[u8; 20]>::try_from(offer_id)
+                            .ok()
+                            .and_then(|offer_id| pending.remove(&offer_id));
+                        if let Some(offer) = offer {
+                            tokio::spawn(self.clone().connect(offer, Some(sdp)));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Opens the data channel of `offer`, ours if the peer sent `answer`,
+    /// and shakes hands over it. The side that made the offer speaks first.
+    async fn connect(self: Arc<Self>, offer: PendingOffer, answer: Option<String>) {
+        let result = timeout(self.handshake_timeout, async {
+            let initiating = answer.is_some();
+            let mut stream = match answer {
+                Some(answer) => offer.answered(answer).await?,
+                None => offer.open().await?,
+            };
+            let peer = Peer {
+                // browsers hide behind mDNS names, which tell us nothing
+                addr: stream
+                    .remote_addr()
+                    .unwrap_or(SocketAddr::from(([0, 0, 0, 0], 0))),
+                peer_id: None,
+                source: PeerSource::WebRtc,
+            };
+            let (peer_id, capabilities) = if initiating {
+                initiate_handshake(&mut stream, &self.handshake, &self.info_hash, &peer).await?
+            } else {
+                accept_handshake(&mut stream, &self.handshake, &self.info_hash, &peer).await?
+            };
+            let stream: Box<dyn PeerTransport> = Box::new(stream);
+            Ok::<_, ClientError>((peer_id, capabilities, peer, stream))
+        })
+        .await;
+        match result {
+            Ok(Ok(peer)) => {
+                let _ = self.incoming.clone().send(peer).await;
+            }
+            Ok(Err(e)) => debug!(error = %e, "WebRTC peer failed to connect"),
+            Err(_) => debug!("WebRTC peer timed out"),
+        }
+    }
+}
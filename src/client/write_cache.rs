@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+
+use bytes::{Bytes, BytesMut};
+
+use super::budget::{MemoryBudget, MemoryKind, Reservation};
+
+enum PieceData {
+    /// Blocks are still coming in.
+    Assembling(BytesMut),
+    /// Every block is in, waiting on its hash check or its write.
+    Assembled(Bytes),
+}
+
+struct CachedPiece {
+    data: PieceData,
+    _reservation: Reservation,
+}
+
+/// Whole pieces assembled in memory, so they can be checked before anything
+/// is written and then written in one go. Holds at most `capacity` bytes,
+/// and never enough to exhaust the memory budget by itself, as that would
+/// stop the blocks its pieces are waiting on from being requested. Pieces
+/// that don't fit are written to disk block by block instead.
+pub struct WriteCache {
+    capacity: usize,
+    used: usize,
+    budget: MemoryBudget,
+    pieces: HashMap<usize, CachedPiece>,
+}
+
+impl std::fmt::Debug for WriteCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WriteCache")
+            .field("capacity", &self.capacity)
+            .field("used", &self.used)
+            .field("pieces", &self.pieces.len())
+            .finish()
+    }
+}
+
+impl WriteCache {
+    pub fn new(capacity: usize, budget: MemoryBudget) -> Self {
+        Self {
+            capacity,
+            used: 0,
+            budget,
+            pieces: HashMap::new(),
+        }
+    }
+
+    pub fn contains(&self, index: usize) -> bool {
+        self.pieces.contains_key(&index)
+    }
+
+    /// Makes room for piece `index` of `length` bytes. Returns false if the
+    /// cache is too full to take it.
+    pub fn admit(&mut self, index: usize, length: usize) -> bool {
+        if self.contains(index) {
+            return true;
+        }
+        if self.used + length > self.capacity || self.budget.used() + length >= self.budget.limit()
+        {
+            return false;
+        }
+        self.used += length;
+        self.pieces.insert(
+            index,
+            CachedPiece {
+                data: PieceData::Assembling(BytesMut::zeroed(length)),
+                _reservation: self.budget.reserve(MemoryKind::PieceAssembly, length),
+            },
+        );
+        true
+    }
+
+    /// Copies block data into an admitted piece that is still assembling.
+    pub fn insert(&mut self, index: usize, begin: usize, data: &[u8]) {
+        if let Some(CachedPiece {
+            data: PieceData::Assembling(piece),
+            ..
+        }) = self.pieces.get_mut(&index)
+        {
+            piece[begin..begin + data.len()].copy_from_slice(data);
+        }
+    }
+
+    /// Marks piece `index` as fully assembled and returns its data.
+    pub fn assemble(&mut self, index: usize) -> Option<Bytes> {
+        let cached = self.pieces.get_mut(&index)?;
+        if let PieceData::Assembling(piece) = &mut cached.data {
+            cached.data = PieceData::Assembled(std::mem::take(piece).freeze());
+        }
+        match &cached.data {
+            PieceData::Assembled(data) => Some(data.clone()),
+            PieceData::Assembling(_) => None,
+        }
+    }
+
+    /// The data of piece `index`, if it is fully assembled.
+    pub fn assembled(&self, index: usize) -> Option<Bytes> {
+        match &self.pieces.get(&index)?.data {
+            PieceData::Assembled(data) => Some(data.clone()),
+            PieceData::Assembling(_) => None,
+        }
+    }
+
+    /// Takes piece `index` out of the cache if it is still assembling,
+    /// returning what it has so far.
+    pub fn take_assembling(&mut self, index: usize) -> Option<Bytes> {
+        let data = match &mut self.pieces.get_mut(&index)?.data {
+            PieceData::Assembling(piece) => std::mem::take(piece).freeze(),
+            PieceData::Assembled(_) => return None,
+        };
+        self.pieces.remove(&index);
+        self.used -= data.len();
+        Some(data)
+    }
+
+    /// Drops piece `index`, giving its room back.
+    pub fn remove(&mut self, index: usize) {
+        if let Some(cached) = self.pieces.remove(&index) {
+            self.used -= match &cached.data {
+                PieceData::Assembling(piece) => piece.len(),
+                PieceData::Assembled(data) => data.len(),
+            };
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.pieces.clear();
+        self.used = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assembles_pieces_within_capacity() {
+        let budget = MemoryBudget::new(1 << 20);
+        let mut cache = WriteCache::new(8, budget.clone());
+        assert!(cache.admit(0, 4));
+        assert!(cache.admit(1, 4));
+        assert!(!cache.admit(2, 1));
+        assert_eq!(budget.used_by(MemoryKind::PieceAssembly), 8);
+
+        let small = MemoryBudget::new(4);
+        assert!(!WriteCache::new(8, small).admit(0, 4));
+
+        cache.insert(0, 2, b"cd");
+        cache.insert(0, 0, b"ab");
+        assert_eq!(cache.assembled(0), None);
+        assert_eq!(cache.assemble(0).as_deref(), Some(&b"abcd"[..]));
+        assert_eq!(cache.assembled(0).as_deref(), Some(&b"abcd"[..]));
+
+        cache.remove(0);
+        assert!(!cache.contains(0));
+        assert_eq!(budget.used_by(MemoryKind::PieceAssembly), 4);
+        assert!(cache.admit(2, 4));
+        cache.clear();
+        assert_eq!(budget.used(), 0);
+    }
+}
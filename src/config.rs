@@ -0,0 +1,670 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::RangeInclusive,
+    time::Duration,
+};
+
+pub const DEFAULT_BLOCK_SIZE: u32 = 2 << 13; // 16KB
+pub const DEFAULT_PORT: u16 = 6881;
+pub const DEFAULT_MEMORY_BUDGET: usize = 64 << 20; // 64MB
+pub const DEFAULT_WRITE_CACHE_SIZE: usize = 16 << 20; // 16MB
+pub const DEFAULT_READ_CACHE_SIZE: usize = 16 << 20; // 16MB
+pub const DEFAULT_PEER_ID_PREFIX: &str = "-rT0001-";
+pub const DEFAULT_DHT_BOOTSTRAP_NODES: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+/// STUN servers WebRTC peers use to find their public addresses.
+pub const DEFAULT_ICE_SERVERS: &[&str] = &[
+    "stun:stun.l.google.com:19302",
+    "stun:global.stun.twilio.com:3478",
+];
+
+/// Whether peer connections use Message Stream Encryption.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncryptionPolicy {
+    /// Plaintext only.
+    Disabled,
+    /// Encrypt when the peer supports it, otherwise fall back to plaintext.
+    Enabled,
+    /// Only talk to peers that encrypt.
+    Forced,
+}
+
+impl std::str::FromStr for EncryptionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disabled" => Ok(Self::Disabled),
+            "enabled" => Ok(Self::Enabled),
+            "forced" => Ok(Self::Forced),
+            _ => Err(format!(
+                "unknown encryption policy `{}`, expected disabled, enabled or forced",
+                s
+            )),
+        }
+    }
+}
+
+/// How space for the torrent's files is set aside before they are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileAllocation {
+    /// Files grow as pieces are written.
+    None,
+    /// Files are extended to full size up front without reserving disk
+    /// space, on filesystems that support holes.
+    Sparse,
+    /// Disk space for every file is reserved up front, so running out of
+    /// space fails at the start rather than halfway through.
+    Full,
+}
+
+/// How the torrent's files are read and written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageBackend {
+    /// Positional reads and writes on the open files.
+    Pread,
+    /// Reads straight from memory maps of the files, which saves a system
+    /// call and a copy per read when verifying and serving large torrents.
+    /// Needs the `mmap` feature and a 64-bit target, and falls back to
+    /// `Pread` without them. Writes stay positional, and files that aren't
+    /// full size when opened, without allocation, are read as with `Pread`.
+    Mmap,
+}
+
+impl std::str::FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pread" => Ok(Self::Pread),
+            "mmap" => Ok(Self::Mmap),
+            _ => Err(format!(
+                "unknown storage backend `{}`, expected pread or mmap",
+                s
+            )),
+        }
+    }
+}
+
+impl std::str::FromStr for FileAllocation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "sparse" => Ok(Self::Sparse),
+            "full" => Ok(Self::Full),
+            _ => Err(format!(
+                "unknown file allocation `{}`, expected none, sparse or full",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyKind {
+    Socks5,
+    /// A proxy that tunnels with `CONNECT`.
+    Http,
+}
+
+/// A proxy that tracker requests and outgoing peer connections go through.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyConfig {
+    pub kind: ProxyKind,
+    pub addr: SocketAddr,
+    /// Username and password, if the proxy wants them.
+    pub credentials: Option<(String, String)>,
+}
+
+impl std::str::FromStr for ProxyConfig {
+    type Err = String;
+
+    /// Parses `socks5://[user:password@]host:port` or the same with `http://`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = s
+            .split_once("://")
+            .ok_or_else(|| format!("proxy `{}` has no scheme", s))?;
+        let kind = match scheme {
+            "socks5" => ProxyKind::Socks5,
+            "http" => ProxyKind::Http,
+            _ => return Err(format!("unsupported proxy scheme `{}`", scheme)),
+        };
+        let rest = rest.trim_end_matches('/');
+        let (credentials, addr) = match rest.rsplit_once('@') {
+            Some((user, addr)) => {
+                let (name, password) = user
+                    .split_once(':')
+                    .ok_or_else(|| "proxy credentials must be user:password".to_string())?;
+                (Some((name.to_string(), password.to_string())), addr)
+            }
+            None => (None, rest),
+        };
+        let addr = addr
+            .parse()
+            .map_err(|_| format!("proxy address `{}` is not ip:port", addr))?;
+        Ok(Self {
+            kind,
+            addr,
+            credentials,
+        })
+    }
+}
+
+/// How HTTP and HTTPS trackers are talked to. Requests made with the same
+/// settings share a pool of connections, whichever torrent makes them.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TrackerConfig {
+    /// Time allowed for a whole announce or scrape request.
+    pub timeout: Duration,
+    /// Time allowed for connecting to a tracker, within `timeout`.
+    pub connect_timeout: Duration,
+    /// How long an idle connection to a tracker is kept for the next request.
+    pub pool_idle_timeout: Duration,
+    /// Times a request is tried again when it gets no answer or a server
+    /// error, after a randomized wait that doubles each time.
+    pub retries: u32,
+    pub user_agent: String,
+    /// PEM certificates to trust for HTTPS trackers instead of the system's
+    /// root certificates, such as a private tracker's own CA.
+    pub tls_roots: Option<Vec<Vec<u8>>>,
+    /// SHA-256 fingerprints of the DER certificates HTTPS trackers may
+    /// present. When set, a tracker is trusted if and only if its certificate
+    /// is one of these, which also works for self-signed certificates. The
+    /// certificate is checked when the response arrives, so this stops us
+    /// believing an impostor, not the request reaching one.
+    pub pinned_certificates: Vec<[u8; 32]>,
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(30),
+            connect_timeout: Duration::from_secs(10),
+            pool_idle_timeout: Duration::from_secs(90),
+            retries: 2,
+            user_agent: format!("rustorrent/{}", env!("CARGO_PKG_VERSION")),
+            tls_roots: None,
+            pinned_certificates: Vec::new(),
+        }
+    }
+}
+
+/// Caps shared by every torrent of a session, on top of each torrent's own.
+/// A client run on its own applies them to itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionLimits {
+    pub max_peers: usize,
+    /// Outgoing connections still being set up, which is what fills router
+    /// NAT tables.
+    pub max_half_open: usize,
+    pub upload_slots: usize,
+}
+
+impl Default for SessionLimits {
+    fn default() -> Self {
+        Self {
+            max_peers: 200,
+            max_half_open: 32,
+            upload_slots: 16,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Bytes asked for in each request, at most 16 KiB as peers refuse
+    /// larger ones.
+    pub block_size: u32,
+    /// Requests kept in flight to each peer at least. Faster peers get more,
+    /// up to `max_pipeline_depth`.
+    pub pipeline_depth: usize,
+    pub max_pipeline_depth: usize,
+    /// Seconds of a peer's download rate to keep requested ahead, on top of
+    /// its round trip time.
+    pub request_queue_time: Duration,
+    pub max_peers: usize,
+    /// Outgoing connections being set up at once.
+    pub max_half_open: usize,
+    pub connect_timeout: Duration,
+    /// Outgoing connections are given up on if the handshake isn't done this
+    /// long after dialing, and incoming ones if the peer's hasn't arrived.
+    pub handshake_timeout: Duration,
+    pub keep_alive_interval: Duration,
+    /// Peers that send nothing, not even a keep-alive, for this long are
+    /// dropped.
+    pub peer_timeout: Duration,
+    /// Peers that unchoke us but leave our requests unanswered this long are
+    /// snubbing us.
+    pub snub_timeout: Duration,
+    pub numwant: u32,
+    pub port: u16,
+    /// When `port` is taken, the ports after it up to this one are tried in
+    /// turn.
+    pub port_range_end: Option<u16>,
+    /// Announced as our IPv4 address, in place of the one the tracker sees
+    /// or a port mapping reports.
+    pub announce_ip: Option<Ipv4Addr>,
+    /// Announced as our IPv6 address. When unset and we listen on IPv6, the
+    /// host's global address is announced if it has one.
+    pub announce_ipv6: Option<Ipv6Addr>,
+    pub encryption: EncryptionPolicy,
+    /// Route tracker requests and outgoing peer connections through a proxy.
+    /// UDP trackers and the DHT are not used while one is set.
+    pub proxy: Option<ProxyConfig>,
+    /// Threads used for piece hashing; 0 means one per core.
+    pub hash_threads: usize,
+    /// Soft cap on bytes held in memory for in-flight data.
+    pub memory_budget: usize,
+    /// Bytes of pieces assembled in memory, hash checked and only then
+    /// written whole. Pieces that don't fit are written block by block as
+    /// they arrive; 0 writes every piece that way.
+    pub write_cache_size: usize,
+    /// Bytes of recently read pieces kept in memory for serving uploads.
+    pub read_cache_size: usize,
+    /// Accept incoming connections on `port`.
+    pub listen: bool,
+    /// Interested peers we upload to at the same time.
+    pub upload_slots: usize,
+    /// The torrent's claim on the session's upload slots, relative to the
+    /// other torrents'. When they run short, each torrent gets slots in
+    /// proportion to its weight.
+    pub upload_weight: u32,
+    /// Look up peers on the DHT as well as the tracker. Ignored for private
+    /// torrents.
+    pub dht: bool,
+    pub dht_bootstrap: Vec<String>,
+    /// Ask the router to forward `port` with NAT-PMP or UPnP while listening.
+    pub nat: bool,
+    /// Relay hole punches between peers behind NATs, and ask peers to relay
+    /// ours to peers we can't dial (BEP 55). Ignored for private torrents.
+    pub holepunch: bool,
+    /// Announce to the torrent's WebSocket trackers and connect to browser
+    /// peers over WebRTC. Needs the `webtorrent` feature, and is off behind a
+    /// proxy.
+    pub webtorrent: bool,
+    /// STUN and TURN server URLs for WebRTC connections.
+    pub ice_servers: Vec<String>,
+    /// Drop peers that send message ids we don't know instead of ignoring
+    /// the messages.
+    pub drop_unknown_messages: bool,
+    pub file_allocation: FileAllocation,
+    pub storage: StorageBackend,
+    /// Replace characters that can't be in a file name, and reserved names,
+    /// instead of refusing the torrent.
+    pub sanitize_paths: bool,
+    /// Write files as `name.part` until all their pieces check out, so
+    /// other programs don't pick up half-downloaded files.
+    pub part_files: bool,
+    /// Move the files here once the download finishes, laid out as they
+    /// were under the output directory.
+    pub move_completed_to: Option<String>,
+    /// A program to run once the download finishes, after any move. It gets
+    /// the torrent in `RUSTORRENT_NAME`, `RUSTORRENT_INFO_HASH`,
+    /// `RUSTORRENT_DIR` (the directory it was saved under) and
+    /// `RUSTORRENT_PATH` (its file or top directory).
+    pub on_complete: Option<String>,
+    /// Keep uploading after the download until we have shared this many
+    /// times the torrent's size.
+    pub seed_ratio: Option<f64>,
+    /// Keep uploading after the download for this long in total, across
+    /// restarts. With a ratio too, seeding stops at whichever comes first.
+    pub seed_time: Option<Duration>,
+    /// The start of our peer id, such as `-rT0001-` in Azureus style. The
+    /// rest of the 20 bytes is random, and a longer prefix is cut short.
+    pub peer_id_prefix: String,
+    /// Our name and version in extended handshakes, which peers show.
+    pub client_name: String,
+    pub tracker: TrackerConfig,
+    pub session_limits: SessionLimits,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            block_size: DEFAULT_BLOCK_SIZE,
+            pipeline_depth: 5,
+            max_pipeline_depth: 250,
+            request_queue_time: Duration::from_secs(3),
+            max_peers: 30,
+            max_half_open: 8,
+            connect_timeout: Duration::from_secs(5),
+            handshake_timeout: Duration::from_secs(10),
+            keep_alive_interval: Duration::from_secs(60),
+            peer_timeout: Duration::from_secs(120),
+            snub_timeout: Duration::from_secs(60),
+            numwant: 100,
+            port: DEFAULT_PORT,
+            port_range_end: None,
+            announce_ip: None,
+            announce_ipv6: None,
+            encryption: EncryptionPolicy::Disabled,
+            proxy: None,
+            hash_threads: 0,
+            memory_budget: DEFAULT_MEMORY_BUDGET,
+            write_cache_size: DEFAULT_WRITE_CACHE_SIZE,
+            read_cache_size: DEFAULT_READ_CACHE_SIZE,
+            listen: true,
+            upload_slots: 4,
+            upload_weight: 1,
+            dht: false,
+            dht_bootstrap: DEFAULT_DHT_BOOTSTRAP_NODES
+                .iter()
+                .map(|node| node.to_string())
+                .collect(),
+            nat: false,
+            holepunch: true,
+            webtorrent: true,
+            ice_servers: DEFAULT_ICE_SERVERS
+                .iter()
+                .map(|server| server.to_string())
+                .collect(),
+            drop_unknown_messages: false,
+            file_allocation: FileAllocation::Sparse,
+            storage: StorageBackend::Pread,
+            sanitize_paths: false,
+            part_files: false,
+            move_completed_to: None,
+            on_complete: None,
+            seed_ratio: None,
+            seed_time: None,
+            peer_id_prefix: DEFAULT_PEER_ID_PREFIX.to_string(),
+            client_name: format!("rustorrent {}", env!("CARGO_PKG_VERSION")),
+            tracker: TrackerConfig::default(),
+            session_limits: SessionLimits::default(),
+        }
+    }
+}
+
+impl ClientConfig {
+    pub fn builder() -> ClientConfigBuilder {
+        ClientConfigBuilder::default()
+    }
+
+    /// Brings the request settings into the range peers will serve, however
+    /// the config was put together.
+    pub(crate) fn clamped(mut self) -> Self {
+        self.block_size = self.block_size.clamp(1, DEFAULT_BLOCK_SIZE);
+        self.pipeline_depth = self.pipeline_depth.max(1);
+        self.max_pipeline_depth = self.max_pipeline_depth.max(self.pipeline_depth);
+        self
+    }
+
+    /// The ports to listen on, in the order they are tried.
+    pub fn ports(&self) -> RangeInclusive<u16> {
+        self.port..=self.port_range_end.unwrap_or(self.port).max(self.port)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ClientConfigBuilder {
+    config: ClientConfig,
+}
+
+impl ClientConfigBuilder {
+    pub fn block_size(mut self, block_size: u32) -> Self {
+        self.config.block_size = block_size;
+        self
+    }
+
+    pub fn pipeline_depth(mut self, pipeline_depth: usize) -> Self {
+        self.config.pipeline_depth = pipeline_depth;
+        self
+    }
+
+    pub fn max_pipeline_depth(mut self, max_pipeline_depth: usize) -> Self {
+        self.config.max_pipeline_depth = max_pipeline_depth;
+        self
+    }
+
+    pub fn request_queue_time(mut self, request_queue_time: Duration) -> Self {
+        self.config.request_queue_time = request_queue_time;
+        self
+    }
+
+    pub fn max_peers(mut self, max_peers: usize) -> Self {
+        self.config.max_peers = max_peers;
+        self
+    }
+
+    pub fn max_half_open(mut self, max_half_open: usize) -> Self {
+        self.config.max_half_open = max_half_open;
+        self
+    }
+
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.config.connect_timeout = connect_timeout;
+        self
+    }
+
+    pub fn handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.config.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    pub fn keep_alive_interval(mut self, keep_alive_interval: Duration) -> Self {
+        self.config.keep_alive_interval = keep_alive_interval;
+        self
+    }
+
+    pub fn peer_timeout(mut self, peer_timeout: Duration) -> Self {
+        self.config.peer_timeout = peer_timeout;
+        self
+    }
+
+    pub fn snub_timeout(mut self, snub_timeout: Duration) -> Self {
+        self.config.snub_timeout = snub_timeout;
+        self
+    }
+
+    pub fn numwant(mut self, numwant: u32) -> Self {
+        self.config.numwant = numwant;
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.config.port = port;
+        self.config.port_range_end = None;
+        self
+    }
+
+    /// Listens on the first free port of `ports`.
+    pub fn port_range(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.config.port = *ports.start();
+        self.config.port_range_end = Some(*ports.end());
+        self
+    }
+
+    pub fn announce_ip(mut self, ip: Ipv4Addr) -> Self {
+        self.config.announce_ip = Some(ip);
+        self
+    }
+
+    pub fn announce_ipv6(mut self, ip: Ipv6Addr) -> Self {
+        self.config.announce_ipv6 = Some(ip);
+        self
+    }
+
+    pub fn encryption(mut self, encryption: EncryptionPolicy) -> Self {
+        self.config.encryption = encryption;
+        self
+    }
+
+    pub fn proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.config.proxy = Some(proxy);
+        self
+    }
+
+    pub fn hash_threads(mut self, hash_threads: usize) -> Self {
+        self.config.hash_threads = hash_threads;
+        self
+    }
+
+    pub fn memory_budget(mut self, memory_budget: usize) -> Self {
+        self.config.memory_budget = memory_budget;
+        self
+    }
+
+    pub fn write_cache_size(mut self, write_cache_size: usize) -> Self {
+        self.config.write_cache_size = write_cache_size;
+        self
+    }
+
+    pub fn read_cache_size(mut self, read_cache_size: usize) -> Self {
+        self.config.read_cache_size = read_cache_size;
+        self
+    }
+
+    pub fn listen(mut self, listen: bool) -> Self {
+        self.config.listen = listen;
+        self
+    }
+
+    pub fn upload_slots(mut self, upload_slots: usize) -> Self {
+        self.config.upload_slots = upload_slots;
+        self
+    }
+
+    pub fn upload_weight(mut self, upload_weight: u32) -> Self {
+        self.config.upload_weight = upload_weight;
+        self
+    }
+
+    pub fn dht(mut self, dht: bool) -> Self {
+        self.config.dht = dht;
+        self
+    }
+
+    pub fn dht_bootstrap(mut self, dht_bootstrap: Vec<String>) -> Self {
+        self.config.dht_bootstrap = dht_bootstrap;
+        self
+    }
+
+    pub fn nat(mut self, nat: bool) -> Self {
+        self.config.nat = nat;
+        self
+    }
+
+    pub fn holepunch(mut self, holepunch: bool) -> Self {
+        self.config.holepunch = holepunch;
+        self
+    }
+
+    pub fn webtorrent(mut self, webtorrent: bool) -> Self {
+        self.config.webtorrent = webtorrent;
+        self
+    }
+
+    pub fn ice_servers(mut self, ice_servers: Vec<String>) -> Self {
+        self.config.ice_servers = ice_servers;
+        self
+    }
+
+    pub fn drop_unknown_messages(mut self, drop_unknown_messages: bool) -> Self {
+        self.config.drop_unknown_messages = drop_unknown_messages;
+        self
+    }
+
+    pub fn file_allocation(mut self, file_allocation: FileAllocation) -> Self {
+        self.config.file_allocation = file_allocation;
+        self
+    }
+
+    pub fn storage(mut self, storage: StorageBackend) -> Self {
+        self.config.storage = storage;
+        self
+    }
+
+    pub fn sanitize_paths(mut self, sanitize_paths: bool) -> Self {
+        self.config.sanitize_paths = sanitize_paths;
+        self
+    }
+
+    pub fn part_files(mut self, part_files: bool) -> Self {
+        self.config.part_files = part_files;
+        self
+    }
+
+    pub fn move_completed_to(mut self, move_completed_to: String) -> Self {
+        self.config.move_completed_to = Some(move_completed_to);
+        self
+    }
+
+    pub fn on_complete(mut self, on_complete: String) -> Self {
+        self.config.on_complete = Some(on_complete);
+        self
+    }
+
+    pub fn seed_ratio(mut self, seed_ratio: f64) -> Self {
+        self.config.seed_ratio = Some(seed_ratio);
+        self
+    }
+
+    pub fn seed_time(mut self, seed_time: Duration) -> Self {
+        self.config.seed_time = Some(seed_time);
+        self
+    }
+
+    pub fn peer_id_prefix(mut self, peer_id_prefix: String) -> Self {
+        self.config.peer_id_prefix = peer_id_prefix;
+        self
+    }
+
+    pub fn client_name(mut self, client_name: String) -> Self {
+        self.config.client_name = client_name;
+        self
+    }
+
+    /// The `User-Agent` of HTTP tracker requests.
+    pub fn user_agent(mut self, user_agent: String) -> Self {
+        self.config.tracker.user_agent = user_agent;
+        self
+    }
+
+    pub fn tracker(mut self, tracker: TrackerConfig) -> Self {
+        self.config.tracker = tracker;
+        self
+    }
+
+    pub fn session_limits(mut self, session_limits: SessionLimits) -> Self {
+        self.config.session_limits = session_limits;
+        self
+    }
+
+    /// Brings settings the client can't work with back into range: the
+    /// block size to 1 byte through 16 KiB, and the pipeline to at least one
+    /// request, up to a maximum no lower than that.
+    pub fn build(self) -> ClientConfig {
+        self.config.clamped()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamps_request_settings() {
+        let config = ClientConfig::builder()
+            .block_size(0)
+            .pipeline_depth(0)
+            .max_pipeline_depth(0)
+            .build();
+        assert_eq!(config.block_size, 1);
+        assert_eq!(config.pipeline_depth, 1);
+        assert_eq!(config.max_pipeline_depth, 1);
+
+        let config = ClientConfig::builder()
+            .block_size(1 << 20)
+            .pipeline_depth(50)
+            .max_pipeline_depth(10)
+            .build();
+        assert_eq!(config.block_size, DEFAULT_BLOCK_SIZE);
+        assert_eq!(config.max_pipeline_depth, 50);
+    }
+}
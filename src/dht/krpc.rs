@@ -0,0 +1,371 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+};
+
+use crate::bencode::{BencodeString, BencodeValue};
+
+use super::NodeId;
+
+const COMPACT_NODE_LEN: usize = 26;
+const COMPACT_PEER_LEN: usize = 6;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KrpcError {
+    pub message: String,
+}
+
+impl Display for KrpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "KrpcError: {}", self.message)
+    }
+}
+
+impl std::error::Error for KrpcError {}
+
+fn error(message: &str) -> KrpcError {
+    KrpcError {
+        message: message.to_string(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddrV4,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    Ping,
+    FindNode {
+        target: NodeId,
+    },
+    GetPeers {
+        info_hash: NodeId,
+    },
+    AnnouncePeer {
+        info_hash: NodeId,
+        port: u16,
+        token: Vec<u8>,
+        implied_port: bool,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Response {
+    pub id: NodeId,
+    pub nodes: Vec<NodeInfo>,
+    pub values: Vec<SocketAddr>,
+    pub token: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Body {
+    Query { id: NodeId, query: Query },
+    Response(Response),
+    Error { code: i64, message: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KrpcMessage {
+    pub transaction: Vec<u8>,
+    pub body: Body,
+}
+
+fn bytes(value: &[u8]) -> BencodeValue {
+    BencodeValue::String(BencodeString::Bytes(value.to_vec()))
+}
+
+fn string(value: &str) -> BencodeValue {
+    BencodeValue::String(BencodeString::String(value.to_string()))
+}
+
+fn get_bytes<'a>(dict: &'a BTreeMap<String, BencodeValue>, key: &str) -> Option<&'a [u8]> {
+    match dict.get(key) {
+        Some(BencodeValue::String(value)) => Some(value.as_bytes()),
+        _ => None,
+    }
+}
+
+fn get_id(dict: &BTreeMap<String, BencodeValue>, key: &str) -> Result<NodeId, KrpcError> {
+    get_bytes(dict, key)
+        .and_then(|id| id.try_into().ok())
+        .ok_or_else(|| error(&format!("missing or invalid {}", key)))
+}
+
+pub fn encode_nodes(nodes: &[NodeInfo]) -> Vec<u8> {
+    let mut compact = Vec::with_capacity(nodes.len() * COMPACT_NODE_LEN);
+    for node in nodes {
+        compact.extend_from_slice(&node.id);
+        compact.extend_from_slice(&node.addr.ip().octets());
+        compact.extend_from_slice(&node.addr.port().to_be_bytes());
+    }
+    compact
+}
+
+pub fn decode_nodes(compact: &[u8]) -> Vec<NodeInfo> {
+    compact
+        .chunks_exact(COMPACT_NODE_LEN)
+        .map(|node| NodeInfo {
+            id: node[..20].try_into().unwrap(),
+            addr: decode_addr(&node[20..]),
+        })
+        .collect()
+}
+
+fn encode_addr(addr: &SocketAddrV4) -> Vec<u8> {
+    let mut compact = addr.ip().octets().to_vec();
+    compact.extend_from_slice(&addr.port().to_be_bytes());
+    compact
+}
+
+fn decode_addr(compact: &[u8]) -> SocketAddrV4 {
+    SocketAddrV4::new(
+        Ipv4Addr::new(compact[0], compact[1], compact[2], compact[3]),
+        u16::from_be_bytes([compact[4], compact[5]]),
+    )
+}
+
+impl KrpcMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut message = BTreeMap::from([("t".to_string(), bytes(&self.transaction))]);
+
+        match &self.body {
+            Body::Query { id, query } => {
+                let mut args = BTreeMap::from([("id".to_string(), bytes(id))]);
+                let name = match query {
+                    Query::Ping => "ping",
+                    Query::FindNode { target } => {
+                        args.insert("target".to_string(), bytes(target));
+                        "find_node"
+                    }
+                    Query::GetPeers { info_hash } => {
+                        args.insert("info_hash".to_string(), bytes(info_hash));
+                        "get_peers"
+                    }
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        token,
+                        implied_port,
+                    } => {
+                        args.insert("info_hash".to_string(), bytes(info_hash));
+                        args.insert("port".to_string(), BencodeValue::Int(*port as i64));
+                        args.insert("token".to_string(), bytes(token));
+                        if *implied_port {
+                            args.insert("implied_port".to_string(), BencodeValue::Int(1));
+                        }
+                        "announce_peer"
+                    }
+                };
+                message.insert("y".to_string(), string("q"));
+                message.insert("q".to_string(), string(name));
+                message.insert("a".to_string(), BencodeValue::Dict(args));
+            }
+            Body::Response(response) => {
+                let mut values = BTreeMap::from([("id".to_string(), bytes(&response.id))]);
+                if !response.nodes.is_empty() {
+                    values.insert("nodes".to_string(), bytes(&encode_nodes(&response.nodes)));
+                }
+                let peers = response
+                    .values
+                    .iter()
+                    .filter_map(|addr| match addr {
+                        SocketAddr::V4(addr) => Some(bytes(&encode_addr(addr))),
+                        SocketAddr::V6(_) => None,
+                    })
+                    .collect::<Vec<_>>();
+                if !peers.is_empty() {
+                    values.insert("values".to_string(), BencodeValue::List(peers));
+                }
+                if let Some(token) = &response.token {
+                    values.insert("token".to_string(), bytes(token));
+                }
+                message.insert("y".to_string(), string("r"));
+                message.insert("r".to_string(), BencodeValue::Dict(values));
+            }
+            Body::Error {
+                code,
+                message: text,
+            } => {
+                message.insert("y".to_string(), string("e"));
+                message.insert(
+                    "e".to_string(),
+                    BencodeValue::List(vec![BencodeValue::Int(*code), string(text)]),
+                );
+            }
+        }
+
+        BencodeValue::Dict(message).encode()
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, KrpcError> {
+        let (value, _) = BencodeValue::parse(data).map_err(|e| error(&e.message))?;
+        let BencodeValue::Dict(message) = value else {
+            return Err(error("message is not a dictionary"));
+        };
+
+        let transaction = get_bytes(&message, "t")
+            .ok_or_else(|| error("missing transaction id"))?
+            .to_vec();
+
+        let body = match get_bytes(&message, "y") {
+            Some(b"q") => Self::decode_query(&message)?,
+            Some(b"r") => Self::decode_response(&message)?,
+            Some(b"e") => match message.get("e") {
+                Some(BencodeValue::List(list)) => match list.as_slice() {
+                    [BencodeValue::Int(code), BencodeValue::String(text), ..] => Body::Error {
+                        code: *code,
+                        message: String::from_utf8_lossy(text.as_bytes()).to_string(),
+                    },
+                    _ => return Err(error("invalid error body")),
+                },
+                _ => return Err(error("missing error body")),
+            },
+            _ => return Err(error("unknown message type")),
+        };
+
+        Ok(Self { transaction, body })
+    }
+
+    fn decode_query(message: &BTreeMap<String, BencodeValue>) -> Result<Body, KrpcError> {
+        let Some(BencodeValue::Dict(args)) = message.get("a") else {
+            return Err(error("missing query arguments"));
+        };
+        let id = get_id(args, "id")?;
+
+        let query = match get_bytes(message, "q") {
+            Some(b"ping") => Query::Ping,
+            Some(b"find_node") => Query::FindNode {
+                target: get_id(args, "target")?,
+            },
+            Some(b"get_peers") => Query::GetPeers {
+                info_hash: get_id(args, "info_hash")?,
+            },
+            Some(b"announce_peer") => {
+                let port = match args.get("port") {
+                    Some(BencodeValue::Int(port)) => u16::try_from(*port).ok(),
+                    _ => None,
+                };
+                let implied_port = matches!(args.get("implied_port"), Some(BencodeValue::Int(1)));
+                Query::AnnouncePeer {
+                    info_hash: get_id(args, "info_hash")?,
+                    port: port
+                        .or(implied_port.then_some(0))
+                        .ok_or_else(|| error("invalid port"))?,
+                    token: get_bytes(args, "token")
+                        .ok_or_else(|| error("missing token"))?
+                        .to_vec(),
+                    implied_port,
+                }
+            }
+            _ => return Err(error("unknown query")),
+        };
+
+        Ok(Body::Query { id, query })
+    }
+
+    fn decode_response(message: &BTreeMap<String, BencodeValue>) -> Result<Body, KrpcError> {
+        let Some(BencodeValue::Dict(values)) = message.get("r") else {
+            return Err(error("missing response values"));
+        };
+
+        let peers = match values.get("values") {
+            Some(BencodeValue::List(peers)) => peers
+                .iter()
+                .filter_map(|peer| match peer {
+                    BencodeValue::String(peer) if peer.as_bytes().len() == COMPACT_PEER_LEN => {
+                        Some(SocketAddr::V4(decode_addr(peer.as_bytes())))
+                    }
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        Ok(Body::Response(Response {
+            id: get_id(values, "id")?,
+            nodes: get_bytes(values, "nodes")
+                .map(decode_nodes)
+                .unwrap_or_default(),
+            values: peers,
+            token: get_bytes(values, "token").map(|token| token.to_vec()),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(message: KrpcMessage) {
+        assert_eq!(KrpcMessage::decode(&message.encode()), Ok(message));
+    }
+
+    #[test]
+    fn test_queries() {
+        let id = [1; 20];
+        for query in [
+            Query::Ping,
+            Query::FindNode { target: [2; 20] },
+            Query::GetPeers { info_hash: [3; 20] },
+            Query::AnnouncePeer {
+                info_hash: [4; 20],
+                port: 6881,
+                token: vec![0xff, 0x00, 0x17],
+                implied_port: false,
+            },
+        ] {
+            round_trip(KrpcMessage {
+                transaction: vec![0, 1],
+                body: Body::Query { id, query },
+            });
+        }
+    }
+
+    #[test]
+    fn test_response() {
+        round_trip(KrpcMessage {
+            transaction: vec![0xaa, 0xbb],
+            body: Body::Response(Response {
+                id: [9; 20],
+                nodes: vec![NodeInfo {
+                    id: [0xfe; 20],
+                    addr: SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881),
+                }],
+                values: vec![SocketAddr::from(([192, 168, 1, 2], 51413))],
+                token: Some(b"tok".to_vec()),
+            }),
+        });
+    }
+
+    #[test]
+    fn test_ping_from_spec() {
+        let message =
+            KrpcMessage::decode(b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe")
+                .unwrap();
+        assert_eq!(message.transaction, b"aa");
+        assert_eq!(
+            message.body,
+            Body::Query {
+                id: *b"abcdefghij0123456789",
+                query: Query::Ping
+            }
+        );
+    }
+
+    #[test]
+    fn test_error() {
+        let message =
+            KrpcMessage::decode(b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee").unwrap();
+        assert_eq!(
+            message.body,
+            Body::Error {
+                code: 201,
+                message: "A Generic Error Ocurred".to_string()
+            }
+        );
+    }
+}
@@ -0,0 +1,429 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Display,
+    io,
+    net::SocketAddr,
+    pin::pin,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use futures::{
+    channel::oneshot,
+    future::{join_all, select, Either},
+};
+use sha1::{Digest, Sha1};
+use tracing::{debug, info, trace, warn};
+
+use crate::runtime::{timeout, Runtime, UdpSocket};
+
+use self::{
+    krpc::{Body, KrpcError, KrpcMessage, NodeInfo, Query, Response},
+    routing::{distance, RoutingTable, K},
+};
+
+pub mod krpc;
+pub mod routing;
+
+pub type NodeId = [u8; 20];
+
+/// Queries sent in parallel during a lookup.
+const ALPHA: usize = 3;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_PACKET_SIZE: usize = 1500;
+const MAX_PEERS_PER_TORRENT: usize = 200;
+
+#[derive(Debug)]
+pub enum DhtError {
+    Io(io::Error),
+    Timeout,
+    Krpc(KrpcError),
+    Remote(i64, String),
+}
+
+impl Display for DhtError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DhtError::Io(e) => write!(f, "Io: {}", e),
+            DhtError::Timeout => write!(f, "Timeout"),
+            DhtError::Krpc(e) => write!(f, "{}", e),
+            DhtError::Remote(code, message) => write!(f, "Remote: {} {}", code, message),
+        }
+    }
+}
+
+impl std::error::Error for DhtError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DhtError::Io(e) => Some(e),
+            DhtError::Krpc(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DhtError {
+    fn from(e: io::Error) -> Self {
+        DhtError::Io(e)
+    }
+}
+
+impl From<KrpcError> for DhtError {
+    fn from(e: KrpcError) -> Self {
+        DhtError::Krpc(e)
+    }
+}
+
+type PendingQueries = HashMap<(Vec<u8>, SocketAddr), oneshot::Sender<Result<Response, DhtError>>>;
+
+struct Inner<R: Runtime> {
+    runtime: R,
+    socket: R::UdpSocket,
+    id: NodeId,
+    secret: [u8; 20],
+    table: Mutex<RoutingTable>,
+    pending: Mutex<PendingQueries>,
+    next_transaction: AtomicU16,
+    /// Peers announced to us, by info hash.
+    peers: Mutex<HashMap<NodeId, Vec<SocketAddr>>>,
+}
+
+/// A BEP 5 DHT node. The node answers queries from a background task until
+/// it is dropped.
+pub struct Dht<R: Runtime> {
+    inner: Arc<Inner<R>>,
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl<R: Runtime> Dht<R> {
+    pub async fn bind(runtime: R, addr: SocketAddr) -> Result<Self, DhtError> {
+        let socket = runtime.bind_udp(addr).await?;
+        let id = rand::random();
+
+        let inner = Arc::new(Inner {
+            runtime: runtime.clone(),
+            socket,
+            id,
+            secret: rand::random(),
+            table: Mutex::new(RoutingTable::new(id)),
+            pending: Mutex::new(HashMap::new()),
+            next_transaction: AtomicU16::new(rand::random()),
+            peers: Mutex::new(HashMap::new()),
+        });
+
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let task_inner = Arc::clone(&inner);
+        runtime.spawn(async move {
+            let receive = pin!(task_inner.receive_loop());
+            if let Either::Left((Err(e), _)) = select(receive, shutdown_rx).await {
+                warn!(error = %e, "dht socket closed");
+            }
+        });
+
+        Ok(Self {
+            inner,
+            _shutdown: shutdown_tx,
+        })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.inner.id
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.socket.local_addr()
+    }
+
+    pub fn num_nodes(&self) -> usize {
+        self.inner.table.lock().unwrap().len()
+    }
+
+    /// Joins the network through the given nodes, then looks up our own id to
+    /// fill the routing table.
+    pub async fn bootstrap(&self, nodes: &[SocketAddr]) {
+        let queries = nodes.iter().map(|addr| {
+            self.inner.query(
+                *addr,
+                Query::FindNode {
+                    target: self.inner.id,
+                },
+            )
+        });
+        for (addr, result) in nodes.iter().zip(join_all(queries).await) {
+            if let Err(e) = result {
+                debug!(%addr, error = %e, "bootstrap node did not answer");
+            }
+        }
+
+        self.inner.lookup(self.inner.id, false).await;
+        info!(nodes = self.num_nodes(), "dht bootstrapped");
+    }
+
+    /// Pings the node at `addr`, such as one a peer told us of with a PORT
+    /// message, adding it to the routing table if it answers.
+    pub fn add_node(&self, addr: SocketAddr) {
+        let inner = Arc::clone(&self.inner);
+        self.inner.runtime.spawn(async move {
+            if let Err(e) = inner.query(addr, Query::Ping).await {
+                debug!(%addr, error = %e, "dht node did not answer ping");
+            }
+        });
+    }
+
+    /// Finds peers for `info_hash`. With `announce_port` set, we also announce
+    /// ourselves to the closest nodes that handed us a token.
+    pub async fn get_peers(
+        &self,
+        info_hash: NodeId,
+        announce_port: Option<u16>,
+    ) -> Vec<SocketAddr> {
+        let lookup = self.inner.lookup(info_hash, true).await;
+        debug!(
+            peers = lookup.peers.len(),
+            nodes = lookup.tokens.len(),
+            "dht get_peers finished"
+        );
+
+        if let Some(port) = announce_port {
+            let announces = lookup.tokens.into_iter().take(K).map(|(node, token)| {
+                self.inner.query(
+                    SocketAddr::V4(node.addr),
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        token,
+                        implied_port: false,
+                    },
+                )
+            });
+            join_all(announces).await;
+        }
+
+        lookup.peers.into_iter().collect()
+    }
+}
+
+#[derive(Default)]
+struct Lookup {
+    /// Nodes that answered a get_peers, with the token to announce to them.
+    tokens: Vec<(NodeInfo, Vec<u8>)>,
+    peers: HashSet<SocketAddr>,
+}
+
+impl<R: Runtime> Inner<R> {
+    fn next_transaction(&self) -> Vec<u8> {
+        self.next_transaction
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes()
+            .to_vec()
+    }
+
+    async fn send(&self, addr: SocketAddr, message: &KrpcMessage) -> Result<(), DhtError> {
+        self.socket
+            .send_to(&message.encode(), addr)
+            .await
+            .map(|_| ())
+            .map_err(DhtError::Io)
+    }
+
+    async fn query(&self, addr: SocketAddr, query: Query) -> Result<Response, DhtError> {
+        let transaction = self.next_transaction();
+        let (tx, rx) = oneshot::channel();
+        let key = (transaction.clone(), addr);
+        self.pending.lock().unwrap().insert(key.clone(), tx);
+
+        let message = KrpcMessage {
+            transaction,
+            body: Body::Query { id: self.id, query },
+        };
+        let result = match self.send(addr, &message).await {
+            Ok(()) => match timeout(&self.runtime, QUERY_TIMEOUT, rx).await {
+                Some(Ok(result)) => result,
+                Some(Err(_)) => Err(DhtError::Timeout),
+                None => Err(DhtError::Timeout),
+            },
+            Err(e) => Err(e),
+        };
+        self.pending.lock().unwrap().remove(&key);
+
+        if let (Ok(response), SocketAddr::V4(addr)) = (&result, addr) {
+            let node = NodeInfo {
+                id: response.id,
+                addr,
+            };
+            self.table.lock().unwrap().insert(node);
+        }
+        result
+    }
+
+    /// Iteratively queries the nodes closest to `target` until no closer ones
+    /// turn up.
+    async fn lookup(&self, target: NodeId, get_peers: bool) -> Lookup {
+        let mut candidates = BTreeMap::new();
+        for node in self.table.lock().unwrap().closest(&target, K) {
+            candidates.insert(distance(&node.id, &target), node);
+        }
+
+        let mut queried = HashSet::new();
+        let mut lookup = Lookup::default();
+        loop {
+            let round = candidates
+                .values()
+                .take(K)
+                .filter(|node| !queried.contains(&node.id))
+                .take(ALPHA)
+                .copied()
+                .collect::<Vec<_>>();
+            if round.is_empty() {
+                break;
+            }
+
+            let queries = round.iter().map(|node| {
+                queried.insert(node.id);
+                let query = if get_peers {
+                    Query::GetPeers { info_hash: target }
+                } else {
+                    Query::FindNode { target }
+                };
+                self.query(SocketAddr::V4(node.addr), query)
+            });
+            let responses = join_all(queries).await;
+
+            for (node, response) in round.into_iter().zip(responses) {
+                let Ok(response) = response else {
+                    candidates.remove(&distance(&node.id, &target));
+                    self.table.lock().unwrap().mark_failed(&node.id);
+                    continue;
+                };
+                for node in response.nodes {
+                    if node.id != self.id {
+                        candidates.insert(distance(&node.id, &target), node);
+                    }
+                }
+                lookup.peers.extend(response.values);
+                if let Some(token) = response.token {
+                    lookup.tokens.push((node, token));
+                }
+            }
+        }
+
+        lookup
+            .tokens
+            .sort_by_key(|(node, _)| distance(&node.id, &target));
+        lookup
+    }
+
+    async fn receive_loop(&self) -> Result<(), DhtError> {
+        let mut buf = vec![0; MAX_PACKET_SIZE];
+        loop {
+            let (len, addr) = self.socket.recv_from(&mut buf).await?;
+
+            let message = match KrpcMessage::decode(&buf[..len]) {
+                Ok(message) => message,
+                Err(e) => {
+                    trace!(%addr, error = %e, "ignoring invalid dht packet");
+                    continue;
+                }
+            };
+
+            match message.body {
+                Body::Query { id, query } => {
+                    self.handle_query(addr, message.transaction, id, query)
+                        .await
+                }
+                Body::Response(response) => self.resolve(message.transaction, addr, Ok(response)),
+                Body::Error {
+                    code,
+                    message: text,
+                } => self.resolve(message.transaction, addr, Err(DhtError::Remote(code, text))),
+            }
+        }
+    }
+
+    fn resolve(&self, transaction: Vec<u8>, addr: SocketAddr, result: Result<Response, DhtError>) {
+        if let Some(tx) = self.pending.lock().unwrap().remove(&(transaction, addr)) {
+            let _ = tx.send(result);
+        }
+    }
+
+    fn token(&self, addr: &SocketAddr) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+        hasher.update(self.secret);
+        hasher.update(addr.ip().to_string());
+        hasher.finalize()[..8].to_vec()
+    }
+
+    async fn handle_query(&self, addr: SocketAddr, transaction: Vec<u8>, id: NodeId, query: Query) {
+        trace!(%addr, ?query, "dht query");
+        if let SocketAddr::V4(addr) = addr {
+            self.table.lock().unwrap().insert(NodeInfo { id, addr });
+        }
+
+        let mut response = Response {
+            id: self.id,
+            ..Default::default()
+        };
+        let body = match query {
+            Query::Ping => Body::Response(response),
+            Query::FindNode { target } => {
+                response.nodes = self.table.lock().unwrap().closest(&target, K);
+                Body::Response(response)
+            }
+            Query::GetPeers { info_hash } => {
+                let peers = self.peers.lock().unwrap().get(&info_hash).cloned();
+                match peers {
+                    Some(peers) => response.values = peers,
+                    None => response.nodes = self.table.lock().unwrap().closest(&info_hash, K),
+                }
+                response.token = Some(self.token(&addr));
+                Body::Response(response)
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                token,
+                implied_port,
+            } => {
+                if token != self.token(&addr) {
+                    Body::Error {
+                        code: 203,
+                        message: "Bad token".to_string(),
+                    }
+                } else {
+                    let port = if implied_port { addr.port() } else { port };
+                    let peer = SocketAddr::new(addr.ip(), port);
+                    let mut peers = self.peers.lock().unwrap();
+                    let peers = peers.entry(info_hash).or_default();
+                    if !peers.contains(&peer) {
+                        if peers.len() >= MAX_PEERS_PER_TORRENT {
+                            peers.remove(0);
+                        }
+                        peers.push(peer);
+                    }
+                    Body::Response(response)
+                }
+            }
+        };
+
+        let message = KrpcMessage { transaction, body };
+        if let Err(e) = self.send(addr, &message).await {
+            debug!(%addr, error = %e, "failed to answer dht query");
+        }
+    }
+}
+
+/// Turns `host:port` strings into addresses, skipping any that don't resolve
+/// and keeping only IPv4 since compact node info is IPv4 only.
+pub async fn resolve_nodes<R: Runtime>(runtime: &R, hosts: &[String]) -> Vec<SocketAddr> {
+    let mut nodes = Vec::new();
+    for host in hosts {
+        match runtime.resolve(host).await {
+            Ok(addrs) => nodes.extend(addrs.into_iter().filter(SocketAddr::is_ipv4)),
+            Err(e) => debug!(host, error = %e, "failed to resolve dht node"),
+        }
+    }
+    nodes
+}
@@ -0,0 +1,174 @@
+use std::time::Instant;
+
+use super::{krpc::NodeInfo, NodeId};
+
+/// Bucket size from BEP 5.
+pub const K: usize = 8;
+/// Failed queries after which a node is replaced by the next one we hear of.
+const MAX_FAILURES: u32 = 2;
+
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut distance = [0; 20];
+    for (i, byte) in distance.iter_mut().enumerate() {
+        *byte = a[i] ^ b[i];
+    }
+    distance
+}
+
+#[derive(Debug)]
+struct Entry {
+    node: NodeInfo,
+    last_seen: Instant,
+    failures: u32,
+}
+
+/// A Kademlia routing table with one bucket of up to [`K`] nodes per bit of
+/// distance from our own id.
+#[derive(Debug)]
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<Entry>>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            buckets: (0..160).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Nodes that share a longer prefix with our id land in higher buckets.
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        let distance = distance(&self.id, id);
+        let leading_zeros = distance
+            .iter()
+            .position(|&b| b != 0)
+            .map(|i| i * 8 + distance[i].leading_zeros() as usize)?;
+        Some(leading_zeros.min(159))
+    }
+
+    /// Records that we heard from `node`. Returns false if there was no room
+    /// for it.
+    pub fn insert(&mut self, node: NodeInfo) -> bool {
+        let Some(index) = self.bucket_index(&node.id) else {
+            // that's us
+            return false;
+        };
+        let bucket = &mut self.buckets[index];
+
+        if let Some(entry) = bucket.iter_mut().find(|e| e.node.id == node.id) {
+            entry.node = node;
+            entry.last_seen = Instant::now();
+            entry.failures = 0;
+            return true;
+        }
+
+        let entry = Entry {
+            node,
+            last_seen: Instant::now(),
+            failures: 0,
+        };
+        if bucket.len() < K {
+            bucket.push(entry);
+            return true;
+        }
+
+        // good nodes are never evicted in favour of new ones
+        let stale = bucket
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| e.failures >= MAX_FAILURES)
+            .min_by_key(|(_, e)| e.last_seen)
+            .map(|(i, _)| i);
+        match stale {
+            Some(i) => {
+                bucket[i] = entry;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn mark_failed(&mut self, id: &NodeId) {
+        let Some(index) = self.bucket_index(id) else {
+            return;
+        };
+        if let Some(entry) = self.buckets[index].iter_mut().find(|e| e.node.id == *id) {
+            entry.failures += 1;
+        }
+    }
+
+    /// The `count` known nodes closest to `target`, nearest first.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter(|e| e.failures < MAX_FAILURES)
+            .map(|e| e.node)
+            .collect::<Vec<_>>();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use super::*;
+
+    fn node(first: u8, last: u8) -> NodeInfo {
+        let mut id = [0; 20];
+        id[0] = first;
+        id[19] = last;
+        NodeInfo {
+            id,
+            addr: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 6881 + last as u16),
+        }
+    }
+
+    #[test]
+    fn test_bucket_capacity_and_eviction() {
+        let mut table = RoutingTable::new([0; 20]);
+        // every node with the top bit set falls in bucket 0
+        for i in 0..K as u8 {
+            assert!(table.insert(node(0x80, i)));
+        }
+        assert!(!table.insert(node(0x80, 100)));
+        assert_eq!(table.len(), K);
+
+        table.mark_failed(&node(0x80, 3).id);
+        table.mark_failed(&node(0x80, 3).id);
+        assert!(table.insert(node(0x80, 100)));
+        assert_eq!(table.len(), K);
+        assert!(!table.closest(&[0; 20], K).contains(&node(0x80, 3)));
+    }
+
+    #[test]
+    fn test_closest() {
+        let mut table = RoutingTable::new([0; 20]);
+        for first in [0x80, 0x40, 0x20, 0x10] {
+            table.insert(node(first, 1));
+        }
+        assert!(!table.insert(NodeInfo {
+            id: [0; 20],
+            addr: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1),
+        }));
+
+        let mut target = [0; 20];
+        target[0] = 0x21;
+        let closest = table.closest(&target, 2);
+        assert_eq!(closest, vec![node(0x20, 1), node(0x10, 1)]);
+    }
+}
@@ -1,4 +1,19 @@
 pub mod bencode;
+#[cfg(feature = "client")]
 pub mod client;
+pub mod config;
+#[cfg(feature = "client")]
+pub mod dht;
 pub mod metainfo;
+#[cfg(feature = "client")]
+pub mod nat;
+#[cfg(feature = "client")]
+pub mod proxy;
+#[cfg(feature = "rpc")]
+pub mod rpc;
+#[cfg(feature = "client")]
+pub mod runtime;
+#[cfg(feature = "client")]
+pub mod session;
+#[cfg(feature = "client")]
 pub mod tracker;
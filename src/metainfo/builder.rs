@@ -0,0 +1,346 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use chrono::Utc;
+use sha1::{Digest, Sha1};
+
+use crate::bencode::{BencodeString, BencodeValue};
+
+const MIN_PIECE_LENGTH: u64 = 16 * 1024;
+const MAX_PIECE_LENGTH: u64 = 16 * 1024 * 1024;
+/// Piece count aimed for when no piece length is given.
+const TARGET_PIECES: u64 = 1500;
+
+#[derive(Debug)]
+pub enum CreateError {
+    Io(io::Error),
+    MissingAnnounce,
+    /// There were no files to put in the torrent.
+    Empty,
+    InvalidPath(PathBuf),
+    InvalidPieceLength(u64),
+}
+
+impl Display for CreateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CreateError::Io(e) => write!(f, "Io: {}", e),
+            CreateError::MissingAnnounce => write!(f, "MissingAnnounce"),
+            CreateError::Empty => write!(f, "Empty"),
+            CreateError::InvalidPath(path) => write!(f, "InvalidPath: {}", path.display()),
+            CreateError::InvalidPieceLength(length) => {
+                write!(f, "InvalidPieceLength: {}", length)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CreateError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CreateError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CreateError {
+    fn from(e: io::Error) -> Self {
+        CreateError::Io(e)
+    }
+}
+
+/// Builds a .torrent from a file or a directory on disk.
+///
+/// ```no_run
+/// use rustorrent::metainfo::MetainfoBuilder;
+///
+/// let torrent = MetainfoBuilder::new("album")
+///     .announce("http://tracker.example.com/announce")
+///     .comment("live recordings")
+///     .build()
+///     .unwrap();
+/// std::fs::write("album.torrent", torrent.encode()).unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct MetainfoBuilder {
+    path: PathBuf,
+    announce_list: Vec<Vec<String>>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    private: bool,
+    piece_length: Option<u64>,
+}
+
+impl MetainfoBuilder {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            announce_list: Vec::new(),
+            comment: None,
+            created_by: Some(format!("rustorrent {}", env!("CARGO_PKG_VERSION"))),
+            private: false,
+            piece_length: None,
+        }
+    }
+
+    /// Adds a tracker in a tier of its own. The first tracker added becomes
+    /// the `announce` key.
+    pub fn announce(mut self, url: impl Into<String>) -> Self {
+        self.announce_list.push(vec![url.into()]);
+        self
+    }
+
+    /// Adds a tier of trackers that are tried in any order.
+    pub fn announce_tier(mut self, tier: Vec<String>) -> Self {
+        if !tier.is_empty() {
+            self.announce_list.push(tier);
+        }
+        self
+    }
+
+    pub fn comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    pub fn created_by(mut self, created_by: impl Into<String>) -> Self {
+        self.created_by = Some(created_by.into());
+        self
+    }
+
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    /// Must be a power of two of at least 16 KiB. Picked from the total size
+    /// when not set.
+    pub fn piece_length(mut self, piece_length: u64) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    /// Reads every file and hashes it into pieces, returning the torrent.
+    /// Use [`BencodeValue::encode`] to get the bytes of the .torrent file.
+    pub fn build(self) -> Result<BencodeValue, CreateError> {
+        let announce = self
+            .announce_list
+            .first()
+            .and_then(|tier| tier.first())
+            .cloned()
+            .ok_or(CreateError::MissingAnnounce)?;
+
+        let name = self
+            .path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| CreateError::InvalidPath(self.path.clone()))?
+            .to_string();
+
+        let metadata = fs::metadata(&self.path)?;
+        let files = if metadata.is_dir() {
+            let mut files = Vec::new();
+            walk(&self.path, &mut files)?;
+            files
+        } else {
+            vec![(self.path.clone(), metadata.len())]
+        };
+        if files.is_empty() {
+            return Err(CreateError::Empty);
+        }
+
+        let total_length = files.iter().map(|(_, length)| length).sum::<u64>();
+        let piece_length = match self.piece_length {
+            Some(length)
+                if length.is_power_of_two()
+                    && (MIN_PIECE_LENGTH..=MAX_PIECE_LENGTH).contains(&length) =>
+            {
+                length
+            }
+            Some(length) => return Err(CreateError::InvalidPieceLength(length)),
+            None => (total_length / TARGET_PIECES)
+                .next_power_of_two()
+                .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH),
+        };
+        let pieces = hash_pieces(&files, piece_length)?;
+
+        let mut info = BTreeMap::from([
+            ("name".to_string(), string(&name)),
+            (
+                "piece length".to_string(),
+                BencodeValue::Int(piece_length as i64),
+            ),
+            (
+                "pieces".to_string(),
+                BencodeValue::String(BencodeString::Bytes(pieces)),
+            ),
+        ]);
+        if metadata.is_dir() {
+            let mut entries = Vec::with_capacity(files.len());
+            for (path, length) in &files {
+                let relative = path.strip_prefix(&self.path).unwrap_or(path);
+                let components = relative
+                    .iter()
+                    .map(|part| part.to_str().map(string))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| CreateError::InvalidPath(path.clone()))?;
+                entries.push(BencodeValue::Dict(BTreeMap::from([
+                    ("length".to_string(), BencodeValue::Int(*length as i64)),
+                    ("path".to_string(), BencodeValue::List(components)),
+                ])));
+            }
+            info.insert("files".to_string(), BencodeValue::List(entries));
+        } else {
+            info.insert("length".to_string(), BencodeValue::Int(total_length as i64));
+        }
+        if self.private {
+            info.insert("private".to_string(), BencodeValue::Int(1));
+        }
+
+        let mut torrent = BTreeMap::from([
+            ("announce".to_string(), string(&announce)),
+            ("info".to_string(), BencodeValue::Dict(info)),
+            (
+                "creation date".to_string(),
+                BencodeValue::Int(Utc::now().timestamp()),
+            ),
+        ]);
+        if self.announce_list.len() > 1 || self.announce_list[0].len() > 1 {
+            let tiers = self
+                .announce_list
+                .iter()
+                .map(|tier| BencodeValue::List(tier.iter().map(|url| string(url)).collect()))
+                .collect();
+            torrent.insert("announce-list".to_string(), BencodeValue::List(tiers));
+        }
+        if let Some(comment) = &self.comment {
+            torrent.insert("comment".to_string(), string(comment));
+        }
+        if let Some(created_by) = &self.created_by {
+            torrent.insert("created by".to_string(), string(created_by));
+        }
+        Ok(BencodeValue::Dict(torrent))
+    }
+}
+
+fn string(s: &str) -> BencodeValue {
+    BencodeValue::String(BencodeString::String(s.to_string()))
+}
+
+/// Collects every file under `dir`, sorted by path so the same directory
+/// always gives the same torrent.
+pub(crate) fn walk(dir: &Path, files: &mut Vec<(PathBuf, u64)>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            walk(&entry.path(), files)?;
+        } else if metadata.is_file() {
+            files.push((entry.path(), metadata.len()));
+        }
+    }
+    Ok(())
+}
+
+/// SHA-1 of each piece of the files laid end to end.
+fn hash_pieces(files: &[(PathBuf, u64)], piece_length: u64) -> io::Result<Vec<u8>> {
+    let mut pieces = Vec::new();
+    let mut buf = vec![0; piece_length as usize];
+    let mut filled = 0;
+    for (path, _) in files {
+        let mut file = File::open(path)?;
+        loop {
+            let n = file.read(&mut buf[filled..])?;
+            if n == 0 {
+                break;
+            }
+            filled += n;
+            if filled == buf.len() {
+                pieces.extend_from_slice(&Sha1::digest(&buf));
+                filled = 0;
+            }
+        }
+    }
+    if filled > 0 {
+        pieces.extend_from_slice(&Sha1::digest(&buf[..filled]));
+    }
+    Ok(pieces)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::{Info, Metainfo};
+
+    #[test]
+    fn builds_multi_file_torrent_from_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("album");
+        fs::create_dir_all(root.join("disc")).unwrap();
+        let first = vec![1u8; 20_000];
+        let second = vec![2u8; 30_000];
+        fs::write(root.join("a.bin"), &first).unwrap();
+        fs::write(root.join("disc").join("b.bin"), &second).unwrap();
+
+        let torrent = MetainfoBuilder::new(&root)
+            .announce("http://tracker.example.com/announce")
+            .announce("udp://backup.example.com:80")
+            .comment("test")
+            .private(true)
+            .piece_length(16 * 1024)
+            .build()
+            .unwrap();
+        let (parsed, _) = BencodeValue::parse_strict(&torrent.encode()).unwrap();
+        let metainfo = Metainfo::new(parsed).unwrap();
+
+        assert_eq!(
+            metainfo.announce.as_deref(),
+            Some("http://tracker.example.com/announce")
+        );
+        assert_eq!(metainfo.announce_list.as_ref().map(Vec::len), Some(2));
+        assert_eq!(metainfo.comment.as_deref(), Some("test"));
+        assert!(metainfo.is_private());
+
+        let Info::MultiFile(info) = &metainfo.info else {
+            panic!("expected a multi-file torrent");
+        };
+        assert_eq!(info.name, "album");
+        let files = info
+            .files
+            .iter()
+            .map(|f| (f.path.join("/"), f.length))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            files,
+            vec![
+                ("a.bin".to_string(), 20_000),
+                ("disc/b.bin".to_string(), 30_000)
+            ]
+        );
+
+        let data = [first, second].concat();
+        let expected = data
+            .chunks(16 * 1024)
+            .map(|piece| Sha1::digest(piece).to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(metainfo.get_peices(), &expected);
+    }
+
+    #[test]
+    fn rejects_torrent_without_tracker() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file.bin");
+        fs::write(&file, b"data").unwrap();
+        assert!(matches!(
+            MetainfoBuilder::new(&file).build(),
+            Err(CreateError::MissingAnnounce)
+        ));
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::BTreeMap;
+
+use crate::bencode::BencodeValue;
+
+use super::{invalid, MetaInfoError};
+
+/// Rewrites the fields of an existing .torrent outside its info dict, such as
+/// its trackers, comment and web seeds. The info dict is written back exactly
+/// as it was read, so the info hash stays the same and the edited torrent
+/// still matches data downloaded with the original, as cross-seeding needs.
+///
+/// ```no_run
+/// use rustorrent::metainfo::MetainfoEditor;
+///
+/// let data = std::fs::read("album.torrent").unwrap();
+/// let mut editor = MetainfoEditor::from_bytes(&data).unwrap();
+/// editor.replace_tracker(
+///     "http://old.example.com/announce",
+///     "http://new.example.com/announce",
+/// );
+/// std::fs::write("album.torrent", editor.to_bytes()).unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct MetainfoEditor {
+    /// Every top-level key but `info`.
+    fields: BTreeMap<String, BencodeValue>,
+    /// The info dict's bytes as they are in the file.
+    info: Vec<u8>,
+}
+
+impl MetainfoEditor {
+    pub fn from_bytes(data: &[u8]) -> Result<Self, MetaInfoError> {
+        let (value, span, _) = BencodeValue::parse_spanned(data)?;
+        let BencodeValue::Dict(mut fields) = value else {
+            return Err(MetaInfoError::InvalidBencodeValue);
+        };
+        let info = match (fields.remove("info"), span.get("info")) {
+            (Some(BencodeValue::Dict(_)), Some(info)) => info.raw(data).to_vec(),
+            _ => return Err(invalid(&BencodeValue::Dict(fields), "info")),
+        };
+        Ok(Self { fields, info })
+    }
+
+    /// The tiers of trackers, from `announce-list` or else `announce`.
+    pub fn trackers(&self) -> Vec<Vec<String>> {
+        let strings = |tier: &BencodeValue| {
+            tier.as_list()
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|url| url.as_str().map(str::to_string))
+                .collect::<Vec<_>>()
+        };
+        match self.fields.get("announce-list") {
+            Some(tiers) => tiers
+                .as_list()
+                .unwrap_or_default()
+                .iter()
+                .map(strings)
+                .filter(|tier| !tier.is_empty())
+                .collect(),
+            None => self
+                .fields
+                .get("announce")
+                .and_then(BencodeValue::as_str)
+                .map(|url| vec![vec![url.to_string()]])
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Replaces every tracker. The first becomes `announce`, and
+    /// `announce-list` is only written when there is more than one. With no
+    /// trackers left the torrent relies on the DHT.
+    pub fn set_trackers(&mut self, tiers: Vec<Vec<String>>) {
+        let tiers = tiers
+            .into_iter()
+            .filter(|tier| !tier.is_empty())
+            .collect::<Vec<_>>();
+        self.fields.remove("announce-list");
+        match tiers.first().and_then(|tier| tier.first()) {
+            Some(first) => self
+                .fields
+                .insert("announce".to_string(), BencodeValue::from(first.as_str())),
+            None => self.fields.remove("announce"),
+        };
+        if tiers.iter().map(Vec::len).sum::<usize>() > 1 {
+            let tiers = tiers
+                .into_iter()
+                .map(|tier| {
+                    BencodeValue::from(tier.into_iter().map(BencodeValue::from).collect::<Vec<_>>())
+                })
+                .collect::<Vec<_>>();
+            self.fields
+                .insert("announce-list".to_string(), BencodeValue::from(tiers));
+        }
+    }
+
+    /// Adds a tracker in a tier of its own after the others, unless the
+    /// torrent already has it.
+    pub fn add_tracker(&mut self, url: &str) {
+        let mut tiers = self.trackers();
+        if tiers.iter().flatten().all(|existing| existing != url) {
+            tiers.push(vec![url.to_string()]);
+            self.set_trackers(tiers);
+        }
+    }
+
+    /// Swaps tracker `from` for `to` where it stands, as when a tracker moves
+    /// or a passkey changes. Returns whether the torrent had `from`.
+    pub fn replace_tracker(&mut self, from: &str, to: &str) -> bool {
+        let mut tiers = self.trackers();
+        let mut found = false;
+        for url in tiers.iter_mut().flatten().filter(|url| *url == from) {
+            *url = to.to_string();
+            found = true;
+        }
+        if found {
+            self.set_trackers(tiers);
+        }
+        found
+    }
+
+    /// Returns whether the torrent had the tracker.
+    pub fn remove_tracker(&mut self, url: &str) -> bool {
+        let mut tiers = self.trackers();
+        let before = tiers.iter().map(Vec::len).sum::<usize>();
+        for tier in &mut tiers {
+            tier.retain(|existing| existing != url);
+        }
+        let removed = tiers.iter().map(Vec::len).sum::<usize>() < before;
+        if removed {
+            self.set_trackers(tiers);
+        }
+        removed
+    }
+
+    pub fn set_comment(&mut self, comment: Option<&str>) {
+        match comment {
+            Some(comment) => self
+                .fields
+                .insert("comment".to_string(), BencodeValue::from(comment)),
+            None => self.fields.remove("comment"),
+        };
+    }
+
+    /// Replaces the web seeds (BEP 19).
+    pub fn set_web_seeds(&mut self, urls: Vec<String>) {
+        if urls.is_empty() {
+            self.fields.remove("url-list");
+            return;
+        }
+        let urls = urls.into_iter().map(BencodeValue::from).collect::<Vec<_>>();
+        self.fields
+            .insert("url-list".to_string(), BencodeValue::from(urls));
+    }
+
+    /// The bytes of the edited .torrent file.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = vec![b'd'];
+        let mut info_written = false;
+        for (key, value) in &self.fields {
+            if !info_written && key.as_str() > "info" {
+                self.write_info(&mut data);
+                info_written = true;
+            }
+            data.extend_from_slice(&BencodeValue::from(key.as_str()).encode());
+            data.extend_from_slice(&value.encode());
+        }
+        if !info_written {
+            self.write_info(&mut data);
+        }
+        data.push(b'e');
+        data
+    }
+
+    fn write_info(&self, data: &mut Vec<u8>) {
+        data.extend_from_slice(&BencodeValue::from("info").encode());
+        data.extend_from_slice(&self.info);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::Metainfo;
+
+    #[test]
+    fn edits_trackers_and_keeps_info_dict_bytes() {
+        // "name" before "length" wouldn't survive re-encoding the dict
+        let info =
+            b"d4:name5:a.bin6:lengthi5e12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let mut torrent = b"d8:announce17:http://a/announce7:comment3:old4:info".to_vec();
+        torrent.extend_from_slice(info);
+        torrent.extend_from_slice(b"8:url-list10:http://w/xe");
+        let original = Metainfo::from_bytes(&torrent).unwrap();
+
+        let mut editor = MetainfoEditor::from_bytes(&torrent).unwrap();
+        assert_eq!(editor.trackers(), vec![vec!["http://a/announce"]]);
+        assert!(editor.replace_tracker("http://a/announce", "http://b/announce"));
+        assert!(!editor.replace_tracker("http://a/announce", "http://c/announce"));
+        editor.add_tracker("udp://c:80");
+        editor.add_tracker("udp://c:80");
+        editor.set_comment(None);
+        editor.set_web_seeds(Vec::new());
+
+        let edited = editor.to_bytes();
+        assert!(edited.windows(info.len()).any(|w| w == info));
+        let metainfo = Metainfo::from_bytes(&edited).unwrap();
+        assert_eq!(metainfo.info_hash(), original.info_hash());
+        assert_eq!(metainfo.announce.as_deref(), Some("http://b/announce"));
+        assert_eq!(
+            metainfo.announce_list,
+            Some(vec![
+                vec!["http://b/announce".to_string()],
+                vec!["udp://c:80".to_string()]
+            ])
+        );
+        assert_eq!(metainfo.comment, None);
+        assert!(metainfo.url_list.is_empty());
+
+        assert!(editor.remove_tracker("udp://c:80"));
+        let metainfo = Metainfo::from_bytes(&editor.to_bytes()).unwrap();
+        assert_eq!(metainfo.announce_list, None);
+        assert!(MetainfoEditor::from_bytes(b"d8:announce1:xe").is_err());
+    }
+}
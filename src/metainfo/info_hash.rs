@@ -0,0 +1,182 @@
+use std::{fmt::Display, str::FromStr};
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Identifies a torrent: the SHA-1 of its info dictionary, or for v2
+/// torrents (BEP 52) the SHA-256.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum InfoHash {
+    V1([u8; 20]),
+    V2([u8; 32]),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InfoHashError(String);
+
+impl Display for InfoHashError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid info hash `{}`", self.0)
+    }
+}
+
+impl std::error::Error for InfoHashError {}
+
+impl InfoHash {
+    /// A hash of 20 or 32 bytes.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match bytes.len() {
+            20 => bytes.try_into().ok().map(InfoHash::V1),
+            32 => bytes.try_into().ok().map(InfoHash::V2),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            InfoHash::V1(hash) => hash,
+            InfoHash::V2(hash) => hash,
+        }
+    }
+
+    /// The 20 bytes that go in handshakes, tracker requests and DHT lookups.
+    /// v2 hashes are truncated there.
+    pub fn truncated(&self) -> [u8; 20] {
+        let mut truncated = [0; 20];
+        truncated.copy_from_slice(&self.as_bytes()[..20]);
+        truncated
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.as_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Parses 40 or 64 hex digits, in either case.
+    pub fn from_hex(s: &str) -> Result<Self, InfoHashError> {
+        let invalid = || InfoHashError(s.to_string());
+        if !s.len().is_multiple_of(2) || !s.is_ascii() {
+            return Err(invalid());
+        }
+        let bytes = (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| invalid())?;
+        InfoHash::from_bytes(&bytes).ok_or_else(invalid)
+    }
+
+    /// Unpadded RFC 4648 base32, as older magnet links use for v1 hashes.
+    pub fn to_base32(&self) -> String {
+        let mut out = String::new();
+        let (mut buffer, mut bits) = (0u32, 0);
+        for &byte in self.as_bytes() {
+            buffer = (buffer << 8) | byte as u32;
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                out.push(BASE32_ALPHABET[(buffer >> bits) as usize & 31] as char);
+            }
+        }
+        if bits > 0 {
+            out.push(BASE32_ALPHABET[(buffer << (5 - bits)) as usize & 31] as char);
+        }
+        out
+    }
+
+    /// Parses unpadded base32, in either case.
+    pub fn from_base32(s: &str) -> Result<Self, InfoHashError> {
+        let invalid = || InfoHashError(s.to_string());
+        let mut bytes = Vec::with_capacity(s.len() * 5 / 8);
+        let (mut buffer, mut bits) = (0u32, 0);
+        for c in s.bytes() {
+            let value = BASE32_ALPHABET
+                .iter()
+                .position(|&a| a == c.to_ascii_uppercase())
+                .ok_or_else(invalid)?;
+            buffer = (buffer << 5) | value as u32;
+            bits += 5;
+            if bits >= 8 {
+                bits -= 8;
+                bytes.push((buffer >> bits) as u8);
+            }
+        }
+        InfoHash::from_bytes(&bytes).ok_or_else(invalid)
+    }
+}
+
+impl Display for InfoHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+/// Accepts hex, or base32 for a v1 hash.
+impl FromStr for InfoHash {
+    type Err = InfoHashError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.len() {
+            32 => InfoHash::from_base32(s),
+            _ => InfoHash::from_hex(s),
+        }
+    }
+}
+
+impl From<[u8; 20]> for InfoHash {
+    fn from(hash: [u8; 20]) -> Self {
+        InfoHash::V1(hash)
+    }
+}
+
+impl From<[u8; 32]> for InfoHash {
+    fn from(hash: [u8; 32]) -> Self {
+        InfoHash::V2(hash)
+    }
+}
+
+impl AsRef<[u8]> for InfoHash {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_formats_hex_and_base32() {
+        let hex = "123456789abcdef000112233445566778899aabb";
+        let hash = InfoHash::V1([
+            0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55,
+            0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb,
+        ]);
+        assert_eq!(hash.to_string(), hex);
+        assert_eq!(hex.to_uppercase().parse::<InfoHash>(), Ok(hash));
+
+        let base32 = hash.to_base32();
+        assert_eq!(base32, "CI2FM6E2XTPPAAAREIZUIVLGO6EJTKV3");
+        assert_eq!(base32.to_lowercase().parse::<InfoHash>(), Ok(hash));
+
+        let v2 = InfoHash::V2([7; 32]);
+        assert_eq!(v2.to_hex().parse::<InfoHash>(), Ok(v2));
+        assert_eq!(v2.truncated(), [7; 20]);
+    }
+
+    #[test]
+    fn rejects_malformed_hashes() {
+        for s in [
+            "",
+            "12",
+            &"g".repeat(40),
+            &"a".repeat(41),
+            "ééé",
+            &"1".repeat(32),
+        ] {
+            assert!(s.parse::<InfoHash>().is_err(), "{:?}", s);
+        }
+        assert_eq!(InfoHash::from_bytes(&[0; 19]), None);
+    }
+}
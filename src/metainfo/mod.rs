@@ -1,9 +1,21 @@
-use std::{collections::BTreeMap, fmt::Debug};
+use std::fmt::{Debug, Display};
 
 use chrono::{DateTime, Utc};
 use sha1::{Digest, Sha1};
 
-use crate::bencode::{BencodeString, BencodeValue};
+use crate::bencode::{BencodeValue, ParseError};
+
+#[cfg(feature = "client")]
+mod builder;
+mod editor;
+mod info_hash;
+
+#[cfg(feature = "client")]
+pub(crate) use self::builder::walk;
+#[cfg(feature = "client")]
+pub use self::builder::{CreateError, MetainfoBuilder};
+pub use self::editor::MetainfoEditor;
+pub use self::info_hash::{InfoHash, InfoHashError};
 
 #[derive(Debug, PartialEq)]
 pub struct BaseInfo {
@@ -43,25 +55,40 @@ pub enum Info {
 
 #[derive(Debug)]
 pub struct Metainfo {
-    torrent_content: BencodeValue,
+    info_hash: InfoHash,
 
     pub info: Info,
-    pub announce: String,
+    /// `None` for a trackerless torrent, found through the DHT alone.
+    pub announce: Option<String>,
     pub announce_list: Option<Vec<Vec<String>>>,
+    /// DHT nodes to join the network through (BEP 5), as `host:port`.
+    pub nodes: Vec<String>,
+    /// Web seeds (BEP 19) that serve the torrent's files over HTTP.
+    pub url_list: Vec<String>,
     pub creation_date: Option<DateTime<Utc>>,
     pub comment: Option<String>,
     pub created_by: Option<String>,
     pub encoding: Option<String>,
 }
 
+#[derive(Debug)]
 pub struct AttributeError {
     pub content: BencodeValue,
     pub attribute: String,
 }
 
+impl Display for AttributeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid attribute `{}`", self.attribute)
+    }
+}
+
+impl std::error::Error for AttributeError {}
+
 pub enum MetaInfoError {
     InvalidAttribute(AttributeError),
     InvalidBencodeValue,
+    Bencode(ParseError),
 }
 
 impl Debug for MetaInfoError {
@@ -71,18 +98,64 @@ impl Debug for MetaInfoError {
                 write!(f, "InvalidAttribute: {:?} {:?}", e.content, e.attribute)
             }
             MetaInfoError::InvalidBencodeValue => write!(f, "InvalidBencodeValue"),
+            MetaInfoError::Bencode(e) => write!(f, "Bencode: {:?}", e),
+        }
+    }
+}
+
+impl Display for MetaInfoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MetaInfoError::InvalidAttribute(e) => write!(f, "InvalidAttribute: {}", e),
+            MetaInfoError::InvalidBencodeValue => write!(f, "InvalidBencodeValue"),
+            MetaInfoError::Bencode(e) => write!(f, "Bencode: {}", e),
         }
     }
 }
 
+impl std::error::Error for MetaInfoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MetaInfoError::InvalidAttribute(e) => Some(e),
+            MetaInfoError::InvalidBencodeValue => None,
+            MetaInfoError::Bencode(e) => Some(e),
+        }
+    }
+}
+
+impl From<ParseError> for MetaInfoError {
+    fn from(e: ParseError) -> Self {
+        MetaInfoError::Bencode(e)
+    }
+}
+
 impl Metainfo {
     pub fn new(bencode_value: BencodeValue) -> Result<Metainfo, MetaInfoError> {
-        match bencode_value.clone() {
-            BencodeValue::Dict(dict) => Metainfo::dict_to_metainfo(bencode_value, &dict),
+        match bencode_value {
+            BencodeValue::Dict(_) => Metainfo::value_to_metainfo(bencode_value),
             _ => Err(MetaInfoError::InvalidBencodeValue),
         }
     }
 
+    /// Parses a torrent file. The info hash is taken over the info dict's
+    /// bytes as they are in the file, so it matches everyone else's even if
+    /// the dict wouldn't re-encode the same way.
+    pub fn from_bytes(data: &[u8]) -> Result<Metainfo, MetaInfoError> {
+        let (value, span, _) = BencodeValue::parse_spanned(data)?;
+        let mut metainfo = Metainfo::new(value)?;
+        if let Some(info) = span.get("info") {
+            metainfo.info_hash = InfoHash::V1(Sha1::digest(info.raw(data)).into());
+        }
+        Ok(metainfo)
+    }
+
+    pub fn name(&self) -> &str {
+        match &self.info {
+            Info::SingleFile(info) => &info.name,
+            Info::MultiFile(info) => &info.name,
+        }
+    }
+
     pub fn get_length(&self) -> u64 {
         match &self.info {
             Info::SingleFile(info) => info.length,
@@ -90,24 +163,8 @@ impl Metainfo {
         }
     }
 
-    pub fn get_info_hash(&self) -> Result<Vec<u8>, MetaInfoError> {
-        let info = match self.torrent_content.get_value("info") {
-            Some(info) => info,
-            None => {
-                return Err(MetaInfoError::InvalidAttribute(AttributeError {
-                    content: self.torrent_content.clone(),
-                    attribute: "info".to_string(),
-                }))
-            }
-        };
-
-        let info_bencoded = info.encode();
-
-        let mut hasher = Sha1::new();
-        hasher.update(info_bencoded);
-        let result = hasher.finalize();
-
-        Ok(result.to_vec())
+    pub fn info_hash(&self) -> &InfoHash {
+        &self.info_hash
     }
 
     pub fn get_peices(&self) -> &Vec<Vec<u8>> {
@@ -117,43 +174,28 @@ impl Metainfo {
         }
     }
 
-    fn dict_to_base_info(dict: &BTreeMap<String, BencodeValue>) -> Result<BaseInfo, MetaInfoError> {
-        let pieces = match dict.get("pieces") {
-            Some(BencodeValue::String(BencodeString::Bytes(b))) => {
-                let mut pieces = Vec::new();
-                for i in (0..b.len()).step_by(20) {
-                    pieces.push(b[i..i + 20].to_vec());
-                }
-                pieces
-            }
-            _ => {
-                return Err(MetaInfoError::InvalidAttribute(AttributeError {
-                    content: BencodeValue::Dict(dict.clone()),
-                    attribute: "pieces".to_string(),
-                }))
-            }
-        };
-
-        let piece_length = match dict.get("piece length") {
-            Some(BencodeValue::Int(i)) => *i as u64,
-            _ => {
-                return Err(MetaInfoError::InvalidAttribute(AttributeError {
-                    content: BencodeValue::Dict(dict.clone()),
-                    attribute: "piece length".to_string(),
-                }))
-            }
+    /// Private torrents (BEP 27) must only get peers from their trackers.
+    pub fn is_private(&self) -> bool {
+        let base_info = match &self.info {
+            Info::SingleFile(info) => &info.base_info,
+            Info::MultiFile(info) => &info.base_info,
         };
+        base_info.private == Some(1)
+    }
 
-        let private = dict
-            .get("private")
-            .map(|v| match v {
-                BencodeValue::Int(i) => Ok(*i),
-                _ => Err(MetaInfoError::InvalidAttribute(AttributeError {
-                    content: BencodeValue::Dict(dict.clone()),
-                    attribute: "private".to_string(),
-                })),
-            })
-            .transpose()?;
+    fn value_to_base_info(info: &BencodeValue) -> Result<BaseInfo, MetaInfoError> {
+        let pieces = info
+            .get_bytes("pieces")
+            .filter(|pieces| pieces.len().is_multiple_of(20))
+            .ok_or_else(|| invalid(info, "pieces"))?
+            .chunks(20)
+            .map(<[u8]>::to_vec)
+            .collect();
+        let piece_length = info
+            .get_int("piece length")
+            .filter(|&length| length > 0)
+            .ok_or_else(|| invalid(info, "piece length"))? as u64;
+        let private = optional(info, "private", BencodeValue::as_int)?;
 
         Ok(BaseInfo {
             pieces,
@@ -162,284 +204,157 @@ impl Metainfo {
         })
     }
 
-    fn dict_to_single_file_info(
-        dict: &BTreeMap<String, BencodeValue>,
-    ) -> Result<SingleFileInfo, MetaInfoError> {
-        let base_info = Metainfo::dict_to_base_info(dict)?;
-
-        let name = match dict.get("name") {
-            Some(BencodeValue::String(BencodeString::String(s))) => s.clone(),
-            _ => {
-                return Err(MetaInfoError::InvalidAttribute(AttributeError {
-                    content: BencodeValue::Dict(dict.clone()),
-                    attribute: "name".to_string(),
-                }))
-            }
-        };
-
-        let length = match dict.get("length") {
-            Some(BencodeValue::Int(i)) => *i as u64,
-            _ => {
-                return Err(MetaInfoError::InvalidAttribute(AttributeError {
-                    content: BencodeValue::Dict(dict.clone()),
-                    attribute: "length".to_string(),
-                }))
-            }
-        };
-
-        let md5sum = dict
-            .get("md5sum")
-            .map(|v| match v {
-                BencodeValue::String(BencodeString::String(s)) => Ok(s.clone()),
-                _ => Err(MetaInfoError::InvalidAttribute(AttributeError {
-                    content: BencodeValue::Dict(dict.clone()),
-                    attribute: "md5sum".to_string(),
-                })),
-            })
-            .transpose()?;
-
+    fn value_to_single_file_info(info: &BencodeValue) -> Result<SingleFileInfo, MetaInfoError> {
         Ok(SingleFileInfo {
-            base_info,
-            name,
-            length,
-            md5sum,
+            base_info: Metainfo::value_to_base_info(info)?,
+            name: required_str(info, "name")?,
+            length: length(info)?,
+            md5sum: optional_str(info, "md5sum")?,
         })
     }
 
     fn parse_file(file: &BencodeValue) -> Result<FileData, MetaInfoError> {
-        match file {
-            BencodeValue::Dict(file_dict) => {
-                let path = match file_dict.get("path") {
-                    Some(BencodeValue::List(path_list)) => path_list
-                        .iter()
-                        .map(|path_item| match path_item {
-                            BencodeValue::String(BencodeString::String(s)) => Ok(s.clone()),
-                            _ => {
-                                return Err(MetaInfoError::InvalidAttribute(AttributeError {
-                                    content: BencodeValue::Dict(file_dict.clone()),
-                                    attribute: "path".to_string(),
-                                }))
-                            }
-                        })
-                        .collect::<Result<Vec<String>, MetaInfoError>>(),
-
-                    _ => {
-                        return Err(MetaInfoError::InvalidAttribute(AttributeError {
-                            content: BencodeValue::Dict(file_dict.clone()),
-                            attribute: "path".to_string(),
-                        }))
-                    }
-                }?;
-
-                let length = match file_dict.get("length") {
-                    Some(BencodeValue::Int(i)) => *i as u64,
-                    _ => {
-                        return Err(MetaInfoError::InvalidAttribute(AttributeError {
-                            content: BencodeValue::Dict(file_dict.clone()),
-                            attribute: "length".to_string(),
-                        }))
-                    }
-                };
-
-                let md5sum = file_dict
-                    .get("md5sum")
-                    .map(|v| match v {
-                        BencodeValue::String(BencodeString::String(s)) => Ok(s.clone()),
-                        _ => Err(MetaInfoError::InvalidAttribute(AttributeError {
-                            content: BencodeValue::Dict(file_dict.clone()),
-                            attribute: "md5sum".to_string(),
-                        })),
-                    })
-                    .transpose()?;
-
-                Ok(FileData {
-                    path,
-                    length,
-                    md5sum,
-                })
-            }
-            _ => {
-                return Err(MetaInfoError::InvalidAttribute(AttributeError {
-                    content: file.clone(),
-                    attribute: "file".to_string(),
-                }))
-            }
+        if file.as_dict().is_none() {
+            return Err(invalid(file, "file"));
         }
+        let path = file
+            .get_list("path")
+            .ok_or_else(|| invalid(file, "path"))?
+            .iter()
+            .map(|part| part.as_str().map(str::to_string))
+            .collect::<Option<Vec<String>>>()
+            .ok_or_else(|| invalid(file, "path"))?;
+
+        Ok(FileData {
+            path,
+            length: length(file)?,
+            md5sum: optional_str(file, "md5sum")?,
+        })
     }
 
-    fn dict_to_multiple_file_info(
-        dict: &BTreeMap<String, BencodeValue>,
-    ) -> Result<MultiFileInfo, MetaInfoError> {
-        let base_info = Metainfo::dict_to_base_info(dict)?;
-
-        let name = match dict.get("name") {
-            Some(BencodeValue::String(BencodeString::String(s))) => s.clone(),
-            _ => {
-                return Err(MetaInfoError::InvalidAttribute(AttributeError {
-                    content: BencodeValue::Dict(dict.clone()),
-                    attribute: "name".to_string(),
-                }))
-            }
-        };
-
-        let files = match dict.get("files") {
-            Some(BencodeValue::List(v)) => v
-                .iter()
-                .map(Metainfo::parse_file)
-                .collect::<Result<Vec<FileData>, MetaInfoError>>(),
-            _ => {
-                return Err(MetaInfoError::InvalidAttribute(AttributeError {
-                    content: BencodeValue::Dict(dict.clone()),
-                    attribute: "files".to_string(),
-                }))
-            }
-        }?;
+    fn value_to_multiple_file_info(info: &BencodeValue) -> Result<MultiFileInfo, MetaInfoError> {
+        let files = info
+            .get_list("files")
+            .ok_or_else(|| invalid(info, "files"))?
+            .iter()
+            .map(Metainfo::parse_file)
+            .collect::<Result<Vec<FileData>, MetaInfoError>>()?;
 
         Ok(MultiFileInfo {
-            base_info,
-            name,
+            base_info: Metainfo::value_to_base_info(info)?,
+            name: required_str(info, "name")?,
             files,
         })
     }
 
-    fn dict_to_info(dict: &BTreeMap<String, BencodeValue>) -> Result<Info, MetaInfoError> {
-        match dict.get("files") {
+    fn value_to_info(info: &BencodeValue) -> Result<Info, MetaInfoError> {
+        let (base_info, total_length) = match info.get_value("files") {
             Some(BencodeValue::List(_)) => {
-                let info = Metainfo::dict_to_multiple_file_info(dict)?;
-                Ok(Info::MultiFile(info))
+                let multi = Metainfo::value_to_multiple_file_info(info)?;
+                let total_length = multi.files.iter().map(|f| f.length).sum::<u64>();
+                (Info::MultiFile(multi), total_length)
             }
             None => {
-                let info = Metainfo::dict_to_single_file_info(dict)?;
-                Ok(Info::SingleFile(info))
+                let single = Metainfo::value_to_single_file_info(info)?;
+                let total_length = single.length;
+                (Info::SingleFile(single), total_length)
             }
-            _ => Err(MetaInfoError::InvalidAttribute(AttributeError {
-                content: BencodeValue::Dict(dict.clone()),
-                attribute: "files".to_string(),
-            })),
+            _ => return Err(invalid(info, "files")),
+        };
+        // one hash for every piece the files cover, no more and no fewer
+        let (pieces, piece_length) = match &base_info {
+            Info::SingleFile(single) => (&single.base_info.pieces, single.base_info.piece_length),
+            Info::MultiFile(multi) => (&multi.base_info.pieces, multi.base_info.piece_length),
+        };
+        if pieces.len() as u64 != total_length.div_ceil(piece_length) {
+            return Err(invalid(info, "pieces"));
         }
+        Ok(base_info)
     }
 
     fn convert_announce_list(value: &BencodeValue) -> Result<Vec<Vec<String>>, MetaInfoError> {
-        match value {
-            BencodeValue::List(list) => list
-                .iter()
-                .map(|item| match item {
-                    BencodeValue::List(inner_list) => {
-                        let mut inner_result = Vec::new();
-                        for inner_item in inner_list {
-                            match inner_item {
-                                BencodeValue::String(BencodeString::String(s)) => {
-                                    inner_result.push(s.clone());
-                                }
-                                _ => {
-                                    return Err(MetaInfoError::InvalidAttribute(AttributeError {
-                                        content: item.clone(),
-                                        attribute: "announce-list".to_string(),
-                                    }))
-                                }
-                            }
-                        }
-                        Ok(inner_result)
-                    }
-                    _ => {
-                        return Err(MetaInfoError::InvalidAttribute(AttributeError {
-                            content: item.clone(),
-                            attribute: "announce-list".to_string(),
-                        }))
-                    }
-                })
-                .collect::<Result<Vec<Vec<String>>, MetaInfoError>>(),
-            _ => Err(MetaInfoError::InvalidAttribute(AttributeError {
-                content: value.clone(),
-                attribute: "announce-list".to_string(),
-            })),
-        }
+        value
+            .as_list()
+            .ok_or_else(|| invalid(value, "announce-list"))?
+            .iter()
+            .map(|tier| {
+                tier.as_list()
+                    .and_then(|urls| {
+                        urls.iter()
+                            .map(|url| url.as_str().map(str::to_string))
+                            .collect::<Option<Vec<String>>>()
+                    })
+                    .ok_or_else(|| invalid(tier, "announce-list"))
+            })
+            .collect()
     }
 
-    fn dict_to_metainfo(
-        bencode_value: BencodeValue,
-        dict: &BTreeMap<String, BencodeValue>,
-    ) -> Result<Metainfo, MetaInfoError> {
-        let announce = match dict.get("announce") {
-            Some(BencodeValue::String(BencodeString::String(s))) => s.clone(),
-            _ => {
-                return Err(MetaInfoError::InvalidAttribute(AttributeError {
-                    content: bencode_value.clone(),
-                    attribute: "announce".to_string(),
-                }))
-            }
+    fn value_to_metainfo(bencode_value: BencodeValue) -> Result<Metainfo, MetaInfoError> {
+        let announce = optional_str(&bencode_value, "announce")?;
+        let creation_date = optional(&bencode_value, "creation date", |value| {
+            DateTime::from_timestamp(value.as_int()?, 0)
+        })?;
+        let comment = optional_str(&bencode_value, "comment")?;
+        let created_by = optional_str(&bencode_value, "created by")?;
+        let encoding = optional_str(&bencode_value, "encoding")?;
+
+        let (info, info_hash) = match bencode_value.get_value("info") {
+            Some(info @ BencodeValue::Dict(_)) => (
+                Metainfo::value_to_info(info)?,
+                InfoHash::V1(Sha1::digest(info.encode()).into()),
+            ),
+            _ => return Err(invalid(&bencode_value, "info")),
         };
 
-        let creation_date = dict
-            .get("creation date")
-            .map(|v| match v {
-                BencodeValue::Int(i) => DateTime::from_timestamp(*i, 0).ok_or(
-                    MetaInfoError::InvalidAttribute(AttributeError {
-                        content: bencode_value.clone(),
-                        attribute: "creation date".to_string(),
-                    }),
-                ),
-
-                _ => {
-                    return Err(MetaInfoError::InvalidAttribute(AttributeError {
-                        content: bencode_value.clone(),
-                        attribute: "creation date".to_string(),
-                    }))
-                }
-            })
-            .transpose()?;
-
-        let comment = dict
-            .get("comment")
-            .map(|v| match v {
-                BencodeValue::String(BencodeString::String(s)) => Ok(s.clone()),
-                _ => Err(MetaInfoError::InvalidAttribute(AttributeError {
-                    content: bencode_value.clone(),
-                    attribute: "comment".to_string(),
-                })),
-            })
+        let announce_list = bencode_value
+            .get_value("announce-list")
+            .map(Metainfo::convert_announce_list)
             .transpose()?;
 
-        let created_by = dict
-            .get("created by")
-            .map(|v| match v {
-                BencodeValue::String(BencodeString::String(s)) => Ok(s.clone()),
-                _ => Err(MetaInfoError::InvalidAttribute(AttributeError {
-                    content: bencode_value.clone(),
-                    attribute: "created by".to_string(),
-                })),
-            })
-            .transpose()?;
-
-        let encoding = dict
-            .get("encoding")
-            .map(|v| match v {
-                BencodeValue::String(BencodeString::String(s)) => Ok(s.clone()),
-                _ => Err(MetaInfoError::InvalidAttribute(AttributeError {
-                    content: bencode_value.clone(),
-                    attribute: "encoding".to_string(),
-                })),
-            })
-            .transpose()?;
+        // a single web seed may be given as a bare string
+        let url_list = match bencode_value.get_value("url-list") {
+            None => Vec::new(),
+            Some(BencodeValue::List(urls)) => urls
+                .iter()
+                .map(|url| {
+                    url.as_str()
+                        .map(str::to_string)
+                        .ok_or_else(|| invalid(url, "url-list"))
+                })
+                .collect::<Result<_, _>>()?,
+            Some(url) => vec![url
+                .as_str()
+                .ok_or_else(|| invalid(url, "url-list"))?
+                .to_string()],
+        };
 
-        let info = match dict.get("info") {
-            Some(BencodeValue::Dict(info_dict)) => Metainfo::dict_to_info(info_dict),
-            _ => Err(MetaInfoError::InvalidAttribute(AttributeError {
-                content: bencode_value.clone(),
-                attribute: "info".to_string(),
-            })),
-        }?;
-
-        let announce_list = dict
-            .get("announce-list")
-            .map(|v| Metainfo::convert_announce_list(v))
-            .transpose()?;
+        let nodes = optional(&bencode_value, "nodes", |nodes| {
+            nodes
+                .as_list()?
+                .iter()
+                .map(|node| match node.as_list()? {
+                    [host, port] => {
+                        let host = host.as_str()?;
+                        let port = u16::try_from(port.as_int()?).ok()?;
+                        // an IPv6 address needs brackets to take a port
+                        Some(match host.contains(':') {
+                            true => format!("[{}]:{}", host, port),
+                            false => format!("{}:{}", host, port),
+                        })
+                    }
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()
+        })?
+        .unwrap_or_default();
 
         Ok(Metainfo {
-            torrent_content: bencode_value,
+            info_hash,
             info,
             announce,
             announce_list,
+            nodes,
+            url_list,
             creation_date,
             comment,
             created_by,
@@ -447,3 +362,108 @@ impl Metainfo {
         })
     }
 }
+
+fn invalid(content: &BencodeValue, attribute: &str) -> MetaInfoError {
+    MetaInfoError::InvalidAttribute(AttributeError {
+        content: content.clone(),
+        attribute: attribute.to_string(),
+    })
+}
+
+/// A file's `length`, which can't be negative.
+fn length(value: &BencodeValue) -> Result<u64, MetaInfoError> {
+    value
+        .get_int("length")
+        .and_then(|length| u64::try_from(length).ok())
+        .ok_or_else(|| invalid(value, "length"))
+}
+
+fn required_str(value: &BencodeValue, key: &str) -> Result<String, MetaInfoError> {
+    value
+        .get_str(key)
+        .map(str::to_string)
+        .ok_or_else(|| invalid(value, key))
+}
+
+/// Converts `key` if it is there, failing if it is there but `convert` can't
+/// make sense of it.
+fn optional<'a, T>(
+    value: &'a BencodeValue,
+    key: &str,
+    convert: impl FnOnce(&'a BencodeValue) -> Option<T>,
+) -> Result<Option<T>, MetaInfoError> {
+    value
+        .get_value(key)
+        .map(|field| convert(field).ok_or_else(|| invalid(value, key)))
+        .transpose()
+}
+
+fn optional_str(value: &BencodeValue, key: &str) -> Result<Option<String>, MetaInfoError> {
+    optional(value, key, |field| field.as_str().map(str::to_string))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_info_dict_as_it_appears_in_the_file() {
+        // "name" sorts before "length", so re-encoding would change the bytes
+        let info =
+            b"d4:name5:a.bin6:lengthi5e12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let mut torrent = b"d8:announce20:http://t.example/ann4:info".to_vec();
+        torrent.extend_from_slice(info);
+        torrent.push(b'e');
+
+        let metainfo = Metainfo::from_bytes(&torrent).unwrap();
+        assert_eq!(metainfo.name(), "a.bin");
+        assert_eq!(
+            metainfo.info_hash(),
+            &InfoHash::V1(Sha1::digest(info).into())
+        );
+
+        let (value, _) = BencodeValue::parse(&torrent).unwrap();
+        assert_ne!(
+            Metainfo::new(value).unwrap().info_hash(),
+            metainfo.info_hash()
+        );
+    }
+
+    fn torrent(info: &str) -> Vec<u8> {
+        format!("d8:announce20:http://t.example/ann4:info{}e", info).into_bytes()
+    }
+
+    fn rejected_attribute(info: &str) -> String {
+        match Metainfo::from_bytes(&torrent(info)) {
+            Err(MetaInfoError::InvalidAttribute(e)) => e.attribute,
+            other => panic!("{} was accepted: {:?}", info, other.map(|m| m.info)),
+        }
+    }
+
+    #[test]
+    fn rejects_lengths_that_dont_fit_the_pieces() {
+        let hashes = format!("6:pieces40:{}", "a".repeat(40));
+        // 20 bytes in two pieces of 16
+        let valid = format!("d6:lengthi20e4:name5:a.bin12:piece lengthi16e{}e", hashes);
+        assert!(Metainfo::from_bytes(&torrent(&valid)).is_ok());
+
+        let zero_piece_length = format!("d6:lengthi20e4:name5:a.bin12:piece lengthi0e{}e", hashes);
+        assert_eq!(rejected_attribute(&zero_piece_length), "piece length");
+        let negative_piece_length =
+            format!("d6:lengthi20e4:name5:a.bin12:piece lengthi-16e{}e", hashes);
+        assert_eq!(rejected_attribute(&negative_piece_length), "piece length");
+
+        let negative_length = format!("d6:lengthi-20e4:name5:a.bin12:piece lengthi16e{}e", hashes);
+        assert_eq!(rejected_attribute(&negative_length), "length");
+        let negative_file = format!(
+            "d5:filesld6:lengthi-1e4:pathl1:aeee4:name1:d12:piece lengthi16e{}e",
+            hashes
+        );
+        assert_eq!(rejected_attribute(&negative_file), "length");
+
+        let extra_pieces = format!("d6:lengthi16e4:name5:a.bin12:piece lengthi16e{}e", hashes);
+        assert_eq!(rejected_attribute(&extra_pieces), "pieces");
+        let missing_pieces = format!("d6:lengthi40e4:name5:a.bin12:piece lengthi16e{}e", hashes);
+        assert_eq!(rejected_attribute(&missing_pieces), "pieces");
+    }
+}
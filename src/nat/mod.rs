@@ -0,0 +1,226 @@
+//! Asks the local router to forward our listen port, so peers behind other
+//! NATs can reach us. NAT-PMP is tried first since it is one round trip,
+//! then UPnP IGD.
+
+use std::{
+    fmt::Display,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tracing::{debug, info, warn};
+
+use crate::runtime::Runtime;
+
+mod natpmp;
+mod upnp;
+
+/// How long each mapping is requested for. It is renewed at half that.
+const LEASE: Duration = Duration::from_secs(60 * 60);
+/// How long to wait before trying again when no mapping could be made.
+const RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug)]
+pub enum NatError {
+    NoGateway,
+    Io(io::Error),
+    Protocol(String),
+}
+
+impl Display for NatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NatError::NoGateway => write!(f, "NoGateway"),
+            NatError::Io(e) => write!(f, "Io: {}", e),
+            NatError::Protocol(e) => write!(f, "Protocol: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for NatError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NatError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for NatError {
+    fn from(e: io::Error) -> Self {
+        NatError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatMethod {
+    NatPmp,
+    Upnp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+/// A port the router has agreed to forward to us.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mapping {
+    pub method: NatMethod,
+    pub protocol: Protocol,
+    pub internal_port: u16,
+    pub external_port: u16,
+    /// Our address as the rest of the internet sees it, if the router said.
+    pub external_ip: Option<IpAddr>,
+    pub lifetime: Duration,
+}
+
+/// Maps `port` for `protocol` on the router with whichever method it
+/// supports.
+pub async fn map_port<R: Runtime>(
+    runtime: &R,
+    protocol: Protocol,
+    port: u16,
+    lifetime: Duration,
+) -> Result<Mapping, NatError> {
+    if let Some(gateway) = default_gateway() {
+        match natpmp::map_port(runtime, gateway, protocol, port, lifetime).await {
+            Ok(mapping) => return Ok(mapping),
+            Err(e) => debug!(%gateway, error = %e, "nat-pmp mapping failed"),
+        }
+    }
+    upnp::map_port(runtime, protocol, port, lifetime).await
+}
+
+/// Keeps the listen port mapped for as long as [`run`](Self::run) is polled.
+/// Clones share the current mapping.
+#[derive(Debug, Clone, Default)]
+pub struct PortMapper {
+    mapping: Arc<Mutex<Option<Mapping>>>,
+}
+
+impl PortMapper {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The TCP mapping currently in place.
+    pub fn mapping(&self) -> Option<Mapping> {
+        self.mapping.lock().unwrap().clone()
+    }
+
+    /// Maps `port` for TCP and UDP, renewing the leases before they run out.
+    /// Never returns.
+    pub async fn run<R: Runtime>(&self, runtime: &R, port: u16) {
+        loop {
+            let delay = match map_port(runtime, Protocol::Tcp, port, LEASE).await {
+                Ok(mapping) => {
+                    info!(
+                        method = ?mapping.method,
+                        external_port = mapping.external_port,
+                        external_ip = ?mapping.external_ip,
+                        "mapped listen port"
+                    );
+                    // the DHT uses the same port over UDP
+                    if let Err(e) = map_port(runtime, Protocol::Udp, port, LEASE).await {
+                        debug!(error = %e, "failed to map udp port");
+                    }
+                    let delay = mapping.lifetime / 2;
+                    *self.mapping.lock().unwrap() = Some(mapping);
+                    delay
+                }
+                Err(e) => {
+                    warn!(error = %e, "failed to map listen port");
+                    *self.mapping.lock().unwrap() = None;
+                    RETRY_DELAY
+                }
+            };
+            runtime.sleep(delay).await;
+        }
+    }
+}
+
+/// The IPv4 default gateway from the kernel routing table. Only Linux
+/// exposes it without extra privileges; elsewhere this is `None` and only
+/// UPnP is tried.
+pub fn default_gateway() -> Option<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    parse_default_route(&routes)
+}
+
+/// The host's global IPv6 address, if it has one. IPv6 needs no port
+/// mapping, so it is where peers on IPv6 reach us. Connecting a UDP socket
+/// only picks a route, it sends nothing.
+pub fn global_ipv6() -> Option<Ipv6Addr> {
+    let socket = std::net::UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).ok()?;
+    // any global address will do, this one is a public DNS server
+    socket
+        .connect(SocketAddr::from((
+            [0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888],
+            53,
+        )))
+        .ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V6(ip) if is_global_ipv6(ip) => Some(ip),
+        _ => None,
+    }
+}
+
+/// Leaves out loopback, link-local and unique local addresses, which peers
+/// elsewhere can't reach.
+fn is_global_ipv6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !ip.is_unspecified()
+        && !ip.is_loopback()
+        && ip.to_ipv4_mapped().is_none()
+        && first & 0xffc0 != 0xfe80
+        && first & 0xfe00 != 0xfc00
+}
+
+/// Finds the route to 0.0.0.0/0 in `/proc/net/route`, whose addresses are
+/// little-endian hex.
+fn parse_default_route(routes: &str) -> Option<Ipv4Addr> {
+    routes.lines().skip(1).find_map(|line| {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        if fields.len() < 8 || fields[1] != "00000000" || fields[7] != "00000000" {
+            return None;
+        }
+        let gateway = u32::from_str_radix(fields[2], 16).ok()?;
+        (gateway != 0).then(|| Ipv4Addr::from(gateway.to_le_bytes()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_default_gateway() {
+        let routes =
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+            eth0\t0000A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0\n\
+            eth0\t00000000\t0100A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0\n";
+        assert_eq!(
+            parse_default_route(routes),
+            Some(Ipv4Addr::new(192, 168, 0, 1))
+        );
+        assert_eq!(parse_default_route(routes.lines().next().unwrap()), None);
+    }
+
+    #[test]
+    fn tells_global_ipv6_addresses_apart() {
+        for (ip, global) in [
+            ("2001:db8::1", true),
+            ("2a00:1450::5", true),
+            ("::1", false),
+            ("fe80::1", false),
+            ("fd12:3456::1", false),
+            ("::ffff:192.0.2.1", false),
+        ] {
+            assert_eq!(is_global_ipv6(ip.parse().unwrap()), global, "{}", ip);
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use crate::runtime::{timeout, Runtime, UdpSocket};
+
+use super::{Mapping, NatError, NatMethod, Protocol};
+
+/// The port NAT-PMP gateways listen on (RFC 6886). PCP gateways answer
+/// version 0 requests on the same port.
+const NATPMP_PORT: u16 = 5351;
+const OP_EXTERNAL_ADDRESS: u8 = 0;
+const OP_MAP_UDP: u8 = 1;
+const OP_MAP_TCP: u8 = 2;
+/// The first retry waits this long and each one after doubles it.
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+const ATTEMPTS: u32 = 4;
+
+pub(super) async fn map_port<R: Runtime>(
+    runtime: &R,
+    gateway: Ipv4Addr,
+    protocol: Protocol,
+    port: u16,
+    lifetime: Duration,
+) -> Result<Mapping, NatError> {
+    let op = match protocol {
+        Protocol::Tcp => OP_MAP_TCP,
+        Protocol::Udp => OP_MAP_UDP,
+    };
+    let mut request = vec![0, op, 0, 0];
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(&(lifetime.as_secs() as u32).to_be_bytes());
+    let response = transact(runtime, gateway, &request).await?;
+    if response.len() < 16 {
+        return Err(NatError::Protocol("mapping response too short".to_string()));
+    }
+    let external_port = u16::from_be_bytes([response[10], response[11]]);
+    let lifetime = u32::from_be_bytes(response[12..16].try_into().unwrap());
+
+    let external_ip = external_address(runtime, gateway).await.ok();
+    Ok(Mapping {
+        method: NatMethod::NatPmp,
+        protocol,
+        internal_port: port,
+        external_port,
+        external_ip: external_ip.map(IpAddr::V4),
+        lifetime: Duration::from_secs(lifetime as u64),
+    })
+}
+
+async fn external_address<R: Runtime>(
+    runtime: &R,
+    gateway: Ipv4Addr,
+) -> Result<Ipv4Addr, NatError> {
+    let response = transact(runtime, gateway, &[0, OP_EXTERNAL_ADDRESS]).await?;
+    if response.len() < 12 {
+        return Err(NatError::Protocol("address response too short".to_string()));
+    }
+    let ip: [u8; 4] = response[8..12].try_into().unwrap();
+    Ok(Ipv4Addr::from(ip))
+}
+
+/// Sends `request` to the gateway with the retry schedule from the RFC and
+/// returns the response once its result code says it succeeded.
+async fn transact<R: Runtime>(
+    runtime: &R,
+    gateway: Ipv4Addr,
+    request: &[u8],
+) -> Result<Vec<u8>, NatError> {
+    let socket = runtime
+        .bind_udp(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))
+        .await?;
+    let addr = SocketAddr::from((gateway, NATPMP_PORT));
+
+    let mut buf = [0u8; 64];
+    let mut wait = INITIAL_TIMEOUT;
+    for _ in 0..ATTEMPTS {
+        socket.send_to(request, addr).await?;
+        loop {
+            let Some(received) = timeout(runtime, wait, socket.recv_from(&mut buf)).await else {
+                break;
+            };
+            let (len, from) = received?;
+            // responses echo the opcode with the high bit set
+            if from != addr || len < 4 || buf[1] != request[1] | 0x80 {
+                continue;
+            }
+            if buf[0] != 0 {
+                return Err(NatError::Protocol(format!(
+                    "unsupported version {}",
+                    buf[0]
+                )));
+            }
+            let result = u16::from_be_bytes([buf[2], buf[3]]);
+            if result != 0 {
+                return Err(NatError::Protocol(format!("result code {}", result)));
+            }
+            return Ok(buf[..len].to_vec());
+        }
+        wait *= 2;
+    }
+    Err(NatError::NoGateway)
+}
@@ -0,0 +1,248 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use futures::{AsyncReadExt, AsyncWriteExt};
+use url::Url;
+
+use crate::runtime::{timeout, Runtime, UdpSocket};
+
+use super::{Mapping, NatError, NatMethod, Protocol};
+
+const SSDP_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250)), 1900);
+const SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+/// Services that can forward ports, in order of preference.
+const WAN_SERVICES: &[&str] = &[
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+/// Largest SOAP response read from the router.
+const MAX_RESPONSE_LENGTH: u64 = 64 * 1024;
+
+/// The router's port forwarding service.
+struct Gateway {
+    control_url: Url,
+    service: &'static str,
+    /// Our address on the router's network, which mappings point at.
+    local_ip: IpAddr,
+}
+
+pub(super) async fn map_port<R: Runtime>(
+    runtime: &R,
+    protocol: Protocol,
+    port: u16,
+    lifetime: Duration,
+) -> Result<Mapping, NatError> {
+    let gateway = discover(runtime).await?;
+    let protocol_name = match protocol {
+        Protocol::Tcp => "TCP",
+        Protocol::Udp => "UDP",
+    };
+    let arguments = [
+        ("NewRemoteHost", String::new()),
+        ("NewExternalPort", port.to_string()),
+        ("NewProtocol", protocol_name.to_string()),
+        ("NewInternalPort", port.to_string()),
+        ("NewInternalClient", gateway.local_ip.to_string()),
+        ("NewEnabled", "1".to_string()),
+        ("NewPortMappingDescription", "rustorrent".to_string()),
+        ("NewLeaseDuration", lifetime.as_secs().to_string()),
+    ];
+    soap(runtime, &gateway, "AddPortMapping", &arguments).await?;
+
+    let external_ip = soap(runtime, &gateway, "GetExternalIPAddress", &[])
+        .await
+        .ok()
+        .and_then(|response| tag(&response, "NewExternalIPAddress")?.parse().ok());
+    Ok(Mapping {
+        method: NatMethod::Upnp,
+        protocol,
+        internal_port: port,
+        external_port: port,
+        external_ip,
+        lifetime,
+    })
+}
+
+/// Finds the router with an SSDP search and reads its device description.
+async fn discover<R: Runtime>(runtime: &R) -> Result<Gateway, NatError> {
+    let socket = runtime
+        .bind_udp(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))
+        .await?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n\r\n",
+        SSDP_ADDR, SEARCH_TARGET
+    );
+    socket.send_to(search.as_bytes(), SSDP_ADDR).await?;
+
+    let mut buf = [0u8; 2048];
+    let location = loop {
+        let Some(received) = timeout(runtime, DISCOVERY_TIMEOUT, socket.recv_from(&mut buf)).await
+        else {
+            return Err(NatError::NoGateway);
+        };
+        let (len, _) = received?;
+        if let Some(location) = header(&String::from_utf8_lossy(&buf[..len]), "location") {
+            break location;
+        }
+    };
+    let location =
+        Url::parse(&location).map_err(|e| NatError::Protocol(format!("bad location: {}", e)))?;
+
+    let description = runtime.http_get(location.as_str()).await?;
+    let (service, control) = control_url(&String::from_utf8_lossy(&description))
+        .ok_or_else(|| NatError::Protocol("router has no port forwarding service".to_string()))?;
+    let control_url = location
+        .join(&control)
+        .map_err(|e| NatError::Protocol(format!("bad control url: {}", e)))?;
+
+    let router = resolve(runtime, &control_url).await?;
+    Ok(Gateway {
+        control_url,
+        service,
+        local_ip: local_ip(router)?,
+    })
+}
+
+/// Calls `action` on the gateway's service and returns the response body.
+async fn soap<R: Runtime>(
+    runtime: &R,
+    gateway: &Gateway,
+    action: &str,
+    arguments: &[(&str, String)],
+) -> Result<String, NatError> {
+    let mut body = format!(
+        "<?xml version=\"1.0\"?>\r\n<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body><u:{} xmlns:u=\"{}\">",
+        action, gateway.service
+    );
+    for (name, value) in arguments {
+        body.push_str(&format!("<{}>{}</{}>", name, value, name));
+    }
+    body.push_str(&format!("</u:{}></s:Body></s:Envelope>\r\n", action));
+
+    let url = &gateway.control_url;
+    let mut path = url.path().to_string();
+    if let Some(query) = url.query() {
+        path.push('?');
+        path.push_str(query);
+    }
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: text/xml; charset=\"utf-8\"\r\nSOAPAction: \"{}#{}\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        url.host_str().unwrap_or_default(),
+        url.port_or_known_default().unwrap_or(80),
+        gateway.service,
+        action,
+        body.len(),
+        body
+    );
+
+    let mut stream = runtime.connect(resolve(runtime, url).await?).await?;
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    (&mut stream)
+        .take(MAX_RESPONSE_LENGTH)
+        .read_to_end(&mut response)
+        .await?;
+    let response = String::from_utf8_lossy(&response).into_owned();
+
+    let status = response.split(' ').nth(1).unwrap_or_default();
+    if status != "200" {
+        let reason = tag(&response, "errorDescription").unwrap_or(status.to_string());
+        return Err(NatError::Protocol(format!("{} failed: {}", action, reason)));
+    }
+    Ok(response)
+}
+
+async fn resolve<R: Runtime>(runtime: &R, url: &Url) -> Result<SocketAddr, NatError> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| NatError::Protocol("control url has no host".to_string()))?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    runtime
+        .resolve(&format!("{}:{}", host, port))
+        .await?
+        .into_iter()
+        .next()
+        .ok_or(NatError::NoGateway)
+}
+
+/// The local address the OS would use to reach `router`. Connecting a UDP
+/// socket only picks a route, it sends nothing.
+fn local_ip(router: SocketAddr) -> Result<IpAddr, NatError> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(router)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// The value of an HTTP-style header, matched case-insensitively.
+fn header(response: &str, name: &str) -> Option<String> {
+    response.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then(|| value.trim().to_string())
+    })
+}
+
+/// The text inside the first `<name>` element.
+fn tag(xml: &str, name: &str) -> Option<String> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", name))?;
+    Some(xml[start..end].trim().to_string())
+}
+
+/// Picks the preferred port forwarding service from a device description and
+/// returns its type and control URL.
+fn control_url(description: &str) -> Option<(&'static str, String)> {
+    let services = description
+        .split("<service>")
+        .skip(1)
+        .filter_map(|service| Some((tag(service, "serviceType")?, tag(service, "controlURL")?)))
+        .collect::<Vec<_>>();
+    WAN_SERVICES.iter().find_map(|wanted| {
+        services
+            .iter()
+            .find(|(service, _)| service == wanted)
+            .map(|(_, control)| (*wanted, control.clone()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ssdp_and_device_description() {
+        let reply = "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\nLocation: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+        assert_eq!(
+            header(reply, "location").as_deref(),
+            Some("http://192.168.1.1:5000/rootDesc.xml")
+        );
+
+        let description = "<root><device><serviceList>\
+            <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+            <controlURL>/ctl/L3F</controlURL></service>\
+            <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+            <controlURL>/ctl/IPConn</controlURL></service>\
+            </serviceList></device></root>";
+        assert_eq!(
+            control_url(description),
+            Some((
+                "urn:schemas-upnp-org:service:WANIPConnection:1",
+                "/ctl/IPConn".to_string()
+            ))
+        );
+        assert_eq!(
+            tag(
+                "<u:R><NewExternalIPAddress>203.0.113.7</NewExternalIPAddress></u:R>",
+                "NewExternalIPAddress"
+            )
+            .as_deref(),
+            Some("203.0.113.7")
+        );
+    }
+}
@@ -0,0 +1,373 @@
+//! Tunnelling TCP connections and tracker requests through a SOCKS5 or HTTP
+//! proxy.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+};
+
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use url::{Host, Url};
+
+use crate::{
+    config::{ProxyConfig, ProxyKind},
+    runtime::{HttpStatusError, Runtime},
+};
+
+/// Largest response head accepted from an HTTP proxy or tracker.
+const MAX_HEAD_LENGTH: usize = 8 * 1024;
+/// Largest tracker response read through the proxy, before decompression.
+const MAX_BODY_LENGTH: usize = 16 * 1024 * 1024;
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// Opens a connection to `host:port` through `proxy`. The proxy resolves
+/// `host`, so names never leak to the local resolver.
+pub async fn connect<R: Runtime>(
+    runtime: &R,
+    proxy: &ProxyConfig,
+    host: &str,
+    port: u16,
+) -> io::Result<R::TcpStream> {
+    let mut stream = runtime.connect(proxy.addr).await?;
+    match proxy.kind {
+        ProxyKind::Socks5 => socks5_connect(&mut stream, proxy, host, port).await?,
+        ProxyKind::Http => http_connect(&mut stream, proxy, host, port).await?,
+    }
+    Ok(stream)
+}
+
+/// Opens a connection to `addr` through `proxy`.
+pub async fn connect_addr<R: Runtime>(
+    runtime: &R,
+    proxy: &ProxyConfig,
+    addr: SocketAddr,
+) -> io::Result<R::TcpStream> {
+    connect(runtime, proxy, &addr.ip().to_string(), addr.port()).await
+}
+
+/// Fetches an `http://` URL through `proxy` and returns the body. HTTPS
+/// would need TLS on top of the tunnel, which we don't have.
+pub async fn http_get<R: Runtime>(
+    runtime: &R,
+    proxy: &ProxyConfig,
+    url: &str,
+    user_agent: &str,
+) -> io::Result<Vec<u8>> {
+    let url = Url::parse(url).map_err(|e| invalid(e.to_string()))?;
+    if url.scheme() != "http" {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{} urls cannot be fetched through a proxy", url.scheme()),
+        ));
+    }
+    let host = match url.host() {
+        Some(Host::Domain(domain)) => domain.to_string(),
+        Some(Host::Ipv4(ip)) => ip.to_string(),
+        Some(Host::Ipv6(ip)) => ip.to_string(),
+        None => return Err(invalid("url has no host")),
+    };
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    let mut path = url.path().to_string();
+    if let Some(query) = url.query() {
+        path.push('?');
+        path.push_str(query);
+    }
+    let mut stream = match proxy.kind {
+        // an HTTP proxy takes the full URL instead of a tunnel
+        ProxyKind::Http => {
+            path = url.to_string();
+            runtime.connect(proxy.addr).await?
+        }
+        ProxyKind::Socks5 => connect(runtime, proxy, &host, port).await?,
+    };
+
+    let host_header = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or(&host), port),
+        None => url.host_str().unwrap_or(&host).to_string(),
+    };
+    let mut request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: {}\r\nAccept-Encoding: gzip\r\n\
+         Connection: close\r\n",
+        path, host_header, user_agent
+    );
+    if let (ProxyKind::Http, Some(auth)) = (proxy.kind, basic_auth(proxy)) {
+        request.push_str(&auth);
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    (&mut stream)
+        .take(MAX_BODY_LENGTH as u64 + MAX_HEAD_LENGTH as u64)
+        .read_to_end(&mut response)
+        .await?;
+    let Some(head_end) = response.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return Err(invalid("malformed http response"));
+    };
+    let head = &response[..head_end];
+    let status = status_code(head)?;
+    if !(200..300).contains(&status) {
+        let head = String::from_utf8_lossy(head);
+        let reason = head
+            .lines()
+            .next()
+            .and_then(|line| line.splitn(3, ' ').nth(2))
+            .unwrap_or_default();
+        let retry_after = head
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("retry-after"))
+            .and_then(|(_, value)| HttpStatusError::parse_retry_after(value));
+        return Err(HttpStatusError {
+            status,
+            reason: reason.trim().to_string(),
+            retry_after,
+        }
+        .into());
+    }
+    Ok(response.split_off(head_end + 4))
+}
+
+/// The SOCKS5 `CONNECT` exchange from RFC 1928, with RFC 1929
+/// username/password authentication when credentials are configured.
+async fn socks5_connect<S>(
+    stream: &mut S,
+    proxy: &ProxyConfig,
+    host: &str,
+    port: u16,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let greeting: &[u8] = match proxy.credentials {
+        Some(_) => &[5, 2, 0, 2],
+        None => &[5, 1, 0],
+    };
+    stream.write_all(greeting).await?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice[0] != 5 {
+        return Err(invalid("not a socks5 proxy"));
+    }
+    match (choice[1], &proxy.credentials) {
+        (0, _) => {}
+        (2, Some((username, password))) => {
+            if username.len() > 255 || password.len() > 255 {
+                return Err(invalid("socks5 credentials too long"));
+            }
+            let mut auth = vec![1, username.len() as u8];
+            auth.extend_from_slice(username.as_bytes());
+            auth.push(password.len() as u8);
+            auth.extend_from_slice(password.as_bytes());
+            stream.write_all(&auth).await?;
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status).await?;
+            if status[1] != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "socks5 proxy rejected the credentials",
+                ));
+            }
+        }
+        _ => return Err(invalid("socks5 proxy offered no usable auth method")),
+    }
+
+    let mut request = vec![5, 1, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            if host.len() > 255 {
+                return Err(invalid("host name too long for socks5"));
+            }
+            request.push(3);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("socks5 connect failed with code {}", reply[1]),
+        ));
+    }
+    // the address the proxy bound, which we have no use for
+    let bound_len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            len[0] as usize
+        }
+        _ => return Err(invalid("bad socks5 address type")),
+    };
+    let mut bound = vec![0u8; bound_len + 2];
+    stream.read_exact(&mut bound).await
+}
+
+/// Asks an HTTP proxy for a tunnel to `host:port`.
+async fn http_connect<S>(
+    stream: &mut S,
+    proxy: &ProxyConfig,
+    host: &str,
+    port: u16,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let target = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{}]:{}", ip, port),
+        _ => format!("{}:{}", host, port),
+    };
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+    if let Some(auth) = basic_auth(proxy) {
+        request.push_str(&auth);
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // read byte by byte so nothing from the tunnel is consumed
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() > MAX_HEAD_LENGTH {
+            return Err(invalid("proxy response head too long"));
+        }
+        stream.read_exact(&mut byte).await?;
+        head.push(byte[0]);
+    }
+    let status = status_code(&head)?;
+    if status != 200 {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("proxy refused to connect with status {}", status),
+        ));
+    }
+    Ok(())
+}
+
+fn status_code(head: &[u8]) -> io::Result<u16> {
+    let head = std::str::from_utf8(head).map_err(|_| invalid("response is not utf-8"))?;
+    head.split(' ')
+        .nth(1)
+        .and_then(|code| code.trim().parse().ok())
+        .ok_or_else(|| invalid("malformed http status line"))
+}
+
+fn basic_auth(proxy: &ProxyConfig) -> Option<String> {
+    let (username, password) = proxy.credentials.as_ref()?;
+    Some(format!(
+        "Proxy-Authorization: Basic {}\r\n",
+        base64(format!("{}:{}", username, password).as_bytes())
+    ))
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{executor::block_on, future::join};
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    use super::*;
+
+    fn proxy(kind: ProxyKind, credentials: Option<(&str, &str)>) -> ProxyConfig {
+        ProxyConfig {
+            kind,
+            addr: SocketAddr::from(([127, 0, 0, 1], 1080)),
+            credentials: credentials.map(|(u, p)| (u.to_string(), p.to_string())),
+        }
+    }
+
+    #[test]
+    fn socks5_connect_with_credentials() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (mut client, mut server) = (client.compat(), server.compat());
+        let proxy = proxy(ProxyKind::Socks5, Some(("user", "pass")));
+
+        let serve = async {
+            let mut greeting = [0u8; 4];
+            server.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 2, 0, 2]);
+            server.write_all(&[5, 2]).await.unwrap();
+
+            let mut auth = [0u8; 11];
+            server.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth, b"\x01\x04user\x04pass");
+            server.write_all(&[1, 0]).await.unwrap();
+
+            let mut request = [0u8; 5 + 19 + 2];
+            server.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request[..5], &[5, 1, 0, 3, 19]);
+            assert_eq!(&request[5..24], b"tracker.example.com");
+            assert_eq!(&request[24..], &80u16.to_be_bytes());
+            server
+                .write_all(&[5, 0, 0, 1, 10, 0, 0, 1, 0x1f, 0x90])
+                .await
+                .unwrap();
+        };
+        let (result, _) = block_on(join(
+            socks5_connect(&mut client, &proxy, "tracker.example.com", 80),
+            serve,
+        ));
+        result.unwrap();
+    }
+
+    #[test]
+    fn http_connect_checks_status() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (mut client, mut server) = (client.compat(), server.compat());
+        let proxy = proxy(ProxyKind::Http, Some(("user", "pass")));
+
+        let serve = async {
+            let mut request = vec![0u8; 1024];
+            let n = server.read(&mut request).await.unwrap();
+            let request = String::from_utf8_lossy(&request[..n]).into_owned();
+            server
+                .write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n")
+                .await
+                .unwrap();
+            request
+        };
+        let (result, request) = block_on(join(
+            http_connect(&mut client, &proxy, "10.0.0.1", 6881),
+            serve,
+        ));
+        assert!(request.starts_with("CONNECT 10.0.0.1:6881 HTTP/1.1\r\n"));
+        assert!(request.contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n"));
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::ConnectionRefused);
+    }
+}
@@ -0,0 +1,383 @@
+//! An HTTP+JSON control server for a [`Session`], so other programs can
+//! manage torrents the way they would with a daemon.
+//!
+//! | Request                        | Body          | Response            |
+//! |--------------------------------|---------------|---------------------|
+//! | `GET /torrents`                |               | `[TorrentInfo]`     |
+//! | `POST /torrents`               | .torrent file | `TorrentInfo`       |
+//! | `GET /torrents/<hash>`         |               | `TorrentInfo`       |
+//! | `GET /torrents/<hash>/peers`   |               | `[PeerInfo]`        |
+//! | `GET /torrents/<hash>/tracker` |               | `TrackerInfo`       |
+//! | `POST /torrents/<hash>/pause`  |               | 204                 |
+//! | `POST /torrents/<hash>/resume` |               | 204                 |
+//! | `DELETE /torrents/<hash>`      |               | 204                 |
+//!
+//! `<hash>` is the info hash in hex or base32. Deleting a torrent keeps its files unless
+//! `?delete_data=true` is given. Errors come back as `{"error": "..."}`.
+
+use std::{io, time::Duration};
+
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use serde::Serialize;
+use tracing::{debug, info};
+
+use crate::{
+    client::state::{PeerSummary, Progress},
+    metainfo::InfoHash,
+    runtime::{timeout, Runtime, TcpListener},
+    session::{Session, TorrentHandle},
+    tracker::TrackerStatus,
+};
+
+/// Largest request line and headers accepted.
+const MAX_HEAD_LENGTH: usize = 8 * 1024;
+/// Largest body accepted, which bounds the size of an uploaded .torrent.
+const MAX_BODY_LENGTH: usize = 16 * 1024 * 1024;
+/// How long a client has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TorrentInfo {
+    pub info_hash: String,
+    pub name: String,
+    pub paused: bool,
+    pub total_length: u64,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// Pieces verified, from 0 to 100.
+    pub percent: f64,
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    pub peers: usize,
+}
+
+impl From<&TorrentHandle> for TorrentInfo {
+    fn from(handle: &TorrentHandle) -> Self {
+        let progress = Progress::from(handle.state().as_ref());
+        Self {
+            info_hash: handle.info_hash().to_string(),
+            name: handle.name().to_string(),
+            paused: handle.is_paused(),
+            total_length: progress.total_length,
+            downloaded: progress.downloaded,
+            uploaded: progress.uploaded,
+            percent: progress.percent(),
+            download_rate: progress.download_rate,
+            upload_rate: progress.upload_rate,
+            peers: progress.peers,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PeerInfo {
+    pub addr: String,
+    pub peer_id: String,
+    pub client: Option<String>,
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+    pub pieces: usize,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    pub requests_in_flight: usize,
+    /// Seconds since the peer last sent a block we asked for.
+    pub since_last_block: Option<f64>,
+    pub bandwidth_delay: u64,
+    pub snubbed: bool,
+}
+
+impl From<&PeerSummary> for PeerInfo {
+    fn from(peer: &PeerSummary) -> Self {
+        Self {
+            addr: peer.addr.to_string(),
+            peer_id: String::from_utf8_lossy(&peer.peer_id).into_owned(),
+            client: peer.client.as_ref().map(|c| c.to_string()),
+            am_choking: peer.am_choking,
+            am_interested: peer.am_interested,
+            peer_choking: peer.peer_choking,
+            peer_interested: peer.peer_interested,
+            pieces: peer.pieces,
+            downloaded: peer.downloaded,
+            uploaded: peer.uploaded,
+            download_rate: peer.download_rate,
+            upload_rate: peer.upload_rate,
+            requests_in_flight: peer.requests_in_flight,
+            since_last_block: peer.since_last_block.map(|d| d.as_secs_f64()),
+            bandwidth_delay: peer.bandwidth_delay,
+            snubbed: peer.snubbed,
+        }
+    }
+}
+
+/// How announcing to the torrent's current tracker is going.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TrackerInfo {
+    pub announce: String,
+    /// RFC 3339, or `None` if no announce has gone through yet.
+    pub last_announce: Option<String>,
+    pub interval: Option<i64>,
+    pub last_error: Option<String>,
+    pub warning: Option<String>,
+    pub failures: u64,
+}
+
+impl From<&TrackerStatus> for TrackerInfo {
+    fn from(status: &TrackerStatus) -> Self {
+        Self {
+            announce: status.announce.clone(),
+            last_announce: status.last_announce.map(|time| time.to_rfc3339()),
+            interval: status.interval,
+            last_error: status.last_error.clone(),
+            warning: status.warning.clone(),
+            failures: status.failures,
+        }
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: u16,
+    body: Option<String>,
+}
+
+impl Response {
+    fn json<T: Serialize>(status: u16, value: &T) -> Self {
+        match serde_json::to_string(value) {
+            Ok(body) => Self {
+                status,
+                body: Some(body),
+            },
+            Err(e) => Self::error(500, &e.to_string()),
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Self {
+            status,
+            body: Some(serde_json::json!({ "error": message }).to_string()),
+        }
+    }
+
+    fn empty() -> Self {
+        Self {
+            status: 204,
+            body: None,
+        }
+    }
+}
+
+/// Handles requests on `listener` one at a time until accepting fails.
+pub async fn serve<R: Runtime>(
+    session: &mut Session<R>,
+    listener: R::TcpListener,
+) -> io::Result<()> {
+    info!(addr = %listener.local_addr()?, "serving control api");
+    loop {
+        let (mut stream, addr) = listener.accept().await?;
+        let runtime = session.runtime().clone();
+        let request = match timeout(&runtime, REQUEST_TIMEOUT, read_request(&mut stream)).await {
+            Some(Ok(request)) => request,
+            Some(Err(e)) => {
+                debug!(%addr, error = %e, "bad control request");
+                let _ = write_response(&mut stream, Response::error(400, &e.to_string())).await;
+                continue;
+            }
+            None => {
+                debug!(%addr, "control request timed out");
+                continue;
+            }
+        };
+
+        debug!(%addr, method = %request.method, path = %request.path, "control request");
+        let response = handle(session, request);
+        if let Err(e) = write_response(&mut stream, response).await {
+            debug!(%addr, error = %e, "failed to send control response");
+        }
+    }
+}
+
+fn handle<R: Runtime>(session: &mut Session<R>, request: Request) -> Response {
+    let (path, query) = request
+        .path
+        .split_once('?')
+        .unwrap_or((request.path.as_str(), ""));
+    let parts = path
+        .split('/')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>();
+
+    match (request.method.as_str(), parts.as_slice()) {
+        ("GET", ["torrents"]) => {
+            let torrents = session
+                .torrents()
+                .iter()
+                .map(TorrentInfo::from)
+                .collect::<Vec<_>>();
+            Response::json(200, &torrents)
+        }
+        ("POST", ["torrents"]) => match session.add_torrent(request.body) {
+            Ok(handle) => Response::json(201, &TorrentInfo::from(&handle)),
+            Err(e) => Response::error(400, &e.to_string()),
+        },
+        (method, ["torrents", hash, rest @ ..]) => {
+            let Some(handle) = hash
+                .parse::<InfoHash>()
+                .ok()
+                .and_then(|info_hash| session.torrent(&info_hash))
+            else {
+                return Response::error(404, "no such torrent");
+            };
+            match (method, rest) {
+                ("GET", []) => Response::json(200, &TorrentInfo::from(handle)),
+                ("GET", ["peers"]) => {
+                    let peers = handle
+                        .peers()
+                        .iter()
+                        .map(PeerInfo::from)
+                        .collect::<Vec<_>>();
+                    Response::json(200, &peers)
+                }
+                ("GET", ["tracker"]) => {
+                    Response::json(200, &TrackerInfo::from(&handle.state().tracker))
+                }
+                ("POST", ["pause"]) => {
+                    handle.pause();
+                    Response::empty()
+                }
+                ("POST", ["resume"]) => {
+                    handle.resume();
+                    Response::empty()
+                }
+                ("DELETE", []) => {
+                    let delete_data = query.split('&').any(|pair| pair == "delete_data=true");
+                    let handle = handle.clone();
+                    session.remove_torrent(&handle, delete_data);
+                    Response::empty()
+                }
+                _ => Response::error(404, "not found"),
+            }
+        }
+        _ => Response::error(404, "not found"),
+    }
+}
+
+async fn read_request<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Request> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4096];
+    let head_end = loop {
+        if let Some(i) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i;
+        }
+        if data.len() > MAX_HEAD_LENGTH {
+            return Err(invalid("request head too long"));
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        data.extend_from_slice(&buf[..n]);
+    };
+
+    let mut body = data.split_off(head_end + 4);
+    let head =
+        std::str::from_utf8(&data[..head_end]).map_err(|_| invalid("request is not utf-8"))?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(path)) = (request_line.next(), request_line.next()) else {
+        return Err(invalid("malformed request line"));
+    };
+    let mut content_length = 0;
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| invalid("bad content length"))?;
+            }
+        }
+    }
+    if content_length > MAX_BODY_LENGTH {
+        return Err(invalid("request body too large"));
+    }
+
+    if body.len() < content_length {
+        let start = body.len();
+        body.resize(content_length, 0);
+        stream.read_exact(&mut body[start..]).await?;
+    }
+    body.truncate(content_length);
+
+    Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        body,
+    })
+}
+
+async fn write_response<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    response: Response,
+) -> io::Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        _ => "Internal Server Error",
+    };
+    let body = response.body.unwrap_or_default();
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        reason,
+        body.len()
+    );
+    if !body.is_empty() {
+        head.push_str("Content-Type: application/json\r\n");
+    }
+    head.push_str("\r\n");
+
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.close().await
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+
+    #[test]
+    fn reads_request_with_body() {
+        let raw = b"POST /torrents HTTP/1.1\r\nHost: x\r\ncontent-length: 5\r\n\r\nhello";
+        let request = block_on(read_request(&mut &raw[..])).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/torrents");
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[test]
+    fn parses_hex_info_hash() {
+        let hash = "00ff10".repeat(6) + "00ff";
+        assert_eq!(
+            hash.parse::<InfoHash>().map(|h| h.to_string()),
+            Ok(hash.clone())
+        );
+        assert!("00ff10".parse::<InfoHash>().is_err());
+        assert!("zz".repeat(20).parse::<InfoHash>().is_err());
+    }
+}
@@ -0,0 +1,139 @@
+use std::{
+    fmt::Display, future::Future, io, net::SocketAddr, ops::Range, pin::pin, time::Duration,
+};
+
+use futures::future::{select, Either};
+
+use crate::{client::transport::PeerTransport, config::TrackerConfig};
+
+#[cfg(feature = "tokio")]
+mod tokio_runtime;
+
+#[cfg(feature = "tokio")]
+pub use tokio_runtime::TokioRuntime;
+
+/// A tracker answered with a status outside 2xx. Carried inside the
+/// `io::Error` that [`Runtime::tracker_get`] fails with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpStatusError {
+    pub status: u16,
+    pub reason: String,
+    /// From a `Retry-After` header given in seconds.
+    pub retry_after: Option<Duration>,
+}
+
+impl HttpStatusError {
+    /// Parses a `Retry-After` header. Only the delay-seconds form is
+    /// understood.
+    pub fn parse_retry_after(value: &str) -> Option<Duration> {
+        value.trim().parse().ok().map(Duration::from_secs)
+    }
+
+    /// The status error inside `e`, if it is one.
+    pub fn from_io(e: &io::Error) -> Option<&Self> {
+        e.get_ref()?.downcast_ref()
+    }
+}
+
+impl Display for HttpStatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "http status {} {}", self.status, self.reason)
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
+
+impl From<HttpStatusError> for io::Error {
+    fn from(e: HttpStatusError) -> Self {
+        io::Error::other(e)
+    }
+}
+
+pub trait UdpSocket: Send + Sync + 'static {
+    fn send_to(
+        &self,
+        buf: &[u8],
+        addr: SocketAddr,
+    ) -> impl Future<Output = io::Result<usize>> + Send;
+
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> impl Future<Output = io::Result<(usize, SocketAddr)>> + Send;
+
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+pub trait TcpListener: Send + Sync + 'static {
+    type TcpStream;
+
+    fn accept(&self) -> impl Future<Output = io::Result<(Self::TcpStream, SocketAddr)>> + Send;
+
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+/// Everything the engine needs from an async runtime. The protocol and
+/// scheduling code only ever goes through this trait, so embedding the client
+/// in another executor means implementing it once.
+pub trait Runtime: Clone + Send + Sync + 'static {
+    type TcpStream: PeerTransport;
+    type TcpListener: TcpListener<TcpStream = Self::TcpStream>;
+    type UdpSocket: UdpSocket;
+
+    /// Runs `future` to completion in the background.
+    fn spawn<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static;
+
+    fn sleep(&self, duration: Duration) -> impl Future<Output = ()> + Send;
+
+    fn connect(&self, addr: SocketAddr)
+        -> impl Future<Output = io::Result<Self::TcpStream>> + Send;
+
+    fn listen(
+        &self,
+        addr: SocketAddr,
+    ) -> impl Future<Output = io::Result<Self::TcpListener>> + Send;
+
+    fn bind_udp(
+        &self,
+        addr: SocketAddr,
+    ) -> impl Future<Output = io::Result<Self::UdpSocket>> + Send;
+
+    /// Resolves a `host:port` string.
+    fn resolve(&self, host: &str) -> impl Future<Output = io::Result<Vec<SocketAddr>>> + Send;
+
+    /// Performs an HTTP GET and returns the response body.
+    fn http_get(&self, url: &str) -> impl Future<Output = io::Result<Vec<u8>>> + Send;
+
+    /// Performs an HTTP GET to a tracker with the timeout, user agent and TLS
+    /// settings in `config`, and returns the response body. The body may be
+    /// gzipped, as gzip is asked for. A status outside 2xx fails with an
+    /// [`HttpStatusError`].
+    fn tracker_get(
+        &self,
+        url: &str,
+        config: &TrackerConfig,
+    ) -> impl Future<Output = io::Result<Vec<u8>>> + Send;
+
+    /// Fetches `range` of the resource at `url` with an HTTP range request.
+    fn http_get_range(
+        &self,
+        url: &str,
+        range: Range<u64>,
+    ) -> impl Future<Output = io::Result<Vec<u8>>> + Send;
+}
+
+/// Resolves to `None` if `future` does not complete within `duration`.
+pub async fn timeout<R, F>(runtime: &R, duration: Duration, future: F) -> Option<F::Output>
+where
+    R: Runtime,
+    F: Future,
+{
+    let future = pin!(future);
+    let sleep = pin!(runtime.sleep(duration));
+    match select(future, sleep).await {
+        Either::Left((output, _)) => Some(output),
+        Either::Right(_) => None,
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::SocketAddr,
+    ops::Range,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use sha2::{Digest, Sha256};
+use tokio::net::{lookup_host, TcpListener, TcpStream, UdpSocket};
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+use super::{HttpStatusError, Runtime};
+use crate::config::TrackerConfig;
+
+impl super::TcpListener for TcpListener {
+    type TcpStream = Compat<TcpStream>;
+
+    async fn accept(&self) -> io::Result<(Self::TcpStream, SocketAddr)> {
+        let (stream, addr) = TcpListener::accept(self).await?;
+        Ok((stream.compat(), addr))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpListener::local_addr(self)
+    }
+}
+
+impl super::UdpSocket for UdpSocket {
+    async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf).await
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+/// The client for requests to trackers with `config`. Clients are shared by
+/// every request with the same settings, so connections are kept open and
+/// reused across announces and torrents.
+fn tracker_client(config: &TrackerConfig) -> io::Result<reqwest::Client> {
+    static CLIENTS: OnceLock<Mutex<HashMap<TrackerConfig, reqwest::Client>>> = OnceLock::new();
+    let mut clients = CLIENTS.get_or_init(Default::default).lock().unwrap();
+    if let Some(client) = clients.get(config) {
+        return Ok(client.clone());
+    }
+
+    let pinned = !config.pinned_certificates.is_empty();
+    let mut client = reqwest::Client::builder()
+        .timeout(config.timeout)
+        .connect_timeout(config.connect_timeout)
+        .pool_idle_timeout(config.pool_idle_timeout)
+        .user_agent(&config.user_agent)
+        // the pins replace the usual chain and hostname checks
+        .danger_accept_invalid_certs(pinned)
+        .tls_info(pinned);
+    if let Some(roots) = &config.tls_roots {
+        client = client.tls_built_in_root_certs(false);
+        for pem in roots {
+            let root = reqwest::Certificate::from_pem(pem).map_err(io::Error::other)?;
+            client = client.add_root_certificate(root);
+        }
+    }
+    let client = client.build().map_err(io::Error::other)?;
+    clients.insert(config.clone(), client.clone());
+    Ok(client)
+}
+
+/// The client for web seeds and other plain requests.
+fn default_client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(reqwest::Client::new)
+}
+
+/// The default runtime. Must be used from within a tokio runtime context.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioRuntime;
+
+impl Runtime for TokioRuntime {
+    type TcpStream = Compat<TcpStream>;
+    type TcpListener = TcpListener;
+    type UdpSocket = UdpSocket;
+
+    fn spawn<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        tokio::spawn(future);
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+
+    async fn connect(&self, addr: SocketAddr) -> io::Result<Self::TcpStream> {
+        let stream = TcpStream::connect(addr).await?;
+        Ok(stream.compat())
+    }
+
+    async fn listen(&self, addr: SocketAddr) -> io::Result<Self::TcpListener> {
+        TcpListener::bind(addr).await
+    }
+
+    async fn bind_udp(&self, addr: SocketAddr) -> io::Result<Self::UdpSocket> {
+        UdpSocket::bind(addr).await
+    }
+
+    async fn resolve(&self, host: &str) -> io::Result<Vec<SocketAddr>> {
+        Ok(lookup_host(host).await?.collect())
+    }
+
+    async fn http_get(&self, url: &str) -> io::Result<Vec<u8>> {
+        let response = default_client()
+            .get(url)
+            .send()
+            .await
+            .map_err(io::Error::other)?;
+        let body = response.bytes().await.map_err(io::Error::other)?;
+        Ok(body.to_vec())
+    }
+
+    async fn tracker_get(&self, url: &str, config: &TrackerConfig) -> io::Result<Vec<u8>> {
+        let pinned = !config.pinned_certificates.is_empty();
+        let response = tracker_client(config)?
+            .get(url)
+            // decoded by the tracker, as reqwest is built without gzip
+            .header(reqwest::header::ACCEPT_ENCODING, "gzip")
+            .send()
+            .await
+            .map_err(|e| {
+                // nothing is listening, which asking again won't change
+                if e.is_connect() && !e.is_timeout() {
+                    io::Error::new(io::ErrorKind::ConnectionRefused, e)
+                } else {
+                    io::Error::other(e)
+                }
+            })?;
+        let status = response.status();
+        if !status.is_success() {
+            let retry_after = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(HttpStatusError::parse_retry_after);
+            return Err(HttpStatusError {
+                status: status.as_u16(),
+                reason: status.canonical_reason().unwrap_or_default().to_string(),
+                retry_after,
+            }
+            .into());
+        }
+        if pinned && response.url().scheme() == "https" {
+            let certificate = response
+                .extensions()
+                .get::<reqwest::tls::TlsInfo>()
+                .and_then(|info| info.peer_certificate());
+            let fingerprint = certificate.map(|der| <[u8; 32]>::from(Sha256::digest(der)));
+            if !fingerprint.is_some_and(|f| config.pinned_certificates.contains(&f)) {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "tracker certificate is not pinned",
+                ));
+            }
+        }
+        let body = response.bytes().await.map_err(io::Error::other)?;
+        Ok(body.to_vec())
+    }
+
+    async fn http_get_range(&self, url: &str, range: Range<u64>) -> io::Result<Vec<u8>> {
+        let response = default_client()
+            .get(url)
+            .header(
+                reqwest::header::RANGE,
+                format!("bytes={}-{}", range.start, range.end.saturating_sub(1)),
+            )
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(io::Error::other)?;
+        let partial = response.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+        let body = response.bytes().await.map_err(io::Error::other)?;
+        if partial {
+            return Ok(body.to_vec());
+        }
+        // the server ignored the range and sent the whole resource
+        body.get(range.start as usize..range.end as usize)
+            .map(|slice| slice.to_vec())
+            .ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+    }
+}
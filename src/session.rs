@@ -0,0 +1,490 @@
+//! The embedding API. A [`Session`] runs any number of torrents in the
+//! background and hands out a [`TorrentHandle`] for each one.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), rustorrent::session::SessionError> {
+//! use futures::StreamExt;
+//! use rustorrent::{
+//!     config::ClientConfig,
+//!     session::{Session, TorrentEvent},
+//! };
+//!
+//! let mut session = Session::new("downloads", ClientConfig::default());
+//! let torrent = session.add_torrent(std::path::Path::new("debian.torrent"))?;
+//!
+//! let mut events = torrent.events();
+//! while let Some(event) = events.next().await {
+//!     if event == TorrentEvent::Finished {
+//!         break;
+//!     }
+//!     println!("{:.1}%", torrent.progress() * 100.0);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    fmt::Display,
+    fs, io,
+    path::{Path, PathBuf},
+    pin::pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures::{
+    channel::mpsc,
+    future::{select, Either},
+    StreamExt,
+};
+use tracing::{info_span, warn, Instrument};
+
+#[cfg(feature = "tokio")]
+use crate::runtime::TokioRuntime;
+use crate::{
+    bencode::ParseError,
+    client::{
+        event::EventBus,
+        file_manager::FileManager,
+        limits::{ConnectionLimits, UploadShare},
+        priority::PriorityHandle,
+        state::{ClientState, PeerSummary, StateHandle},
+        stream::FileStream,
+        Client, ClientError,
+    },
+    config::ClientConfig,
+    metainfo::{InfoHash, MetaInfoError, Metainfo},
+    runtime::Runtime,
+    tracker::{Tracker, TrackerError},
+};
+
+pub use crate::client::event::TorrentEvent;
+
+#[derive(Debug)]
+pub enum SessionError {
+    Io(io::Error),
+    /// The torrent file isn't valid bencode.
+    Bencode(ParseError),
+    /// The metainfo is malformed or incomplete.
+    Tracker(TrackerError),
+    /// The torrent's files can't be opened where they would go.
+    Client(ClientError),
+}
+
+impl Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionError::Io(e) => write!(f, "Io: {}", e),
+            SessionError::Bencode(e) => write!(f, "Bencode: {}", e),
+            SessionError::Tracker(e) => write!(f, "Tracker: {}", e),
+            SessionError::Client(e) => write!(f, "Client: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SessionError::Io(e) => Some(e),
+            SessionError::Bencode(e) => Some(e),
+            SessionError::Tracker(e) => Some(e),
+            SessionError::Client(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for SessionError {
+    fn from(e: io::Error) -> Self {
+        SessionError::Io(e)
+    }
+}
+
+impl From<ParseError> for SessionError {
+    fn from(e: ParseError) -> Self {
+        SessionError::Bencode(e)
+    }
+}
+
+impl From<MetaInfoError> for SessionError {
+    fn from(e: MetaInfoError) -> Self {
+        match e {
+            MetaInfoError::Bencode(e) => SessionError::Bencode(e),
+            e => SessionError::Tracker(TrackerError::from(e)),
+        }
+    }
+}
+
+impl From<TrackerError> for SessionError {
+    fn from(e: TrackerError) -> Self {
+        SessionError::Tracker(e)
+    }
+}
+
+impl From<ClientError> for SessionError {
+    fn from(e: ClientError) -> Self {
+        SessionError::Client(e)
+    }
+}
+
+/// Where to read a torrent's metainfo from.
+pub enum TorrentSource {
+    Path(PathBuf),
+    Bytes(Vec<u8>),
+}
+
+impl From<&Path> for TorrentSource {
+    fn from(path: &Path) -> Self {
+        TorrentSource::Path(path.to_path_buf())
+    }
+}
+
+impl From<PathBuf> for TorrentSource {
+    fn from(path: PathBuf) -> Self {
+        TorrentSource::Path(path)
+    }
+}
+
+impl From<Vec<u8>> for TorrentSource {
+    fn from(bytes: Vec<u8>) -> Self {
+        TorrentSource::Bytes(bytes)
+    }
+}
+
+impl From<&[u8]> for TorrentSource {
+    fn from(bytes: &[u8]) -> Self {
+        TorrentSource::Bytes(bytes.to_vec())
+    }
+}
+
+enum Command {
+    Pause,
+    Resume,
+    Recheck,
+    /// Stops the torrent for good, deleting its data if set.
+    Remove(bool),
+}
+
+pub struct Session<R: Runtime> {
+    runtime: R,
+    output_dir: String,
+    config: ClientConfig,
+    /// Slots shared by every torrent, from the config's session limits.
+    limits: ConnectionLimits,
+    torrents: Vec<TorrentHandle>,
+}
+
+#[cfg(feature = "tokio")]
+impl Session<TokioRuntime> {
+    /// Must be called from within a tokio runtime context.
+    pub fn new(output_dir: impl Into<String>, config: ClientConfig) -> Self {
+        Self::with_runtime(output_dir, config, TokioRuntime)
+    }
+}
+
+impl<R: Runtime> Session<R> {
+    pub fn with_runtime(output_dir: impl Into<String>, config: ClientConfig, runtime: R) -> Self {
+        Self {
+            runtime,
+            output_dir: output_dir.into(),
+            limits: ConnectionLimits::new(&config.session_limits),
+            config,
+            torrents: Vec::new(),
+        }
+    }
+
+    /// Starts downloading a torrent into the session's output directory. The
+    /// torrent keeps running until it is removed, or until the session and
+    /// every handle to it are dropped. Adding a torrent the session already
+    /// has returns its existing handle.
+    pub fn add_torrent(
+        &mut self,
+        torrent: impl Into<TorrentSource>,
+    ) -> Result<TorrentHandle, SessionError> {
+        let bytes = match torrent.into() {
+            TorrentSource::Path(path) => fs::read(path)?,
+            TorrentSource::Bytes(bytes) => bytes,
+        };
+        let metainfo = Metainfo::from_bytes(&bytes)?;
+        let info_hash = *metainfo.info_hash();
+        if let Some(handle) = self.torrent(&info_hash) {
+            return Ok(handle.clone());
+        }
+        let name = metainfo.name().to_string();
+        let tracker = Tracker::from_metainfo(metainfo, &self.config);
+
+        let mut client = Client::with_runtime(
+            tracker,
+            self.output_dir.clone(),
+            self.config.clone(),
+            self.runtime.clone(),
+        )?;
+        client.set_connection_limits(self.limits.clone());
+        let (commands_tx, commands_rx) = mpsc::unbounded();
+        let handle = TorrentHandle {
+            info_hash,
+            name,
+            commands: commands_tx,
+            state: client.state_handle(),
+            priority: client.priority_handle(),
+            events: client.events(),
+            files: client.file_manager().clone(),
+            upload_share: client.upload_share().clone(),
+            paused: Arc::new(AtomicBool::new(false)),
+        };
+
+        let span = info_span!("torrent", %info_hash);
+        self.runtime.spawn(
+            run_torrent(
+                client,
+                commands_rx,
+                handle.events.clone(),
+                handle.paused.clone(),
+            )
+            .instrument(span),
+        );
+        self.torrents.push(handle.clone());
+        Ok(handle)
+    }
+
+    /// Stops a torrent and drops it from the session, deleting its
+    /// downloaded files and resume data if `delete_data` is set. This
+    /// happens in the background; the handle's events end with
+    /// [`TorrentEvent::Removed`] once it is done. Returns false if the
+    /// torrent isn't in the session.
+    pub fn remove_torrent(&mut self, handle: &TorrentHandle, delete_data: bool) -> bool {
+        let Some(position) = self
+            .torrents
+            .iter()
+            .position(|t| t.info_hash == handle.info_hash)
+        else {
+            return false;
+        };
+        let torrent = self.torrents.remove(position);
+        let _ = torrent
+            .commands
+            .unbounded_send(Command::Remove(delete_data));
+        true
+    }
+
+    pub fn torrents(&self) -> &[TorrentHandle] {
+        &self.torrents
+    }
+
+    pub fn torrent(&self, info_hash: &InfoHash) -> Option<&TorrentHandle> {
+        self.torrents.iter().find(|t| &t.info_hash == info_hash)
+    }
+
+    /// The connection and upload slots the session's torrents share.
+    pub fn connection_limits(&self) -> &ConnectionLimits {
+        &self.limits
+    }
+
+    pub fn runtime(&self) -> &R {
+        &self.runtime
+    }
+}
+
+/// Controls and observes one torrent of a [`Session`]. Cheap to clone.
+#[derive(Clone)]
+pub struct TorrentHandle {
+    info_hash: InfoHash,
+    name: String,
+    commands: mpsc::UnboundedSender<Command>,
+    state: StateHandle,
+    priority: PriorityHandle,
+    events: EventBus,
+    files: FileManager,
+    upload_share: UploadShare,
+    paused: Arc<AtomicBool>,
+}
+
+impl TorrentHandle {
+    pub fn info_hash(&self) -> &InfoHash {
+        &self.info_hash
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Disconnects from every peer and saves progress until [`resume`] is
+    /// called.
+    ///
+    /// [`resume`]: TorrentHandle::resume
+    pub fn pause(&self) {
+        let _ = self.commands.unbounded_send(Command::Pause);
+    }
+
+    pub fn resume(&self) {
+        let _ = self.commands.unbounded_send(Command::Resume);
+    }
+
+    /// Hashes the torrent's data on disk again and downloads whatever is
+    /// missing or damaged, as after moving files or an unclean shutdown. A
+    /// paused torrent stays paused.
+    pub fn force_recheck(&self) {
+        let _ = self.commands.unbounded_send(Command::Recheck);
+    }
+
+    /// Asks for piece `index` within `millis` milliseconds, ahead of every
+    /// other piece. Meant for streaming, where the player needs a given
+    /// piece soon.
+    pub fn set_piece_deadline(&self, index: usize, millis: u64) {
+        self.priority
+            .set_piece_deadline(index, Duration::from_millis(millis));
+    }
+
+    /// Reads file `file_index` of the torrent from the start, waiting for
+    /// pieces that haven't arrived and downloading them ahead of the rest.
+    /// Returns `None` if there is no such file.
+    pub fn read_stream(&self, file_index: usize) -> Option<FileStream> {
+        FileStream::new(
+            self.files.clone(),
+            file_index,
+            &self.state,
+            self.priority.clone(),
+            &self.events,
+        )
+    }
+
+    /// Sets the torrent's claim on the session's upload slots relative to
+    /// the other torrents', in place of the config's `upload_weight`.
+    pub fn set_upload_weight(&self, weight: u32) {
+        self.upload_share.set_weight(weight);
+    }
+
+    pub fn upload_weight(&self) -> u32 {
+        self.upload_share.weight()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Fraction of the torrent downloaded, from 0 to 1.
+    pub fn progress(&self) -> f64 {
+        self.state.snapshot().progress()
+    }
+
+    pub fn peers(&self) -> Vec<PeerSummary> {
+        self.state.snapshot().peers.clone()
+    }
+
+    pub fn state(&self) -> Arc<ClientState> {
+        self.state.snapshot()
+    }
+
+    /// A stream of everything that happens to the torrent from now on.
+    pub fn events(&self) -> mpsc::UnboundedReceiver<TorrentEvent> {
+        self.events.subscribe()
+    }
+}
+
+async fn run_torrent<R: Runtime>(
+    mut client: Client<R>,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    events: EventBus,
+    paused: Arc<AtomicBool>,
+) {
+    // after the download, until the config's seeding limits are reached
+    let mut seeding = false;
+    // done seeding, so only a recheck or removal does anything
+    let mut stopped = false;
+    loop {
+        if stopped || paused.load(Ordering::Relaxed) {
+            match commands.next().await {
+                Some(Command::Resume) if !stopped => {
+                    paused.store(false, Ordering::Relaxed);
+                    events.emit(TorrentEvent::Resumed);
+                }
+                Some(Command::Recheck) => {
+                    client.recheck().await;
+                    seeding = false;
+                    stopped = false;
+                }
+                Some(Command::Remove(delete_data)) => {
+                    remove(&client, delete_data, &events);
+                    break;
+                }
+                Some(_) => {}
+                None => break,
+            }
+            continue;
+        }
+
+        let outcome = {
+            let mut task = pin!(if seeding {
+                Either::Left(client.seed())
+            } else {
+                Either::Right(client.download())
+            });
+            loop {
+                match select(task.as_mut(), commands.next()).await {
+                    Either::Left((result, _)) => break Either::Left(result),
+                    // already running
+                    Either::Right((Some(Command::Resume), _)) => continue,
+                    Either::Right((command, _)) => break Either::Right(command),
+                }
+            }
+        };
+
+        match outcome {
+            Either::Left(Ok(())) if seeding => {
+                client.shutdown().await;
+                stopped = true;
+            }
+            Either::Left(Ok(())) => {
+                events.emit(TorrentEvent::Finished);
+                seeding = true;
+            }
+            // the disk may come back, say once space is freed, so the
+            // torrent waits to be resumed rather than giving up
+            Either::Left(Err(ClientError::Disk(e))) => {
+                warn!(error = %e, "disk failed, pausing torrent");
+                client.shutdown().await;
+                paused.store(true, Ordering::Relaxed);
+                events.emit(TorrentEvent::Failed(e.to_string()));
+                events.emit(TorrentEvent::Paused);
+            }
+            Either::Left(Err(e)) => {
+                warn!(error = %e, "torrent failed");
+                events.emit(TorrentEvent::Failed(e.to_string()));
+                break;
+            }
+            // pieces found missing are downloaded before seeding again
+            Either::Right(Some(Command::Recheck)) => {
+                client.recheck().await;
+                seeding = false;
+            }
+            Either::Right(Some(Command::Remove(delete_data))) => {
+                client.shutdown().await;
+                remove(&client, delete_data, &events);
+                break;
+            }
+            Either::Right(Some(_)) => {
+                client.shutdown().await;
+                paused.store(true, Ordering::Relaxed);
+                events.emit(TorrentEvent::Paused);
+            }
+            // every handle is gone
+            Either::Right(None) => {
+                client.shutdown().await;
+                break;
+            }
+        }
+    }
+}
+
+/// Deletes a stopped torrent's data if asked to, then tells its subscribers
+/// it is gone.
+fn remove<R: Runtime>(client: &Client<R>, delete_data: bool, events: &EventBus) {
+    if delete_data {
+        if let Err(e) = client.delete_data() {
+            warn!(error = %e, "failed to delete torrent data");
+        }
+    }
+    events.emit(TorrentEvent::Removed);
+}
@@ -0,0 +1,141 @@
+use std::net::{IpAddr, Ipv6Addr};
+
+use url::{form_urlencoded::byte_serialize, Url};
+
+use crate::metainfo::InfoHash;
+
+use super::{AnnounceEvent, TransferStats};
+
+/// What an HTTP announce tells the tracker. Every value is percent-encoded
+/// byte by byte, so binary ones like the info hash and peer ID go through
+/// intact.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnounceRequest {
+    pub info_hash: InfoHash,
+    pub peer_id: Vec<u8>,
+    pub port: u16,
+    /// Our address as seen from outside, when we know it.
+    pub ip: Option<IpAddr>,
+    /// Our IPv6 address as well (BEP 7), so peers on either network can
+    /// reach us.
+    pub ipv6: Option<Ipv6Addr>,
+    pub key: u32,
+    pub numwant: u32,
+    pub transfer: TransferStats,
+    pub event: Option<AnnounceEvent>,
+    /// The `tracker id` the tracker gave us last time.
+    pub tracker_id: Option<String>,
+}
+
+impl AnnounceRequest {
+    /// The announce URL with the request added to any query it already has.
+    pub fn url(&self, announce: &str) -> Result<Url, url::ParseError> {
+        let mut url = Url::parse(announce)?;
+        let query = match url.query() {
+            Some(existing) if !existing.is_empty() => format!("{}&{}", existing, self.query()),
+            _ => self.query(),
+        };
+        url.set_query(Some(&query));
+        Ok(url)
+    }
+
+    /// The request as a query string.
+    pub fn query(&self) -> String {
+        let mut params: Vec<(&str, Vec<u8>)> = vec![
+            ("info_hash", self.info_hash.truncated().to_vec()),
+            ("peer_id", self.peer_id.clone()),
+            ("port", self.port.to_string().into_bytes()),
+        ];
+        if let Some(ip) = self.ip {
+            params.push(("ip", ip.to_string().into_bytes()));
+        }
+        if let Some(ipv6) = self.ipv6 {
+            params.push(("ipv6", ipv6.to_string().into_bytes()));
+        }
+        params.extend([
+            ("key", format!("{:08X}", self.key).into_bytes()),
+            ("numwant", self.numwant.to_string().into_bytes()),
+            ("compact", b"1".to_vec()),
+            ("uploaded", self.transfer.uploaded.to_string().into_bytes()),
+            (
+                "downloaded",
+                self.transfer.downloaded.to_string().into_bytes(),
+            ),
+            ("left", self.transfer.left.to_string().into_bytes()),
+        ]);
+        if let Some(event) = self.event {
+            params.push(("event", event.as_str().as_bytes().to_vec()));
+        }
+        if let Some(tracker_id) = &self.tracker_id {
+            params.push(("trackerid", tracker_id.as_bytes().to_vec()));
+        }
+
+        params
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, byte_serialize(value).collect::<String>()))
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: InfoHash::V1([0x12; 20]),
+            peer_id: b"-rT0001-\xff\x00 abcdefgh".to_vec(),
+            port: 6881,
+            ip: None,
+            ipv6: None,
+            key: 0xbeef,
+            numwant: 50,
+            transfer: TransferStats {
+                uploaded: 1,
+                downloaded: 2,
+                left: 3,
+            },
+            event: Some(AnnounceEvent::Started),
+            tracker_id: None,
+        }
+    }
+
+    #[test]
+    fn encodes_binary_params_byte_by_byte() {
+        let query = request().query();
+        assert_eq!(
+            query,
+            format!(
+                "info_hash={}&peer_id=-rT0001-%FF%00+abcdefgh&port=6881&key=0000BEEF\
+                 &numwant=50&compact=1&uploaded=1&downloaded=2&left=3&event=started",
+                "%12".repeat(20)
+            )
+        );
+    }
+
+    #[test]
+    fn announces_both_addresses() {
+        let query = AnnounceRequest {
+            ip: Some(IpAddr::from([203, 0, 113, 7])),
+            ipv6: Some("2001:db8::7".parse().unwrap()),
+            ..request()
+        }
+        .query();
+        assert!(query.contains("&port=6881&ip=203.0.113.7&ipv6=2001%3Adb8%3A%3A7&key="));
+    }
+
+    #[test]
+    fn keeps_the_announce_urls_own_query() {
+        let url = request()
+            .url("http://tracker.example/announce?passkey=a%2Fb")
+            .unwrap();
+        let query = url.query().unwrap();
+        assert!(query.starts_with("passkey=a%2Fb&info_hash=%12%12"));
+        assert_eq!(url.path(), "/announce");
+
+        let url = request().url("http://tracker.example/announce").unwrap();
+        assert!(url.query().unwrap().starts_with("info_hash="));
+        assert!(request().url("not a url").is_err());
+    }
+}
@@ -1,69 +1,272 @@
 use std::{
+    collections::HashMap,
     fmt::{Debug, Display},
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    io::{self, Read},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     str::FromStr,
+    time::Duration,
 };
 
 use chrono::{DateTime, Utc};
-use rand::Rng;
+use flate2::read::GzDecoder;
+use futures::future::join_all;
+use rand::{seq::SliceRandom, Rng};
+use tracing::{debug, instrument, warn};
 
 use crate::{
-    bencode::{BencodeString, BencodeValue},
-    metainfo::Metainfo,
+    bencode::BencodeValue,
+    config::{ClientConfig, ProxyConfig, TrackerConfig},
+    metainfo::{InfoHash, MetaInfoError, Metainfo},
+    proxy,
+    runtime::{timeout, HttpStatusError, Runtime},
 };
 
+mod announce;
+mod udp;
+#[cfg(feature = "webtorrent")]
+pub mod websocket;
+
+pub use self::announce::AnnounceRequest;
+
+/// Wait after the first failed announce, doubled for each one after it.
+const RETRY_BASE: Duration = Duration::from_secs(15);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30 * 60);
+/// Wait before retrying a tracker request that got no answer, doubled for
+/// each retry after it.
+const REQUEST_RETRY_BASE: Duration = Duration::from_millis(500);
+/// Largest tracker response accepted once decompressed.
+const MAX_DECODED_LENGTH: u64 = 16 * 1024 * 1024;
+
+/// A tracker answered with an HTTP error status.
 pub struct InvalidResponseError {
     pub url: String,
-    pub status: reqwest::StatusCode,
+    pub status: u16,
     pub message: String,
+    /// How long the tracker asked us to wait before trying again.
+    pub retry_after: Option<Duration>,
 }
 
 impl Debug for InvalidResponseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "InvalidResponseError: url: {}, status: {}, message: {}",
-            self.url, self.status, self.message
+            "InvalidResponseError: url: {}, status: {}, message: {}, retry_after: {:?}",
+            self.url, self.status, self.message, self.retry_after
         )
     }
 }
 
+impl Display for InvalidResponseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} answered {}: {}", self.url, self.status, self.message)
+    }
+}
+
+impl std::error::Error for InvalidResponseError {}
+
 #[derive(Debug)]
 pub enum TrackerError {
-    InvalidMetainfo,
+    InvalidMetainfo(MetaInfoError),
     InvalidInfoHash,
     GetPeersFailure(String),
     GetAccounceError(String),
     InvalidResponse(InvalidResponseError),
     ResponseParseError(String),
+    ScrapeError(String),
 }
 
 impl Display for TrackerError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            TrackerError::InvalidMetainfo => write!(f, "InvalidMetainfo"),
+            TrackerError::InvalidMetainfo(e) => write!(f, "InvalidMetainfo: {}", e),
             TrackerError::InvalidInfoHash => write!(f, "InvalidInfoHash"),
             TrackerError::GetPeersFailure(e) => write!(f, "GetPeersFailure: {}", e),
             TrackerError::GetAccounceError(e) => write!(f, "GetAccounceError: {}", e),
             TrackerError::InvalidResponse(e) => write!(f, "InvalidResponse: {:?}", e),
             TrackerError::ResponseParseError(e) => write!(f, "ResponseParseError: {}", e),
+            TrackerError::ScrapeError(e) => write!(f, "ScrapeError: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TrackerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TrackerError::InvalidMetainfo(e) => Some(e),
+            TrackerError::InvalidResponse(e) => Some(e),
+            _ => None,
         }
     }
 }
 
+impl TrackerError {
+    /// Wraps a failed GET of `url`, keeping HTTP error statuses apart from
+    /// other failures, which become `other`.
+    fn from_http(url: &str, e: io::Error, other: fn(String) -> TrackerError) -> Self {
+        match HttpStatusError::from_io(&e) {
+            Some(status) => TrackerError::InvalidResponse(InvalidResponseError {
+                url: url.to_string(),
+                status: status.status,
+                message: status.reason.clone(),
+                retry_after: status.retry_after,
+            }),
+            None => other(e.to_string()),
+        }
+    }
+
+    /// How long the tracker asked us to wait before trying again, if it said.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            TrackerError::InvalidResponse(e) => e.retry_after,
+            _ => None,
+        }
+    }
+
+    /// Whether the tracker turned the request away in a way that asking
+    /// again won't change: it refused us or doesn't serve the URL.
+    pub fn is_permanent(&self) -> bool {
+        matches!(
+            self,
+            TrackerError::InvalidResponse(InvalidResponseError {
+                status: 401 | 403 | 404 | 410,
+                ..
+            })
+        )
+    }
+}
+
+impl From<MetaInfoError> for TrackerError {
+    fn from(e: MetaInfoError) -> Self {
+        TrackerError::InvalidMetainfo(e)
+    }
+}
+
+/// Lifecycle events reported to the tracker. Regular re-announces carry none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl AnnounceEvent {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AnnounceEvent::Started => "started",
+            AnnounceEvent::Completed => "completed",
+            AnnounceEvent::Stopped => "stopped",
+        }
+    }
+}
+
+/// Byte counts reported with every announce.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransferStats {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+}
+
 #[derive(Debug)]
 pub struct Tracker {
     metainfo: Metainfo,
+    /// Announce URLs grouped into tiers, in the order they are tried.
+    tiers: Vec<Vec<String>>,
+    current_tracker: String,
     peer_id: Vec<u8>,
+    /// Sent with every announce so trackers can tell us apart from other
+    /// clients behind the same address.
+    key: u32,
+    /// The `tracker id` each tracker last gave us, echoed back to it.
+    tracker_ids: HashMap<String, String>,
+    port: u16,
+    numwant: u32,
+    transfer: TransferStats,
+    /// Whether a `started` announce has gone through and not been followed by
+    /// a `stopped` one.
+    started: bool,
 
     last_announce: Option<DateTime<Utc>>,
     last_interval: Option<i64>,
+    last_min_interval: Option<i64>,
+    last_error: Option<String>,
+    last_warning: Option<String>,
+    failures: u64,
+    /// Announces that have failed since the last one that went through.
+    consecutive_failures: u32,
+    /// Set when the last failed announce came with a `Retry-After`.
+    retry_after: Option<Duration>,
+    proxy: Option<ProxyConfig>,
+    http: TrackerConfig,
+    /// Where peers can reach us from outside our NAT, when a port mapping
+    /// says so.
+    external_port: Option<u16>,
+    external_ip: Option<IpAddr>,
+    /// Configured, and announced in place of `external_ip`.
+    announce_ip: Option<Ipv4Addr>,
+    ipv6: Option<Ipv6Addr>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TrackerStatus {
+    pub announce: String,
+    pub last_announce: Option<DateTime<Utc>>,
+    pub interval: Option<i64>,
+    /// Why the most recent announce failed, if it did.
+    pub last_error: Option<String>,
+    /// The `warning message` the tracker sent with its last answer.
+    pub warning: Option<String>,
+    /// Number of announces that have failed.
+    pub failures: u64,
+}
+
+/// Where we heard of a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerSource {
+    Tracker,
+    Dht,
+    /// Peer exchange (BEP 11).
+    Pex,
+    /// Local service discovery (BEP 14).
+    Lsd,
+    /// The peer connected to us.
+    Incoming,
+    /// A relay told us to connect while the peer connects to us (BEP 55).
+    Holepunch,
+    /// A WebTorrent peer, connected over WebRTC through a WebSocket tracker.
+    WebRtc,
+}
+
+impl PeerSource {
+    /// Lower is dialed first: peers dialing us back through a hole punch,
+    /// since they only try for a moment, peers on the local network, then
+    /// the ones the tracker vouches for, then the rest.
+    pub fn priority(self) -> u8 {
+        match self {
+            PeerSource::Holepunch => 0,
+            PeerSource::Lsd => 1,
+            PeerSource::Tracker => 2,
+            PeerSource::Pex => 3,
+            PeerSource::Dht => 4,
+            PeerSource::Incoming | PeerSource::WebRtc => 5,
+        }
+    }
+
+    /// Private torrents (BEP 27) only take peers from their trackers, and the
+    /// ones that connect to us because a tracker told them about us.
+    pub fn allowed_when_private(self) -> bool {
+        matches!(
+            self,
+            PeerSource::Tracker | PeerSource::Incoming | PeerSource::WebRtc
+        )
+    }
 }
 
 #[derive(Debug)]
 pub struct Peer {
     pub addr: SocketAddr,
     pub peer_id: Option<Vec<u8>>,
+    pub source: PeerSource,
 }
 
 impl Clone for Peer {
@@ -71,6 +274,7 @@ impl Clone for Peer {
         Self {
             addr: self.addr,
             peer_id: self.peer_id.clone(),
+            source: self.source,
         }
     }
 }
@@ -91,11 +295,23 @@ pub struct TrackerSuccessResponse {
     pub interval: i64,
     pub min_interval: Option<i64>,
     pub tracker_id: Option<String>,
+    pub warning_message: Option<String>,
     pub complete: i64,
     pub incomplete: i64,
     pub peers: Peers,
 }
 
+/// Swarm statistics for one torrent, as reported by a scrape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrapeStats {
+    /// Seeders.
+    pub complete: i64,
+    /// Leechers.
+    pub incomplete: i64,
+    /// Number of times the torrent has been downloaded to completion.
+    pub downloaded: i64,
+}
+
 #[derive(Debug)]
 pub struct TrackerFailureResponse {
     pub failure_reason: String,
@@ -108,15 +324,59 @@ pub enum TrackerResponse {
 }
 
 impl Tracker {
-    pub fn new(torrent_content: BencodeValue) -> Result<Self, TrackerError> {
-        let metainfo = Metainfo::new(torrent_content).map_err(|_| TrackerError::InvalidMetainfo)?;
+    pub fn new(torrent_content: BencodeValue, config: &ClientConfig) -> Result<Self, TrackerError> {
+        Ok(Tracker::from_metainfo(
+            Metainfo::new(torrent_content)?,
+            config,
+        ))
+    }
 
-        Ok(Self {
+    pub fn from_metainfo(metainfo: Metainfo, config: &ClientConfig) -> Self {
+        let tiers = Tracker::get_tiers(&metainfo);
+        // empty for a trackerless torrent
+        let current_tracker = tiers
+            .first()
+            .and_then(|tier| tier.first())
+            .cloned()
+            .unwrap_or_default();
+        let left = metainfo.get_length();
+
+        Self {
             metainfo,
-            peer_id: Tracker::get_peer_id(),
+            tiers,
+            current_tracker,
+            peer_id: Tracker::get_peer_id(config.peer_id_prefix.as_bytes()),
+            key: rand::random(),
+            tracker_ids: HashMap::new(),
+            port: config.port,
+            numwant: config.numwant,
+            transfer: TransferStats {
+                left,
+                ..Default::default()
+            },
+            started: false,
             last_announce: None,
             last_interval: None,
-        })
+            last_min_interval: None,
+            last_error: None,
+            last_warning: None,
+            failures: 0,
+            consecutive_failures: 0,
+            retry_after: None,
+            proxy: config.proxy.clone(),
+            http: config.tracker.clone(),
+            external_port: None,
+            external_ip: None,
+            announce_ip: config.announce_ip,
+            ipv6: config.announce_ipv6,
+        }
+    }
+
+    fn http_client(&self) -> HttpClient<'_> {
+        HttpClient {
+            proxy: self.proxy.as_ref(),
+            config: &self.http,
+        }
     }
 
     pub fn get_metainfo(&self) -> &Metainfo {
@@ -127,209 +387,253 @@ impl Tracker {
         self.peer_id.clone()
     }
 
-    pub async fn get_peers(&mut self) -> Result<Peers, TrackerError> {
-        // if let Some(last_announce) = self.last_announce {
-        //     if let Some(last_interval) = self.last_interval {
-        //         let elapsed = Utc::now()
-        //             .signed_duration_since(last_announce)
-        //             .num_seconds();
-        //         println!("{}, {}", last_interval, elapsed);
-        //         if elapsed < last_interval {
-        //             sleep(Duration::from_secs((last_interval - elapsed) as u64)).await;
-        //         }
-        //     }
-        // }
-
-        let response = self.get_announce().await?;
+    /// The WebSocket trackers of a WebTorrent swarm, which are announced to
+    /// apart from the others.
+    pub fn websocket_trackers(&self) -> Vec<String> {
+        self.tiers
+            .iter()
+            .flatten()
+            .filter(|announce| is_websocket(announce))
+            .cloned()
+            .collect()
+    }
+
+    /// The announce tiers in their current order.
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    pub fn status(&self) -> TrackerStatus {
+        TrackerStatus {
+            announce: self.current_tracker.clone(),
+            last_announce: self.last_announce,
+            interval: self.last_interval,
+            last_error: self.last_error.clone(),
+            warning: self.last_warning.clone(),
+            failures: self.failures,
+        }
+    }
+
+    /// Whether the tracker knows we are in the swarm.
+    pub fn is_started(&self) -> bool {
+        self.started
+    }
+
+    /// The interval the tracker asked us to re-announce at, if it has answered.
+    pub fn interval(&self) -> Option<Duration> {
+        self.last_interval
+            .map(|interval| interval.max(self.last_min_interval.unwrap_or(0)))
+            .map(|interval| Duration::from_secs(interval.max(0) as u64))
+    }
+
+    /// The least time the tracker wants between our announces, if it said.
+    pub fn min_interval(&self) -> Option<Duration> {
+        self.last_min_interval
+            .map(|interval| Duration::from_secs(interval.max(0) as u64))
+    }
+
+    /// How long to wait before trying again after the last announce failed,
+    /// doubling with every failure in a row, or longer if the tracker asked.
+    /// `None` if it went through.
+    pub fn retry_delay(&self) -> Option<Duration> {
+        let failures = self.consecutive_failures.checked_sub(1)?;
+        let backoff = (RETRY_BASE * 2u32.saturating_pow(failures.min(16))).min(MAX_RETRY_DELAY);
+        Some(backoff.max(self.retry_after.unwrap_or_default()))
+    }
+
+    /// The port we listen on, announced unless a port mapping says otherwise.
+    pub fn set_port(&mut self, port: u16) {
+        self.port = port;
+    }
+
+    /// Announces `port`, and `ip` when known, instead of the listen port.
+    pub fn set_external_addr(&mut self, port: Option<u16>, ip: Option<IpAddr>) {
+        self.external_port = port;
+        self.external_ip = ip;
+    }
+
+    /// Announces `ip` as our IPv6 address, unless we already have one.
+    pub fn set_ipv6(&mut self, ip: Option<Ipv6Addr>) {
+        self.ipv6 = self.ipv6.or(ip);
+    }
+
+    /// Updates the byte counts sent with the next announce.
+    pub fn set_transfer(&mut self, transfer: TransferStats) {
+        self.transfer = transfer;
+    }
+
+    /// Announces with `event=started` until one has gone through, and without
+    /// an event after that.
+    pub async fn get_peers<R: Runtime>(&mut self, runtime: &R) -> Result<Peers, TrackerError> {
+        let event = (!self.started).then_some(AnnounceEvent::Started);
+        self.announce(runtime, event).await
+    }
+
+    pub async fn announce<R: Runtime>(
+        &mut self,
+        runtime: &R,
+        event: Option<AnnounceEvent>,
+    ) -> Result<Peers, TrackerError> {
+        let response = self.get_announce(runtime, event).await.inspect_err(|e| {
+            self.last_error = Some(e.to_string());
+            self.failures += 1;
+            self.consecutive_failures += 1;
+            self.retry_after = e.retry_after();
+        })?;
+        self.last_announce = Some(Utc::now());
+        self.retry_after = None;
+
         let peers = match response {
             TrackerResponse::Success(success_response) => {
+                if let Some(warning) = &success_response.warning_message {
+                    warn!(warning, "tracker sent a warning");
+                }
                 self.last_interval = Some(success_response.interval);
+                self.last_min_interval = success_response.min_interval;
+                self.last_error = None;
+                self.last_warning = success_response.warning_message;
+                self.consecutive_failures = 0;
                 success_response.peers
             }
             TrackerResponse::Failure(failure_response) => {
+                warn!(reason = %failure_response.failure_reason, "tracker returned failure");
+                self.last_error = Some(failure_response.failure_reason.clone());
+                self.failures += 1;
+                self.consecutive_failures += 1;
                 return Err(TrackerError::GetPeersFailure(
                     failure_response.failure_reason,
-                ))
+                ));
             }
         };
 
-        self.last_announce = Some(Utc::now());
-
+        match event {
+            Some(AnnounceEvent::Started) => self.started = true,
+            Some(AnnounceEvent::Stopped) => self.started = false,
+            _ => {}
+        }
         Ok(peers)
     }
 
-    fn parse_peers(value: &BencodeValue) -> Result<Peers, TrackerError> {
-        match value {
-            BencodeValue::String(BencodeString::Bytes(raw_peers)) => {
-                let mut peers = Vec::new();
-                for peer in raw_peers.chunks(6) {
-                    let port = u16::from(peer[4]) << 8 | u16::from(peer[5]);
-                    peers.push(Peer {
-                        addr: SocketAddr::new(
-                            IpAddr::V4(Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3])),
-                            port,
-                        ),
-                        peer_id: None,
-                    });
+    /// Splits a compact peer string into 6-byte IPv4 entries (BEP 23) or
+    /// 18-byte IPv6 entries (BEP 7).
+    fn parse_compact_peers(raw_peers: &[u8], ipv6: bool) -> Peers {
+        let ip_len = if ipv6 { 16 } else { 4 };
+        raw_peers
+            .chunks_exact(ip_len + 2)
+            .map(|peer| {
+                let ip = if ipv6 {
+                    let octets: [u8; 16] = peer[..16].try_into().unwrap();
+                    IpAddr::V6(Ipv6Addr::from(octets))
+                } else {
+                    IpAddr::V4(Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]))
+                };
+                let port = u16::from_be_bytes([peer[ip_len], peer[ip_len + 1]]);
+                Peer {
+                    addr: SocketAddr::new(ip, port),
+                    peer_id: None,
+                    source: PeerSource::Tracker,
                 }
-                return Ok(peers);
+            })
+            .collect()
+    }
+
+    fn parse_peers(value: &BencodeValue, ipv6: bool) -> Result<Peers, TrackerError> {
+        match value {
+            BencodeValue::String(raw_peers) => {
+                // compact peers that happen to be valid UTF-8 are parsed as strings
+                Ok(Tracker::parse_compact_peers(raw_peers.as_bytes(), ipv6))
             }
-            BencodeValue::List(peers) => {
-                let mut parsed_peers = Vec::new();
-                for peer in peers {
-                    match peer {
-                        BencodeValue::Dict(dict) => {
-                            let ip = match dict.get("ip") {
-                                Some(BencodeValue::String(BencodeString::String(ip))) => ip.clone(),
-                                _ => {
-                                    return Err(TrackerError::GetPeersFailure(
-                                        "ip key not found".to_string(),
-                                    ))
-                                }
-                            };
-
-                            let port = match dict.get("port") {
-                                Some(BencodeValue::Int(port)) => *port,
-                                _ => {
-                                    return Err(TrackerError::GetPeersFailure(
-                                        "port key not found".to_string(),
-                                    ))
-                                }
-                            };
-
-                            let peer_id = dict
-                                .get("peer id")
-                                .map(|peer_id| match peer_id {
-                                    BencodeValue::String(BencodeString::String(peer_id)) => {
-                                        Some(peer_id.bytes().collect::<Vec<u8>>())
-                                    }
-                                    BencodeValue::String(BencodeString::Bytes(peer_id)) => {
-                                        Some(peer_id.clone())
-                                    }
-                                    _ => None,
-                                })
-                                .flatten();
-
-                            parsed_peers.push(Peer {
-                                peer_id,
-                                addr: SocketAddr::new(
-                                    IpAddr::from_str(&ip).map_err(|e| {
-                                        TrackerError::GetPeersFailure(e.to_string())
-                                    })?,
-                                    port as u16,
-                                ),
-                            });
-                        }
-                        _ => {
-                            return Err(TrackerError::GetPeersFailure("invalid peers".to_string()))
-                        }
+            BencodeValue::List(peers) => peers
+                .iter()
+                .map(|peer| {
+                    if peer.as_dict().is_none() {
+                        return Err(TrackerError::GetPeersFailure("invalid peers".to_string()));
                     }
-                }
-                return Ok(parsed_peers);
-            }
-            _ => return Err(TrackerError::GetPeersFailure("invalid peers".to_string())),
+                    let ip = peer.get_str("ip").ok_or_else(|| {
+                        TrackerError::GetPeersFailure("ip key not found".to_string())
+                    })?;
+                    let port = peer.get_int("port").ok_or_else(|| {
+                        TrackerError::GetPeersFailure("port key not found".to_string())
+                    })?;
+                    let ip = IpAddr::from_str(ip)
+                        .map_err(|e| TrackerError::GetPeersFailure(e.to_string()))?;
+
+                    Ok(Peer {
+                        peer_id: peer.get_bytes("peer id").map(<[u8]>::to_vec),
+                        addr: SocketAddr::new(ip, port as u16),
+                        source: PeerSource::Tracker,
+                    })
+                })
+                .collect(),
+            _ => Err(TrackerError::GetPeersFailure("invalid peers".to_string())),
         }
     }
 
     fn parse_success_response(
         value: &BencodeValue,
     ) -> Result<TrackerSuccessResponse, TrackerError> {
-        let interval = match value.get_value("interval") {
-            Some(interval) => match interval {
-                BencodeValue::Int(interval) => *interval,
-                _ => unreachable!(),
-            },
-            None => {
-                return Err(TrackerError::ResponseParseError(
-                    "interval key not found".to_string(),
-                ))
-            }
-        };
-
-        let min_interval = match value.get_value("min interval") {
-            Some(min_interval) => match min_interval {
-                BencodeValue::Int(min_interval) => Some(*min_interval),
-                _ => {
-                    return Err(TrackerError::ResponseParseError(
-                        "min interval key not found".to_string(),
-                    ))
-                }
-            },
-            None => None,
-        };
-
-        let tracker_id = match value.get_value("tracker id") {
-            Some(tracker_id) => match tracker_id {
-                BencodeValue::String(BencodeString::String(tracker_id)) => Some(tracker_id.clone()),
-                _ => {
-                    return Err(TrackerError::ResponseParseError(
-                        "tracker id key not found".to_string(),
-                    ))
-                }
-            },
-            None => None,
-        };
-
-        let complete = match value.get_value("complete") {
-            Some(complete) => match complete {
-                BencodeValue::Int(complete) => *complete,
-                _ => {
-                    return Err(TrackerError::ResponseParseError(
-                        "complete key not found".to_string(),
-                    ))
-                }
-            },
-            None => {
-                return Err(TrackerError::ResponseParseError(
-                    "complete key not found".to_string(),
-                ))
-            }
-        };
-
-        let incomplete = match value.get_value("incomplete") {
-            Some(incomplete) => match incomplete {
-                BencodeValue::Int(incomplete) => *incomplete,
-                _ => {
-                    return Err(TrackerError::ResponseParseError(
-                        "incomplete key not found".to_string(),
-                    ))
-                }
-            },
-            None => {
-                return Err(TrackerError::ResponseParseError(
-                    "incomplete key not found".to_string(),
-                ))
-            }
-        };
-
-        let Some(Ok(peers)) = value.get_value("peers").map(Tracker::parse_peers) else {
+        let missing =
+            |key: &str| TrackerError::ResponseParseError(format!("{} key not found", key));
+        let int = |key: &str| value.get_int(key).ok_or_else(|| missing(key));
+
+        let min_interval = value
+            .get_value("min interval")
+            .map(|field| field.as_int().ok_or_else(|| missing("min interval")))
+            .transpose()?;
+        let tracker_id = value
+            .get_value("tracker id")
+            .map(|field| {
+                field
+                    .as_str()
+                    .map(str::to_string)
+                    .ok_or_else(|| missing("tracker id"))
+            })
+            .transpose()?;
+        let warning_message = value
+            .get_value("warning message")
+            .map(|field| {
+                field
+                    .as_bytes()
+                    .map(|warning| String::from_utf8_lossy(warning).into_owned())
+                    .ok_or_else(|| missing("warning message"))
+            })
+            .transpose()?;
+
+        let peers4 = value.get_value("peers");
+        let peers6 = value.get_value("peers6");
+        if peers4.is_none() && peers6.is_none() {
             return Err(TrackerError::ResponseParseError(
                 "peers key not found".to_string(),
             ));
+        }
+        let mut peers = match peers4 {
+            Some(peers) => Tracker::parse_peers(peers, false)?,
+            None => Vec::new(),
         };
+        if let Some(peers6) = peers6 {
+            peers.extend(Tracker::parse_peers(peers6, true)?);
+        }
 
         Ok(TrackerSuccessResponse {
-            interval,
+            interval: int("interval")?,
             min_interval,
             tracker_id,
-            complete,
-            incomplete,
+            warning_message,
+            complete: int("complete")?,
+            incomplete: int("incomplete")?,
             peers,
         })
     }
 
     fn to_tracker_response(parsed_value: &BencodeValue) -> Result<TrackerResponse, TrackerError> {
-        let failure_response = parsed_value.get_value("failure reason").map(|value| {
-            let failure_reason = match value {
-                BencodeValue::String(BencodeString::String(reason)) => reason.clone(),
-                _ => unreachable!(),
-            };
-
-            TrackerResponse::Failure(TrackerFailureResponse { failure_reason })
-        });
-
-        if let Some(failure_response) = failure_response {
-            return Ok(failure_response);
+        if let Some(reason) = parsed_value.get_value("failure reason") {
+            let failure_reason = String::from_utf8_lossy(reason.as_bytes().ok_or_else(|| {
+                TrackerError::ResponseParseError("invalid failure reason".to_string())
+            })?)
+            .into_owned();
+            return Ok(TrackerResponse::Failure(TrackerFailureResponse {
+                failure_reason,
+            }));
         }
 
         let success_response = Tracker::parse_success_response(parsed_value)?;
@@ -337,56 +641,261 @@ impl Tracker {
         Ok(TrackerResponse::Success(success_response))
     }
 
-    pub async fn get_announce(&self) -> Result<TrackerResponse, TrackerError> {
-        let mut url = String::from(&self.metainfo.announce);
+    /// Per BEP 12 the announce-list replaces the announce key when present, and
+    /// trackers within a tier are shuffled once up front. A trackerless
+    /// torrent has no tiers.
+    fn get_tiers(metainfo: &Metainfo) -> Vec<Vec<String>> {
+        let mut tiers = metainfo
+            .announce_list
+            .iter()
+            .flatten()
+            .filter(|tier| !tier.is_empty())
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if tiers.is_empty() {
+            return metainfo
+                .announce
+                .iter()
+                .map(|announce| vec![announce.clone()])
+                .collect();
+        }
 
-        let info_hash = self
-            .metainfo
-            .get_info_hash()
-            .expect("Error getting info hash");
+        let mut rng = rand::thread_rng();
+        for tier in &mut tiers {
+            tier.shuffle(&mut rng);
+        }
+        tiers
+    }
 
-        let url_encoded_info_hash =
-            url::form_urlencoded::byte_serialize(&info_hash).collect::<String>();
+    /// Announces to the first tracker that answers, trying tiers in order and
+    /// every tracker within a tier before moving on. The tracker that answers
+    /// is moved to the front of its tier so it is tried first next time.
+    #[instrument(skip(self, runtime))]
+    pub async fn get_announce<R: Runtime>(
+        &mut self,
+        runtime: &R,
+        event: Option<AnnounceEvent>,
+    ) -> Result<TrackerResponse, TrackerError> {
+        let mut request = self.announce_request(event);
+
+        let mut last_error = None;
+        for tier in 0..self.tiers.len() {
+            for i in 0..self.tiers[tier].len() {
+                let announce = self.tiers[tier][i].clone();
+                if is_websocket(&announce) {
+                    continue;
+                }
+                request.tracker_id = self.tracker_ids.get(&announce).cloned();
+                match Tracker::announce_to(runtime, self.http_client(), &announce, &request).await {
+                    Ok(response) => {
+                        if let TrackerResponse::Success(TrackerSuccessResponse {
+                            tracker_id: Some(tracker_id),
+                            ..
+                        }) = &response
+                        {
+                            self.tracker_ids
+                                .insert(announce.clone(), tracker_id.clone());
+                        }
+                        let tracker = self.tiers[tier].remove(i);
+                        self.tiers[tier].insert(0, tracker);
+                        self.current_tracker = announce;
+                        return Ok(response);
+                    }
+                    Err(e) => {
+                        warn!(announce, error = %e, "announce failed, trying next tracker");
+                        last_error = Some(e);
+                    }
+                }
+            }
+        }
 
-        url.push_str(format!("?info_hash={}", url_encoded_info_hash).as_str());
-        url.push_str(
-            format!(
-                "&peer_id={}",
-                String::from_utf8(self.peer_id.clone()).unwrap()
-            )
-            .as_str(),
+        Err(last_error.unwrap_or_else(|| {
+            TrackerError::GetAccounceError("no trackers to announce to".to_string())
+        }))
+    }
+
+    /// Asks the trackers for swarm statistics without announcing, trying each
+    /// one in tier order until one answers.
+    #[instrument(skip(self, runtime))]
+    pub async fn scrape<R: Runtime>(&self, runtime: &R) -> Result<ScrapeStats, TrackerError> {
+        let mut last_error = None;
+        for announce in self.tiers.iter().flatten() {
+            match self.scrape_one(runtime, announce).await {
+                Some(Ok(stats)) => return Ok(stats),
+                Some(Err(e)) => {
+                    warn!(announce, error = %e, "scrape failed, trying next tracker");
+                    last_error = Some(e);
+                }
+                None => {}
+            }
+        }
+
+        Err(last_error
+            .unwrap_or_else(|| TrackerError::ScrapeError("no trackers to scrape".to_string())))
+    }
+
+    /// Scrapes every tracker at once, for what each of them knows of the
+    /// swarm. Trackers that can't be scraped, such as WebSocket ones, are
+    /// left out.
+    #[instrument(skip(self, runtime))]
+    pub async fn scrape_all<R: Runtime>(
+        &self,
+        runtime: &R,
+    ) -> Vec<(String, Result<ScrapeStats, TrackerError>)> {
+        let scrapes = self.tiers.iter().flatten().map(|announce| async move {
+            let result = self.scrape_one(runtime, announce).await?;
+            Some((announce.clone(), result))
+        });
+        join_all(scrapes).await.into_iter().flatten().collect()
+    }
+
+    /// `None` if `announce` can't be scraped from here.
+    async fn scrape_one<R: Runtime>(
+        &self,
+        runtime: &R,
+        announce: &str,
+    ) -> Option<Result<ScrapeStats, TrackerError>> {
+        let info_hash = self.metainfo.info_hash();
+        if is_websocket(announce) {
+            return None;
+        }
+        if announce.starts_with("udp://") {
+            // UDP would go around the proxy
+            if self.proxy.is_some() {
+                return None;
+            }
+            return Some(udp::scrape(runtime, announce, info_hash).await);
+        }
+        Some(Tracker::http_scrape(runtime, self.http_client(), announce, info_hash).await)
+    }
+
+    async fn http_scrape<R: Runtime>(
+        runtime: &R,
+        client: HttpClient<'_>,
+        announce: &str,
+        info_hash: &InfoHash,
+    ) -> Result<ScrapeStats, TrackerError> {
+        let Some(scrape) = Tracker::scrape_url(announce) else {
+            return Err(TrackerError::ScrapeError(format!(
+                "tracker does not support scrape: {}",
+                announce
+            )));
+        };
+        let separator = if scrape.contains('?') { '&' } else { '?' };
+        let url = format!(
+            "{}{}info_hash={}",
+            scrape,
+            separator,
+            url::form_urlencoded::byte_serialize(&info_hash.truncated()).collect::<String>()
         );
-        url.push_str("&port=6881");
-        url.push_str("&numwant=100");
 
-        println!("GET {}", &url);
-        let response = reqwest::get(&url)
+        debug!(url = %url, "scraping");
+        let bytes = http_get(runtime, client, &url)
             .await
-            .map_err(|e| TrackerError::GetAccounceError(e.to_string()))?;
-        println!("GET {}", response.status());
+            .map_err(|e| TrackerError::from_http(&url, e, TrackerError::ScrapeError))?;
+        Tracker::parse_scrape_response(&bytes, info_hash)
+    }
 
-        let bytes = response
-            .bytes()
+    /// By convention a tracker supports scrape when the last path segment of
+    /// its announce URL starts with `announce`, which is swapped for `scrape`.
+    pub fn scrape_url(announce: &str) -> Option<String> {
+        let (base, query) = match announce.split_once('?') {
+            Some((base, query)) => (base, Some(query)),
+            None => (announce, None),
+        };
+        let slash = base.rfind('/')?;
+        let rest = base[slash + 1..].strip_prefix("announce")?;
+
+        let mut url = format!("{}/scrape{}", &base[..slash], rest);
+        if let Some(query) = query {
+            url.push('?');
+            url.push_str(query);
+        }
+        Some(url)
+    }
+
+    /// Parses the raw body of a scrape response, picking out `info_hash`.
+    pub fn parse_scrape_response(
+        bytes: &[u8],
+        info_hash: &InfoHash,
+    ) -> Result<ScrapeStats, TrackerError> {
+        let (parsed, _) =
+            BencodeValue::parse(bytes).map_err(|e| TrackerError::ResponseParseError(e.message))?;
+
+        if let Some(BencodeValue::String(reason)) = parsed.get_value("failure reason") {
+            return Err(TrackerError::ScrapeError(
+                String::from_utf8_lossy(reason.as_bytes()).to_string(),
+            ));
+        }
+
+        let Some(files) = parsed.get_dict("files") else {
+            return Err(TrackerError::ResponseParseError(
+                "files key not found".to_string(),
+            ));
+        };
+        // binary dict keys are decoded lossily, so fall back to the only entry
+        let stats = files
+            .get(String::from_utf8_lossy(&info_hash.truncated()).as_ref())
+            .or_else(|| (files.len() == 1).then(|| files.values().next()).flatten())
+            .ok_or_else(|| TrackerError::ScrapeError("torrent not in scrape".to_string()))?;
+
+        let field = |key: &str| {
+            stats
+                .get_int(key)
+                .ok_or_else(|| TrackerError::ResponseParseError(format!("{} key not found", key)))
+        };
+        Ok(ScrapeStats {
+            complete: field("complete")?,
+            incomplete: field("incomplete")?,
+            downloaded: field("downloaded")?,
+        })
+    }
+
+    async fn announce_to<R: Runtime>(
+        runtime: &R,
+        client: HttpClient<'_>,
+        announce: &str,
+        request: &AnnounceRequest,
+    ) -> Result<TrackerResponse, TrackerError> {
+        let url = request
+            .url(announce)
+            .map_err(|e| TrackerError::GetAccounceError(format!("{}: {}", e, announce)))?;
+
+        debug!(url = %url, "announcing");
+        let bytes = http_get(runtime, client, url.as_str())
             .await
-            .map_err(|e| {
-                TrackerError::InvalidResponse(InvalidResponseError {
-                    url,
-                    status: e
-                        .status()
-                        .unwrap_or(reqwest::StatusCode::INTERNAL_SERVER_ERROR),
-                    message: e.to_string(),
-                })
-            })?
-            .to_vec();
+            .map_err(|e| TrackerError::from_http(announce, e, TrackerError::GetAccounceError))?;
+        debug!(len = bytes.len(), "announce response");
+
+        Tracker::parse_response(&bytes)
+    }
 
+    fn announce_request(&self, event: Option<AnnounceEvent>) -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: *self.metainfo.info_hash(),
+            peer_id: self.peer_id.clone(),
+            port: self.external_port.unwrap_or(self.port),
+            ip: self.announce_ip.map(IpAddr::V4).or(self.external_ip),
+            ipv6: self.ipv6,
+            key: self.key,
+            numwant: self.numwant,
+            transfer: self.transfer,
+            event,
+            tracker_id: None,
+        }
+    }
+
+    /// Parses the raw body of an announce response.
+    pub fn parse_response(bytes: &[u8]) -> Result<TrackerResponse, TrackerError> {
         let (parsed_bencode, _) =
-            BencodeValue::parse(&bytes).map_err(|e| TrackerError::ResponseParseError(e.message))?;
+            BencodeValue::parse(bytes).map_err(|e| TrackerError::ResponseParseError(e.message))?;
 
         Tracker::to_tracker_response(&parsed_bencode)
     }
 
-    fn get_peer_id() -> Vec<u8> {
-        let mut peer_id = Vec::from(b"-rT0001-");
+    fn get_peer_id(prefix: &[u8]) -> Vec<u8> {
+        let mut peer_id = prefix[..prefix.len().min(20)].to_vec();
         let mut rng = rand::thread_rng();
         for _ in 0..(20 - peer_id.len()) {
             let random_char = (rng.gen_range(0..26) + 97) as u8;
@@ -395,3 +904,93 @@ impl Tracker {
         peer_id
     }
 }
+
+/// The settings HTTP tracker requests are made with.
+#[derive(Clone, Copy)]
+struct HttpClient<'a> {
+    proxy: Option<&'a ProxyConfig>,
+    config: &'a TrackerConfig,
+}
+
+/// WebTorrent trackers, which only speak to peers that can do WebRTC.
+pub fn is_websocket(announce: &str) -> bool {
+    announce.starts_with("ws://") || announce.starts_with("wss://")
+}
+
+/// GETs `url`, going through the proxy when there is one, and decompresses
+/// the body if it is gzipped. Failures that may go away are retried as the
+/// config says, with jitter so torrents that failed together don't all
+/// retry at once.
+async fn http_get<R: Runtime>(
+    runtime: &R,
+    client: HttpClient<'_>,
+    url: &str,
+) -> io::Result<Vec<u8>> {
+    let mut retries = 0;
+    let body = loop {
+        match http_get_once(runtime, client, url).await {
+            Err(e) if retries < client.config.retries && is_transient(&e) => {
+                let delay = (REQUEST_RETRY_BASE * 2u32.saturating_pow(retries))
+                    .mul_f64(rand::thread_rng().gen_range(0.5..1.5));
+                debug!(error = %e, ?delay, "tracker request failed, retrying");
+                runtime.sleep(delay).await;
+                retries += 1;
+            }
+            result => break result?,
+        }
+    };
+    gunzip(body)
+}
+
+/// Whether asking again might get an answer: the tracker didn't reply, or
+/// failed without saying when to come back.
+fn is_transient(e: &io::Error) -> bool {
+    match HttpStatusError::from_io(e) {
+        Some(status) => status.status >= 500 && status.retry_after.is_none(),
+        // a certificate that isn't pinned won't become pinned, and a
+        // tracker that refused the connection is down rather than busy
+        None => !matches!(
+            e.kind(),
+            io::ErrorKind::PermissionDenied | io::ErrorKind::ConnectionRefused
+        ),
+    }
+}
+
+async fn http_get_once<R: Runtime>(
+    runtime: &R,
+    client: HttpClient<'_>,
+    url: &str,
+) -> io::Result<Vec<u8>> {
+    match client.proxy {
+        None => runtime.tracker_get(url, client.config).await,
+        Some(proxy) => {
+            let config = client.config;
+            timeout(
+                runtime,
+                config.timeout,
+                proxy::http_get(runtime, proxy, url, &config.user_agent),
+            )
+            .await
+            .unwrap_or_else(|| Err(io::ErrorKind::TimedOut.into()))
+        }
+    }
+}
+
+/// Decompresses a gzipped body. Bencode never starts with the gzip magic,
+/// so anything else is passed through as is.
+fn gunzip(body: Vec<u8>) -> io::Result<Vec<u8>> {
+    if !body.starts_with(&[0x1f, 0x8b]) {
+        return Ok(body);
+    }
+    let mut decoded = Vec::new();
+    GzDecoder::new(body.as_slice())
+        .take(MAX_DECODED_LENGTH + 1)
+        .read_to_end(&mut decoded)?;
+    if decoded.len() as u64 > MAX_DECODED_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "decompressed response is too large",
+        ));
+    }
+    Ok(decoded)
+}
@@ -0,0 +1,134 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use tracing::debug;
+
+use crate::{
+    metainfo::InfoHash,
+    runtime::{timeout, Runtime, UdpSocket},
+};
+
+use super::{ScrapeStats, TrackerError};
+
+/// Magic constant that opens every UDP tracker connection (BEP 15).
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+const ATTEMPTS: usize = 2;
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn error(message: impl Into<String>) -> TrackerError {
+    TrackerError::ScrapeError(message.into())
+}
+
+/// Scrapes a single info hash from a `udp://host:port` tracker.
+pub(super) async fn scrape<R: Runtime>(
+    runtime: &R,
+    tracker: &str,
+    info_hash: &InfoHash,
+) -> Result<ScrapeStats, TrackerError> {
+    let url = url::Url::parse(tracker).map_err(|e| error(e.to_string()))?;
+    let (Some(host), Some(port)) = (url.host_str(), url.port()) else {
+        return Err(error(format!("invalid udp tracker: {}", tracker)));
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addr = runtime
+        .resolve(&format!("{}:{}", host, port))
+        .await
+        .map_err(|e| error(e.to_string()))?
+        .into_iter()
+        .next()
+        .ok_or_else(|| error(format!("failed to resolve {}", host)))?;
+
+    let local = match addr {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = runtime
+        .bind_udp(local)
+        .await
+        .map_err(|e| error(e.to_string()))?;
+
+    let request = header(PROTOCOL_ID, ACTION_CONNECT);
+    let response = transact(runtime, &socket, addr, request, ACTION_CONNECT).await?;
+    if response.len() < 8 {
+        return Err(error("connect response too short"));
+    }
+    let connection_id = u64::from_be_bytes(response[0..8].try_into().unwrap());
+
+    let mut request = header(connection_id, ACTION_SCRAPE);
+    request.extend_from_slice(&info_hash.truncated());
+    let response = transact(runtime, &socket, addr, request, ACTION_SCRAPE).await?;
+    if response.len() < 12 {
+        return Err(error("scrape response too short"));
+    }
+
+    let field = |i: usize| u32::from_be_bytes(response[i * 4..i * 4 + 4].try_into().unwrap());
+    Ok(ScrapeStats {
+        complete: field(0) as i64,
+        downloaded: field(1) as i64,
+        incomplete: field(2) as i64,
+    })
+}
+
+/// Every request starts with a connection id, the action and a transaction id,
+/// which is filled in by [`transact`].
+fn header(connection_id: u64, action: u32) -> Vec<u8> {
+    let mut header = Vec::with_capacity(16);
+    header.extend_from_slice(&connection_id.to_be_bytes());
+    header.extend_from_slice(&action.to_be_bytes());
+    header.extend_from_slice(&[0; 4]);
+    header
+}
+
+/// Sends `request` under a fresh transaction id, retrying on timeout, and
+/// returns the body of the matching response.
+async fn transact<R: Runtime>(
+    runtime: &R,
+    socket: &R::UdpSocket,
+    addr: SocketAddr,
+    mut request: Vec<u8>,
+    action: u32,
+) -> Result<Vec<u8>, TrackerError> {
+    let transaction_id: u32 = rand::random();
+    request[12..16].copy_from_slice(&transaction_id.to_be_bytes());
+
+    let mut buf = vec![0u8; 1024];
+    for attempt in 0..ATTEMPTS {
+        socket
+            .send_to(&request, addr)
+            .await
+            .map_err(|e| error(e.to_string()))?;
+
+        loop {
+            let Some(received) =
+                timeout(runtime, RESPONSE_TIMEOUT, socket.recv_from(&mut buf)).await
+            else {
+                debug!(attempt, "udp tracker timed out");
+                break;
+            };
+            let (len, from) = received.map_err(|e| error(e.to_string()))?;
+            if from != addr || len < 8 {
+                continue;
+            }
+
+            let response_action = u32::from_be_bytes(buf[0..4].try_into().unwrap());
+            let response_transaction = u32::from_be_bytes(buf[4..8].try_into().unwrap());
+            if response_transaction != transaction_id {
+                continue;
+            }
+            if response_action == ACTION_ERROR {
+                return Err(error(String::from_utf8_lossy(&buf[8..len]).to_string()));
+            }
+            if response_action != action {
+                return Err(error(format!("unexpected action {}", response_action)));
+            }
+            return Ok(buf[8..len].to_vec());
+        }
+    }
+
+    Err(error("udp tracker did not respond"))
+}
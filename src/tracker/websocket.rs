@@ -0,0 +1,257 @@
+//! The WebSocket tracker protocol of WebTorrent. Browsers can't open TCP
+//! connections, so instead of handing out addresses the tracker relays
+//! WebRTC offers and answers between the peers of a swarm, who then connect
+//! to each other directly.
+//!
+//! Messages are JSON, with info hashes, peer ids and offer ids sent as
+//! "binary strings": one character per byte, from U+0000 to U+00FF.
+
+use futures::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+use crate::metainfo::InfoHash;
+
+use super::{AnnounceEvent, TrackerError, TransferStats};
+
+pub fn to_binary_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| char::from(b)).collect()
+}
+
+/// `None` if a character doesn't fit in a byte.
+pub fn from_binary_string(s: &str) -> Option<Vec<u8>> {
+    s.chars().map(|c| u8::try_from(c).ok()).collect()
+}
+
+/// An offer we make to whichever peer the tracker passes it to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WsOffer {
+    pub offer_id: [u8; 20],
+    pub sdp: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WsMessage {
+    /// The tracker's reply to an announce.
+    Announced {
+        interval: Option<u64>,
+        complete: Option<u64>,
+        incomplete: Option<u64>,
+    },
+    /// Another peer's offer, for us to answer.
+    Offer {
+        peer_id: Vec<u8>,
+        offer_id: Vec<u8>,
+        sdp: String,
+    },
+    /// A peer's answer to one of our offers.
+    Answer {
+        peer_id: Vec<u8>,
+        offer_id: Vec<u8>,
+        sdp: String,
+    },
+    Failure(String),
+}
+
+#[derive(Deserialize)]
+struct Description {
+    sdp: String,
+}
+
+#[derive(Deserialize)]
+struct RawMessage {
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+    interval: Option<u64>,
+    complete: Option<u64>,
+    incomplete: Option<u64>,
+    peer_id: Option<String>,
+    offer_id: Option<String>,
+    offer: Option<Description>,
+    answer: Option<Description>,
+}
+
+impl WsMessage {
+    pub fn parse(text: &str) -> Result<Self, TrackerError> {
+        let raw: RawMessage = serde_json::from_str(text)
+            .map_err(|e| TrackerError::ResponseParseError(e.to_string()))?;
+        if let Some(reason) = raw.failure_reason {
+            return Ok(WsMessage::Failure(reason));
+        }
+
+        let ids = || {
+            let binary = |s: Option<&String>| s.and_then(|s| from_binary_string(s));
+            binary(raw.peer_id.as_ref())
+                .zip(binary(raw.offer_id.as_ref()))
+                .ok_or_else(|| {
+                    TrackerError::ResponseParseError("missing peer or offer id".to_string())
+                })
+        };
+        if let Some(offer) = &raw.offer {
+            let (peer_id, offer_id) = ids()?;
+            return Ok(WsMessage::Offer {
+                peer_id,
+                offer_id,
+                sdp: offer.sdp.clone(),
+            });
+        }
+        if let Some(answer) = &raw.answer {
+            let (peer_id, offer_id) = ids()?;
+            return Ok(WsMessage::Answer {
+                peer_id,
+                offer_id,
+                sdp: answer.sdp.clone(),
+            });
+        }
+        Ok(WsMessage::Announced {
+            interval: raw.interval,
+            complete: raw.complete,
+            incomplete: raw.incomplete,
+        })
+    }
+}
+
+/// An announce carrying `offers` for the tracker to hand out.
+pub fn announce_request(
+    info_hash: &InfoHash,
+    peer_id: &[u8],
+    transfer: TransferStats,
+    event: Option<AnnounceEvent>,
+    offers: &[WsOffer],
+) -> Value {
+    let mut request = json!({
+        "action": "announce",
+        "info_hash": to_binary_string(&info_hash.truncated()),
+        "peer_id": to_binary_string(peer_id),
+        "uploaded": transfer.uploaded,
+        "downloaded": transfer.downloaded,
+        "left": transfer.left,
+        "numwant": offers.len(),
+        "offers": offers
+            .iter()
+            .map(|offer| json!({
+                "offer_id": to_binary_string(&offer.offer_id),
+                "offer": { "type": "offer", "sdp": offer.sdp },
+            }))
+            .collect::<Vec<_>>(),
+    });
+    if let Some(event) = event {
+        request["event"] = json!(event.as_str());
+    }
+    request
+}
+
+/// Our answer to the offer `offer_id` from `to_peer_id`.
+pub fn answer_request(
+    info_hash: &InfoHash,
+    peer_id: &[u8],
+    to_peer_id: &[u8],
+    offer_id: &[u8],
+    sdp: &str,
+) -> Value {
+    json!({
+        "action": "announce",
+        "info_hash": to_binary_string(&info_hash.truncated()),
+        "peer_id": to_binary_string(peer_id),
+        "to_peer_id": to_binary_string(to_peer_id),
+        "offer_id": to_binary_string(offer_id),
+        "answer": { "type": "answer", "sdp": sdp },
+    })
+}
+
+/// A connection to a WebSocket tracker.
+pub struct WsTracker {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl WsTracker {
+    pub async fn connect(announce: &str) -> Result<Self, TrackerError> {
+        let (socket, _) = tokio_tungstenite::connect_async(announce)
+            .await
+            .map_err(|e| TrackerError::GetAccounceError(format!("{}: {}", announce, e)))?;
+        Ok(Self { socket })
+    }
+
+    pub async fn send(&mut self, request: &Value) -> Result<(), TrackerError> {
+        self.socket
+            .send(Message::text(request.to_string()))
+            .await
+            .map_err(|e| TrackerError::GetAccounceError(e.to_string()))
+    }
+
+    /// The next message from the tracker, or `None` once it hangs up.
+    pub async fn next(&mut self) -> Option<Result<WsMessage, TrackerError>> {
+        loop {
+            match self.socket.next().await? {
+                Ok(Message::Text(text)) => return Some(WsMessage::parse(&text)),
+                Ok(Message::Close(_)) => return None,
+                // pings are answered by the socket itself
+                Ok(_) => continue,
+                Err(e) => return Some(Err(TrackerError::GetAccounceError(e.to_string()))),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_and_writes_tracker_messages() {
+        let peer_id = [0xff, 0x00, b'a'];
+        assert_eq!(to_binary_string(&peer_id), "\u{ff}\u{0}a");
+        assert_eq!(from_binary_string("\u{ff}\u{0}a"), Some(peer_id.to_vec()));
+        assert_eq!(from_binary_string("\u{100}"), None);
+
+        let info_hash = InfoHash::V1([7; 20]);
+        let offers = [WsOffer {
+            offer_id: [1; 20],
+            sdp: "v=0".to_string(),
+        }];
+        let transfer = TransferStats {
+            uploaded: 1,
+            downloaded: 2,
+            left: 3,
+        };
+        let request = announce_request(
+            &info_hash,
+            b"-RT0001-abcdefghijkl",
+            transfer,
+            Some(AnnounceEvent::Started),
+            &offers,
+        );
+        assert_eq!(request["info_hash"], "\u{7}".repeat(20));
+        assert_eq!(request["event"], "started");
+        assert_eq!(request["numwant"], 1);
+        assert_eq!(request["offers"][0]["offer"]["sdp"], "v=0");
+
+        let offer = format!(
+            r#"{{"action":"announce","info_hash":"x","peer_id":"ÿb","offer_id":"{}","offer":{{"type":"offer","sdp":"v=1"}}}}"#,
+            "\\u0001".repeat(20)
+        );
+        assert_eq!(
+            WsMessage::parse(&offer).unwrap(),
+            WsMessage::Offer {
+                peer_id: vec![0xff, b'b'],
+                offer_id: vec![1; 20],
+                sdp: "v=1".to_string(),
+            }
+        );
+        assert_eq!(
+            WsMessage::parse(r#"{"action":"announce","interval":120,"complete":3}"#).unwrap(),
+            WsMessage::Announced {
+                interval: Some(120),
+                complete: Some(3),
+                incomplete: None,
+            }
+        );
+        assert_eq!(
+            WsMessage::parse(r#"{"failure reason":"unknown torrent"}"#).unwrap(),
+            WsMessage::Failure("unknown torrent".to_string())
+        );
+        assert!(WsMessage::parse(r#"{"answer":{"sdp":"v=0"}}"#).is_err());
+    }
+}
@@ -0,0 +1,9 @@
+//! In-process test harness: a mock HTTP tracker, a scriptable mock peer that
+//! speaks the wire protocol over any async stream, and helpers for building
+//! small torrents in memory.
+
+#![allow(dead_code)]
+
+pub mod peer;
+pub mod torrent;
+pub mod tracker;
@@ -0,0 +1,259 @@
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+use super::torrent::TestTorrent;
+
+const PSTR: &[u8; 19] = b"BitTorrent protocol";
+const HANDSHAKE_LEN: usize = 49 + PSTR.len();
+
+pub const CHOKE: u8 = 0;
+pub const UNCHOKE: u8 = 1;
+pub const INTERESTED: u8 = 2;
+pub const NOT_INTERESTED: u8 = 3;
+pub const HAVE: u8 = 4;
+pub const BITFIELD: u8 = 5;
+pub const REQUEST: u8 = 6;
+pub const PIECE: u8 = 7;
+pub const CANCEL: u8 = 8;
+pub const EXTENDED: u8 = 20;
+
+/// A raw wire message. `id` is `None` for keep-alives.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WireMessage {
+    pub id: Option<u8>,
+    pub payload: Vec<u8>,
+}
+
+/// Thin framing layer over a stream, for scripting exchanges by hand.
+pub struct Wire<S> {
+    stream: S,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Wire<S> {
+    pub fn new(stream: S) -> Self {
+        Self { stream }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    pub async fn read_handshake(&mut self) -> std::io::Result<Vec<u8>> {
+        let mut handshake = vec![0u8; HANDSHAKE_LEN];
+        self.stream.read_exact(&mut handshake).await?;
+        Ok(handshake)
+    }
+
+    pub async fn write_handshake(
+        &mut self,
+        info_hash: &[u8],
+        peer_id: &[u8],
+    ) -> std::io::Result<()> {
+        self.write_handshake_with_reserved(info_hash, peer_id, [0; 8])
+            .await
+    }
+
+    pub async fn write_handshake_with_reserved(
+        &mut self,
+        info_hash: &[u8],
+        peer_id: &[u8],
+        reserved: [u8; 8],
+    ) -> std::io::Result<()> {
+        let mut handshake = Vec::with_capacity(HANDSHAKE_LEN);
+        handshake.push(PSTR.len() as u8);
+        handshake.extend_from_slice(PSTR);
+        handshake.extend_from_slice(&reserved);
+        handshake.extend_from_slice(info_hash);
+        handshake.extend_from_slice(peer_id);
+        self.stream.write_all(&handshake).await
+    }
+
+    pub async fn read_message(&mut self) -> std::io::Result<WireMessage> {
+        let len = self.stream.read_u32().await? as usize;
+        if len == 0 {
+            return Ok(WireMessage {
+                id: None,
+                payload: Vec::new(),
+            });
+        }
+
+        let mut message = vec![0u8; len];
+        self.stream.read_exact(&mut message).await?;
+        Ok(WireMessage {
+            id: Some(message[0]),
+            payload: message.split_off(1),
+        })
+    }
+
+    pub async fn write_message(&mut self, id: u8, payload: &[u8]) -> std::io::Result<()> {
+        let mut message = Vec::with_capacity(5 + payload.len());
+        message.extend_from_slice(&(payload.len() as u32 + 1).to_be_bytes());
+        message.push(id);
+        message.extend_from_slice(payload);
+        self.stream.write_all(&message).await
+    }
+}
+
+/// Everything a mock peer observed over one connection.
+#[derive(Debug, Default)]
+pub struct PeerLog {
+    pub handshake: Vec<u8>,
+    pub messages: Vec<WireMessage>,
+}
+
+/// A well-behaved remote peer serving the pieces of `torrent` it has.
+#[derive(Clone)]
+pub struct MockPeer {
+    peer_id: Vec<u8>,
+    info_hash: [u8; 20],
+    piece_length: usize,
+    data: Vec<u8>,
+    have: HashSet<usize>,
+    num_pieces: usize,
+    /// Pieces served with flipped bytes the first time they are requested.
+    corrupt: Arc<Mutex<HashSet<usize>>>,
+    /// Sent right after the bitfield.
+    extra: Vec<(u8, Vec<u8>)>,
+    /// Hangs up after serving this many blocks on a connection.
+    hang_up_after: Option<usize>,
+}
+
+impl MockPeer {
+    pub fn seeder(torrent: &TestTorrent, peer_id: &[u8; 20]) -> Self {
+        let have = (0..torrent.num_pieces()).collect();
+        Self::with_pieces(torrent, peer_id, have)
+    }
+
+    pub fn with_pieces(torrent: &TestTorrent, peer_id: &[u8; 20], have: HashSet<usize>) -> Self {
+        Self {
+            peer_id: peer_id.to_vec(),
+            info_hash: torrent.info_hash(),
+            piece_length: torrent.piece_length as usize,
+            data: torrent.data(),
+            have,
+            num_pieces: torrent.num_pieces(),
+            corrupt: Arc::default(),
+            extra: Vec::new(),
+            hang_up_after: None,
+        }
+    }
+
+    pub fn corrupting(self, index: usize) -> Self {
+        self.corrupt.lock().unwrap().insert(index);
+        self
+    }
+
+    pub fn sending(mut self, id: u8, payload: &[u8]) -> Self {
+        self.extra.push((id, payload.to_vec()));
+        self
+    }
+
+    pub fn hanging_up_after(mut self, blocks: usize) -> Self {
+        self.hang_up_after = Some(blocks);
+        self
+    }
+
+    fn bitfield(&self) -> Vec<u8> {
+        let mut bitfield = vec![0u8; self.num_pieces.div_ceil(8)];
+        for &index in &self.have {
+            bitfield[index / 8] |= 1 << (7 - index % 8);
+        }
+        bitfield
+    }
+
+    /// Answers the handshake, advertises its pieces and serves requests until
+    /// the other side hangs up.
+    pub async fn serve<S>(&self, stream: S) -> std::io::Result<PeerLog>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut wire = Wire::new(stream);
+        let handshake = wire.read_handshake().await?;
+        wire.write_handshake(&self.info_hash, &self.peer_id).await?;
+        self.serve_messages(wire, handshake).await
+    }
+
+    /// Dials `addr` and serves the client on the other end, speaking first as
+    /// the connecting side does.
+    pub async fn connect(&self, addr: SocketAddr) -> std::io::Result<PeerLog> {
+        let mut wire = Wire::new(TcpStream::connect(addr).await?);
+        wire.write_handshake(&self.info_hash, &self.peer_id).await?;
+        let handshake = wire.read_handshake().await?;
+        self.serve_messages(wire, handshake).await
+    }
+
+    async fn serve_messages<S>(
+        &self,
+        mut wire: Wire<S>,
+        handshake: Vec<u8>,
+    ) -> std::io::Result<PeerLog>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut log = PeerLog {
+            handshake,
+            ..Default::default()
+        };
+        wire.write_message(BITFIELD, &self.bitfield()).await?;
+        let mut served = 0;
+        for (id, payload) in &self.extra {
+            wire.write_message(*id, payload).await?;
+        }
+
+        loop {
+            let message = match wire.read_message().await {
+                Ok(message) => message,
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(log),
+                Err(e) => return Err(e),
+            };
+
+            match message.id {
+                Some(INTERESTED) => wire.write_message(UNCHOKE, &[]).await?,
+                Some(REQUEST) => {
+                    let index = u32::from_be_bytes(message.payload[0..4].try_into().unwrap());
+                    let begin = u32::from_be_bytes(message.payload[4..8].try_into().unwrap());
+                    let length = u32::from_be_bytes(message.payload[8..12].try_into().unwrap());
+                    if self.have.contains(&(index as usize)) {
+                        let start = index as usize * self.piece_length + begin as usize;
+                        let mut payload = message.payload[0..8].to_vec();
+                        payload.extend_from_slice(&self.data[start..start + length as usize]);
+                        if begin == 0 && self.corrupt.lock().unwrap().remove(&(index as usize)) {
+                            payload[8] ^= 0xff;
+                        }
+                        wire.write_message(PIECE, &payload).await?;
+                        served += 1;
+                        if self.hang_up_after.is_some_and(|limit| served >= limit) {
+                            return Ok(log);
+                        }
+                    }
+                }
+                _ => {}
+            }
+            log.messages.push(message);
+        }
+    }
+
+    /// Serves every connection accepted on a fresh local port.
+    pub async fn listen(self) -> (SocketAddr, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let peer = self.clone();
+                tokio::spawn(async move {
+                    let _ = peer.serve(stream).await;
+                });
+            }
+        });
+        (addr, task)
+    }
+}
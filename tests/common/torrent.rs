@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+
+use rustorrent::bencode::{BencodeString, BencodeValue};
+use sha1::{Digest, Sha1};
+
+/// Deterministic pseudo-random bytes so failures are reproducible.
+pub fn test_data(len: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+    (0..len)
+        .map(|_| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 33) as u8
+        })
+        .collect()
+}
+
+fn string(s: &str) -> BencodeValue {
+    BencodeValue::String(BencodeString::String(s.to_string()))
+}
+
+pub struct TestTorrent {
+    pub name: String,
+    pub piece_length: u64,
+    /// `None` for a trackerless torrent.
+    pub announce: Option<String>,
+    pub announce_list: Option<Vec<Vec<String>>>,
+    pub url_list: Vec<String>,
+    /// DHT bootstrap nodes (BEP 5).
+    pub nodes: Vec<(String, u16)>,
+    /// `None` for single file torrents, otherwise the path of every file.
+    pub paths: Option<Vec<Vec<String>>>,
+    pub files: Vec<Vec<u8>>,
+}
+
+impl TestTorrent {
+    pub fn single_file(name: &str, data: Vec<u8>, piece_length: u64) -> Self {
+        Self {
+            name: name.to_string(),
+            piece_length,
+            announce: Some(String::from("http://127.0.0.1:1/announce")),
+            announce_list: None,
+            url_list: Vec::new(),
+            nodes: Vec::new(),
+            paths: None,
+            files: vec![data],
+        }
+    }
+
+    pub fn multi_file(name: &str, files: Vec<(&str, Vec<u8>)>, piece_length: u64) -> Self {
+        let (paths, files) = files
+            .into_iter()
+            .map(|(path, data)| (path.split('/').map(String::from).collect(), data))
+            .unzip();
+        Self {
+            name: name.to_string(),
+            piece_length,
+            announce: Some(String::from("http://127.0.0.1:1/announce")),
+            announce_list: None,
+            url_list: Vec::new(),
+            nodes: Vec::new(),
+            paths: Some(paths),
+            files,
+        }
+    }
+
+    pub fn with_announce(mut self, announce: &str) -> Self {
+        self.announce = Some(announce.to_string());
+        self
+    }
+
+    /// Drops the tracker, leaving `nodes` to find peers through.
+    pub fn trackerless(mut self, nodes: Vec<(String, u16)>) -> Self {
+        self.announce = None;
+        self.nodes = nodes;
+        self
+    }
+
+    pub fn with_announce_list(mut self, tiers: Vec<Vec<String>>) -> Self {
+        self.announce_list = Some(tiers);
+        self
+    }
+
+    pub fn with_url_list(mut self, urls: Vec<String>) -> Self {
+        self.url_list = urls;
+        self
+    }
+
+    /// All file contents concatenated in torrent order.
+    pub fn data(&self) -> Vec<u8> {
+        self.files.concat()
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.data().len().div_ceil(self.piece_length as usize)
+    }
+
+    pub fn piece(&self, index: usize) -> Vec<u8> {
+        self.data()
+            .chunks(self.piece_length as usize)
+            .nth(index)
+            .expect("piece index out of range")
+            .to_vec()
+    }
+
+    pub fn info(&self) -> BencodeValue {
+        let pieces = self
+            .data()
+            .chunks(self.piece_length as usize)
+            .flat_map(|piece| Sha1::digest(piece).to_vec())
+            .collect::<Vec<u8>>();
+
+        let mut info = BTreeMap::from([
+            ("name".to_string(), string(&self.name)),
+            (
+                "piece length".to_string(),
+                BencodeValue::Int(self.piece_length as i64),
+            ),
+            (
+                "pieces".to_string(),
+                BencodeValue::String(BencodeString::Bytes(pieces)),
+            ),
+        ]);
+
+        match &self.paths {
+            None => {
+                info.insert(
+                    "length".to_string(),
+                    BencodeValue::Int(self.files[0].len() as i64),
+                );
+            }
+            Some(paths) => {
+                let files = paths
+                    .iter()
+                    .zip(&self.files)
+                    .map(|(path, data)| {
+                        BencodeValue::Dict(BTreeMap::from([
+                            ("length".to_string(), BencodeValue::Int(data.len() as i64)),
+                            (
+                                "path".to_string(),
+                                BencodeValue::List(path.iter().map(|p| string(p)).collect()),
+                            ),
+                        ]))
+                    })
+                    .collect();
+                info.insert("files".to_string(), BencodeValue::List(files));
+            }
+        }
+
+        BencodeValue::Dict(info)
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        Sha1::digest(self.info().encode()).into()
+    }
+
+    pub fn to_bencode(&self) -> BencodeValue {
+        let mut torrent = BTreeMap::from([("info".to_string(), self.info())]);
+        if let Some(announce) = &self.announce {
+            torrent.insert("announce".to_string(), string(announce));
+        }
+        if let Some(tiers) = &self.announce_list {
+            let tiers = tiers
+                .iter()
+                .map(|tier| BencodeValue::List(tier.iter().map(|url| string(url)).collect()))
+                .collect();
+            torrent.insert("announce-list".to_string(), BencodeValue::List(tiers));
+        }
+        if !self.url_list.is_empty() {
+            let urls = self.url_list.iter().map(|url| string(url)).collect();
+            torrent.insert("url-list".to_string(), BencodeValue::List(urls));
+        }
+        if !self.nodes.is_empty() {
+            let nodes = self
+                .nodes
+                .iter()
+                .map(|(host, port)| {
+                    BencodeValue::List(vec![string(host), BencodeValue::Int(*port as i64)])
+                })
+                .collect();
+            torrent.insert("nodes".to_string(), BencodeValue::List(nodes));
+        }
+        BencodeValue::Dict(torrent)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_bencode().encode()
+    }
+}
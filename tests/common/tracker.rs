@@ -0,0 +1,147 @@
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use rustorrent::bencode::{BencodeString, BencodeValue};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+/// A minimal HTTP tracker that answers every announce with a fixed compact
+/// peer list and records the requests it was sent.
+pub struct MockTracker {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<String>>>,
+    task: JoinHandle<()>,
+}
+
+impl MockTracker {
+    pub async fn start(peers: Vec<SocketAddr>) -> Self {
+        let response = Self::response(&peers);
+        Self::start_with_response(response).await
+    }
+
+    /// Serves `response` verbatim as the body of every announce.
+    pub async fn start_with_response(response: Vec<u8>) -> Self {
+        Self::start_with_reply("200 OK", "", response).await
+    }
+
+    /// Answers every request with `status`, the extra header lines in
+    /// `headers`, each ending in CRLF, and `body`.
+    pub async fn start_with_reply(status: &str, headers: &str, body: Vec<u8>) -> Self {
+        let head = format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+            status,
+            body.len(),
+            headers
+        );
+        let mut response = head.into_bytes();
+        response.extend_from_slice(&body);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let task = tokio::spawn({
+            let requests = Arc::clone(&requests);
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let requests = Arc::clone(&requests);
+                    let response = response.clone();
+                    tokio::spawn(async move { handle_request(stream, &response, &requests).await });
+                }
+            }
+        });
+
+        Self {
+            addr,
+            requests,
+            task,
+        }
+    }
+
+    pub fn response(peers: &[SocketAddr]) -> Vec<u8> {
+        let mut compact = Vec::new();
+        let mut compact6 = Vec::new();
+        for peer in peers {
+            let (entries, ip) = match peer {
+                SocketAddr::V4(peer) => (&mut compact, peer.ip().octets().to_vec()),
+                SocketAddr::V6(peer) => (&mut compact6, peer.ip().octets().to_vec()),
+            };
+            entries.extend_from_slice(&ip);
+            entries.extend_from_slice(&peer.port().to_be_bytes());
+        }
+
+        let mut response = BTreeMap::from([
+            ("interval".to_string(), BencodeValue::Int(1800)),
+            (
+                "complete".to_string(),
+                BencodeValue::Int(peers.len() as i64),
+            ),
+            ("incomplete".to_string(), BencodeValue::Int(0)),
+            (
+                "peers".to_string(),
+                BencodeValue::String(BencodeString::Bytes(compact)),
+            ),
+        ]);
+        if !compact6.is_empty() {
+            response.insert(
+                "peers6".to_string(),
+                BencodeValue::String(BencodeString::Bytes(compact6)),
+            );
+        }
+        BencodeValue::Dict(response).encode()
+    }
+
+    pub fn announce_url(&self) -> String {
+        format!("http://{}/announce", self.addr)
+    }
+
+    /// Raw query strings of every request received so far.
+    pub fn announces(&self) -> Vec<String> {
+        self.requests()
+            .iter()
+            .map(|target| target.split_once('?').map(|(_, q)| q).unwrap_or_default())
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Path and query of every request received so far.
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockTracker {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn handle_request(
+    mut stream: TcpStream,
+    response: &[u8],
+    requests: &Mutex<Vec<String>>,
+) -> Option<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let target = request.lines().next()?.split(' ').nth(1)?.to_string();
+    // recorded before answering, so it is visible once the client has a reply
+    requests.lock().unwrap().push(target);
+
+    stream.write_all(response).await.ok()?;
+    stream.shutdown().await.ok()
+}
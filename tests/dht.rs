@@ -0,0 +1,49 @@
+use std::{net::SocketAddr, time::Duration};
+
+use rustorrent::{dht::Dht, runtime::TokioRuntime};
+use tokio::time::timeout;
+
+const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+async fn local_node() -> Dht<TokioRuntime> {
+    Dht::bind(TokioRuntime, SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn finds_peers_announced_by_another_node() {
+    let router = local_node().await;
+    let router_addr = router.local_addr().unwrap();
+    let seeder = local_node().await;
+    let leecher = local_node().await;
+    let info_hash = [7; 20];
+
+    timeout(TEST_TIMEOUT, async {
+        seeder.bootstrap(&[router_addr]).await;
+        leecher.bootstrap(&[router_addr]).await;
+        assert!(seeder.num_nodes() > 0);
+
+        seeder.get_peers(info_hash, Some(5000)).await;
+        let peers = leecher.get_peers(info_hash, None).await;
+        assert_eq!(peers, vec![SocketAddr::from(([127, 0, 0, 1], 5000))]);
+    })
+    .await
+    .expect("dht lookup timed out");
+}
+
+#[tokio::test]
+async fn adds_nodes_that_answer_a_ping() {
+    let node = local_node().await;
+    let other = local_node().await;
+
+    node.add_node(other.local_addr().unwrap());
+    timeout(TEST_TIMEOUT, async {
+        while node.num_nodes() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("node was never added");
+    assert_eq!(other.num_nodes(), 1);
+}
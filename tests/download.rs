@@ -0,0 +1,2080 @@
+mod common;
+
+use std::{collections::HashSet, net::SocketAddr, time::Duration};
+
+use common::{
+    peer::{
+        MockPeer, Wire, BITFIELD, CANCEL, CHOKE, EXTENDED, HAVE, INTERESTED, PIECE, REQUEST,
+        UNCHOKE,
+    },
+    torrent::{test_data, TestTorrent},
+    tracker::MockTracker,
+};
+use futures::{FutureExt, StreamExt};
+use rustorrent::{
+    client::{
+        cross_seed,
+        event::TorrentEvent,
+        limits::{ConnectionLimits, Slot},
+        metrics::{self, Metric},
+        mse,
+        selection::Sequential,
+        Client,
+    },
+    config::{ClientConfig, EncryptionPolicy, SessionLimits},
+    dht::Dht,
+    metainfo::Metainfo,
+    runtime::{Runtime, TokioRuntime},
+    tracker::{Peer, PeerSource, Tracker},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::timeout,
+};
+use tokio_util::compat::{FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+
+const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+const PIECE_LENGTH: u64 = 32 * 1024;
+
+fn new_client(
+    torrent: &TestTorrent,
+    output_dir: &str,
+    config: ClientConfig,
+) -> Client<TokioRuntime> {
+    let tracker = Tracker::new(torrent.to_bencode(), &config).unwrap();
+    Client::new(tracker, output_dir.to_string(), config).expect("failed to open torrent files")
+}
+
+fn free_port() -> u16 {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+fn local_peer() -> Peer {
+    Peer {
+        addr: SocketAddr::from(([127, 0, 0, 1], 6881)),
+        peer_id: None,
+        source: PeerSource::Tracker,
+    }
+}
+
+#[tokio::test]
+async fn downloads_single_file_from_one_peer() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file("single.bin", test_data(100_000, 1), PIECE_LENGTH);
+    let config = ClientConfig::builder().max_peers(1).build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let seeder = MockPeer::seeder(&torrent, b"-MK0001-aaaaaaaaaaaa");
+    let peer_task = tokio::spawn(async move { seeder.serve(peer_end).await });
+
+    let peer_id = client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .ok();
+    assert_eq!(peer_id.as_deref(), Some(&b"-MK0001-aaaaaaaaaaaa"[..]));
+
+    let state = client.state_handle();
+    assert_eq!(state.snapshot().downloaded, 0);
+    assert_eq!(state.progress().percent(), 0.0);
+
+    timeout(TEST_TIMEOUT, client.download())
+        .await
+        .expect("download timed out")
+        .expect("download failed");
+
+    let downloaded = std::fs::read(dir.path().join("single.bin")).unwrap();
+    assert_eq!(downloaded, torrent.data());
+
+    let progress = state.progress();
+    assert_eq!(progress.percent(), 100.0);
+    assert_eq!(progress.downloaded, torrent.data().len() as u64);
+    assert_eq!(progress.eta(), Some(std::time::Duration::ZERO));
+
+    let state = state.snapshot();
+    assert!(state.is_complete());
+    assert_eq!(state.completed_pieces(), torrent.num_pieces());
+    assert_eq!(state.peers.len(), 1);
+    assert_eq!(state.peers[0].downloaded, torrent.data().len() as u64);
+    assert_eq!(
+        state.peers[0].client.as_ref().map(|c| c.name.as_str()),
+        Some("Unknown (MK)")
+    );
+    assert!(state.peers[0].download_rate > 0.0);
+    assert!(state.pieces.iter().all(|p| p.availability == 1));
+
+    drop(client);
+    let log = peer_task.await.unwrap().unwrap();
+    assert_eq!(&log.handshake[28..48], &torrent.info_hash());
+}
+
+#[tokio::test]
+async fn requests_pieces_in_order_with_sequential_selection() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file("sequential.bin", test_data(300_000, 15), PIECE_LENGTH);
+    let config = ClientConfig::builder().max_peers(1).build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+    client.set_piece_selection(Sequential);
+
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let seeder = MockPeer::seeder(&torrent, b"-MK0001-sequentialxx");
+    let peer_task = tokio::spawn(async move { seeder.serve(peer_end).await });
+    client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .unwrap();
+
+    timeout(TEST_TIMEOUT, client.download())
+        .await
+        .expect("download timed out")
+        .expect("download failed");
+    let downloaded = std::fs::read(dir.path().join("sequential.bin")).unwrap();
+    assert_eq!(downloaded, torrent.data());
+
+    drop(client);
+    let log = peer_task.await.unwrap().unwrap();
+    let requested = log
+        .messages
+        .iter()
+        .filter(|m| m.id == Some(REQUEST))
+        .map(|m| u32::from_be_bytes(m.payload[..4].try_into().unwrap()))
+        .collect::<Vec<_>>();
+    assert_eq!(requested.first(), Some(&0));
+    assert!(requested.windows(2).all(|w| w[0] <= w[1]));
+}
+
+#[tokio::test]
+async fn clamps_block_size_of_hand_built_configs() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file("clamped.bin", test_data(100_000, 37), PIECE_LENGTH);
+    let config = ClientConfig {
+        max_peers: 1,
+        block_size: 1 << 20,
+        pipeline_depth: 0,
+        ..Default::default()
+    };
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let seeder = MockPeer::seeder(&torrent, b"-MK0001-clampedxxxxx");
+    let peer_task = tokio::spawn(async move { seeder.serve(peer_end).await });
+    client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .unwrap();
+
+    timeout(TEST_TIMEOUT, client.download())
+        .await
+        .expect("download timed out")
+        .expect("download failed");
+    let downloaded = std::fs::read(dir.path().join("clamped.bin")).unwrap();
+    assert_eq!(downloaded, torrent.data());
+
+    drop(client);
+    let log = peer_task.await.unwrap().unwrap();
+    let lengths = log
+        .messages
+        .iter()
+        .filter(|m| m.id == Some(REQUEST))
+        .map(|m| u32::from_be_bytes(m.payload[8..12].try_into().unwrap()))
+        .collect::<Vec<_>>();
+    assert!(!lengths.is_empty());
+    assert!(lengths.iter().all(|&len| len <= 16 * 1024), "{:?}", lengths);
+}
+
+#[tokio::test]
+async fn downloads_over_encrypted_connection() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file("encrypted.bin", test_data(70_000, 8), PIECE_LENGTH);
+    let config = ClientConfig::builder()
+        .max_peers(1)
+        .encryption(EncryptionPolicy::Forced)
+        .build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let seeder = MockPeer::seeder(&torrent, b"-MK0001-eeeeeeeeeeee");
+    let info_hash = torrent.info_hash();
+    let peer_task = tokio::spawn(async move {
+        let stream = mse::accept(peer_end.compat(), &info_hash, EncryptionPolicy::Forced).await?;
+        assert!(stream.is_encrypted());
+        seeder.serve(stream.compat()).await
+    });
+
+    client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .expect("encrypted handshake failed");
+    timeout(TEST_TIMEOUT, client.download())
+        .await
+        .expect("download timed out")
+        .expect("download failed");
+
+    let downloaded = std::fs::read(dir.path().join("encrypted.bin")).unwrap();
+    assert_eq!(downloaded, torrent.data());
+    drop(client);
+    peer_task.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn redownloads_piece_that_fails_verification() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file("corrupt.bin", test_data(100_000, 8), PIECE_LENGTH);
+    let config = ClientConfig::builder().max_peers(1).build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let seeder = MockPeer::seeder(&torrent, b"-MK0001-iiiiiiiiiiii").corrupting(1);
+    let peer_task = tokio::spawn(async move { seeder.serve(peer_end).await });
+
+    assert!(client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .is_ok());
+    timeout(TEST_TIMEOUT, client.download())
+        .await
+        .expect("download timed out")
+        .expect("download failed");
+
+    let downloaded = std::fs::read(dir.path().join("corrupt.bin")).unwrap();
+    assert_eq!(downloaded, torrent.data());
+    assert!(client.snapshot().is_complete());
+
+    drop(client);
+    let log = peer_task.await.unwrap().unwrap();
+    let first_block_requests = log
+        .messages
+        .iter()
+        .filter(|m| m.id == Some(REQUEST) && m.payload[0..8] == [0, 0, 0, 1, 0, 0, 0, 0])
+        .count();
+    assert_eq!(first_block_requests, 2);
+    let haves = log.messages.iter().filter(|m| m.id == Some(HAVE)).count();
+    assert_eq!(haves, torrent.num_pieces());
+}
+
+#[tokio::test]
+async fn bans_peer_that_keeps_sending_corrupt_pieces() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file(
+        "banned.bin",
+        test_data(4 * PIECE_LENGTH as usize, 18),
+        PIECE_LENGTH,
+    );
+    let config = ClientConfig::builder().max_peers(1).build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let seeder = MockPeer::seeder(&torrent, b"-MK0001-llllllllllll")
+        .corrupting(0)
+        .corrupting(1)
+        .corrupting(2);
+    tokio::spawn(async move { seeder.serve(peer_end).await });
+    assert!(client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .is_ok());
+
+    // the only peer is banned before it can resend the last corrupt piece
+    let result = timeout(Duration::from_secs(2), client.download()).await;
+    assert!(result.is_err());
+    assert_eq!(client.metrics().get(Metric::PeersBanned), 1);
+    let state = client.snapshot();
+    assert!(state.peers.is_empty());
+    assert!(!state.is_complete());
+}
+
+#[tokio::test]
+async fn resumes_from_verified_pieces_on_disk() {
+    let dir = tempfile::tempdir().unwrap();
+    let output_dir = dir.path().to_str().unwrap();
+    let torrent = TestTorrent::single_file("resume.bin", test_data(100_000, 9), PIECE_LENGTH);
+    let mut client = new_client(
+        &torrent,
+        output_dir,
+        ClientConfig::builder().max_peers(1).build(),
+    );
+
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let seeder = MockPeer::seeder(&torrent, b"-MK0001-jjjjjjjjjjjj");
+    tokio::spawn(async move { seeder.serve(peer_end).await });
+    assert!(client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .is_ok());
+    timeout(TEST_TIMEOUT, client.download())
+        .await
+        .expect("download timed out")
+        .expect("download failed");
+    drop(client);
+    assert!(dir.path().join("resume.bin.resume").exists());
+
+    // damage the second piece behind the client's back
+    let mut data = std::fs::read(dir.path().join("resume.bin")).unwrap();
+    data[PIECE_LENGTH as usize] ^= 0xff;
+    std::fs::write(dir.path().join("resume.bin"), data).unwrap();
+
+    let client = new_client(&torrent, output_dir, ClientConfig::default());
+    let state = client.snapshot();
+    let completed = state.pieces.iter().map(|p| p.completed).collect::<Vec<_>>();
+    assert_eq!(completed, vec![true, false, true, true]);
+    assert_eq!(state.downloaded, 100_000 - PIECE_LENGTH);
+}
+
+#[tokio::test]
+async fn resumes_partly_downloaded_pieces_block_by_block() {
+    let dir = tempfile::tempdir().unwrap();
+    let output_dir = dir.path().to_str().unwrap();
+    let data = test_data(100_000, 21);
+    let torrent = TestTorrent::single_file("partial.bin", data.clone(), PIECE_LENGTH);
+    let config = ClientConfig::builder().max_peers(1).build();
+    let mut client = new_client(&torrent, output_dir, config.clone());
+
+    // three of the seven blocks, then the peer goes away
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let flaky = MockPeer::seeder(&torrent, b"-MK0001-pppppppppppp").hanging_up_after(3);
+    let peer_task = tokio::spawn(async move { flaky.serve(peer_end).await });
+    assert!(client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .is_ok());
+    let _ = timeout(Duration::from_millis(500), client.download()).await;
+    assert!(peer_task.is_finished());
+    client.shutdown().await;
+    drop(client);
+
+    let mut client = new_client(&torrent, output_dir, config);
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let seeder = MockPeer::seeder(&torrent, b"-MK0001-qqqqqqqqqqqq");
+    let peer_task = tokio::spawn(async move { seeder.serve(peer_end).await });
+    assert!(client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .is_ok());
+    timeout(TEST_TIMEOUT, client.download())
+        .await
+        .expect("download timed out")
+        .expect("download failed");
+    client.shutdown().await;
+    drop(client);
+
+    let log = peer_task.await.unwrap().unwrap();
+    let requests = log
+        .messages
+        .iter()
+        .filter(|m| m.id == Some(REQUEST))
+        .count();
+    assert_eq!(requests, 4);
+    assert_eq!(std::fs::read(dir.path().join("partial.bin")).unwrap(), data);
+}
+
+#[tokio::test]
+async fn recheck_finds_existing_data_and_downloads_the_rest() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = test_data(100_000, 13);
+    let torrent = TestTorrent::single_file("recheck.bin", data.clone(), PIECE_LENGTH);
+
+    // data copied in from elsewhere, with no resume file and a bad third piece
+    let mut copied = data.clone();
+    copied[2 * PIECE_LENGTH as usize] ^= 0xff;
+    std::fs::write(dir.path().join("recheck.bin"), copied).unwrap();
+
+    let config = ClientConfig::builder().max_peers(1).build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+    assert_eq!(client.snapshot().downloaded, 0);
+    client.recheck().await;
+    let state = client.snapshot();
+    let completed = state.pieces.iter().map(|p| p.completed).collect::<Vec<_>>();
+    assert_eq!(completed, vec![true, true, false, true]);
+    assert_eq!(state.downloaded, 100_000 - PIECE_LENGTH);
+
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let seeder = MockPeer::seeder(&torrent, b"-MK0001-rrrrrrrrrrrr");
+    tokio::spawn(async move { seeder.serve(peer_end).await });
+    assert!(client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .is_ok());
+    timeout(TEST_TIMEOUT, client.download())
+        .await
+        .expect("download timed out")
+        .expect("download failed");
+    client.shutdown().await;
+
+    assert_eq!(std::fs::read(dir.path().join("recheck.bin")).unwrap(), data);
+}
+
+#[tokio::test]
+async fn downloads_trackerless_torrent_from_peers_on_its_dht_nodes() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = test_data(80_000, 35);
+    let torrent = TestTorrent::single_file("trackerless.bin", data.clone(), PIECE_LENGTH);
+    let (seeder, _task) = MockPeer::seeder(&torrent, b"-MK0001-tttttttttttt")
+        .listen()
+        .await;
+
+    // the node the torrent names learns of the seeder from another
+    let local = SocketAddr::from(([127, 0, 0, 1], 0));
+    let router = Dht::bind(TokioRuntime, local).await.unwrap();
+    let router_addr = router.local_addr().unwrap();
+    let announcer = Dht::bind(TokioRuntime, local).await.unwrap();
+    announcer.bootstrap(&[router_addr]).await;
+    announcer
+        .get_peers(torrent.info_hash(), Some(seeder.port()))
+        .await;
+
+    let torrent = torrent.trackerless(vec![("127.0.0.1".to_string(), router_addr.port())]);
+    let metainfo = Metainfo::from_bytes(&torrent.to_bytes()).unwrap();
+    assert_eq!(metainfo.announce, None);
+    assert_eq!(metainfo.nodes, vec![router_addr.to_string()]);
+
+    let config = ClientConfig::builder()
+        .max_peers(1)
+        .port(free_port())
+        .dht(true)
+        .dht_bootstrap(Vec::new())
+        .build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+    timeout(TEST_TIMEOUT, client.download())
+        .await
+        .expect("download timed out")
+        .expect("download failed");
+    assert_eq!(
+        std::fs::read(dir.path().join("trackerless.bin")).unwrap(),
+        data
+    );
+}
+
+#[tokio::test]
+async fn downloads_from_peers_announced_by_tracker() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = test_data(4 * PIECE_LENGTH as usize + 123, 2);
+    let torrent = TestTorrent::single_file("tracked.bin", data, PIECE_LENGTH);
+
+    let (first, _first_task) =
+        MockPeer::with_pieces(&torrent, b"-MK0001-bbbbbbbbbbbb", HashSet::from([0, 1, 2]))
+            .listen()
+            .await;
+    let (second, _second_task) = MockPeer::seeder(&torrent, b"-MK0001-cccccccccccc")
+        .listen()
+        .await;
+
+    let tracker = MockTracker::start(vec![first, second]).await;
+    let torrent = torrent.with_announce(&tracker.announce_url());
+    let config = ClientConfig::builder().max_peers(2).build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+
+    timeout(TEST_TIMEOUT, client.download())
+        .await
+        .expect("download timed out")
+        .expect("download failed");
+
+    let downloaded = std::fs::read(dir.path().join("tracked.bin")).unwrap();
+    assert_eq!(downloaded, torrent.data());
+
+    let info_hash = url::form_urlencoded::byte_serialize(&torrent.info_hash()).collect::<String>();
+    let announces = tracker.announces();
+    assert!(!announces.is_empty());
+    assert!(announces[0].contains(&format!("info_hash={}", info_hash)));
+}
+
+#[tokio::test]
+async fn downloads_from_a_swarm_smaller_than_max_peers() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = test_data(3 * PIECE_LENGTH as usize + 77, 36);
+    let torrent = TestTorrent::single_file("small.bin", data, PIECE_LENGTH);
+    let (seeder, _task) = MockPeer::seeder(&torrent, b"-MK0001-ssssssssssss")
+        .listen()
+        .await;
+
+    // a single peer, well short of the default max_peers
+    let tracker = MockTracker::start(vec![seeder]).await;
+    let torrent = torrent.with_announce(&tracker.announce_url());
+    let config = ClientConfig::builder().listen(false).build();
+    assert!(config.max_peers > 1);
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+
+    timeout(TEST_TIMEOUT, client.download())
+        .await
+        .expect("download timed out")
+        .expect("download failed");
+
+    let downloaded = std::fs::read(dir.path().join("small.bin")).unwrap();
+    assert_eq!(downloaded, torrent.data());
+    assert_eq!(tracker.announces().len(), 2, "{:?}", tracker.announces());
+}
+
+#[tokio::test]
+async fn gives_up_on_peers_that_never_answer_the_handshake() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = test_data(2 * PIECE_LENGTH as usize, 26);
+    let torrent = TestTorrent::single_file("silent.bin", data, PIECE_LENGTH);
+
+    // accepts connections and then says nothing
+    let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let silent_addr = silent.local_addr().unwrap();
+    let _silent_task = tokio::spawn(async move {
+        let mut connections = Vec::new();
+        while let Ok((stream, _)) = silent.accept().await {
+            connections.push(stream);
+        }
+    });
+    let (seeder, _task) = MockPeer::seeder(&torrent, b"-MK0001-qqqqqqqqqqqq")
+        .listen()
+        .await;
+
+    let tracker = MockTracker::start(vec![silent_addr, seeder]).await;
+    let torrent = torrent.with_announce(&tracker.announce_url());
+    let config = ClientConfig::builder()
+        .max_peers(1)
+        .handshake_timeout(Duration::from_millis(300))
+        .build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+
+    timeout(TEST_TIMEOUT, client.download())
+        .await
+        .expect("download timed out")
+        .expect("download failed");
+
+    let downloaded = std::fs::read(dir.path().join("silent.bin")).unwrap();
+    assert_eq!(downloaded, torrent.data());
+}
+
+#[tokio::test]
+async fn does_not_connect_to_itself() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = test_data(2 * PIECE_LENGTH as usize, 27);
+    let torrent = TestTorrent::single_file("itself.bin", data, PIECE_LENGTH);
+    let (seeder, _task) = MockPeer::seeder(&torrent, b"-MK0001-iiiiiiiiiiii")
+        .listen()
+        .await;
+
+    // the tracker hands our own address back, once first and once again
+    let port = free_port();
+    let own = SocketAddr::from(([127, 0, 0, 1], port));
+    let tracker = MockTracker::start(vec![own, own, seeder]).await;
+    let torrent = torrent.with_announce(&tracker.announce_url());
+    let config = ClientConfig::builder().max_peers(1).port(port).build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+    let mut events = client.events().subscribe();
+
+    timeout(TEST_TIMEOUT, client.download())
+        .await
+        .expect("download timed out")
+        .expect("download failed");
+
+    let downloaded = std::fs::read(dir.path().join("itself.bin")).unwrap();
+    assert_eq!(downloaded, torrent.data());
+    let mut connected = Vec::new();
+    while let Some(Some(event)) = events.next().now_or_never() {
+        if let TorrentEvent::PeerConnected(addr) = event {
+            connected.push(addr);
+        }
+    }
+    assert_eq!(connected, vec![seeder]);
+}
+
+#[tokio::test]
+async fn reconnects_to_peers_that_hang_up() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = test_data(4 * PIECE_LENGTH as usize, 17);
+    let torrent = TestTorrent::single_file("flaky.bin", data, PIECE_LENGTH);
+
+    // eight blocks in all, so the client has to come back at least once
+    let (flaky, _task) = MockPeer::seeder(&torrent, b"-MK0001-kkkkkkkkkkkk")
+        .hanging_up_after(4)
+        .listen()
+        .await;
+    let tracker = MockTracker::start(vec![flaky]).await;
+    let torrent = torrent.with_announce(&tracker.announce_url());
+    let config = ClientConfig::builder().max_peers(1).build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+
+    timeout(TEST_TIMEOUT, client.download())
+        .await
+        .expect("download timed out")
+        .expect("download failed");
+
+    let downloaded = std::fs::read(dir.path().join("flaky.bin")).unwrap();
+    assert_eq!(downloaded, torrent.data());
+    assert_eq!(tracker.announces().len(), 2, "{:?}", tracker.announces());
+}
+
+#[tokio::test]
+async fn accepts_incoming_peer_connections() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file("incoming.bin", test_data(80_000, 7), PIECE_LENGTH);
+    // the tracker knows nobody, so the only peer is the one dialing in
+    let tracker = MockTracker::start(Vec::new()).await;
+    let torrent = torrent.with_announce(&tracker.announce_url());
+    let port = free_port();
+    let config = ClientConfig::builder().max_peers(1).port(port).build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+
+    let seeder = MockPeer::seeder(&torrent, b"-MK0001-hhhhhhhhhhhh");
+    let peer_task = tokio::spawn(async move {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        loop {
+            match seeder.connect(addr).await {
+                Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                    tokio::time::sleep(Duration::from_millis(10)).await
+                }
+                result => return result,
+            }
+        }
+    });
+
+    timeout(TEST_TIMEOUT, client.download())
+        .await
+        .expect("download timed out")
+        .expect("download failed");
+
+    let downloaded = std::fs::read(dir.path().join("incoming.bin")).unwrap();
+    assert_eq!(downloaded, torrent.data());
+
+    drop(client);
+    let log = peer_task.await.unwrap().unwrap();
+    assert_eq!(&log.handshake[28..48], &torrent.info_hash());
+}
+
+#[tokio::test]
+async fn drops_incoming_connections_past_max_half_open() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file("half.bin", test_data(40_000, 39), PIECE_LENGTH);
+    let tracker = MockTracker::start(Vec::new()).await;
+    let torrent = torrent.with_announce(&tracker.announce_url());
+    let port = free_port();
+    let config = ClientConfig::builder()
+        .max_peers(1)
+        .max_half_open(1)
+        .port(port)
+        .build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+
+    let seeder = MockPeer::seeder(&torrent, b"-MK0001-halfopenxxxx");
+    let peer_task = tokio::spawn(async move {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        // takes the only handshake and then says nothing
+        let silent = loop {
+            match tokio::net::TcpStream::connect(addr).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        if seeder.connect(addr).await.is_ok() {
+            return false;
+        }
+
+        drop(silent);
+        while seeder.connect(addr).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        true
+    });
+
+    timeout(TEST_TIMEOUT, client.download())
+        .await
+        .expect("download timed out")
+        .expect("download failed");
+    let downloaded = std::fs::read(dir.path().join("half.bin")).unwrap();
+    assert_eq!(downloaded, torrent.data());
+
+    drop(client);
+    assert!(
+        peer_task.await.unwrap(),
+        "got in while a handshake was running"
+    );
+}
+
+#[tokio::test]
+async fn listens_on_the_next_port_when_the_first_is_taken() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file("ports.bin", test_data(1_000, 8), PIECE_LENGTH);
+    let tracker = MockTracker::start(Vec::new()).await;
+    let torrent = torrent.with_announce(&tracker.announce_url());
+    let taken = std::net::TcpListener::bind("0.0.0.0:0").unwrap();
+    let first = taken.local_addr().unwrap().port();
+    let config = ClientConfig::builder()
+        .port_range(first..=first.saturating_add(20))
+        .build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+
+    // nobody to download from, so just let it get as far as announcing
+    let _ = timeout(Duration::from_millis(500), client.download()).await;
+
+    let port = client.listen_port().expect("not listening");
+    assert_ne!(port, first);
+    assert!(port > first && port <= first.saturating_add(20));
+    let announces = tracker.announces();
+    assert!(announces[0].contains(&format!("&port={}&", port)));
+}
+
+#[tokio::test]
+async fn sends_bitfield_and_interest_after_handshake() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file("scripted.bin", test_data(50_000, 3), PIECE_LENGTH);
+    let config = ClientConfig::builder().max_peers(1).build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let script = tokio::spawn(async move {
+        let mut wire = Wire::new(peer_end);
+        let handshake = wire.read_handshake().await.unwrap();
+        wire.write_handshake(&handshake[28..48], b"-MK0001-dddddddddddd")
+            .await
+            .unwrap();
+
+        let bitfield = wire.read_message().await.unwrap();
+        wire.write_message(BITFIELD, &[0b1000_0000]).await.unwrap();
+        let interested = wire.read_message().await.unwrap();
+        (bitfield, interested)
+    });
+
+    assert!(client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .is_ok());
+    let (bitfield, interested) = timeout(TEST_TIMEOUT, async {
+        // the client only processes peer messages while downloading
+        tokio::select! {
+            result = script => result.unwrap(),
+            _ = client.download() => unreachable!("download cannot finish"),
+        }
+    })
+    .await
+    .expect("exchange timed out");
+
+    assert_eq!(bitfield.id, Some(BITFIELD));
+    assert_eq!(bitfield.payload, vec![0]);
+    assert_eq!(interested.id, Some(INTERESTED));
+}
+
+#[tokio::test]
+async fn advertises_dht_in_handshake_only_when_enabled() {
+    for dht in [false, true] {
+        let dir = tempfile::tempdir().unwrap();
+        let torrent = TestTorrent::single_file("reserved.bin", test_data(1000, 25), PIECE_LENGTH);
+        let config = ClientConfig::builder().max_peers(1).dht(dht).build();
+        let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+
+        let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+        let script = tokio::spawn(async move {
+            let mut wire = Wire::new(peer_end);
+            let handshake = wire.read_handshake().await.unwrap();
+            wire.write_handshake(&handshake[28..48], b"-MK0001-rrrrrrrrrrrr")
+                .await
+                .unwrap();
+            handshake
+        });
+
+        assert!(client
+            .add_peer_stream(local_peer(), client_end.compat())
+            .await
+            .is_ok());
+        let handshake = timeout(TEST_TIMEOUT, script).await.unwrap().unwrap();
+        // the extension protocol is always on
+        let expected = if dht {
+            [0, 0, 0, 0, 0, 0x10, 0, 1]
+        } else {
+            [0, 0, 0, 0, 0, 0x10, 0, 0]
+        };
+        assert_eq!(handshake[20..28], expected, "dht: {}", dht);
+    }
+}
+
+#[tokio::test]
+async fn identifies_itself_as_configured() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file("ident.bin", test_data(1000, 31), PIECE_LENGTH);
+    let config = ClientConfig::builder()
+        .max_peers(1)
+        .peer_id_prefix("-qB4630-".to_string())
+        .client_name("qBittorrent/4.6.3".to_string())
+        .build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let mut wire = Wire::new(peer_end);
+    wire.write_handshake_with_reserved(
+        &torrent.info_hash(),
+        b"-MK0001-iiiiiiiiiiii",
+        [0, 0, 0, 0, 0, 0x10, 0, 0],
+    )
+    .await
+    .unwrap();
+    assert!(client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .is_ok());
+
+    let handshake = wire.read_handshake().await.unwrap();
+    assert_eq!(&handshake[48..56], b"-qB4630-");
+    let extended = timeout(TEST_TIMEOUT, async {
+        loop {
+            let message = wire.read_message().await.unwrap();
+            if message.id == Some(EXTENDED) && message.payload[0] == 0 {
+                return message.payload[1..].to_vec();
+            }
+        }
+    })
+    .await
+    .unwrap();
+    let name = b"1:v17:qBittorrent/4.6.3";
+    assert!(extended.windows(name.len()).any(|w| w == name));
+}
+
+/// Connects a scripted peer at `addr` that takes hole punch messages under
+/// extended id 3 and says it listens on `port`.
+async fn holepunch_peer(
+    client: &mut Client<TokioRuntime>,
+    info_hash: &[u8],
+    addr: SocketAddr,
+    port: u16,
+) -> Wire<tokio::io::DuplexStream> {
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let mut wire = Wire::new(peer_end);
+    let peer_id = format!("-MK0001-{:012}", addr.port());
+    wire.write_handshake_with_reserved(info_hash, peer_id.as_bytes(), [0, 0, 0, 0, 0, 0x10, 0, 0])
+        .await
+        .unwrap();
+    let handshake = format!("\0d1:md12:ut_holepunchi3ee1:pi{}ee", port);
+    wire.write_message(EXTENDED, handshake.as_bytes())
+        .await
+        .unwrap();
+    let peer = Peer {
+        addr,
+        peer_id: None,
+        source: PeerSource::Tracker,
+    };
+    client
+        .add_peer_stream(peer, client_end.compat())
+        .await
+        .unwrap();
+    wire.read_handshake().await.unwrap();
+    wire
+}
+
+/// The next hole punch message the client sends under extended id 3.
+async fn read_holepunch(wire: &mut Wire<tokio::io::DuplexStream>) -> Vec<u8> {
+    loop {
+        let message = wire.read_message().await.unwrap();
+        if message.id == Some(EXTENDED) && message.payload[0] == 3 {
+            return message.payload[1..].to_vec();
+        }
+    }
+}
+
+#[tokio::test]
+async fn relays_hole_punches_and_connects_when_told_to() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file("punch.bin", test_data(50_000, 29), PIECE_LENGTH);
+    let config = ClientConfig::builder().max_peers(2).build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+    let info_hash = torrent.info_hash();
+
+    // both came in from other ports than the ones they listen on
+    let first = SocketAddr::from(([127, 0, 0, 1], 50001));
+    let second = SocketAddr::from(([127, 0, 0, 2], 50002));
+    let mut first = holepunch_peer(&mut client, &info_hash, first, 7001).await;
+    let mut second = holepunch_peer(&mut client, &info_hash, second, 7002).await;
+    let (seeder, _task) = MockPeer::seeder(&torrent, b"-MK0001-pppppppppppp")
+        .listen()
+        .await;
+
+    let script = async {
+        // ut_holepunch under the client's id 1: rendezvous with 127.0.0.2:7002
+        let rendezvous = [0, 0, 127, 0, 0, 2, 0x1b, 0x5a, 0, 0, 0, 0];
+        let to_first = loop {
+            first
+                .write_message(EXTENDED, &[&[1][..], &rendezvous].concat())
+                .await?;
+            let reply = read_holepunch(&mut first).await;
+            // until the client has read the second peer's extended handshake
+            if reply[0] != 2 {
+                break reply;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        let to_second = read_holepunch(&mut second).await;
+
+        // and with a peer the client doesn't know
+        let unknown = [0, 0, 127, 0, 0, 9, 0, 9, 0, 0, 0, 0];
+        first
+            .write_message(EXTENDED, &[&[1][..], &unknown].concat())
+            .await?;
+        let error = read_holepunch(&mut first).await;
+
+        // then make room and tell the client to connect to the seeder
+        drop(second);
+        let mut connect = vec![1, 1, 0, 127, 0, 0, 1];
+        connect.extend_from_slice(&seeder.port().to_be_bytes());
+        connect.extend_from_slice(&[0; 4]);
+        first.write_message(EXTENDED, &connect).await?;
+        Ok::<_, std::io::Error>((to_first, to_second, error, first))
+    };
+
+    let (to_first, to_second, error) = timeout(TEST_TIMEOUT, async {
+        let download = client.download();
+        tokio::pin!(download);
+        let (to_first, to_second, error, _first) = tokio::select! {
+            result = script => result.unwrap(),
+            result = &mut download => unreachable!("nobody has any pieces yet: {:?}", result),
+        };
+        download.await.expect("download failed");
+        (to_first, to_second, error)
+    })
+    .await
+    .expect("hole punch timed out");
+
+    assert_eq!(to_first, [1, 0, 127, 0, 0, 2, 0x1b, 0x5a, 0, 0, 0, 0]);
+    assert_eq!(to_second, [1, 0, 127, 0, 0, 1, 0x1b, 0x59, 0, 0, 0, 0]);
+    assert_eq!(error, [2, 0, 127, 0, 0, 9, 0, 9, 0, 0, 0, 2]);
+    let downloaded = std::fs::read(dir.path().join("punch.bin")).unwrap();
+    assert_eq!(downloaded, torrent.data());
+}
+
+#[tokio::test]
+async fn sends_interested_only_when_interest_changes() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file("interest.bin", test_data(50_000, 24), PIECE_LENGTH);
+    let config = ClientConfig::builder().max_peers(1).build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let script = tokio::spawn(async move {
+        let mut wire = Wire::new(peer_end);
+        let handshake = wire.read_handshake().await.unwrap();
+        wire.write_handshake(&handshake[28..48], b"-MK0001-ssssssssssss")
+            .await
+            .unwrap();
+
+        wire.read_message().await.unwrap();
+        wire.write_message(BITFIELD, &[0b1000_0000]).await.unwrap();
+        let interested = wire.read_message().await.unwrap();
+        // neither changes whether we are interested
+        wire.write_message(HAVE, &[0, 0, 0, 1]).await.unwrap();
+        wire.write_message(HAVE, &[0, 0, 0, 0]).await.unwrap();
+        wire.write_message(UNCHOKE, &[]).await.unwrap();
+        let next = wire.read_message().await.unwrap();
+        (interested, next)
+    });
+
+    assert!(client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .is_ok());
+    let (interested, next) = timeout(TEST_TIMEOUT, async {
+        tokio::select! {
+            result = script => result.unwrap(),
+            _ = client.download() => unreachable!("download cannot finish"),
+        }
+    })
+    .await
+    .expect("exchange timed out");
+
+    assert_eq!(interested.id, Some(INTERESTED));
+    assert_eq!(next.id, Some(REQUEST));
+}
+
+#[tokio::test]
+async fn asks_again_for_blocks_dropped_by_a_choke() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file("choke.bin", test_data(50_000, 33), PIECE_LENGTH);
+    let config = ClientConfig::builder().max_peers(1).build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let data = torrent.data();
+    let script = tokio::spawn(async move {
+        let mut wire = Wire::new(peer_end);
+        let handshake = wire.read_handshake().await.unwrap();
+        wire.write_handshake(&handshake[28..48], b"-MK0001-cccccccccccc")
+            .await
+            .unwrap();
+        wire.write_message(BITFIELD, &[0b1100_0000]).await.unwrap();
+
+        // all four blocks, which a choke without the fast extension drops
+        async fn requests(wire: &mut Wire<tokio::io::DuplexStream>) -> Vec<Vec<u8>> {
+            let mut requests = Vec::new();
+            while requests.len() < 4 {
+                let message = wire.read_message().await.unwrap();
+                if message.id == Some(REQUEST) {
+                    requests.push(message.payload);
+                }
+            }
+            requests.sort();
+            requests
+        }
+        wire.write_message(UNCHOKE, &[]).await.unwrap();
+        let before = requests(&mut wire).await;
+        wire.write_message(CHOKE, &[]).await.unwrap();
+        wire.write_message(UNCHOKE, &[]).await.unwrap();
+        let after = requests(&mut wire).await;
+
+        for request in &after {
+            let index = u32::from_be_bytes(request[0..4].try_into().unwrap()) as usize;
+            let begin = u32::from_be_bytes(request[4..8].try_into().unwrap()) as usize;
+            let length = u32::from_be_bytes(request[8..12].try_into().unwrap()) as usize;
+            let start = index * PIECE_LENGTH as usize + begin;
+            let payload = [&request[0..8], &data[start..start + length]].concat();
+            wire.write_message(PIECE, &payload).await.unwrap();
+        }
+        (before, after, wire)
+    });
+
+    assert!(client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .is_ok());
+    let (before, after) = timeout(TEST_TIMEOUT, async {
+        let download = client.download();
+        tokio::pin!(download);
+        let (before, after, _wire) = tokio::select! {
+            result = script => result.unwrap(),
+            result = &mut download => unreachable!("the peer sent nothing yet: {:?}", result),
+        };
+        download.await.expect("download failed");
+        (before, after)
+    })
+    .await
+    .expect("download timed out");
+
+    assert_eq!(before, after);
+    let downloaded = std::fs::read(dir.path().join("choke.bin")).unwrap();
+    assert_eq!(downloaded, torrent.data());
+}
+
+#[tokio::test]
+async fn completes_under_tight_memory_budget() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file("budget.bin", test_data(200_000, 5), PIECE_LENGTH);
+    // smaller than a single block, so every piece message exhausts the budget
+    let config = ClientConfig::builder()
+        .max_peers(1)
+        .memory_budget(1024)
+        .build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let seeder = MockPeer::seeder(&torrent, b"-MK0001-ffffffffffff");
+    tokio::spawn(async move { seeder.serve(peer_end).await });
+
+    assert!(client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .is_ok());
+    timeout(TEST_TIMEOUT, client.download())
+        .await
+        .expect("download timed out")
+        .expect("download failed");
+
+    let downloaded = std::fs::read(dir.path().join("budget.bin")).unwrap();
+    assert_eq!(downloaded, torrent.data());
+}
+
+#[tokio::test]
+async fn uploads_completed_pieces_to_interested_peer() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file("upload.bin", test_data(50_000, 6), PIECE_LENGTH);
+    let config = ClientConfig::builder().max_peers(1).build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let data = torrent.data();
+    let script = tokio::spawn(async move {
+        let mut wire = Wire::new(peer_end);
+        let handshake = wire.read_handshake().await.unwrap();
+        wire.write_handshake(&handshake[28..48], b"-MK0001-gggggggggggg")
+            .await
+            .unwrap();
+        // only the first piece, so the client is still downloading afterwards
+        wire.write_message(BITFIELD, &[0b1000_0000]).await.unwrap();
+
+        loop {
+            let message = wire.read_message().await.unwrap();
+            match message.id {
+                Some(INTERESTED) => wire.write_message(UNCHOKE, &[]).await.unwrap(),
+                Some(REQUEST) => {
+                    let begin = u32::from_be_bytes(message.payload[4..8].try_into().unwrap());
+                    let length = u32::from_be_bytes(message.payload[8..12].try_into().unwrap());
+                    let mut payload = message.payload[0..8].to_vec();
+                    payload
+                        .extend_from_slice(&data[begin as usize..begin as usize + length as usize]);
+                    wire.write_message(PIECE, &payload).await.unwrap();
+                }
+                // the piece is only ours to upload once it has been verified
+                Some(HAVE) => wire.write_message(INTERESTED, &[]).await.unwrap(),
+                Some(UNCHOKE) => {
+                    let mut request = Vec::new();
+                    request.extend_from_slice(&0u32.to_be_bytes());
+                    request.extend_from_slice(&100u32.to_be_bytes());
+                    request.extend_from_slice(&1000u32.to_be_bytes());
+                    wire.write_message(REQUEST, &request).await.unwrap();
+                }
+                // hand the connection back so the client doesn't see a disconnect
+                Some(PIECE) => return (message.payload, wire),
+                _ => {}
+            }
+        }
+    });
+
+    assert!(client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .is_ok());
+    let (piece, _wire) = timeout(TEST_TIMEOUT, async {
+        tokio::select! {
+            result = script => result.unwrap(),
+            _ = client.download() => unreachable!("download cannot finish"),
+        }
+    })
+    .await
+    .expect("upload timed out");
+
+    assert_eq!(&piece[0..4], &0u32.to_be_bytes());
+    assert_eq!(&piece[4..8], &100u32.to_be_bytes());
+    assert_eq!(&piece[8..], &torrent.data()[100..1100]);
+
+    let state = client.snapshot();
+    assert_eq!(state.uploaded, 1000);
+    assert_eq!(state.peers[0].uploaded, 1000);
+}
+
+#[tokio::test]
+async fn drops_requests_past_the_upload_queue_cap() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file("flood.bin", test_data(50_000, 38), PIECE_LENGTH);
+    let config = ClientConfig::builder().max_peers(1).build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let data = torrent.data();
+    let script = tokio::spawn(async move {
+        let mut wire = Wire::new(peer_end);
+        let handshake = wire.read_handshake().await.unwrap();
+        wire.write_handshake(&handshake[28..48], b"-MK0001-floodfloodfl")
+            .await
+            .unwrap();
+        wire.write_message(BITFIELD, &[0b1000_0000]).await.unwrap();
+
+        loop {
+            let message = wire.read_message().await.unwrap();
+            match message.id {
+                Some(INTERESTED) => wire.write_message(UNCHOKE, &[]).await.unwrap(),
+                Some(REQUEST) => {
+                    let begin = u32::from_be_bytes(message.payload[4..8].try_into().unwrap());
+                    let length = u32::from_be_bytes(message.payload[8..12].try_into().unwrap());
+                    let mut payload = message.payload[0..8].to_vec();
+                    payload
+                        .extend_from_slice(&data[begin as usize..begin as usize + length as usize]);
+                    wire.write_message(PIECE, &payload).await.unwrap();
+                }
+                Some(HAVE) => wire.write_message(INTERESTED, &[]).await.unwrap(),
+                Some(UNCHOKE) => break,
+                _ => {}
+            }
+        }
+
+        // far more requests than we read answers to, until the end
+        for i in 0..1000u32 {
+            let mut request = Vec::new();
+            request.extend_from_slice(&0u32.to_be_bytes());
+            request.extend_from_slice(&(i * 31).to_be_bytes());
+            request.extend_from_slice(&1000u32.to_be_bytes());
+            wire.write_message(REQUEST, &request).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let mut pieces = 0;
+        while let Ok(message) = timeout(Duration::from_millis(500), wire.read_message()).await {
+            if message.unwrap().id == Some(PIECE) {
+                pieces += 1;
+            }
+        }
+        (pieces, wire)
+    });
+
+    assert!(client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .is_ok());
+    let (pieces, _wire) = timeout(TEST_TIMEOUT, async {
+        tokio::select! {
+            result = script => result.unwrap(),
+            _ = client.download() => unreachable!("download cannot finish"),
+        }
+    })
+    .await
+    .expect("upload timed out");
+
+    // what fit in the pipe, and then the queue
+    assert!(pieces > 0);
+    assert!(pieces < 400, "{}", pieces);
+}
+
+#[tokio::test]
+async fn announces_started_completed_and_stopped_on_shutdown() {
+    let dir = tempfile::tempdir().unwrap();
+    let length = 2 * PIECE_LENGTH as usize + 17;
+    let torrent = TestTorrent::single_file("events.bin", test_data(length, 9), PIECE_LENGTH);
+    let (seeder, _seeder_task) = MockPeer::seeder(&torrent, b"-MK0001-ffffffffffff")
+        .listen()
+        .await;
+
+    let tracker = MockTracker::start(vec![seeder]).await;
+    let torrent = torrent.with_announce(&tracker.announce_url());
+    let config = ClientConfig::builder().max_peers(1).build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+
+    timeout(TEST_TIMEOUT, client.download())
+        .await
+        .expect("download timed out")
+        .expect("download failed");
+    client.shutdown().await;
+
+    let announces = tracker.announces();
+    assert_eq!(announces.len(), 3);
+    assert!(announces[0].contains("event=started"));
+    assert!(announces[0].contains(&format!("left={}", length)));
+    assert!(announces[0].contains("downloaded=0"));
+    assert!(announces[1].contains("event=completed"));
+    assert!(announces[1].contains("left=0"));
+    assert!(announces[1].contains(&format!("downloaded={}", length)));
+    assert!(announces[2].contains("event=stopped"));
+}
+
+#[tokio::test]
+async fn stops_seeding_once_seed_time_is_up() {
+    let dir = tempfile::tempdir().unwrap();
+    let output_dir = dir.path().to_str().unwrap();
+    let torrent = TestTorrent::single_file("seed.bin", test_data(50_000, 15), PIECE_LENGTH);
+    let (seeder, _seeder_task) = MockPeer::seeder(&torrent, b"-MK0001-ssssssssssss")
+        .listen()
+        .await;
+    let tracker = MockTracker::start(vec![seeder]).await;
+    let torrent = torrent.with_announce(&tracker.announce_url());
+    let config = ClientConfig::builder()
+        .max_peers(1)
+        .listen(false)
+        .seed_time(Duration::from_millis(300))
+        .build();
+    let mut client = new_client(&torrent, output_dir, config.clone());
+
+    timeout(TEST_TIMEOUT, client.download())
+        .await
+        .expect("download timed out")
+        .expect("download failed");
+    let started = std::time::Instant::now();
+    timeout(TEST_TIMEOUT, client.seed())
+        .await
+        .expect("seeding timed out")
+        .expect("seeding failed");
+    assert!(started.elapsed() >= Duration::from_millis(300));
+    let announces = tracker.announces();
+    assert!(announces.last().unwrap().contains("event=stopped"));
+    assert!(client.snapshot().peers.is_empty());
+    drop(client);
+
+    // the limit was reached, so a restarted client doesn't seed again
+    let mut client = new_client(&torrent, output_dir, config);
+    timeout(Duration::from_millis(100), client.seed())
+        .await
+        .expect("seeded again after restart")
+        .expect("seeding failed");
+}
+
+#[tokio::test]
+async fn shutdown_saves_progress_and_closes_peers() {
+    let dir = tempfile::tempdir().unwrap();
+    let output_dir = dir.path().to_str().unwrap();
+    let torrent = TestTorrent::single_file("partial.bin", test_data(100_000, 10), PIECE_LENGTH);
+    let config = ClientConfig::builder().max_peers(1).build();
+    let mut client = new_client(&torrent, output_dir, config.clone());
+
+    // the peer only has the first piece, so the download never finishes
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let peer = MockPeer::with_pieces(&torrent, b"-MK0001-gggggggggggg", HashSet::from([0]));
+    let peer_task = tokio::spawn(async move { peer.serve(peer_end).await });
+    assert!(client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .is_ok());
+
+    let mut events = client.events().subscribe();
+    let piece_done = async {
+        while let Some(event) = events.next().await {
+            if event == TorrentEvent::PieceCompleted(0) {
+                break;
+            }
+        }
+    };
+    timeout(TEST_TIMEOUT, async {
+        tokio::select! {
+            _ = client.download() => panic!("download finished without the missing pieces"),
+            _ = piece_done => {}
+        }
+    })
+    .await
+    .expect("first piece timed out");
+    client.shutdown().await;
+
+    // the peer sees the connection close
+    let log = timeout(TEST_TIMEOUT, peer_task).await.unwrap().unwrap();
+    assert!(log.is_ok());
+
+    let client = new_client(&torrent, output_dir, config);
+    let completed = client
+        .snapshot()
+        .pieces
+        .iter()
+        .map(|p| p.completed)
+        .collect::<Vec<_>>();
+    assert_eq!(completed, vec![true, false, false, false]);
+}
+
+#[tokio::test]
+async fn downloads_multi_file_torrent_across_file_boundaries() {
+    let dir = tempfile::tempdir().unwrap();
+    let files = vec![
+        ("a.bin", test_data(20_000, 11)),
+        ("disc/b.bin", test_data(50_000, 12)),
+        ("disc/deep/c.bin", test_data(30_001, 13)),
+    ];
+    let torrent = TestTorrent::multi_file("album", files.clone(), PIECE_LENGTH);
+    let config = ClientConfig::builder().max_peers(1).build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let seeder = MockPeer::seeder(&torrent, b"-MK0001-hhhhhhhhhhhh");
+    tokio::spawn(async move { seeder.serve(peer_end).await });
+    assert!(client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .is_ok());
+
+    timeout(TEST_TIMEOUT, client.download())
+        .await
+        .expect("download timed out")
+        .expect("download failed");
+
+    for (path, data) in files {
+        let downloaded = std::fs::read(dir.path().join("album").join(path)).unwrap();
+        assert_eq!(downloaded, data, "{}", path);
+    }
+}
+
+#[tokio::test]
+async fn cross_seeds_files_found_under_other_names() {
+    let dir = tempfile::tempdir().unwrap();
+    let files = vec![
+        ("a.bin", test_data(20_000, 31)),
+        ("disc/b.bin", test_data(50_000, 32)),
+        ("disc/deep/c.bin", test_data(30_001, 33)),
+    ];
+    let torrent = TestTorrent::multi_file("album", files.clone(), PIECE_LENGTH);
+
+    // another torrent's layout of the same data, and a file that only looks
+    // like one of ours
+    let data_dir = dir.path().join("old");
+    std::fs::create_dir_all(data_dir.join("sub")).unwrap();
+    std::fs::write(data_dir.join("sub/a.bin"), &files[0].1).unwrap();
+    std::fs::write(data_dir.join("x1.bin"), &files[1].1).unwrap();
+    std::fs::write(data_dir.join("decoy.bin"), test_data(50_000, 34)).unwrap();
+    std::fs::write(data_dir.join("other.bin"), &files[2].1).unwrap();
+
+    let output_dir = dir.path().join("new");
+    let output_dir = output_dir.to_str().unwrap();
+    let config = ClientConfig::default();
+    let metainfo = Metainfo::new(torrent.to_bencode()).unwrap();
+    let matches = cross_seed::find_matches(&metainfo.info, &data_dir, output_dir, &config).unwrap();
+    let sources = matches
+        .iter()
+        .map(|m| m.source.strip_prefix(&data_dir).unwrap().to_path_buf())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        sources,
+        ["sub/a.bin", "x1.bin", "other.bin"].map(std::path::PathBuf::from)
+    );
+    assert_eq!(cross_seed::link_matches(&matches).unwrap(), 3);
+
+    let mut client = new_client(&torrent, output_dir, config);
+    client.recheck().await;
+    assert!(client.snapshot().is_complete());
+    for (path, data) in files {
+        let linked = std::fs::read(dir.path().join("new/album").join(path)).unwrap();
+        assert_eq!(linked, data, "{}", path);
+    }
+}
+
+#[tokio::test]
+async fn downloads_torrent_with_odd_piece_length() {
+    let dir = tempfile::tempdir().unwrap();
+    // neither the pieces nor the file are a whole number of blocks
+    let torrent = TestTorrent::single_file("odd.bin", test_data(123_457, 19), 20_001);
+    let config = ClientConfig::builder().max_peers(1).build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let seeder = MockPeer::seeder(&torrent, b"-MK0001-mmmmmmmmmmmm");
+    tokio::spawn(async move { seeder.serve(peer_end).await });
+    assert!(client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .is_ok());
+
+    timeout(TEST_TIMEOUT, client.download())
+        .await
+        .expect("download timed out")
+        .expect("download failed");
+
+    let downloaded = std::fs::read(dir.path().join("odd.bin")).unwrap();
+    assert_eq!(downloaded, torrent.data());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn moves_finished_download_and_runs_completion_hook() {
+    use futures::AsyncReadExt;
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let done = dir.path().join("done");
+    let hook = dir.path().join("hook.sh");
+    let hook_out = dir.path().join("hook.out");
+    std::fs::write(
+        &hook,
+        format!(
+            "#!/bin/sh\nprintf '%s %s' \"$RUSTORRENT_NAME\" \"$RUSTORRENT_PATH\" > {}\n",
+            hook_out.display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let files = vec![
+        ("a.bin", test_data(20_000, 41)),
+        ("disc/b.bin", test_data(50_000, 42)),
+    ];
+    let torrent = TestTorrent::multi_file("album", files.clone(), PIECE_LENGTH);
+    let config = ClientConfig::builder()
+        .max_peers(1)
+        .part_files(true)
+        .move_completed_to(done.to_str().unwrap().to_string())
+        .on_complete(hook.to_str().unwrap().to_string())
+        .build();
+    let downloads = dir.path().join("downloads");
+    let mut client = new_client(&torrent, downloads.to_str().unwrap(), config);
+    assert!(downloads.join("album/disc/b.bin.part").exists());
+
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let seeder = MockPeer::seeder(&torrent, b"-MK0001-oooooooooooo");
+    tokio::spawn(async move { seeder.serve(peer_end).await });
+    assert!(client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .is_ok());
+    timeout(TEST_TIMEOUT, client.download())
+        .await
+        .expect("download timed out")
+        .expect("download failed");
+
+    assert!(!downloads.join("album").exists());
+    assert!(done.join("album.resume").exists());
+    for (path, data) in &files {
+        let moved = std::fs::read(done.join("album").join(path)).unwrap();
+        assert_eq!(&moved, data, "{}", path);
+    }
+    // still served from the new place
+    let mut stream = client.read_stream(0).unwrap();
+    let mut first = Vec::new();
+    timeout(TEST_TIMEOUT, stream.read_to_end(&mut first))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(first, files[0].1);
+
+    timeout(TEST_TIMEOUT, async {
+        while !hook_out.exists() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("hook did not run");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(
+        std::fs::read_to_string(&hook_out).unwrap(),
+        format!("album {}", done.join("album").display())
+    );
+}
+
+#[tokio::test]
+async fn ignores_unknown_message_ids() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file("unknown.bin", test_data(50_000, 14), PIECE_LENGTH);
+    let seeder = MockPeer::seeder(&torrent, b"-MK0001-iiiiiiiiiiii").sending(20, b"d1:md1:xi1eee");
+
+    let config = ClientConfig::builder().max_peers(1).build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let peer = seeder.clone();
+    tokio::spawn(async move { peer.serve(peer_end).await });
+    assert!(client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .is_ok());
+    timeout(TEST_TIMEOUT, client.download())
+        .await
+        .expect("download timed out")
+        .expect("download failed");
+
+    // in strict mode the same peer gets dropped
+    let dir = tempfile::tempdir().unwrap();
+    let config = ClientConfig::builder()
+        .max_peers(1)
+        .drop_unknown_messages(true)
+        .build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+    let mut events = client.events().subscribe();
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(async move { seeder.serve(peer_end).await });
+    assert!(client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .is_ok());
+    let disconnected = async {
+        while let Some(event) = events.next().await {
+            if matches!(event, TorrentEvent::PeerDisconnected(_)) {
+                break;
+            }
+        }
+    };
+    timeout(TEST_TIMEOUT, async {
+        tokio::select! {
+            _ = client.download() => panic!("download finished with the peer dropped"),
+            _ = disconnected => {}
+        }
+    })
+    .await
+    .expect("peer was not dropped");
+}
+
+#[tokio::test]
+async fn exports_metrics_over_http() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file("metrics.bin", test_data(100_000, 13), PIECE_LENGTH);
+    let config = ClientConfig::builder().max_peers(1).build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let seeder = MockPeer::seeder(&torrent, b"-MK0001-mmmmmmmmmmmm");
+    tokio::spawn(async move { seeder.serve(peer_end).await });
+    client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .ok()
+        .unwrap();
+
+    timeout(TEST_TIMEOUT, client.download())
+        .await
+        .expect("download timed out")
+        .expect("download failed");
+
+    let metrics = client.metrics();
+    assert_eq!(
+        metrics.get(Metric::PiecesCompleted),
+        torrent.num_pieces() as u64
+    );
+    assert_eq!(metrics.get(Metric::BytesDownloaded), 100_000);
+    assert_eq!(metrics.get(Metric::PeersConnected), 1);
+
+    let listener = TokioRuntime
+        .listen(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { metrics::serve(&TokioRuntime, listener, metrics).await });
+
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("\nrustorrent_downloaded_bytes_total 100000\n"));
+}
+
+/// Serves `files` by URL path over HTTP, honouring single `Range` headers
+/// the way a web seed would.
+async fn serve_web_seed(files: Vec<(String, Vec<u8>)>) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let files = files.clone();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request).into_owned();
+                let path = request.split(' ').nth(1).unwrap_or_default();
+                let Some((_, data)) = files.iter().find(|(p, _)| p == path) else {
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+                        .await;
+                    return;
+                };
+                let range = request.lines().find_map(|line| {
+                    let (from, to) = line
+                        .to_ascii_lowercase()
+                        .strip_prefix("range: bytes=")?
+                        .split_once('-')
+                        .map(|(a, b)| (a.to_string(), b.to_string()))?;
+                    Some((from.parse::<usize>().ok()?, to.parse::<usize>().ok()? + 1))
+                });
+                let (status, body) = match range {
+                    Some((from, to)) => ("206 Partial Content", &data[from..to]),
+                    None => ("200 OK", &data[..]),
+                };
+                let mut response = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    body.len()
+                )
+                .into_bytes();
+                response.extend_from_slice(body);
+                let _ = stream.write_all(&response).await;
+            });
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn downloads_from_web_seed_without_peers() {
+    let dir = tempfile::tempdir().unwrap();
+    let files = vec![
+        ("a.bin", test_data(20_000, 15)),
+        ("sub dir/b.bin", test_data(70_000, 16)),
+    ];
+    let served = files
+        .iter()
+        .map(|(path, data)| {
+            let path = format!("/seed/album/{}", path.replace(' ', "%20"));
+            (path, data.clone())
+        })
+        .collect();
+    let seed = serve_web_seed(served).await;
+
+    let tracker = MockTracker::start(Vec::new()).await;
+    let torrent = TestTorrent::multi_file("album", files.clone(), PIECE_LENGTH)
+        .with_announce(&tracker.announce_url())
+        .with_url_list(vec![format!("http://{}/seed/", seed)]);
+    let config = ClientConfig::builder().port(free_port()).build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+
+    timeout(TEST_TIMEOUT, client.download())
+        .await
+        .expect("download timed out")
+        .expect("download failed");
+
+    for (path, data) in files {
+        let downloaded = std::fs::read(dir.path().join("album").join(path)).unwrap();
+        assert_eq!(downloaded, data, "{}", path);
+    }
+}
+
+#[tokio::test]
+async fn drops_peer_sending_truncated_have() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file("truncated.bin", test_data(50_000, 20), PIECE_LENGTH);
+    let seeder = MockPeer::seeder(&torrent, b"-MK0001-nnnnnnnnnnnn").sending(HAVE, &[0, 0, 1]);
+
+    let config = ClientConfig::builder().max_peers(1).build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+    let mut events = client.events().subscribe();
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(async move { seeder.serve(peer_end).await });
+    assert!(client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .is_ok());
+    let disconnected = async {
+        while let Some(event) = events.next().await {
+            if matches!(event, TorrentEvent::PeerDisconnected(_)) {
+                break;
+            }
+        }
+    };
+    timeout(TEST_TIMEOUT, async {
+        tokio::select! {
+            _ = client.download() => panic!("download finished with the peer dropped"),
+            _ = disconnected => {}
+        }
+    })
+    .await
+    .expect("peer was not dropped");
+    assert!(client.snapshot().peers.is_empty());
+}
+
+#[tokio::test]
+async fn drops_peer_whose_bitfield_sets_spare_bits() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file("spare.bin", test_data(50_000, 23), PIECE_LENGTH);
+    let seeder = MockPeer::seeder(&torrent, b"-MK0001-rrrrrrrrrrrr")
+        // two pieces, so only the top two bits may be set
+        .sending(BITFIELD, &[0b11100000]);
+
+    let config = ClientConfig::builder().max_peers(1).build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+    let mut events = client.events().subscribe();
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(async move { seeder.serve(peer_end).await });
+    assert!(client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .is_ok());
+    let disconnected = async {
+        while let Some(event) = events.next().await {
+            if matches!(event, TorrentEvent::PeerDisconnected(_)) {
+                break;
+            }
+        }
+    };
+    timeout(TEST_TIMEOUT, async {
+        tokio::select! {
+            _ = client.download() => panic!("download finished with the peer dropped"),
+            _ = disconnected => {}
+        }
+    })
+    .await
+    .expect("peer was not dropped");
+    assert!(client.snapshot().peers.is_empty());
+}
+
+#[tokio::test]
+async fn keeps_downloading_after_a_peer_resets_mid_message() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file("reset.bin", test_data(90_000, 21), PIECE_LENGTH);
+    let config = ClientConfig::builder().max_peers(2).build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+
+    // advertises every piece, then hangs up halfway through a message
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let info_hash = torrent.info_hash();
+    tokio::spawn(async move {
+        let mut wire = Wire::new(peer_end);
+        wire.read_handshake().await?;
+        wire.write_handshake(&info_hash, b"-MK0001-oooooooooooo")
+            .await?;
+        let mut stream = wire.into_inner();
+        stream.write_all(&[0, 0, 0, 100, BITFIELD, 0xff]).await?;
+        stream.shutdown().await
+    });
+    assert!(client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .is_ok());
+
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let seeder = MockPeer::seeder(&torrent, b"-MK0001-pppppppppppp");
+    tokio::spawn(async move { seeder.serve(peer_end).await });
+    let peer = Peer {
+        addr: SocketAddr::from(([127, 0, 0, 2], 6881)),
+        peer_id: None,
+        source: PeerSource::Tracker,
+    };
+    assert!(client
+        .add_peer_stream(peer, client_end.compat())
+        .await
+        .is_ok());
+
+    timeout(TEST_TIMEOUT, client.download())
+        .await
+        .expect("download timed out")
+        .expect("download failed");
+    let downloaded = std::fs::read(dir.path().join("reset.bin")).unwrap();
+    assert_eq!(downloaded, torrent.data());
+}
+
+#[tokio::test]
+async fn drops_peer_announcing_oversized_message() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file("oversized.bin", test_data(50_000, 22), PIECE_LENGTH);
+    let config = ClientConfig::builder().max_peers(1).build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+    let mut events = client.events().subscribe();
+
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let info_hash = torrent.info_hash();
+    let (sent_tx, sent_rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let mut wire = Wire::new(peer_end);
+        wire.read_handshake().await?;
+        wire.write_handshake(&info_hash, b"-MK0001-qqqqqqqqqqqq")
+            .await?;
+        let mut stream = wire.into_inner();
+        stream.write_all(&[0xff, 0xff, 0xff, 0xff, PIECE]).await?;
+        let _ = sent_tx.send(());
+        // keeps the connection open, so only the length can get us dropped
+        std::future::pending::<std::io::Result<()>>().await
+    });
+    assert!(client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .is_ok());
+    sent_rx.await.unwrap();
+
+    let disconnected = async {
+        while let Some(event) = events.next().await {
+            if matches!(event, TorrentEvent::PeerDisconnected(_)) {
+                break;
+            }
+        }
+    };
+    timeout(TEST_TIMEOUT, async {
+        tokio::select! {
+            _ = client.download() => panic!("download finished with the peer dropped"),
+            _ = disconnected => {}
+        }
+    })
+    .await
+    .expect("peer was not dropped");
+    assert!(client.snapshot().peers.is_empty());
+}
+
+#[tokio::test]
+async fn cancels_duplicate_request_once_the_block_arrives() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file("cancel.bin", test_data(16 * 1024, 25), PIECE_LENGTH);
+    let config = ClientConfig::builder().max_peers(2).build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+    // close enough to its deadline that the block is requested twice
+    client.set_piece_deadline(0, Duration::ZERO);
+
+    // asks first and never answers
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let info_hash = torrent.info_hash();
+    let (requested_tx, requested_rx) = tokio::sync::oneshot::channel();
+    let slow = tokio::spawn(async move {
+        let mut wire = Wire::new(peer_end);
+        wire.read_handshake().await.unwrap();
+        wire.write_handshake(&info_hash, b"-MK0001-rrrrrrrrrrrr")
+            .await
+            .unwrap();
+        wire.read_message().await.unwrap();
+        wire.write_message(BITFIELD, &[0b1000_0000]).await.unwrap();
+        wire.read_message().await.unwrap();
+        wire.write_message(UNCHOKE, &[]).await.unwrap();
+        let request = wire.read_message().await.unwrap();
+        let _ = requested_tx.send(());
+        loop {
+            let message = wire.read_message().await.unwrap();
+            if message.id == Some(CANCEL) {
+                return (request, message);
+            }
+        }
+    });
+    assert!(client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .is_ok());
+
+    // only unchokes once the slow peer has the request, then serves it
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let piece = torrent.piece(0);
+    tokio::spawn(async move {
+        let mut wire = Wire::new(peer_end);
+        wire.read_handshake().await?;
+        wire.write_handshake(&info_hash, b"-MK0001-ssssssssssss")
+            .await?;
+        wire.read_message().await?;
+        wire.write_message(BITFIELD, &[0b1000_0000]).await?;
+        wire.read_message().await?;
+        let _ = requested_rx.await;
+        wire.write_message(UNCHOKE, &[]).await?;
+        let request = wire.read_message().await?;
+        let mut payload = request.payload[..8].to_vec();
+        payload.extend_from_slice(&piece);
+        wire.write_message(PIECE, &payload).await?;
+        std::future::pending::<std::io::Result<()>>().await
+    });
+    let peer = Peer {
+        addr: SocketAddr::from(([127, 0, 0, 2], 6881)),
+        peer_id: None,
+        source: PeerSource::Tracker,
+    };
+    assert!(client
+        .add_peer_stream(peer, client_end.compat())
+        .await
+        .is_ok());
+
+    timeout(TEST_TIMEOUT, client.download())
+        .await
+        .expect("download timed out")
+        .expect("download failed");
+    let (request, cancel) = timeout(TEST_TIMEOUT, slow)
+        .await
+        .expect("request was not cancelled")
+        .unwrap();
+
+    assert_eq!(request.id, Some(REQUEST));
+    assert_eq!(cancel.payload, request.payload);
+}
+
+#[tokio::test]
+async fn shares_connection_limit_between_clients() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file("shared.bin", test_data(50_000, 26), PIECE_LENGTH);
+    let config = ClientConfig::builder()
+        .session_limits(SessionLimits {
+            max_peers: 1,
+            ..SessionLimits::default()
+        })
+        .build();
+    let limits = ConnectionLimits::new(&config.session_limits);
+    let mut first = new_client(&torrent, dir.path().to_str().unwrap(), config.clone());
+    let mut second = new_client(&torrent, dir.path().to_str().unwrap(), config);
+    first.set_connection_limits(limits.clone());
+    second.set_connection_limits(limits.clone());
+
+    let seeder = MockPeer::seeder(&torrent, b"-MK0001-tttttttttttt");
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let serving = seeder.clone();
+    tokio::spawn(async move { serving.serve(peer_end).await });
+    assert!(first
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .is_ok());
+
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(async move { seeder.serve(peer_end).await });
+    assert!(second
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .is_err());
+    assert_eq!(limits.used(Slot::Connection), 1);
+
+    drop(first);
+    assert_eq!(limits.used(Slot::Connection), 0);
+}
+
+#[tokio::test]
+async fn reports_requests_in_flight_and_snubbing_peers() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file("snub.bin", test_data(40_000, 27), PIECE_LENGTH);
+    let config = ClientConfig::builder()
+        .max_peers(1)
+        .snub_timeout(Duration::from_millis(200))
+        .build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+    let state = client.state_handle();
+
+    // unchokes us, takes our requests and never answers them
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let info_hash = torrent.info_hash();
+    tokio::spawn(async move {
+        let mut wire = Wire::new(peer_end);
+        wire.read_handshake().await?;
+        wire.write_handshake(&info_hash, b"-MK0001-snubsnubsnub")
+            .await?;
+        wire.write_message(BITFIELD, &[0xc0]).await?;
+        wire.write_message(UNCHOKE, &[]).await?;
+        while wire.read_message().await.is_ok() {}
+        std::io::Result::Ok(())
+    });
+    client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .unwrap();
+
+    let snubbed = async {
+        loop {
+            let snapshot = state.snapshot();
+            if let Some(peer) = snapshot.peers.first().filter(|p| p.snubbed) {
+                assert!(peer.requests_in_flight > 0);
+                assert_eq!(peer.since_last_block, None);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    };
+    timeout(TEST_TIMEOUT, async {
+        tokio::select! {
+            _ = client.download() => panic!("download finished without data"),
+            _ = snubbed => {}
+        }
+    })
+    .await
+    .expect("peer was never reported snubbing us");
+}
+
+#[tokio::test]
+async fn drops_silent_peer_and_requests_its_blocks_elsewhere() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file("silent.bin", test_data(40_000, 26), PIECE_LENGTH);
+    let config = ClientConfig::builder()
+        .max_peers(1)
+        .peer_timeout(Duration::from_millis(300))
+        .build();
+    let mut client = new_client(&torrent, dir.path().to_str().unwrap(), config);
+    let mut events = client.events().subscribe();
+
+    // unchokes us, takes our requests and then says nothing at all
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let info_hash = torrent.info_hash();
+    let (requested_tx, requested_rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let mut wire = Wire::new(peer_end);
+        wire.read_handshake().await?;
+        wire.write_handshake(&info_hash, b"-MK0001-ssssssssssss")
+            .await?;
+        wire.write_message(BITFIELD, &[0xc0]).await?;
+        wire.write_message(UNCHOKE, &[]).await?;
+        while wire.read_message().await?.id != Some(REQUEST) {}
+        let _ = requested_tx.send(());
+        std::future::pending::<std::io::Result<()>>().await
+    });
+    assert!(client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .is_ok());
+
+    let disconnected = async {
+        requested_rx.await.unwrap();
+        while let Some(event) = events.next().await {
+            if matches!(event, TorrentEvent::PeerDisconnected(_)) {
+                break;
+            }
+        }
+    };
+    timeout(TEST_TIMEOUT, async {
+        tokio::select! {
+            _ = client.download() => panic!("download finished without data"),
+            _ = disconnected => {}
+        }
+    })
+    .await
+    .expect("silent peer was not dropped");
+
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let seeder = MockPeer::seeder(&torrent, b"-MK0001-tttttttttttt");
+    tokio::spawn(async move { seeder.serve(peer_end).await });
+    assert!(client
+        .add_peer_stream(local_peer(), client_end.compat())
+        .await
+        .is_ok());
+    timeout(TEST_TIMEOUT, client.download())
+        .await
+        .expect("download timed out")
+        .expect("download failed");
+    let downloaded = std::fs::read(dir.path().join("silent.bin")).unwrap();
+    assert_eq!(downloaded, torrent.data());
+}
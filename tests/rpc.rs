@@ -0,0 +1,124 @@
+mod common;
+
+use std::{net::SocketAddr, time::Duration};
+
+use common::{
+    peer::MockPeer,
+    torrent::{test_data, TestTorrent},
+    tracker::MockTracker,
+};
+use rustorrent::{
+    config::ClientConfig,
+    rpc,
+    runtime::{Runtime, TokioRuntime},
+    session::Session,
+};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+async fn request(addr: SocketAddr, method: &str, path: &str, body: &[u8]) -> (u16, Value) {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let head = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n",
+        method,
+        path,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await.unwrap();
+    stream.write_all(body).await.unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    let body = if body.is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_str(body).unwrap()
+    };
+    (status, body)
+}
+
+#[tokio::test]
+async fn controls_torrents_over_http() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file("rpc.bin", test_data(100_000, 14), 32 * 1024);
+    let (seeder, _) = MockPeer::seeder(&torrent, b"-MK0001-rrrrrrrrrrrr")
+        .listen()
+        .await;
+    let tracker = MockTracker::start(vec![seeder]).await;
+    let torrent = torrent.with_announce(&tracker.announce_url());
+
+    let config = ClientConfig::builder().max_peers(1).listen(false).build();
+    let mut session = Session::new(dir.path().to_str().unwrap(), config);
+    let listener = TokioRuntime
+        .listen(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { rpc::serve(&mut session, listener).await });
+
+    let (status, added) = request(addr, "POST", "/torrents", &torrent.to_bytes()).await;
+    assert_eq!(status, 201);
+    let info_hash = torrent
+        .info_hash()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    assert_eq!(added["info_hash"], info_hash.as_str());
+    assert_eq!(added["name"], "rpc.bin");
+    assert_eq!(added["total_length"], 100_000);
+
+    let path = format!("/torrents/{}", info_hash);
+    tokio::time::timeout(TEST_TIMEOUT, async {
+        loop {
+            let (status, info) = request(addr, "GET", &path, b"").await;
+            assert_eq!(status, 200);
+            if info["percent"] == 100.0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("download timed out");
+    let downloaded = std::fs::read(dir.path().join("rpc.bin")).unwrap();
+    assert_eq!(downloaded, torrent.data());
+
+    let (status, torrents) = request(addr, "GET", "/torrents", b"").await;
+    assert_eq!(status, 200);
+    assert_eq!(torrents.as_array().map(Vec::len), Some(1));
+
+    let (status, peers) = request(addr, "GET", &format!("{}/peers", path), b"").await;
+    assert_eq!(status, 200);
+    assert!(peers.is_array());
+
+    let (status, tracker_info) = request(addr, "GET", &format!("{}/tracker", path), b"").await;
+    assert_eq!(status, 200);
+    assert_eq!(tracker_info["announce"], tracker.announce_url().as_str());
+    assert!(tracker_info["last_announce"].is_string());
+    assert_eq!(tracker_info["failures"], 0);
+
+    let (status, _) = request(addr, "POST", &format!("{}/pause", path), b"").await;
+    assert_eq!(status, 204);
+
+    let (status, error) =
+        request(addr, "GET", &format!("/torrents/{}", "00".repeat(20)), b"").await;
+    assert_eq!(status, 404);
+    assert!(error["error"].is_string());
+
+    let (status, _) = request(addr, "POST", "/torrents", b"not a torrent").await;
+    assert_eq!(status, 400);
+
+    let (status, _) = request(addr, "DELETE", &path, b"").await;
+    assert_eq!(status, 204);
+    let (status, torrents) = request(addr, "GET", "/torrents", b"").await;
+    assert_eq!(status, 200);
+    assert_eq!(torrents.as_array().map(Vec::len), Some(0));
+    assert!(dir.path().join("rpc.bin").exists());
+}
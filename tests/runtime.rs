@@ -0,0 +1,136 @@
+mod common;
+
+use std::{future::Future, io, net::SocketAddr, ops::Range, thread, time::Duration};
+
+use common::{
+    peer::MockPeer,
+    torrent::{test_data, TestTorrent},
+};
+use futures::{channel::oneshot, executor::block_on};
+use rustorrent::{
+    client::Client,
+    config::{ClientConfig, TrackerConfig},
+    runtime::{Runtime, TcpListener, UdpSocket},
+    tracker::{Peer, PeerSource, Tracker},
+};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+struct NoListener;
+
+impl TcpListener for NoListener {
+    type TcpStream = futures::io::Cursor<Vec<u8>>;
+
+    async fn accept(&self) -> io::Result<(Self::TcpStream, SocketAddr)> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+struct NoUdp;
+
+impl UdpSocket for NoUdp {
+    async fn send_to(&self, _buf: &[u8], _addr: SocketAddr) -> io::Result<usize> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    async fn recv_from(&self, _buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+/// A runtime with no reactor at all: every task gets its own thread.
+#[derive(Clone)]
+struct ThreadRuntime;
+
+impl Runtime for ThreadRuntime {
+    type TcpStream = futures::io::Cursor<Vec<u8>>;
+    type TcpListener = NoListener;
+    type UdpSocket = NoUdp;
+
+    fn spawn<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        thread::spawn(move || block_on(future));
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            thread::sleep(duration);
+            let _ = tx.send(());
+        });
+        let _ = rx.await;
+    }
+
+    async fn connect(&self, _addr: SocketAddr) -> io::Result<Self::TcpStream> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    async fn listen(&self, _addr: SocketAddr) -> io::Result<Self::TcpListener> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    async fn bind_udp(&self, _addr: SocketAddr) -> io::Result<Self::UdpSocket> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    async fn resolve(&self, _host: &str) -> io::Result<Vec<SocketAddr>> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    async fn http_get(&self, _url: &str) -> io::Result<Vec<u8>> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    async fn tracker_get(&self, _url: &str, _config: &TrackerConfig) -> io::Result<Vec<u8>> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    async fn http_get_range(&self, _url: &str, _range: Range<u64>) -> io::Result<Vec<u8>> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+#[test]
+fn downloads_without_tokio_runtime() {
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file("threads.bin", test_data(70_000, 4), 32 * 1024);
+    let config = ClientConfig::builder().max_peers(1).build();
+    let tracker = Tracker::new(torrent.to_bencode(), &config).unwrap();
+    let mut client = Client::with_runtime(
+        tracker,
+        dir.path().to_str().unwrap().to_string(),
+        config,
+        ThreadRuntime,
+    )
+    .expect("failed to open torrent files");
+
+    // duplex streams only need wakers, not a tokio reactor
+    let (client_end, peer_end) = tokio::io::duplex(64 * 1024);
+    let seeder = MockPeer::seeder(&torrent, b"-MK0001-eeeeeeeeeeee");
+    thread::spawn(move || block_on(seeder.serve(peer_end)));
+
+    let peer = Peer {
+        addr: SocketAddr::from(([127, 0, 0, 1], 6881)),
+        peer_id: None,
+        source: PeerSource::Tracker,
+    };
+    block_on(async {
+        assert!(client
+            .add_peer_stream(peer, client_end.compat())
+            .await
+            .is_ok());
+        assert!(client.download().await.is_ok());
+    });
+
+    let downloaded = std::fs::read(dir.path().join("threads.bin")).unwrap();
+    assert_eq!(downloaded, torrent.data());
+}
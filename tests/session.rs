@@ -0,0 +1,169 @@
+mod common;
+
+use std::{collections::BTreeMap, error::Error, time::Duration};
+
+use common::{
+    peer::MockPeer,
+    torrent::{test_data, TestTorrent},
+    tracker::MockTracker,
+};
+use futures::{channel::mpsc::UnboundedReceiver, AsyncReadExt, StreamExt};
+use rustorrent::{
+    bencode::{BencodeString, BencodeValue},
+    config::ClientConfig,
+    session::{Session, SessionError, TorrentEvent},
+};
+use tokio::time::timeout;
+
+const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+const PIECE_LENGTH: u64 = 32 * 1024;
+
+async fn seeded_torrent(name: &str, seed: u64) -> (TestTorrent, MockTracker) {
+    let torrent = TestTorrent::single_file(name, test_data(150_000, seed), PIECE_LENGTH);
+    let (seeder, _) = MockPeer::seeder(&torrent, b"-MK0001-kkkkkkkkkkkk")
+        .listen()
+        .await;
+    let tracker = MockTracker::start(vec![seeder]).await;
+    (torrent.with_announce(&tracker.announce_url()), tracker)
+}
+
+async fn wait_for(events: &mut UnboundedReceiver<TorrentEvent>, expected: TorrentEvent) {
+    timeout(TEST_TIMEOUT, async {
+        while let Some(event) = events.next().await {
+            if event == expected {
+                return;
+            }
+        }
+        panic!("event stream ended before {:?}", expected);
+    })
+    .await
+    .unwrap_or_else(|_| panic!("timed out waiting for {:?}", expected));
+}
+
+#[tokio::test]
+async fn downloads_torrent_added_from_bytes() {
+    let dir = tempfile::tempdir().unwrap();
+    let (torrent, _tracker) = seeded_torrent("session.bin", 10).await;
+    let config = ClientConfig::builder().max_peers(1).listen(false).build();
+    let mut session = Session::new(dir.path().to_str().unwrap(), config);
+
+    let handle = session.add_torrent(torrent.to_bytes()).unwrap();
+    let mut events = handle.events();
+    assert_eq!(handle.info_hash().as_bytes(), torrent.info_hash());
+    assert_eq!(session.torrents().len(), 1);
+
+    wait_for(&mut events, TorrentEvent::Finished).await;
+    assert_eq!(handle.progress(), 1.0);
+    let downloaded = std::fs::read(dir.path().join("session.bin")).unwrap();
+    assert_eq!(downloaded, torrent.data());
+}
+
+#[tokio::test]
+async fn pauses_and_resumes() {
+    let dir = tempfile::tempdir().unwrap();
+    let (torrent, _tracker) = seeded_torrent("paused.bin", 11).await;
+    let path = dir.path().join("paused.torrent");
+    std::fs::write(&path, torrent.to_bytes()).unwrap();
+    let config = ClientConfig::builder().max_peers(1).listen(false).build();
+    let mut session = Session::new(dir.path().to_str().unwrap(), config);
+
+    let handle = session.add_torrent(path).unwrap();
+    let mut events = handle.events();
+    handle.pause();
+    wait_for(&mut events, TorrentEvent::Paused).await;
+    assert!(handle.is_paused());
+
+    handle.resume();
+    wait_for(&mut events, TorrentEvent::Resumed).await;
+    wait_for(&mut events, TorrentEvent::Finished).await;
+    assert!(!handle.is_paused());
+    let downloaded = std::fs::read(dir.path().join("paused.bin")).unwrap();
+    assert_eq!(downloaded, torrent.data());
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn pauses_when_the_disk_is_full() {
+    let dir = tempfile::tempdir().unwrap();
+    let (torrent, _tracker) = seeded_torrent("full.bin", 16).await;
+    // every write to /dev/full fails with ENOSPC
+    std::os::unix::fs::symlink("/dev/full", dir.path().join("full.bin")).unwrap();
+    let config = ClientConfig::builder().max_peers(1).listen(false).build();
+    let mut session = Session::new(dir.path().to_str().unwrap(), config);
+
+    let handle = session.add_torrent(torrent.to_bytes()).unwrap();
+    let mut events = handle.events();
+    let failure = timeout(TEST_TIMEOUT, async {
+        loop {
+            match events.next().await {
+                Some(TorrentEvent::Failed(reason)) => return reason,
+                Some(_) => {}
+                None => panic!("event stream ended"),
+            }
+        }
+    })
+    .await
+    .expect("disk failure not reported");
+    assert!(failure.contains("failed to write"), "{}", failure);
+    wait_for(&mut events, TorrentEvent::Paused).await;
+    assert!(handle.is_paused());
+    assert_eq!(session.torrents().len(), 1);
+}
+
+#[tokio::test]
+async fn removes_torrent_and_deletes_its_data() {
+    let dir = tempfile::tempdir().unwrap();
+    let (torrent, _tracker) = seeded_torrent("removed.bin", 15).await;
+    let config = ClientConfig::builder().max_peers(1).listen(false).build();
+    let mut session = Session::new(dir.path().to_str().unwrap(), config);
+
+    let handle = session.add_torrent(torrent.to_bytes()).unwrap();
+    let mut events = handle.events();
+    wait_for(&mut events, TorrentEvent::Finished).await;
+    assert!(dir.path().join("removed.bin").exists());
+
+    assert!(session.remove_torrent(&handle, true));
+    wait_for(&mut events, TorrentEvent::Removed).await;
+    assert!(session.torrents().is_empty());
+    assert!(!session.remove_torrent(&handle, true));
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn streams_file_while_it_downloads() {
+    let dir = tempfile::tempdir().unwrap();
+    let (torrent, _tracker) = seeded_torrent("stream.bin", 14).await;
+    let config = ClientConfig::builder().max_peers(1).listen(false).build();
+    let mut session = Session::new(dir.path().to_str().unwrap(), config);
+
+    let handle = session.add_torrent(torrent.to_bytes()).unwrap();
+    assert!(handle.read_stream(1).is_none());
+    let mut stream = handle.read_stream(0).unwrap();
+    assert_eq!(stream.len(), torrent.data().len() as u64);
+
+    let mut read = Vec::new();
+    timeout(TEST_TIMEOUT, stream.read_to_end(&mut read))
+        .await
+        .expect("stream timed out")
+        .unwrap();
+    assert_eq!(read, torrent.data());
+}
+
+#[tokio::test]
+async fn reports_why_a_torrent_was_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut session = Session::new(dir.path().to_str().unwrap(), ClientConfig::default());
+
+    let garbage = session.add_torrent(&b"not bencode"[..]).err().unwrap();
+    assert!(matches!(garbage, SessionError::Bencode(_)));
+
+    let no_info = BencodeValue::Dict(BTreeMap::from([(
+        "announce".to_string(),
+        BencodeValue::String(BencodeString::String("http://tracker".to_string())),
+    )]));
+    let error: Box<dyn Error + Send + Sync> =
+        session.add_torrent(no_info.encode()).err().unwrap().into();
+    let metainfo = error.source().unwrap();
+    assert!(metainfo.to_string().starts_with("InvalidMetainfo"));
+    assert!(metainfo.source().is_some());
+}
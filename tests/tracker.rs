@@ -0,0 +1,529 @@
+mod common;
+
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use common::{
+    torrent::{test_data, TestTorrent},
+    tracker::MockTracker,
+};
+use rustorrent::{
+    bencode::{BencodeString, BencodeValue},
+    client::Client,
+    config::{ClientConfig, ProxyConfig, ProxyKind, TrackerConfig},
+    runtime::TokioRuntime,
+    tracker::{ScrapeStats, Tracker, TrackerError},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+};
+
+const DEAD_TRACKER: &str = "http://127.0.0.1:1/announce";
+
+fn tracker(tiers: Vec<Vec<String>>) -> Tracker {
+    let torrent =
+        TestTorrent::single_file("tiers.bin", test_data(1024, 6), 1024).with_announce_list(tiers);
+    Tracker::new(torrent.to_bencode(), &ClientConfig::default()).unwrap()
+}
+
+#[tokio::test]
+async fn falls_back_to_next_tier() {
+    let peer = SocketAddr::from(([10, 0, 0, 1], 51413));
+    let live = MockTracker::start(vec![peer]).await;
+    let mut tracker = tracker(vec![
+        vec![DEAD_TRACKER.to_string()],
+        vec![live.announce_url()],
+    ]);
+
+    let peers = tracker.get_peers(&TokioRuntime).await.ok().unwrap();
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].addr, peer);
+    assert_eq!(live.announces().len(), 1);
+
+    let status = tracker.status();
+    assert_eq!(status.announce, live.announce_url());
+    assert!(status.last_error.is_none());
+}
+
+#[tokio::test]
+async fn backs_off_failing_trackers_and_honours_min_interval() {
+    let mut dead = tracker(vec![vec![DEAD_TRACKER.to_string()]]);
+    assert!(dead.retry_delay().is_none());
+    assert!(dead.get_peers(&TokioRuntime).await.is_err());
+    let first = dead.retry_delay().unwrap();
+    assert!(dead.get_peers(&TokioRuntime).await.is_err());
+    assert_eq!(dead.retry_delay(), Some(first * 2));
+
+    let (response, _) = BencodeValue::parse(&MockTracker::response(&[])).unwrap();
+    let BencodeValue::Dict(mut response) = response else {
+        unreachable!()
+    };
+    response.insert("interval".to_string(), BencodeValue::Int(10));
+    response.insert("min interval".to_string(), BencodeValue::Int(120));
+    let live = MockTracker::start_with_response(BencodeValue::Dict(response).encode()).await;
+    let mut tracker = tracker(vec![vec![live.announce_url()]]);
+    tracker.get_peers(&TokioRuntime).await.ok().unwrap();
+    assert!(tracker.retry_delay().is_none());
+    assert_eq!(tracker.min_interval(), Some(Duration::from_secs(120)));
+    assert_eq!(tracker.interval(), Some(Duration::from_secs(120)));
+}
+
+#[tokio::test]
+async fn client_waits_out_min_interval_before_announcing_again() {
+    let (response, _) = BencodeValue::parse(&MockTracker::response(&[])).unwrap();
+    let BencodeValue::Dict(mut response) = response else {
+        unreachable!()
+    };
+    response.insert("interval".to_string(), BencodeValue::Int(1800));
+    response.insert("min interval".to_string(), BencodeValue::Int(1800));
+    let live = MockTracker::start_with_response(BencodeValue::Dict(response).encode()).await;
+
+    // nobody to download from, so the client keeps looking for peers
+    let dir = tempfile::tempdir().unwrap();
+    let torrent = TestTorrent::single_file("lonely.bin", test_data(4096, 7), 1024)
+        .with_announce(&live.announce_url());
+    let config = ClientConfig::builder().listen(false).build();
+    let tracker = Tracker::new(torrent.to_bencode(), &config).unwrap();
+    let mut client: Client<TokioRuntime> =
+        Client::new(tracker, dir.path().to_str().unwrap().to_string(), config).unwrap();
+
+    let result = tokio::time::timeout(Duration::from_secs(2), client.download()).await;
+    assert!(result.is_err());
+    assert_eq!(live.announces().len(), 1, "{:?}", live.announces());
+}
+
+#[tokio::test]
+async fn sends_key_and_echoes_tracker_id() {
+    let (response, _) = BencodeValue::parse(&MockTracker::response(&[])).unwrap();
+    let BencodeValue::Dict(mut response) = response else {
+        unreachable!()
+    };
+    response.insert(
+        "tracker id".to_string(),
+        BencodeValue::String(BencodeString::String("abc 123".to_string())),
+    );
+    let live = MockTracker::start_with_response(BencodeValue::Dict(response).encode()).await;
+    let mut tracker = tracker(vec![vec![live.announce_url()]]);
+
+    tracker.get_peers(&TokioRuntime).await.ok().unwrap();
+    tracker.get_peers(&TokioRuntime).await.ok().unwrap();
+
+    let announces = live.announces();
+    let key = |query: &str| {
+        query
+            .split('&')
+            .find(|param| param.starts_with("key="))
+            .map(str::to_string)
+    };
+    assert!(key(&announces[0]).is_some());
+    assert_eq!(key(&announces[0]), key(&announces[1]));
+    assert!(!announces[0].contains("trackerid="));
+    assert!(announces[1].contains("&trackerid=abc+123"));
+}
+
+#[tokio::test]
+async fn sends_user_agent_and_gives_up_after_timeout() {
+    // reads the request head and then never answers
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (head_tx, mut head_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut head = vec![0; 4096];
+        let len = stream.read(&mut head).await.unwrap();
+        head_tx
+            .send(String::from_utf8_lossy(&head[..len]).to_lowercase())
+            .unwrap();
+        std::future::pending::<()>().await;
+    });
+
+    let torrent = TestTorrent::single_file("agent.bin", test_data(1024, 7), 1024)
+        .with_announce(&format!("http://{}/announce", addr));
+    let config = ClientConfig::builder()
+        .tracker(TrackerConfig {
+            timeout: Duration::from_millis(300),
+            user_agent: "test-agent/1.0".to_string(),
+            ..TrackerConfig::default()
+        })
+        .build();
+    let mut tracker = Tracker::new(torrent.to_bencode(), &config).unwrap();
+
+    let result = tokio::time::timeout(Duration::from_secs(5), tracker.get_peers(&TokioRuntime))
+        .await
+        .expect("announce ignored the tracker timeout");
+    assert!(result.is_err());
+    let head = head_rx.recv().await.unwrap();
+    assert!(head.contains("user-agent: test-agent/1.0"), "{}", head);
+}
+
+/// A SOCKS5 proxy without authentication that relays to IPv4 targets,
+/// counting the connections it makes.
+async fn socks5_proxy() -> (SocketAddr, tokio::sync::mpsc::UnboundedReceiver<SocketAddr>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (targets_tx, targets_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (mut client, _) = listener.accept().await.unwrap();
+            let targets = targets_tx.clone();
+            tokio::spawn(async move {
+                let mut greeting = [0u8; 3];
+                client.read_exact(&mut greeting).await.unwrap();
+                client.write_all(&[5, 0]).await.unwrap();
+                let mut request = [0u8; 10];
+                client.read_exact(&mut request).await.unwrap();
+                assert_eq!(&request[..4], &[5, 1, 0, 1]);
+                let ip: [u8; 4] = request[4..8].try_into().unwrap();
+                let target = SocketAddr::from((ip, u16::from_be_bytes([request[8], request[9]])));
+                let _ = targets.send(target);
+
+                let mut upstream = TcpStream::connect(target).await.unwrap();
+                client
+                    .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                    .await
+                    .unwrap();
+                let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+            });
+        }
+    });
+    (addr, targets_rx)
+}
+
+#[tokio::test]
+async fn announces_through_socks5_proxy() {
+    let peer = SocketAddr::from(([10, 0, 0, 1], 51413));
+    let live = MockTracker::start(vec![peer]).await;
+    let (proxy_addr, mut targets) = socks5_proxy().await;
+    let torrent = TestTorrent::single_file("proxied.bin", test_data(1024, 6), 1024)
+        .with_announce_list(vec![vec![live.announce_url()]]);
+    let config = ClientConfig::builder()
+        .proxy(ProxyConfig {
+            kind: ProxyKind::Socks5,
+            addr: proxy_addr,
+            credentials: None,
+        })
+        .build();
+    let mut tracker = Tracker::new(torrent.to_bencode(), &config).unwrap();
+
+    let peers = tracker.get_peers(&TokioRuntime).await.ok().unwrap();
+    assert_eq!(peers[0].addr, peer);
+    assert_eq!(live.announces().len(), 1);
+    let target = targets.recv().await.unwrap();
+    assert_eq!(live.announce_url(), format!("http://{}/announce", target));
+}
+
+#[tokio::test]
+async fn parses_ipv4_and_ipv6_compact_peers() {
+    let v4 = SocketAddr::from(([10, 0, 0, 1], 51413));
+    let v6 = SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1], 6881));
+    let live = MockTracker::start(vec![v4, v6]).await;
+    let mut tracker = tracker(vec![vec![live.announce_url()]]);
+
+    let peers = tracker.get_peers(&TokioRuntime).await.ok().unwrap();
+    let addrs = peers.iter().map(|peer| peer.addr).collect::<Vec<_>>();
+    assert_eq!(addrs, vec![v4, v6]);
+    assert!(live.announces()[0].contains("compact=1"));
+}
+
+#[tokio::test]
+async fn announces_mapped_external_address() {
+    let live = MockTracker::start(Vec::new()).await;
+    let mut tracker = tracker(vec![vec![live.announce_url()]]);
+    tracker.set_external_addr(Some(40000), Some("203.0.113.7".parse().unwrap()));
+
+    tracker.get_peers(&TokioRuntime).await.ok().unwrap();
+    let query = &live.announces()[0];
+    assert!(query.contains("&port=40000"));
+    assert!(query.contains("&ip=203.0.113.7"));
+}
+
+#[tokio::test]
+async fn announces_configured_ipv4_and_ipv6_addresses() {
+    let live = MockTracker::start(Vec::new()).await;
+    let torrent = TestTorrent::single_file("dual.bin", test_data(1024, 28), 1024)
+        .with_announce(&live.announce_url());
+    let config = ClientConfig::builder()
+        .announce_ip("198.51.100.4".parse().unwrap())
+        .announce_ipv6("2001:db8::4".parse().unwrap())
+        .build();
+    let mut tracker = Tracker::new(torrent.to_bencode(), &config).unwrap();
+    // configured addresses win over mapped and detected ones
+    tracker.set_external_addr(None, Some("203.0.113.7".parse().unwrap()));
+    tracker.set_ipv6(Some("2001:db8::5".parse().unwrap()));
+
+    tracker.get_peers(&TokioRuntime).await.ok().unwrap();
+    let query = &live.announces()[0];
+    assert!(
+        query.contains("&ip=198.51.100.4&ipv6=2001%3Adb8%3A%3A4&"),
+        "{}",
+        query
+    );
+}
+
+#[tokio::test]
+async fn promotes_working_tracker_within_tier() {
+    let live = MockTracker::start(Vec::new()).await;
+    let mut tracker = tracker(vec![vec![DEAD_TRACKER.to_string(), live.announce_url()]]);
+
+    assert!(tracker.get_peers(&TokioRuntime).await.is_ok());
+    assert_eq!(
+        tracker.tiers(),
+        &[vec![live.announce_url(), DEAD_TRACKER.to_string()]]
+    );
+
+    // the working tracker is now tried first, so the dead one is skipped
+    assert!(tracker.get_peers(&TokioRuntime).await.is_ok());
+    assert_eq!(live.announces().len(), 2);
+}
+
+#[tokio::test]
+async fn accepts_gzipped_responses_and_reports_warnings() {
+    let peer = SocketAddr::from(([10, 0, 0, 2], 6881));
+    let (response, _) = BencodeValue::parse(&MockTracker::response(&[peer])).unwrap();
+    let BencodeValue::Dict(mut response) = response else {
+        unreachable!()
+    };
+    response.insert(
+        "warning message".to_string(),
+        BencodeValue::String(BencodeString::String("upgrade your client".to_string())),
+    );
+    let mut gzipped = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gzipped
+        .write_all(&BencodeValue::Dict(response).encode())
+        .unwrap();
+    let live = MockTracker::start_with_reply(
+        "200 OK",
+        "Content-Encoding: gzip\r\n",
+        gzipped.finish().unwrap(),
+    )
+    .await;
+    let mut tracker = tracker(vec![vec![live.announce_url()]]);
+
+    let peers = tracker.get_peers(&TokioRuntime).await.ok().unwrap();
+    assert_eq!(peers[0].addr, peer);
+    assert_eq!(
+        tracker.status().warning.as_deref(),
+        Some("upgrade your client")
+    );
+}
+
+#[tokio::test]
+async fn reports_http_errors_and_honours_retry_after() {
+    let live = MockTracker::start_with_reply(
+        "403 Forbidden",
+        "Retry-After: 3600\r\n",
+        b"unregistered torrent".to_vec(),
+    )
+    .await;
+    let mut tracker = tracker(vec![vec![live.announce_url()]]);
+
+    let Err(TrackerError::InvalidResponse(e)) = tracker.get_peers(&TokioRuntime).await else {
+        panic!("expected an http error");
+    };
+    assert_eq!(e.status, 403);
+    assert_eq!(e.retry_after, Some(Duration::from_secs(3600)));
+    assert!(TrackerError::InvalidResponse(e).is_permanent());
+    assert_eq!(tracker.retry_delay(), Some(Duration::from_secs(3600)));
+}
+
+#[tokio::test]
+async fn reports_error_when_every_tracker_fails() {
+    let mut tracker = tracker(vec![
+        vec![DEAD_TRACKER.to_string()],
+        vec!["http://127.0.0.1:2/announce".to_string()],
+    ]);
+
+    assert!(tracker.get_peers(&TokioRuntime).await.is_err());
+    assert!(tracker.status().last_error.is_some());
+}
+
+#[tokio::test]
+async fn retries_server_errors_over_one_kept_alive_connection() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let requests = Arc::new(AtomicUsize::new(0));
+    let peer = SocketAddr::from(([10, 0, 0, 1], 6881));
+    tokio::spawn({
+        let connections = connections.clone();
+        let requests = requests.clone();
+        async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                connections.fetch_add(1, Ordering::SeqCst);
+                let requests = requests.clone();
+                tokio::spawn(async move {
+                    let mut head = Vec::new();
+                    let mut buf = [0; 1024];
+                    loop {
+                        let Ok(len @ 1..) = stream.read(&mut buf).await else {
+                            return;
+                        };
+                        head.extend_from_slice(&buf[..len]);
+                        if !head.ends_with(b"\r\n\r\n") {
+                            continue;
+                        }
+                        head.clear();
+                        // the first request fails, as an overloaded tracker's would
+                        let (status, body) = match requests.fetch_add(1, Ordering::SeqCst) {
+                            0 => ("503 Service Unavailable", Vec::new()),
+                            _ => ("200 OK", MockTracker::response(&[peer])),
+                        };
+                        let head = format!(
+                            "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n",
+                            status,
+                            body.len()
+                        );
+                        stream.write_all(head.as_bytes()).await.unwrap();
+                        stream.write_all(&body).await.unwrap();
+                    }
+                });
+            }
+        }
+    });
+
+    let mut tracker = tracker(vec![vec![format!("http://{}/announce", addr)]]);
+    let peers = tracker.get_peers(&TokioRuntime).await.unwrap();
+    assert_eq!(peers[0].addr, peer);
+    tracker.announce(&TokioRuntime, None).await.unwrap();
+
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+}
+
+fn scrape_response(info_hash: &[u8]) -> Vec<u8> {
+    let stats = BencodeValue::Dict(BTreeMap::from([
+        ("complete".to_string(), BencodeValue::Int(5)),
+        ("incomplete".to_string(), BencodeValue::Int(3)),
+        ("downloaded".to_string(), BencodeValue::Int(42)),
+    ]));
+    let mut files = Vec::new();
+    files.extend_from_slice(b"d5:filesd20:");
+    files.extend_from_slice(info_hash);
+    files.extend_from_slice(&stats.encode());
+    files.extend_from_slice(b"ee");
+    files
+}
+
+#[test]
+fn derives_scrape_url_from_announce() {
+    assert_eq!(
+        Tracker::scrape_url("http://example.com/announce").as_deref(),
+        Some("http://example.com/scrape")
+    );
+    assert_eq!(
+        Tracker::scrape_url("http://example.com/x/announce.php?passkey=1").as_deref(),
+        Some("http://example.com/x/scrape.php?passkey=1")
+    );
+    assert_eq!(Tracker::scrape_url("http://example.com/a"), None);
+    assert_eq!(Tracker::scrape_url("http://example.com/announce/x"), None);
+}
+
+#[tokio::test]
+async fn scrapes_http_tracker() {
+    let torrent = TestTorrent::single_file("scrape.bin", test_data(1024, 7), 1024);
+    let live = MockTracker::start_with_response(scrape_response(&torrent.info_hash())).await;
+    let torrent = torrent.with_announce(&live.announce_url());
+    let tracker = Tracker::new(torrent.to_bencode(), &ClientConfig::default()).unwrap();
+
+    let stats = tracker.scrape(&TokioRuntime).await.ok().unwrap();
+    assert_eq!(
+        stats,
+        ScrapeStats {
+            complete: 5,
+            incomplete: 3,
+            downloaded: 42
+        }
+    );
+
+    let requests = live.requests();
+    assert_eq!(requests.len(), 1);
+    assert!(requests[0].starts_with("/scrape?info_hash="));
+}
+
+#[tokio::test]
+async fn scrapes_every_tracker() {
+    let torrent = TestTorrent::single_file("scrape_all.bin", test_data(1024, 9), 1024);
+    let live = MockTracker::start_with_response(scrape_response(&torrent.info_hash())).await;
+    let dead = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dead_announce = format!("http://{}/announce", dead.local_addr().unwrap());
+    drop(dead);
+    let torrent = torrent.with_announce_list(vec![
+        vec![
+            live.announce_url(),
+            "wss://tracker.example/announce".to_string(),
+        ],
+        vec![dead_announce.clone()],
+    ]);
+    let tracker = Tracker::new(torrent.to_bencode(), &ClientConfig::default()).unwrap();
+
+    let scrapes = tracker.scrape_all(&TokioRuntime).await;
+    assert_eq!(scrapes.len(), 2);
+    let results = scrapes
+        .iter()
+        .map(|(announce, result)| (announce.as_str(), result.as_ref().ok()))
+        .collect::<Vec<_>>();
+    assert!(results.contains(&(dead_announce.as_str(), None)));
+    assert!(results.contains(&(
+        live.announce_url().as_str(),
+        Some(&ScrapeStats {
+            complete: 5,
+            incomplete: 3,
+            downloaded: 42
+        })
+    )));
+}
+
+#[tokio::test]
+async fn scrapes_udp_tracker() {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let announce = format!("udp://{}/announce", socket.local_addr().unwrap());
+    let torrent = TestTorrent::single_file("udp.bin", test_data(1024, 8), 1024);
+    let info_hash = torrent.info_hash();
+    let torrent = torrent.with_announce(&announce);
+    let tracker = Tracker::new(torrent.to_bencode(), &ClientConfig::default()).unwrap();
+
+    let server = tokio::spawn(async move {
+        let connection_id = 0x1122_3344_5566_7788u64;
+        let mut buf = [0u8; 1024];
+
+        let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!(len, 16);
+        assert_eq!(buf[0..8], 0x41727101980u64.to_be_bytes());
+        let mut response = vec![0, 0, 0, 0];
+        response.extend_from_slice(&buf[12..16]);
+        response.extend_from_slice(&connection_id.to_be_bytes());
+        socket.send_to(&response, from).await.unwrap();
+
+        let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!(len, 36);
+        assert_eq!(buf[0..8], connection_id.to_be_bytes());
+        assert_eq!(buf[8..12], 2u32.to_be_bytes());
+        assert_eq!(buf[16..36], info_hash);
+        let mut response = vec![0, 0, 0, 2];
+        response.extend_from_slice(&buf[12..16]);
+        for value in [7u32, 100, 2] {
+            response.extend_from_slice(&value.to_be_bytes());
+        }
+        socket.send_to(&response, from).await.unwrap();
+    });
+
+    let stats = tracker.scrape(&TokioRuntime).await.ok().unwrap();
+    server.await.unwrap();
+    assert_eq!(
+        stats,
+        ScrapeStats {
+            complete: 7,
+            incomplete: 2,
+            downloaded: 100
+        }
+    );
+}